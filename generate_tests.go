@@ -26,7 +26,7 @@ func writeTest(outputFile *os.File, fileInfo *fs.FileInfo, moduleName string, in
 
 	outputFile.WriteString("\n")
 	writeLine(outputFile, "#[test]", indentationLevel)
-	writeLine(outputFile, fmt.Sprintf("fn %s_test() -> VMResult {", name), indentationLevel)
+	writeLine(outputFile, fmt.Sprintf("fn %s_test() -> Result<(), LoxError> {", name), indentationLevel)
 
 	// Write test body.
 	var path string
@@ -67,23 +67,23 @@ func writeTest(outputFile *os.File, fileInfo *fs.FileInfo, moduleName string, in
 
 	if len(assertValues) > 0 {
 		// This test expects certain values to be printed.
-		writeLine(outputFile, "vm.interpret(source)?;", indentationLevel+1)
+		writeLine(outputFile, "vm.capture_output = true;", indentationLevel+1)
+		writeLine(outputFile, "let mut result = vm.interpret(source)?;", indentationLevel+1)
 
 		// Write one assertion for each expected value.
 		for i := len(assertValues) - 1; i >= 0; i-- {
 			writeLine(outputFile, "assert_eq!(", indentationLevel+1)
 			writeLine(outputFile, fmt.Sprintf("\"%s\",", assertValues[i]), indentationLevel+2)
-			writeLine(outputFile, "vm.printed_values.pop().unwrap().to_string()", indentationLevel+2)
+			writeLine(outputFile, "result.printed_values.pop().unwrap().to_string()", indentationLevel+2)
 			writeLine(outputFile, ");", indentationLevel+1)
 		}
 
 	} else if len(assertError) > 0 {
 		// This test expects a specific error.
-		writeLine(outputFile, "#[allow(unused_must_use)]", indentationLevel+1)
-		writeLine(outputFile, "{ vm.interpret(source); }", indentationLevel+1)
+		writeLine(outputFile, "let error = vm.interpret(source).unwrap_err();", indentationLevel+1)
 		writeLine(outputFile, "assert_eq!(", indentationLevel+1)
 		writeLine(outputFile, fmt.Sprintf("\"%s\",", assertError), indentationLevel+2)
-		writeLine(outputFile, "vm.latest_error_message", indentationLevel+2)
+		writeLine(outputFile, "error.message", indentationLevel+2)
 		writeLine(outputFile, ");", indentationLevel+1)
 	}
 
@@ -1,21 +1,34 @@
-use crate::value::value::Value;
+use std::rc::Rc;
+
+use crate::value::{string::LoxString, value::Value};
 
 /// The set of the VM's instruction codes.
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Instruction {
     /// The number of arguments with which the function is being called.
     OpCall(usize),
     /// The index of the constant in the [Chunk]'s constants array.
     OpConstant(usize),
     OpNil,
+    /// Pushes the number 0, without a constant.
+    OpZero,
+    /// Pushes the number 1, without a constant.
+    OpOne,
+    /// The small integer to push as a number, without a constant.
+    OpSmallInt(i8),
     OpTrue,
-    /// The index of the variable name in the [Chunk]'s constants array.
+    /// The slot of the global variable, named in [Chunk::globals].
     OpDefineGlobal(usize),
+    /// The offset used to calculate the bytecode instruction to jump to,
+    /// skipping over the deferred code that follows.
+    OpDefer(usize),
+    OpEndDefer,
     OpEqual,
     OpFalse,
-    /// The index of the variable name in the [Chunk]'s constants array.
+    /// The slot of the global variable, named in [Chunk::globals].
     OpGetGlobal(usize),
-    /// The index of the variable name in the [Chunk]'s constants array.
+    /// The slot of the global variable, named in [Chunk::globals].
     OpSetGlobal(usize),
     /// The index of the variable in the [CallFrame]'s part of the stack.
     OpGetLocal(usize),
@@ -38,10 +51,74 @@ pub enum Instruction {
     OpNegate,
     OpPrint,
     OpReturn,
+    OpSlice,
+    /// An instruction registered with [crate::vm::vm::VM::register_extension]:
+    /// the extension's id, and the number of arguments it pops.
+    OpExtension(u8, u8),
+    /// The index of the class name in the [Chunk]'s constants array.
+    OpClass(usize),
+    /// The index of the property name in the [Chunk]'s constants array.
+    OpGetProperty(usize),
+    /// The index of the property name in the [Chunk]'s constants array.
+    OpSetProperty(usize),
+    /// The index of the method name in the [Chunk]'s constants array.
+    /// Adds the function at the top of the stack as a method of the class below it.
+    OpMethod(usize),
+    /// Copies the methods of the superclass below the top of the stack into the class at the top.
+    OpInherit,
+    /// The index of the method name in the [Chunk]'s constants array.
+    /// Pushes the superclass's method bound to `this`.
+    OpGetSuper(usize),
+    /// The number of elements, popped into a new list.
+    OpBuildList(usize),
+    /// Pushes the element of the list below the top of the stack at the index on top.
+    OpIndexGet,
+    /// Sets the element of a list at an index to the value on top of the stack.
+    OpIndexSet,
+    /// The offset used to calculate the bytecode instruction to jump to if the value on top of
+    /// the stack is nil, which is left there.
+    OpJumpIfNil(usize),
+    /// The offset used to calculate the bytecode instruction of the catch block, where a value
+    /// thrown before the matching [Instruction::OpPopCatch] is caught.
+    OpSetupCatch(usize),
+    /// Ends the try block of the latest [Instruction::OpSetupCatch], which then catches nothing.
+    OpPopCatch,
+    /// Throws the value on top of the stack.
+    OpThrow,
+    /// The index of the property name in the [Chunk]'s constants array.
+    /// Adds the function at the top of the stack as a getter of the class below it.
+    OpGetter(usize),
+    /// The index of the property name in the [Chunk]'s constants array.
+    /// Adds the function at the top of the stack as a setter of the class below it.
+    OpSetter(usize),
+    /// Adds the function at the top of the stack as the field initializer of the class below it.
+    OpFields,
+    /// Whether the instance below the top of the stack was created from the class at the top,
+    /// or one of its subclasses.
+    OpIs,
+    /// The number of arguments with which the function is being called, in a `return`.
+    /// Reuses the [crate::vm::call_frame::CallFrame] of the returning function when possible,
+    /// and otherwise calls like [Instruction::OpCall].
+    OpTailCall(usize),
+    /// The number of instructions to jump over if the value on top of the stack is not equal to
+    /// the one below it. Pops only the top value, so that the one below can be compared again.
+    OpJumpIfNotEqual(usize),
+    /// The offset used to calculate the bytecode instruction to jump to if the value on top of
+    /// the stack is truthy, which is left there. Written by [crate::optimizer::optimize] in place
+    /// of an [Instruction::OpNot] and an [Instruction::OpJumpIfFalse].
+    OpJumpIfTrue(usize),
+    /// The indexes of two variables in the [CallFrame]'s part of the stack, which are added like
+    /// [Instruction::OpAdd] would. Written by [crate::optimizer::optimize] in place of two
+    /// [Instruction::OpGetLocal]s and an [Instruction::OpAdd].
+    OpAddLocals(usize, usize),
+    /// Pushes a copy of the value on top of the stack.
+    OpDup,
+    /// The number of values to pop from the stack, like as many [Instruction::OpPop]s.
+    OpPopN(usize),
 }
 
 /// A chunk of bytecode.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Chunk {
     /// Holds the Chunk's bytecode.
     pub bytecode: Vec<Instruction>,
@@ -51,6 +128,24 @@ pub struct Chunk {
     pub lines: Vec<i32>,
     /// Holds the Chunk's constant values.
     pub constants: Vec<Value>,
+    /// The name of the source the Chunk was compiled from, like a file path or `<repl>`.
+    ///
+    /// Empty if the source has no name.
+    pub source_name: Rc<str>,
+    /// The name of each global variable slot, shared by the chunks compiled together.
+    ///
+    /// Covers at least the slots the Chunk's instructions use.
+    pub globals: Rc<Vec<String>>,
+}
+
+/// Formats a position in the source code for diagnostics, as `name:line`,
+/// or as `line N` if the source has no name.
+pub fn format_location(source_name: &str, line: i32) -> String {
+    if source_name.is_empty() {
+        format!("line {}", line)
+    } else {
+        format!("{}:{}", source_name, line)
+    }
 }
 
 impl Chunk {
@@ -59,6 +154,8 @@ impl Chunk {
             bytecode: Vec::new(),
             constants: Vec::new(),
             lines: Vec::new(),
+            source_name: Rc::from(""),
+            globals: Rc::new(Vec::new()),
         }
     }
 
@@ -78,6 +175,16 @@ impl Chunk {
         &self.constants[index]
     }
 
+    /// Reads the string constant naming a class, a property or a method.
+    ///
+    /// The compiler only names them with strings, and loading bytecode checks that it does too.
+    pub fn read_name(&self, index: usize) -> &LoxString {
+        match &self.constants[index] {
+            Value::String(name) => name,
+            constant => panic!("Constant {} is not a name: {}", index, constant),
+        }
+    }
+
     /// Adds a constant to the [Chunk]'s [ValueArray] and returns the index.
     pub fn add_constant(&mut self, value: Value) -> usize {
         self.constants.push(value);
@@ -85,66 +192,451 @@ impl Chunk {
     }
 
     pub fn disassemble(&self, name: &str) {
-        println!("== {} ==", name);
-        self.bytecode
-            .iter()
-            .enumerate()
-            .for_each(|(i, _)| self.disassemble_instruction(i));
-        println!("== /{} ==\n", name);
+        print!(
+            "{}",
+            self.disassembly(name, cfg!(feature = "color_disassembly"))
+        );
+    }
+
+    /// Formats the whole [Chunk] as disassembly, followed by the disassembly of every
+    /// function among its constants, each under a header like `== fn isEven (arity 1) ==`.
+    pub fn disassembly(&self, name: &str, color: bool) -> String {
+        let mut output = format!("== {} ==\n", name);
+        for index in 0..self.bytecode.len() {
+            output.push_str(&self.format_instruction(index, color));
+            output.push('\n');
+        }
+        output.push_str(&format!("== /{} ==\n\n", name));
+
+        for constant in &self.constants {
+            if let Value::Function(function) = constant {
+                let name = format!("fn {} (arity {})", function.name, function.arity);
+                output.push_str(&function.chunk.disassembly(&name, color));
+            }
+        }
+        output
     }
 
-    // TODO: implement Display for [Instruction] instead
     pub fn disassemble_instruction(&self, index: usize) {
-        print!("{:?} ", index);
-        if index > 0 && self.lines[index] == self.lines[index - 1] {
-            print!("      |\t\t");
+        println!(
+            "{}",
+            self.format_instruction(index, cfg!(feature = "color_disassembly"))
+        );
+    }
+
+    /// Formats the instruction at `index` as a line of disassembly, with aligned columns:
+    /// the offset, the line (or `|` if unchanged), the instruction name and its operand.
+    ///
+    /// Constants are shown inline and jumps show the offset they land on, e.g. `-> 0024`.
+    /// If `color` is true, the instruction name is colored according to its class.
+    pub fn format_instruction(&self, index: usize, color: bool) -> String {
+        let line = if index > 0 && self.lines[index] == self.lines[index - 1] {
+            "   |".to_string()
         } else {
-            print!("line: {:?}\t\t", self.lines[index]);
-        }
+            format!("{:>4}", self.lines[index])
+        };
 
         let instruction = self.bytecode[index];
-        match instruction {
+        let operand = match instruction {
+            Instruction::OpDefineGlobal(slot)
+            | Instruction::OpGetGlobal(slot)
+            | Instruction::OpSetGlobal(slot) => match self.globals.get(slot) {
+                Some(name) => format!("{:>4} '{}'", slot, name),
+                None => format!("{:>4}", slot),
+            },
             Instruction::OpConstant(idx)
-            | Instruction::OpDefineGlobal(idx)
-            | Instruction::OpGetGlobal(idx)
-            | Instruction::OpSetGlobal(idx) => {
-                let constant = &self.constants[idx];
-                if let Value::Function(f) = constant {
-                    println!("{:?}    \tvalue: <fn {}>", instruction, f.name);
-                } else {
-                    println!("{:?}    \tvalue: {:?}", instruction, constant);
+            | Instruction::OpClass(idx)
+            | Instruction::OpGetProperty(idx)
+            | Instruction::OpSetProperty(idx)
+            | Instruction::OpMethod(idx)
+            | Instruction::OpGetter(idx)
+            | Instruction::OpSetter(idx)
+            | Instruction::OpGetSuper(idx) => format!("{:>4} '{}'", idx, self.constants[idx]),
+            Instruction::OpJumpIfFalse(offset)
+            | Instruction::OpJumpIfTrue(offset)
+            | Instruction::OpJumpIfNil(offset)
+            | Instruction::OpJumpIfNotEqual(offset)
+            | Instruction::OpJump(offset)
+            | Instruction::OpDefer(offset)
+            | Instruction::OpSetupCatch(offset) => {
+                format!("{:>4} -> {:04}", offset, index + 1 + offset)
+            }
+            Instruction::OpLoop(offset) => {
+                // A malformed loop could point before the start of the chunk.
+                match (index + 1).checked_sub(offset) {
+                    Some(target) => format!("{:>4} -> {:04}", offset, target),
+                    None => format!("{:>4} -> ????", offset),
                 }
             }
-            Instruction::OpCall(_) => {
-                println!("{:?}", instruction);
+            Instruction::OpCall(arg_count) | Instruction::OpTailCall(arg_count) => {
+                match self.call_target(index) {
+                    Some(target) => format!("{:>4} calls {}", arg_count, target),
+                    None => format!("{:>4}", arg_count),
+                }
             }
-            // Locals have are 1 ahead, because of the 0 slot being reserved for the function.
-            // Instruction::OpSetLocal(idx) | Instruction::OpGetLocal(idx) => {
-            //     let constant = &self.constants[idx - 1];
-            //     println!("{:?}    \tvalue: {:?}", instruction, constant);
-            // }
-            Instruction::OpJumpIfFalse(val)
-            | Instruction::OpJump(val)
-            | Instruction::OpLoop(val)
-            | Instruction::OpSetLocal(val)
-            | Instruction::OpGetLocal(val) => {
-                println!("{:?}    \tvalue: {:?}", instruction, val);
+            Instruction::OpSetLocal(val)
+            | Instruction::OpGetLocal(val)
+            | Instruction::OpPopN(val) => {
+                format!("{:>4}", val)
+            }
+            Instruction::OpAddLocals(a, b) => format!("{:>4} {}", a, b),
+            Instruction::OpSmallInt(n) => format!("{:>4}", n),
+            Instruction::OpExtension(id, arg_count) => format!("{:>4} ({} args)", id, arg_count),
+            _ => String::new(),
+        };
+
+        let name = if color {
+            format!(
+                "{}{:<16}\x1b[0m",
+                instruction_color(instruction),
+                instruction.name()
+            )
+        } else {
+            format!("{:<16}", instruction.name())
+        };
+        format!("{:04} {} {} {}", index, line, name, operand)
+            .trim_end()
+            .to_string()
+    }
+
+    /// Finds the name of the global called by the OpCall or OpTailCall at `index`, if it is
+    /// statically known.
+    ///
+    /// Walks back over the code that pushed the arguments to find the instruction that pushed
+    /// the callee. Gives up on control flow, since the code before a jump target is not
+    /// necessarily what ran before it.
+    fn call_target(&self, index: usize) -> Option<String> {
+        let arg_count = match self.bytecode[index] {
+            Instruction::OpCall(arg_count) | Instruction::OpTailCall(arg_count) => arg_count,
+            _ => return None,
+        };
+
+        // The number of values, counting down from the top of the stack, still to be accounted for.
+        let mut needed = arg_count + 1;
+        for i in (0..index).rev() {
+            let instruction = self.bytecode[i];
+            let (pops, pushes) = instruction.stack_effect()?;
+            if pushes >= needed {
+                return match instruction {
+                    Instruction::OpGetGlobal(slot) if pushes == needed => {
+                        self.globals.get(slot).cloned()
+                    }
+                    _ => None,
+                };
             }
-            Instruction::OpNegate
-            | Instruction::OpEqual
+            needed = needed - pushes + pops;
+        }
+        None
+    }
+}
+
+impl Instruction {
+    /// The number of values the instruction pops from and pushes to the stack,
+    /// or [None] for control flow instructions.
+    pub(crate) fn stack_effect(&self) -> Option<(usize, usize)> {
+        match self {
+            Instruction::OpConstant(_)
+            | Instruction::OpNil
+            | Instruction::OpZero
+            | Instruction::OpOne
+            | Instruction::OpSmallInt(_)
+            | Instruction::OpTrue
+            | Instruction::OpFalse
+            | Instruction::OpGetGlobal(_)
+            | Instruction::OpGetLocal(_)
+            | Instruction::OpAddLocals(_, _)
+            | Instruction::OpClass(_)
+            | Instruction::OpGetSuper(_) => Some((0, 1)),
+            Instruction::OpDefineGlobal(_) | Instruction::OpPop | Instruction::OpPrint => {
+                Some((1, 0))
+            }
+            Instruction::OpInherit => Some((2, 0)),
+            Instruction::OpPopN(count) => Some((*count, 0)),
+            Instruction::OpDup => Some((1, 2)),
+            Instruction::OpSetGlobal(_)
+            | Instruction::OpSetLocal(_)
+            | Instruction::OpNot
+            | Instruction::OpNegate
+            | Instruction::OpGetProperty(_) => Some((1, 1)),
+            Instruction::OpEqual
             | Instruction::OpGreater
             | Instruction::OpLess
             | Instruction::OpAdd
             | Instruction::OpSubtract
             | Instruction::OpMultiply
             | Instruction::OpDivide
-            | Instruction::OpFalse
-            | Instruction::OpNil
-            | Instruction::OpTrue
-            | Instruction::OpNot
-            | Instruction::OpPop
-            | Instruction::OpPrint
-            | Instruction::OpReturn => println!("{:?}", instruction),
+            | Instruction::OpSetProperty(_)
+            | Instruction::OpMethod(_)
+            | Instruction::OpGetter(_)
+            | Instruction::OpSetter(_)
+            | Instruction::OpFields
+            | Instruction::OpIs
+            | Instruction::OpIndexGet => Some((2, 1)),
+            Instruction::OpSlice | Instruction::OpIndexSet => Some((3, 1)),
+            Instruction::OpBuildList(count) => Some((*count, 1)),
+            Instruction::OpCall(arg_count) | Instruction::OpTailCall(arg_count) => {
+                Some((arg_count + 1, 1))
+            }
+            Instruction::OpExtension(_, arg_count) => Some((*arg_count as usize, 1)),
+            Instruction::OpJump(_)
+            | Instruction::OpJumpIfFalse(_)
+            | Instruction::OpJumpIfTrue(_)
+            | Instruction::OpJumpIfNil(_)
+            | Instruction::OpJumpIfNotEqual(_)
+            | Instruction::OpLoop(_)
+            | Instruction::OpDefer(_)
+            | Instruction::OpEndDefer
+            | Instruction::OpSetupCatch(_)
+            | Instruction::OpPopCatch
+            | Instruction::OpThrow
+            | Instruction::OpReturn => None,
         }
     }
+
+    /// The instruction's name, without its operand.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Instruction::OpCall(_) => "OpCall",
+            Instruction::OpConstant(_) => "OpConstant",
+            Instruction::OpNil => "OpNil",
+            Instruction::OpZero => "OpZero",
+            Instruction::OpOne => "OpOne",
+            Instruction::OpSmallInt(_) => "OpSmallInt",
+            Instruction::OpTrue => "OpTrue",
+            Instruction::OpDefineGlobal(_) => "OpDefineGlobal",
+            Instruction::OpDefer(_) => "OpDefer",
+            Instruction::OpEndDefer => "OpEndDefer",
+            Instruction::OpEqual => "OpEqual",
+            Instruction::OpFalse => "OpFalse",
+            Instruction::OpGetGlobal(_) => "OpGetGlobal",
+            Instruction::OpSetGlobal(_) => "OpSetGlobal",
+            Instruction::OpGetLocal(_) => "OpGetLocal",
+            Instruction::OpSetLocal(_) => "OpSetLocal",
+            Instruction::OpGreater => "OpGreater",
+            Instruction::OpJump(_) => "OpJump",
+            Instruction::OpJumpIfFalse(_) => "OpJumpIfFalse",
+            Instruction::OpLess => "OpLess",
+            Instruction::OpLoop(_) => "OpLoop",
+            Instruction::OpAdd => "OpAdd",
+            Instruction::OpSubtract => "OpSubtract",
+            Instruction::OpMultiply => "OpMultiply",
+            Instruction::OpDivide => "OpDivide",
+            Instruction::OpPop => "OpPop",
+            Instruction::OpNot => "OpNot",
+            Instruction::OpNegate => "OpNegate",
+            Instruction::OpPrint => "OpPrint",
+            Instruction::OpReturn => "OpReturn",
+            Instruction::OpSlice => "OpSlice",
+            Instruction::OpExtension(_, _) => "OpExtension",
+            Instruction::OpClass(_) => "OpClass",
+            Instruction::OpGetProperty(_) => "OpGetProperty",
+            Instruction::OpSetProperty(_) => "OpSetProperty",
+            Instruction::OpMethod(_) => "OpMethod",
+            Instruction::OpInherit => "OpInherit",
+            Instruction::OpGetSuper(_) => "OpGetSuper",
+            Instruction::OpBuildList(_) => "OpBuildList",
+            Instruction::OpIndexGet => "OpIndexGet",
+            Instruction::OpIndexSet => "OpIndexSet",
+            Instruction::OpJumpIfNil(_) => "OpJumpIfNil",
+            Instruction::OpSetupCatch(_) => "OpSetupCatch",
+            Instruction::OpPopCatch => "OpPopCatch",
+            Instruction::OpThrow => "OpThrow",
+            Instruction::OpGetter(_) => "OpGetter",
+            Instruction::OpSetter(_) => "OpSetter",
+            Instruction::OpFields => "OpFields",
+            Instruction::OpIs => "OpIs",
+            Instruction::OpTailCall(_) => "OpTailCall",
+            Instruction::OpJumpIfNotEqual(_) => "OpJumpIfNotEqual",
+            Instruction::OpJumpIfTrue(_) => "OpJumpIfTrue",
+            Instruction::OpAddLocals(_, _) => "OpAddLocals",
+            Instruction::OpDup => "OpDup",
+            Instruction::OpPopN(_) => "OpPopN",
+        }
+    }
+}
+
+/// The ANSI color used for an instruction's name in colored disassembly, by instruction class.
+fn instruction_color(instruction: Instruction) -> &'static str {
+    match instruction {
+        // Constants and literals: cyan.
+        Instruction::OpConstant(_)
+        | Instruction::OpNil
+        | Instruction::OpZero
+        | Instruction::OpOne
+        | Instruction::OpSmallInt(_)
+        | Instruction::OpTrue
+        | Instruction::OpFalse
+        | Instruction::OpClass(_)
+        | Instruction::OpMethod(_)
+        | Instruction::OpGetter(_)
+        | Instruction::OpSetter(_)
+        | Instruction::OpFields
+        | Instruction::OpInherit
+        | Instruction::OpBuildList(_) => "\x1b[36m",
+        // Variables: green.
+        Instruction::OpDefineGlobal(_)
+        | Instruction::OpGetGlobal(_)
+        | Instruction::OpSetGlobal(_)
+        | Instruction::OpGetLocal(_)
+        | Instruction::OpSetLocal(_)
+        | Instruction::OpGetProperty(_)
+        | Instruction::OpSetProperty(_)
+        | Instruction::OpGetSuper(_)
+        | Instruction::OpIndexGet
+        | Instruction::OpIndexSet => "\x1b[32m",
+        // Control flow: magenta.
+        Instruction::OpCall(_)
+        | Instruction::OpTailCall(_)
+        | Instruction::OpJump(_)
+        | Instruction::OpJumpIfFalse(_)
+        | Instruction::OpJumpIfTrue(_)
+        | Instruction::OpJumpIfNil(_)
+        | Instruction::OpJumpIfNotEqual(_)
+        | Instruction::OpLoop(_)
+        | Instruction::OpDefer(_)
+        | Instruction::OpEndDefer
+        | Instruction::OpSetupCatch(_)
+        | Instruction::OpPopCatch
+        | Instruction::OpThrow
+        | Instruction::OpReturn => "\x1b[35m",
+        // Operators: yellow.
+        Instruction::OpEqual
+        | Instruction::OpGreater
+        | Instruction::OpLess
+        | Instruction::OpAdd
+        | Instruction::OpAddLocals(_, _)
+        | Instruction::OpSubtract
+        | Instruction::OpMultiply
+        | Instruction::OpDivide
+        | Instruction::OpIs
+        | Instruction::OpNot
+        | Instruction::OpNegate
+        | Instruction::OpSlice
+        | Instruction::OpExtension(_, _) => "\x1b[33m",
+        Instruction::OpPop | Instruction::OpPopN(_) | Instruction::OpDup | Instruction::OpPrint => {
+            "\x1b[0m"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::function::Function;
+
+    fn chunk() -> Chunk {
+        let mut chunk = Chunk::new();
+        let constant = chunk.add_constant(Value::Number(1.5));
+        chunk.write(Instruction::OpConstant(constant), 1);
+        chunk.write(Instruction::OpJumpIfFalse(2), 1);
+        chunk.write(Instruction::OpPop, 2);
+        chunk.write(Instruction::OpLoop(4), 2);
+        chunk.write(Instruction::OpReturn, 3);
+        chunk
+    }
+
+    #[test]
+    fn format_constant() {
+        assert_eq!(
+            "0000    1 OpConstant          0 '1.5'",
+            chunk().format_instruction(0, false)
+        );
+    }
+
+    #[test]
+    fn format_jump_targets() {
+        let chunk = chunk();
+        assert_eq!(
+            "0001    | OpJumpIfFalse       2 -> 0004",
+            chunk.format_instruction(1, false)
+        );
+        assert_eq!(
+            "0003    | OpLoop              4 -> 0000",
+            chunk.format_instruction(3, false)
+        );
+    }
+
+    #[test]
+    fn format_without_operand() {
+        assert_eq!("0004    3 OpReturn", chunk().format_instruction(4, false));
+    }
+
+    #[test]
+    fn format_colored() {
+        assert_eq!(
+            "0004    3 \x1b[35mOpReturn        \x1b[0m",
+            chunk().format_instruction(4, true)
+        );
+    }
+
+    #[test]
+    fn call_targets() {
+        let mut chunk = Chunk::new();
+        chunk.globals = Rc::new(vec!["isEven".to_string()]);
+        let argument = chunk.add_constant(Value::Number(2.0));
+        chunk.write(Instruction::OpGetGlobal(0), 1);
+        chunk.write(Instruction::OpConstant(argument), 1);
+        chunk.write(Instruction::OpConstant(argument), 1);
+        chunk.write(Instruction::OpAdd, 1);
+        chunk.write(Instruction::OpCall(1), 1);
+        // Calling the result of a call has no statically known target.
+        chunk.write(Instruction::OpCall(0), 1);
+        assert_eq!(
+            "0004    | OpCall              1 calls isEven",
+            chunk.format_instruction(4, false)
+        );
+        assert_eq!(
+            "0005    | OpCall              0",
+            chunk.format_instruction(5, false)
+        );
+    }
+
+    #[test]
+    fn disassemble_functions() {
+        let mut function = Function::new();
+        function.name = "isEven".to_string();
+        function.arity = 1;
+        function.chunk.write(Instruction::OpNil, 2);
+        function.chunk.write(Instruction::OpReturn, 2);
+
+        let mut chunk = Chunk::new();
+        let constant = chunk.add_constant(Value::Function(Rc::new(function)));
+        chunk.write(Instruction::OpConstant(constant), 1);
+        chunk.write(Instruction::OpReturn, 3);
+
+        assert_eq!(
+            "== <script> ==
+0000    1 OpConstant          0 '<fn isEven>'
+0001    3 OpReturn
+== /<script> ==
+
+== fn isEven (arity 1) ==
+0000    2 OpNil
+0001    | OpReturn
+== /fn isEven (arity 1) ==
+
+",
+            chunk.disassembly("<script>", false)
+        );
+    }
+
+    #[test]
+    fn locations() {
+        assert_eq!("line 3", format_location("", 3));
+        assert_eq!("main.lox:3", format_location("main.lox", 3));
+    }
+
+    #[test]
+    fn read_names() {
+        let mut chunk = chunk();
+        let field = LoxString::from("field");
+        let name = chunk.add_constant(Value::String(field.clone()));
+        assert!(LoxString::ptr_eq(&field, chunk.read_name(name)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Constant 0 is not a name: 1.5")]
+    fn read_non_string_name() {
+        chunk().read_name(0);
+    }
 }
@@ -1,16 +1,32 @@
-use core::f64;
-use std::{rc::Rc, usize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::io::Write;
+use std::rc::Rc;
 
 use crate::{
-    chunk::Instruction,
+    chunk::{format_location, Instruction},
+    diagnostic::{Catalog, Diagnostic, ErrorOutput},
+    optimizer,
     parser::Parser,
     scanner::{Scanner, Token, TokenType},
+    suggestion::{did_you_mean, KEYWORDS},
     value::{
         function::{Function, FunctionType},
+        number::parse_number,
+        string::LoxString,
         value::Value,
     },
+    vm::vm::FRAME_SLOTS,
 };
 
+/// The offset emitted in jump instructions before they are patched.
+const JUMP_PLACEHOLDER: usize = 0xffff;
+
+/// The maximum number of nested scopes in one function, counting blocks and `for` loops.
+pub const MAX_SCOPE_DEPTH: i32 = 255;
+/// The maximum number of nested `while` and `for` loops in one function.
+pub const MAX_LOOP_DEPTH: usize = 64;
+
 #[derive(Clone, Copy, PartialEq, PartialOrd)]
 enum Precedence {
     None,
@@ -23,14 +39,16 @@ enum Precedence {
     Factor,
     Unary,
     Call,
-    Primary,
 }
 
 #[derive(PartialEq)]
 enum ParseFn {
     Call,
+    Subscript,
+    List,
     Grouping,
-    // Dot,
+    Dot,
+    OptionalDot,
     Unary,
     Binary,
     Variable,
@@ -39,8 +57,11 @@ enum ParseFn {
     And,
     Literal,
     Or,
-    // Super,
-    // This,
+    Super,
+    This,
+    Increment,
+    InvalidIncrement,
+    Match,
     None,
 }
 
@@ -59,6 +80,8 @@ struct Local {
     ///
     /// A depth of -1 indicates that the variable has not been initialized.
     depth: i32,
+    /// Whether the variable is read or assigned, or is a parameter.
+    used: bool,
 }
 
 pub struct Compiler {
@@ -74,7 +97,15 @@ pub struct Compiler {
     /// relative to the stack frame.
     locals: Vec<Local>,
     /// The number of blocks surrounding the code that is currently being compiled.
+    ///
+    /// At most [MAX_SCOPE_DEPTH].
     scope_depth: i32,
+    /// The number of loops surrounding the code that is currently being compiled.
+    ///
+    /// At most [MAX_LOOP_DEPTH].
+    loop_depth: usize,
+    /// Whether the expression of a `defer` statement is currently being compiled.
+    compiling_defer: bool,
 }
 
 impl Compiler {
@@ -84,10 +115,209 @@ impl Compiler {
             function_type,
             locals: Vec::new(),
             scope_depth: 0,
+            loop_depth: 0,
+            compiling_defer: false,
+        }
+    }
+}
+
+/// Options that control how source code is compiled.
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    /// The maximum size of the source code, in bytes.
+    pub max_source_size: usize,
+    /// The maximum length of a single line of source code, in characters.
+    pub max_line_length: usize,
+    /// Global variables that are defined outside of the compiled program,
+    /// like native functions or globals from earlier REPL lines.
+    pub known_globals: Vec<String>,
+    /// The names of the global variable slots already in use, in slot order, so that the
+    /// compiled code shares them. Filled in by the [crate::vm::vm::VM] from its globals.
+    ///
+    /// The other globals of the program get the next slots.
+    pub global_slots: Vec<String>,
+    /// The names defined for conditional compilation with `#if NAME ... #else ... #end`,
+    /// with their values. A name counts as set unless its value is `false` or `0`.
+    pub defines: HashMap<String, String>,
+    /// The messages of compile errors and warnings.
+    pub catalog: Rc<Catalog>,
+    /// Where compile errors and warnings are written to.
+    pub error_output: ErrorOutput,
+    /// The extra instructions that calls to these names compile to, unless a local variable
+    /// has the same name. Filled in by the [crate::vm::vm::VM] from its registered extensions.
+    pub extensions: HashMap<String, ExtensionOpcode>,
+    /// The names of the native functions, checked by [Lint::ShadowedNative].
+    /// Filled in by the [crate::vm::vm::VM] from its registered natives.
+    pub natives: HashSet<String>,
+    /// The level of lints selected by name, like with `--warn unused-local`.
+    /// The lints that are left out are off.
+    pub lints: HashMap<Lint, LintLevel>,
+    /// Whether every warning is reported as an error instead, which stops the compilation.
+    pub deny_warnings: bool,
+    /// Whether to optimize the compiled bytecode with [crate::optimizer::optimize].
+    pub optimize: bool,
+}
+
+impl CompileOptions {
+    /// Returns whether a lint is off, reported as a warning or reported as an error.
+    pub fn lint_level(&self, lint: Lint) -> LintLevel {
+        let level = self.lints.get(&lint).copied().unwrap_or(LintLevel::Allow);
+        if level == LintLevel::Warn && self.deny_warnings {
+            return LintLevel::Deny;
+        }
+        level
+    }
+}
+
+/// A check for likely mistakes that do not stop the source code from compiling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    /// A global variable that is used but never defined in the program.
+    ///
+    /// The check does not follow control flow.
+    UndefinedGlobal,
+    /// An assignment used as the condition of an if or while statement, like `if (a = b)`,
+    /// which is likely a typo for `==`.
+    ///
+    /// Wrapping the assignment in an extra set of parentheses, `if ((a = b))`, silences it.
+    AssignmentInCondition,
+    /// A global definition or assignment with the name of a native function, which replaces
+    /// the native for the rest of the program.
+    ShadowedNative,
+    /// A local variable that is never used. Parameters and names starting with `_` are not
+    /// reported.
+    UnusedLocal,
+    /// A local variable with the same name as a local of an enclosing block.
+    Shadowing,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 5] = [
+        Lint::UndefinedGlobal,
+        Lint::AssignmentInCondition,
+        Lint::ShadowedNative,
+        Lint::UnusedLocal,
+        Lint::Shadowing,
+    ];
+
+    /// The name the lint is selected by, like `unused-local`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::UndefinedGlobal => "undefined-global",
+            Lint::AssignmentInCondition => "assignment-in-condition",
+            Lint::ShadowedNative => "shadowed-native",
+            Lint::UnusedLocal => "unused-local",
+            Lint::Shadowing => "shadowing",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::ALL.iter().copied().find(|lint| lint.name() == name)
+    }
+
+    /// The warning the lint reports.
+    pub fn diagnostic(self) -> Diagnostic {
+        match self {
+            Lint::UndefinedGlobal => Diagnostic::UndefinedGlobal,
+            Lint::AssignmentInCondition => Diagnostic::AssignmentInCondition,
+            Lint::ShadowedNative => Diagnostic::ShadowedNativeWarning,
+            Lint::UnusedLocal => Diagnostic::UnusedLocal,
+            Lint::Shadowing => Diagnostic::ShadowedLocal,
+        }
+    }
+}
+
+/// What the compiler does with the problems a [Lint] finds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LintLevel {
+    Allow,
+    Warn,
+    /// Report them as errors, which stop the compilation.
+    Deny,
+}
+
+/// An extra instruction, compiled to [Instruction::OpExtension].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExtensionOpcode {
+    /// The id that the VM runs the instruction's handler by.
+    pub id: u8,
+    /// The number of arguments the instruction takes.
+    pub arity: u8,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            max_source_size: 16 * 1024 * 1024,
+            max_line_length: 10_000,
+            known_globals: Vec::new(),
+            global_slots: Vec::new(),
+            defines: HashMap::new(),
+            catalog: Rc::new(Catalog::default()),
+            error_output: ErrorOutput::default(),
+            extensions: HashMap::new(),
+            lints: HashMap::new(),
+            deny_warnings: false,
+            natives: HashSet::new(),
+            optimize: false,
         }
     }
 }
 
+/// An error that stopped source code from being compiled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub code: Diagnostic,
+    /// The error message, without the location.
+    pub message: String,
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// A problem in the source code that does not stop it from being compiled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    /// The name of the source where the problem is found, empty if it has none.
+    pub source_name: String,
+    /// The line in the source code where the problem is found.
+    pub line: i32,
+    pub code: Diagnostic,
+    pub message: String,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[{}] warning[{}]: {}",
+            format_location(&self.source_name, self.line),
+            self.code.code(),
+            self.message
+        )
+    }
+}
+
+/// The result of successfully compiling source code.
+pub struct CompileOutput {
+    /// The top-level script.
+    pub function: Function,
+    pub warnings: Vec<Warning>,
+}
+
+/// Several sources compiled together, with one top-level script per source.
+///
+/// The sources are meant to run in order in the same [VM], sharing its global variables.
+/// Their chunks share the same [Rc] for equal string constants.
+pub struct Program {
+    /// The top-level script of each source, with the source's name, in the order they were given.
+    pub scripts: Vec<(String, Function)>,
+    pub warnings: Vec<Warning>,
+}
+
 /// Manages a collection of [Compiler]s.
 pub struct CompilerManager {
     /// The index of the [Compiler] currently in use, in the compilers array.
@@ -95,33 +325,378 @@ pub struct CompilerManager {
     compilers: Vec<Compiler>,
     scanner: Scanner,
     parser: Parser,
+    /// The names of the global variables defined anywhere in the program.
+    defined_globals: HashSet<String>,
+    /// The name of each global variable slot, shared with the compiled chunks.
+    global_names: Rc<Vec<String>>,
+    /// The slot of each global variable, by name.
+    global_slots: HashMap<String, usize>,
+    /// Whether uses of global variables are recorded in `global_uses`.
+    track_global_uses: bool,
+    /// Every use of a global variable in the program, if tracked.
+    global_uses: Vec<GlobalUse>,
+    /// The strings used as constants in the program, so that equal ones share one allocation.
+    strings: HashSet<LoxString>,
+    /// The name of the source currently being compiled.
+    source_name: Rc<str>,
+    /// The messages of compile errors.
+    catalog: Rc<Catalog>,
+    /// Where compile errors are written to.
+    error_output: ErrorOutput,
+    /// Whether assignments used as conditions are warned about.
+    warn_assignment_in_condition: bool,
+    /// Where the target of the latest assignment starts in the source,
+    /// to tell whether a condition is an assignment.
+    last_assignment: Option<usize>,
+    /// The warnings found while compiling, in the order of the source.
+    warnings: Vec<Warning>,
+    /// The extra instructions, by the name they are called with.
+    extensions: HashMap<String, ExtensionOpcode>,
+    /// Whether each class being compiled has a superclass, innermost last.
+    classes: Vec<bool>,
+    /// The arity of each global declared with `fun` in the program, or `None` if the global
+    /// may hold something else, because it is also declared otherwise or assigned.
+    global_arities: HashMap<String, Option<usize>>,
+    /// Every call made directly on a global variable, like `f(1)`.
+    global_calls: Vec<GlobalCall>,
+    /// The global variable the callee of the call being compiled was read from, if the callee
+    /// is just that variable.
+    call_target: Option<String>,
+    /// What to do with globals named like natives.
+    native_shadowing: LintLevel,
+    /// The names of the native functions.
+    natives: HashSet<String>,
+    /// The jumps of the `?.` in the expressions being compiled, to patch at the end of their
+    /// chains, innermost last.
+    optional_jumps: Vec<usize>,
+    /// Whether local variables that are never used are warned about.
+    warn_unused_locals: bool,
+    /// Whether local variables shadowing the locals of enclosing blocks are warned about.
+    warn_shadowing: bool,
+}
+
+/// A use of a global variable, as far as it is needed to warn about it being undefined.
+struct GlobalUse {
+    name: String,
+    source_name: Rc<str>,
+    line: i32,
+    /// The local variables in scope at the use, suggested as corrections.
+    locals: Vec<String>,
+}
+
+/// A call made directly on a global variable, whose arguments are checked against the
+/// function's arity once the whole program is compiled.
+struct GlobalCall {
+    name: String,
+    arg_count: usize,
+    source_name: Rc<str>,
+    line: i32,
 }
 
 impl CompilerManager {
-    pub fn compile(source: String) -> Result<Function, String> {
-        let source = source.chars().collect();
+    pub fn compile(source: String) -> Result<Function, CompileError> {
+        CompilerManager::compile_with_options(source, &CompileOptions::default())
+            .map(|output| output.function)
+    }
 
+    pub fn compile_with_options(
+        source: String,
+        options: &CompileOptions,
+    ) -> Result<CompileOutput, CompileError> {
+        let mut program = CompilerManager::compile_many(&[("", &source)], options)?;
+        let (_, function) = program.scripts.pop().unwrap();
+        Ok(CompileOutput {
+            function,
+            warnings: program.warnings,
+        })
+    }
+
+    /// Compiles several named sources into one [Program].
+    ///
+    /// The names appear in diagnostics and stack traces, an empty name leaves them out.
+    /// Globals defined in any of the sources count as defined for all of them
+    /// when warning about undefined globals. Compilation stops at the first source with an error.
+    pub fn compile_many(
+        sources: &[(&str, &str)],
+        options: &CompileOptions,
+    ) -> Result<Program, CompileError> {
         let mut compiler_manager = CompilerManager {
             current: -1,
             compilers: Vec::new(),
-            scanner: Scanner::init(source),
+            scanner: Scanner::init(Vec::new()),
             parser: Parser::init(),
+            defined_globals: HashSet::new(),
+            global_names: Rc::new(options.global_slots.clone()),
+            global_slots: options
+                .global_slots
+                .iter()
+                .enumerate()
+                .map(|(slot, name)| (name.clone(), slot))
+                .collect(),
+            track_global_uses: options.lint_level(Lint::UndefinedGlobal) != LintLevel::Allow,
+            global_uses: Vec::new(),
+            strings: HashSet::new(),
+            source_name: Rc::from(""),
+            catalog: Rc::clone(&options.catalog),
+            error_output: options.error_output.clone(),
+            warn_assignment_in_condition: options.lint_level(Lint::AssignmentInCondition)
+                != LintLevel::Allow,
+            last_assignment: None,
+            warnings: Vec::new(),
+            extensions: options.extensions.clone(),
+            classes: Vec::new(),
+            global_arities: HashMap::new(),
+            global_calls: Vec::new(),
+            call_target: None,
+            native_shadowing: options.lint_level(Lint::ShadowedNative),
+            natives: options.natives.clone(),
+            warn_unused_locals: options.lint_level(Lint::UnusedLocal) != LintLevel::Allow,
+            warn_shadowing: options.lint_level(Lint::Shadowing) != LintLevel::Allow,
+            optional_jumps: Vec::new(),
         };
 
+        let mut scripts = Vec::new();
+        for (name, source) in sources {
+            CompilerManager::check_source_limits(name, source, options)?;
+            let mut function = compiler_manager.compile_source(name, source, options)?;
+            if options.optimize {
+                function = optimizer::optimize(&function);
+            }
+            scripts.push((name.to_string(), function));
+        }
+        compiler_manager.check_global_calls()?;
+
+        let mut warnings = std::mem::take(&mut compiler_manager.warnings);
+        if compiler_manager.track_global_uses {
+            warnings.extend(compiler_manager.undefined_globals(options));
+        }
+        CompilerManager::report_warnings(&warnings, options)?;
+        Ok(Program { scripts, warnings })
+    }
+
+    /// Prints the warnings, and those of denied lints as errors, returning the first of these.
+    fn report_warnings(warnings: &[Warning], options: &CompileOptions) -> Result<(), CompileError> {
+        let denied: Vec<Diagnostic> = Lint::ALL
+            .iter()
+            .copied()
+            .filter(|lint| options.lint_level(*lint) == LintLevel::Deny)
+            .map(Lint::diagnostic)
+            .collect();
+        let mut first_error = None;
+        let mut output = &options.error_output;
+        for warning in warnings {
+            if !denied.contains(&warning.code) {
+                let _ = writeln!(output, "{}", warning);
+                continue;
+            }
+            let _ = writeln!(
+                output,
+                "[{}] error[{}]: {}",
+                format_location(&warning.source_name, warning.line),
+                warning.code.code(),
+                warning.message
+            );
+            first_error.get_or_insert(CompileError {
+                code: warning.code,
+                message: warning.message.clone(),
+            });
+        }
+        match first_error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Compiles one source into its top-level script, starting with a fresh scanner and parser.
+    fn compile_source(
+        &mut self,
+        name: &str,
+        source: &str,
+        options: &CompileOptions,
+    ) -> Result<Function, CompileError> {
+        self.source_name = Rc::from(name);
+        self.scanner = Scanner::init(source.chars().collect());
+        self.scanner.defines = options.defines.clone();
+        self.parser = Parser::init();
+
         // Add the [Compiler] responsible for compiling the top-level script.
-        compiler_manager.init_compiler(FunctionType::Script);
+        self.init_compiler(FunctionType::Script);
 
-        compiler_manager.advance();
-        while !compiler_manager.match_token(TokenType::Eof) {
-            compiler_manager.declaration();
+        self.advance();
+        while !self.match_token(TokenType::Eof) {
+            self.declaration();
         }
-        let compiled_function = compiler_manager.end();
+        let compiled_function = self.end();
 
-        if compiler_manager.parser.had_error {
-            Err(compiler_manager.parser.error_message.clone())
-        } else {
-            Ok(compiled_function)
+        if let Some(error) = self.parser.error.take() {
+            return Err(error);
+        }
+        Ok(compiled_function)
+    }
+
+    /// Reports the direct calls of global functions with the wrong number of arguments,
+    /// returning the first one as the error.
+    ///
+    /// Only globals that always hold the same function are checked: the VM checks the other
+    /// calls when they run.
+    fn check_global_calls(&self) -> Result<(), CompileError> {
+        let mut first_error = None;
+        for call in &self.global_calls {
+            let arity = match self.global_arities.get(&call.name) {
+                Some(Some(arity)) => *arity,
+                _ => continue,
+            };
+            if call.arg_count == arity {
+                continue;
+            }
+            let diagnostic = Diagnostic::WrongGlobalArgumentCount;
+            let message = self.catalog.message(
+                diagnostic,
+                &[&arity.to_string(), &call.arg_count.to_string()],
+            );
+            let _ = writeln!(
+                &self.error_output,
+                "[{}] error[{}] at {:?}: {}",
+                format_location(&call.source_name, call.line),
+                diagnostic.code(),
+                call.name,
+                message
+            );
+            first_error.get_or_insert(CompileError {
+                code: diagnostic,
+                message,
+            });
+        }
+        match first_error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Records what a global variable is declared or assigned as: a function with the given
+    /// arity, or `None` for anything else.
+    fn record_global_arity(&mut self, name: String, arity: Option<usize>) {
+        let recorded = self.global_arities.entry(name).or_insert(arity);
+        if *recorded != arity {
+            *recorded = None;
+        }
+    }
+
+    /// Records an assignment to a variable, which makes the arity of a global unknown.
+    fn record_assignment(&mut self, name: Token, set_op: Instruction) {
+        if let Instruction::OpSetGlobal(slot) = set_op {
+            let global = self.global_names[slot].clone();
+            self.record_global_arity(global, None);
+            self.check_shadowed_native(name);
+        }
+    }
+
+    /// Warns about or rejects a global definition or assignment that replaces a native function,
+    /// depending on the level of [Lint::ShadowedNative].
+    fn check_shadowed_native(&mut self, name: Token) {
+        if self.native_shadowing == LintLevel::Allow {
+            return;
+        }
+        let lexeme = self.lexeme_to_string(name);
+        if !self.natives.contains(&lexeme) {
+            return;
+        }
+        if self.native_shadowing == LintLevel::Deny {
+            self.error_with_args_at(name, Diagnostic::ShadowedNative, &[&lexeme]);
+            return;
+        }
+        self.warnings.push(Warning {
+            source_name: self.source_name.to_string(),
+            line: name.line,
+            code: Diagnostic::ShadowedNativeWarning,
+            message: self
+                .catalog
+                .message(Diagnostic::ShadowedNativeWarning, &[&lexeme]),
+        });
+    }
+
+    /// Warns about every use of a global variable that is neither defined in the program
+    /// nor known to exist already. Likely a typo, so a close name is suggested if there is one.
+    fn undefined_globals(&self, options: &CompileOptions) -> Vec<Warning> {
+        self.global_uses
+            .iter()
+            .filter(|usage| {
+                !self.defined_globals.contains(&usage.name)
+                    && !options.known_globals.contains(&usage.name)
+            })
+            .map(|usage| {
+                let candidates = self
+                    .defined_globals
+                    .iter()
+                    .chain(&options.known_globals)
+                    .chain(&usage.locals)
+                    .map(String::as_str)
+                    .chain(KEYWORDS);
+                Warning {
+                    source_name: usage.source_name.to_string(),
+                    line: usage.line,
+                    code: Diagnostic::UndefinedGlobal,
+                    message: options.catalog.message(
+                        Diagnostic::UndefinedGlobal,
+                        &[
+                            &usage.name,
+                            &did_you_mean(&usage.name, candidates, &options.catalog),
+                        ],
+                    ),
+                }
+            })
+            .collect()
+    }
+
+    /// Rejects source code that exceeds the size limits, before any of it is scanned.
+    fn check_source_limits(
+        source_name: &str,
+        source: &str,
+        options: &CompileOptions,
+    ) -> Result<(), CompileError> {
+        if source.len() > options.max_source_size {
+            let message = options.catalog.message(
+                Diagnostic::SourceTooLarge,
+                &[
+                    &source.len().to_string(),
+                    &options.max_source_size.to_string(),
+                ],
+            );
+            let code = Diagnostic::SourceTooLarge;
+            let mut output = &options.error_output;
+            let _ = if source_name.is_empty() {
+                writeln!(output, "error[{}]: {}", code.code(), &message)
+            } else {
+                writeln!(
+                    output,
+                    "[{}] error[{}]: {}",
+                    source_name,
+                    code.code(),
+                    &message
+                )
+            };
+            return Err(CompileError { code, message });
+        }
+
+        for (i, line) in source.lines().enumerate() {
+            let length = line.chars().count();
+            if length > options.max_line_length {
+                let message = options.catalog.message(
+                    Diagnostic::LineTooLong,
+                    &[&length.to_string(), &options.max_line_length.to_string()],
+                );
+                let code = Diagnostic::LineTooLong;
+                let _ = writeln!(
+                    &options.error_output,
+                    "[{}] error[{}]: {}",
+                    format_location(source_name, i as i32 + 1),
+                    code.code(),
+                    &message
+                );
+                return Err(CompileError { code, message });
+            }
         }
+        Ok(())
     }
 
     fn current_compiler(&mut self) -> &mut Compiler {
@@ -155,11 +730,24 @@ impl CompilerManager {
                     self.parser.current,
                     match e {
                         crate::scanner::ScannerError::UnexpectedCharacter => {
-                            "Unexpected character."
+                            Diagnostic::UnexpectedCharacter
+                        }
+                        crate::scanner::ScannerError::UnterminatedString => {
+                            Diagnostic::UnterminatedString
                         }
-                        crate::scanner::ScannerError::UnterminatedString => "Unterminated string.",
                         // TODO: remove this error
-                        crate::scanner::ScannerError::UninitializedToken => "Uninitialized token.",
+                        crate::scanner::ScannerError::UninitializedToken => {
+                            Diagnostic::UninitializedToken
+                        }
+                        crate::scanner::ScannerError::InvalidDirective => {
+                            Diagnostic::InvalidDirective
+                        }
+                        crate::scanner::ScannerError::UnmatchedDirective => {
+                            Diagnostic::UnmatchedDirective
+                        }
+                        crate::scanner::ScannerError::UnterminatedConditional => {
+                            Diagnostic::UnterminatedConditional
+                        }
                     },
                 ),
                 _ => break,
@@ -167,35 +755,47 @@ impl CompilerManager {
         }
     }
 
-    fn error(&mut self, message: &str) {
-        self.error_at(self.parser.previous, message);
+    fn error(&mut self, diagnostic: Diagnostic) {
+        self.error_at(self.parser.previous, diagnostic);
+    }
+
+    fn error_at(&mut self, token: Token, diagnostic: Diagnostic) {
+        self.error_with_args_at(token, diagnostic, &[]);
     }
 
-    fn error_at(&mut self, token: Token, message: &str) {
+    fn error_with_args_at(&mut self, token: Token, diagnostic: Diagnostic, args: &[&str]) {
         if self.parser.panic_mode {
             return;
         }
+        let message = self.catalog.message(diagnostic, args);
 
         self.parser.panic_mode = true;
-        eprint!("[line {}] Error", token.line);
-
-        match &token.token_type {
-            TokenType::Eof => eprint!(" at end"),
-            TokenType::Error(_) => {}
-            _ => eprint!(" at {:?}", self.lexeme_to_string(token)),
-        }
-
-        eprintln!(": {}", &message);
+        let place = match &token.token_type {
+            TokenType::Eof => " at end".to_string(),
+            TokenType::Error(_) => String::new(),
+            _ => format!(" at {:?}", self.lexeme_to_string(token)),
+        };
+        let _ = writeln!(
+            &self.error_output,
+            "[{}] error[{}]{}: {}",
+            format_location(&self.source_name, token.line),
+            diagnostic.code(),
+            place,
+            &message
+        );
         self.parser.had_error = true;
-        self.parser.error_message = message.to_string();
+        self.parser.error = Some(CompileError {
+            code: diagnostic,
+            message,
+        });
     }
 
-    fn consume(&mut self, token_type: TokenType, message: &str) {
+    fn consume(&mut self, token_type: TokenType, diagnostic: Diagnostic) {
         if self.parser.current.token_type == token_type {
             self.advance();
             return;
         }
-        self.error_at(self.parser.current, message);
+        self.error_at(self.parser.current, diagnostic);
     }
 
     fn emit_instruction(&mut self, instruction: Instruction) {
@@ -220,7 +820,7 @@ impl CompilerManager {
     fn make_constant(&mut self, value: Value) -> usize {
         let constant_index = self.current_compiler().function.chunk.add_constant(value);
         if constant_index as u8 > u8::MAX {
-            self.error("Too many constants in one chunk.");
+            self.error(Diagnostic::TooManyConstants);
             return 0;
         }
         constant_index
@@ -228,20 +828,20 @@ impl CompilerManager {
 
     fn end(&mut self) -> Function {
         self.emit_return();
+        self.warn_unused_locals(0);
 
         // conditional compilation for logging
         #[cfg(feature = "debug_print_code")]
-        {
-            let mut name = self.current_compiler().function.name.clone();
-            if name.is_empty() {
-                name = "<script>".to_string();
-            }
-            if !self.parser.had_error {
-                self.print_current_chunk_constants();
-                self.current_compiler().function.chunk.disassemble(&name);
-            }
+        // Functions are disassembled along with the script that contains them.
+        if !self.parser.had_error && self.current_compiler().function_type == FunctionType::Script {
+            self.print_current_chunk_constants();
+            self.current_compiler()
+                .function
+                .chunk
+                .disassemble("<script>");
         }
 
+        self.current_compiler().function.chunk.globals = Rc::clone(&self.global_names);
         // TODO: refactor cloning?
         let compiled_function = self.current_compiler().function.clone();
         self.compilers.pop();
@@ -251,25 +851,73 @@ impl CompilerManager {
 
     // TODO: current compiler?
     fn begin_scope(&mut self) {
+        if self.current_compiler().scope_depth == MAX_SCOPE_DEPTH {
+            self.error_with_args_at(
+                self.parser.previous,
+                Diagnostic::TooManyScopes,
+                &[&MAX_SCOPE_DEPTH.to_string()],
+            );
+        }
         self.current_compiler().scope_depth += 1;
     }
 
     fn end_scope(&mut self) {
         self.current_compiler().scope_depth -= 1;
 
+        let scope_depth = self.current_compiler().scope_depth;
+        let locals = &self.current_compiler().locals;
+        let first = locals
+            .iter()
+            .position(|local| local.depth > scope_depth)
+            .unwrap_or(locals.len());
+        self.warn_unused_locals(first);
+
         // pop all local variables for the scope that is ending
-        for i in (0..self.current_compiler().locals.len()).rev() {
-            if self.current_compiler().locals.get(i).unwrap().depth
-                > self.current_compiler().scope_depth
-            {
-                self.emit_instruction(Instruction::OpPop);
-                self.current_compiler().locals.pop();
+        let count = self.current_compiler().locals.len() - first;
+        self.current_compiler().locals.truncate(first);
+        match count {
+            0 => {}
+            1 => self.emit_instruction(Instruction::OpPop),
+            count => self.emit_instruction(Instruction::OpPopN(count)),
+        }
+    }
+
+    /// Warns about the locals from the given index on that are never used.
+    fn warn_unused_locals(&mut self, first: usize) {
+        if !self.warn_unused_locals {
+            return;
+        }
+        let unused: Vec<Token> = self.current_compiler().locals[first..]
+            .iter()
+            .filter(|local| !local.used)
+            .map(|local| local.name)
+            .collect();
+        for name in unused {
+            let lexeme = self.lexeme_to_string(name);
+            if !lexeme.starts_with('_') {
+                self.warn(name.line, Diagnostic::UnusedLocal, &[&lexeme]);
             }
         }
     }
 
+    /// Records a warning at a line of the source being compiled.
+    fn warn(&mut self, line: i32, code: Diagnostic, args: &[&str]) {
+        self.warnings.push(Warning {
+            source_name: self.source_name.to_string(),
+            line,
+            code,
+            message: self.catalog.message(code, args),
+        });
+    }
+
     fn emit_return(&mut self) {
-        self.emit_instruction(Instruction::OpNil);
+        match self.current_compiler().function_type {
+            // An initializer returns the instance, which is in slot 0.
+            FunctionType::Initializer => self.emit_instruction(Instruction::OpGetLocal(0)),
+            // A setter returns the assigned value, its parameter.
+            FunctionType::Setter => self.emit_instruction(Instruction::OpGetLocal(1)),
+            _ => self.emit_instruction(Instruction::OpNil),
+        }
         self.emit_instruction(Instruction::OpReturn);
     }
 
@@ -291,22 +939,42 @@ impl CompilerManager {
         self.advance();
         let prefix_rule = CompilerManager::rules(self.parser.previous.token_type);
         if prefix_rule.prefix == ParseFn::None {
-            self.error("Expect expression.");
+            self.error(Diagnostic::ExpectExpression);
             return;
         }
 
         let can_assign: bool = precedence <= Precedence::Assignment as i32;
+        let is_variable = prefix_rule.prefix == ParseFn::Variable;
         self.parse_fn(prefix_rule.prefix, can_assign);
+        // Only a call on the variable itself, like `f(1)`, calls what the global holds.
+        let mut global = match self.current_compiler().function.chunk.bytecode.last() {
+            Some(&Instruction::OpGetGlobal(slot)) if is_variable => {
+                Some(self.global_names[slot].clone())
+            }
+            _ => None,
+        };
 
+        let optional_jumps = self.optional_jumps.len();
         while precedence <= CompilerManager::rules(self.parser.current.token_type).precedence as i32
         {
             self.advance();
             let infix_rule = CompilerManager::rules(self.parser.previous.token_type);
-            self.parse_fn(infix_rule.infix, can_assign);
+            let global = global.take();
+            if infix_rule.infix == ParseFn::Call {
+                self.call_target = global;
+            }
+            // An operator ends the chain that `?.` skips, as in `a?.b + 1`.
+            if infix_rule.precedence != Precedence::Call {
+                self.patch_optional_jumps(optional_jumps);
+            }
+            // Nothing can be assigned in a chain that may have been skipped.
+            let in_optional_chain = self.optional_jumps.len() > optional_jumps;
+            self.parse_fn(infix_rule.infix, can_assign && !in_optional_chain);
         }
+        self.patch_optional_jumps(optional_jumps);
 
         if can_assign && self.match_token(TokenType::Equal) {
-            self.error("Invalid assignment target.");
+            self.error(Diagnostic::InvalidAssignmentTarget);
         }
     }
 
@@ -315,7 +983,7 @@ impl CompilerManager {
             return false;
         }
         self.advance();
-        return true;
+        true
     }
 
     fn check(&self, tt: TokenType) -> bool {
@@ -327,7 +995,9 @@ impl CompilerManager {
     }
 
     fn declaration(&mut self) {
-        if self.match_token(TokenType::Fun) {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Fun) {
             self.fun_declaration();
         } else if self.match_token(TokenType::Var) {
             self.var_declaration();
@@ -342,7 +1012,7 @@ impl CompilerManager {
 
     fn var_declaration(&mut self) {
         // TODO: global variables?
-        let global = self.parse_variable("Expect variable name.");
+        let global = self.parse_variable(Diagnostic::ExpectVariableName);
 
         if self.match_token(TokenType::Equal) {
             self.expression();
@@ -352,33 +1022,223 @@ impl CompilerManager {
         }
         self.consume(
             TokenType::Semicolon,
-            "Expect ';' after variable declaration.",
+            Diagnostic::ExpectSemicolonAfterVariable,
         );
 
         // TODO: global variables?
+        if self.current_compiler().scope_depth == 0 {
+            let name = self.global_names[global].clone();
+            self.record_global_arity(name, None);
+        }
+        self.define_variable(global);
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, Diagnostic::ExpectClassName);
+        let class_name = self.parser.previous;
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        self.emit_instruction(Instruction::OpClass(name_constant));
+        let mut global = 0;
+        if self.current_compiler().scope_depth == 0 {
+            self.record_global_arity(self.lexeme_to_string(class_name), None);
+            self.check_shadowed_native(class_name);
+            global = self.global_slot(class_name);
+        }
         self.define_variable(global);
+
+        let mut has_superclass = false;
+        if self.match_token(TokenType::Less) {
+            self.consume(TokenType::Identifier, Diagnostic::ExpectSuperclassName);
+            if self.identifiers_equal(class_name, self.parser.previous) {
+                self.error(Diagnostic::InheritFromSelf);
+            }
+            self.variable(false);
+            self.named_variable(class_name, false);
+            self.emit_instruction(Instruction::OpInherit);
+            has_superclass = true;
+        }
+        self.classes.push(has_superclass);
+
+        // Load the class back onto the stack, for the methods to be added to it.
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, Diagnostic::ExpectBraceBeforeClassBody);
+        let mut fields = None;
+        // Without the brace, whatever follows would be parsed as methods.
+        while !self.parser.panic_mode
+            && !self.check(TokenType::RightBrace)
+            && !self.check(TokenType::Eof)
+        {
+            self.method(class_name, &mut fields);
+        }
+        self.consume(TokenType::RightBrace, Diagnostic::ExpectBraceAfterClassBody);
+        if let Some(compiler) = fields {
+            self.compilers.push(compiler);
+            self.current += 1;
+            let function = self.end();
+            self.emit_constant(Value::Function(Rc::new(function)));
+            self.emit_instruction(Instruction::OpFields);
+        }
+        self.emit_instruction(Instruction::OpPop);
+        self.classes.pop();
+    }
+
+    /// Compiles a method, an accessor or a field declaration of a class.
+    ///
+    /// The initializers of the class's fields are compiled into one function, which is kept in
+    /// `fields` between them.
+    fn method(&mut self, class_name: Token, fields: &mut Option<Compiler>) {
+        self.consume(TokenType::Identifier, Diagnostic::ExpectMethodName);
+        if self.check(TokenType::Equal) || self.check(TokenType::Semicolon) {
+            self.field(class_name, fields);
+            return;
+        }
+        // `get` and `set` only start a getter or setter before a name, and name methods otherwise.
+        let kind = self.lexeme_to_string(self.parser.previous);
+        if (kind == "get" || kind == "set") && self.check(TokenType::Identifier) {
+            self.accessor(kind == "get");
+            return;
+        }
+        let name_constant = self.identifier_constant(self.parser.previous);
+        let function_type = if self.lexeme_to_string(self.parser.previous) == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit_instruction(Instruction::OpMethod(name_constant));
+    }
+
+    /// Compiles a field declaration, `name = value;` or `name;` for a field starting as nil,
+    /// into the function initializing the fields, as an assignment to a field of `this`.
+    fn field(&mut self, class_name: Token, fields: &mut Option<Compiler>) {
+        let name = self.parser.previous;
+        match fields.take() {
+            Some(compiler) => {
+                self.compilers.push(compiler);
+                self.current += 1;
+            }
+            None => {
+                self.init_compiler(FunctionType::Method);
+                // Named after the class, which is what stack traces show for its construction.
+                self.current_compiler().function.name = self.lexeme_to_string(class_name);
+                self.begin_scope();
+            }
+        }
+
+        let name_constant = self.identifier_constant(name);
+        self.emit_instruction(Instruction::OpGetLocal(0));
+        if self.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_instruction(Instruction::OpNil);
+        }
+        self.consume(TokenType::Semicolon, Diagnostic::ExpectSemicolonAfterField);
+        self.emit_instructions(
+            Instruction::OpSetProperty(name_constant),
+            Instruction::OpPop,
+        );
+
+        *fields = self.compilers.pop();
+        self.current -= 1;
+    }
+
+    /// Compiles a getter, `get name { ... }`, which has no parameter list,
+    /// or a setter, `set name(value) { ... }`.
+    fn accessor(&mut self, getter: bool) {
+        self.advance();
+        let name = self.parser.previous;
+        let name_constant = self.identifier_constant(name);
+        if getter {
+            self.init_compiler(FunctionType::Method);
+            self.begin_scope();
+            self.consume(
+                TokenType::LeftBrace,
+                Diagnostic::ExpectBraceBeforeFunctionBody,
+            );
+            self.block();
+            let function = self.end();
+            self.emit_constant(Value::Function(Rc::new(function)));
+            self.emit_instruction(Instruction::OpGetter(name_constant));
+        } else {
+            self.function(FunctionType::Setter);
+            let chunk = &self.current_compiler().function.chunk;
+            if let Some(Value::Function(function)) = chunk.constants.last() {
+                if function.arity != 1 {
+                    self.error_at(name, Diagnostic::SetterArity);
+                }
+            }
+            self.emit_instruction(Instruction::OpSetter(name_constant));
+        }
     }
 
     fn fun_declaration(&mut self) {
-        let global = self.parse_variable("Expect function name.");
+        let global = self.parse_variable(Diagnostic::ExpectFunctionName);
         self.mark_initialized();
         self.function(FunctionType::Function);
+        if self.current_compiler().scope_depth == 0 {
+            let name = self.global_names[global].clone();
+            let chunk = &self.current_compiler().function.chunk;
+            if let Some(Value::Function(function)) = chunk.constants.last() {
+                let arity = function.arity;
+                self.record_global_arity(name, Some(arity));
+            }
+        }
         self.define_variable(global);
     }
 
-    fn parse_variable(&mut self, error_message: &str) -> usize {
-        self.consume(TokenType::Identifier, error_message);
+    fn parse_variable(&mut self, diagnostic: Diagnostic) -> usize {
+        self.consume(TokenType::Identifier, diagnostic);
 
         self.declare_variable();
         // TODO: current scope depth
         if self.current_compiler().scope_depth > 0 {
             return 0;
         }
-        return self.identifier_constant(self.parser.previous);
+        self.check_shadowed_native(self.parser.previous);
+        self.global_slot(self.parser.previous)
+    }
+
+    /// Returns the slot of the global variable with the given name, giving it the next one if
+    /// the program did not use it yet.
+    fn global_slot(&mut self, name: Token) -> usize {
+        let name = self.lexeme_to_string(name);
+        if let Some(&slot) = self.global_slots.get(&name) {
+            return slot;
+        }
+        let slot = self.global_names.len();
+        Rc::make_mut(&mut self.global_names).push(name.clone());
+        self.global_slots.insert(name, slot);
+        slot
     }
 
     fn identifier_constant(&mut self, name: Token) -> usize {
-        return self.make_constant(Value::String(Rc::new(self.lexeme_to_string(name))));
+        let name = self.intern(self.lexeme_to_string(name));
+        // Reuse the constant if the name is already in the chunk.
+        let existing = self
+            .current_compiler()
+            .function
+            .chunk
+            .constants
+            .iter()
+            .position(
+                |constant| matches!(constant, Value::String(s) if LoxString::ptr_eq(s, &name)),
+            );
+        match existing {
+            Some(index) => index,
+            None => self.make_constant(Value::String(name)),
+        }
+    }
+
+    /// Returns the shared copy of a string, adding it if it is new.
+    fn intern(&mut self, string: String) -> LoxString {
+        if let Some(interned) = self.strings.get(string.as_str()) {
+            return interned.clone();
+        }
+        let interned = LoxString::from(string);
+        self.strings.insert(interned.clone());
+        interned
     }
 
     // Add variable to the scope.
@@ -406,7 +1266,20 @@ impl CompilerManager {
         }
 
         if error {
-            self.error("Already variable with this name in this scope.");
+            self.error(Diagnostic::DuplicateLocal);
+        } else if self.warn_shadowing {
+            let outer: Vec<Token> = self.current_compiler().locals[1..]
+                .iter()
+                .filter(|local| local.depth != -1)
+                .map(|local| local.name)
+                .collect();
+            let shadows = outer
+                .into_iter()
+                .any(|outer| self.identifiers_equal(name, outer));
+            if shadows {
+                let lexeme = self.lexeme_to_string(name);
+                self.warn(name.line, Diagnostic::ShadowedLocal, &[&lexeme]);
+            }
         }
 
         self.add_local(name);
@@ -420,6 +1293,8 @@ impl CompilerManager {
             return;
         }
 
+        let name = self.global_names[global].clone();
+        self.defined_globals.insert(name);
         self.emit_instruction(Instruction::OpDefineGlobal(global));
     }
 
@@ -437,14 +1312,17 @@ impl CompilerManager {
     }
 
     fn add_local(&mut self, name: Token) {
-        if self.current_compiler().locals.len() as u16 == u16::MAX {
-            self.error("Too many local variables in function.");
+        // The function's own slot counts, so that every local fits in its call frame.
+        if self.current_compiler().locals.len() == FRAME_SLOTS {
+            self.error(Diagnostic::TooManyLocals);
             return;
         }
         // When declaring a local, set the depth to -1, indicating it has not been initialized.
-        self.current_compiler()
-            .locals
-            .push(Local { name, depth: -1 });
+        self.current_compiler().locals.push(Local {
+            name,
+            depth: -1,
+            used: false,
+        });
     }
 
     fn identifiers_equal(&self, t_1: Token, t_2: Token) -> bool {
@@ -457,7 +1335,7 @@ impl CompilerManager {
                 return false;
             }
         }
-        return true;
+        true
     }
 
     /// Advance until one of a number of tokens is found, so that one error does not
@@ -477,6 +1355,9 @@ impl CompilerManager {
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Print
+                | TokenType::Defer
+                | TokenType::Throw
+                | TokenType::Try
                 | TokenType::Return => return,
                 _ => {}
             }
@@ -495,7 +1376,9 @@ impl CompilerManager {
                 line: 0,
             },
             depth: 0,
+            used: true,
         });
+        compiler.function.chunk.source_name = Rc::clone(&self.source_name);
         self.compilers.push(compiler);
         self.current += 1;
 
@@ -513,6 +1396,12 @@ impl CompilerManager {
             self.if_statement();
         } else if self.match_token(TokenType::Return) {
             self.return_statement();
+        } else if self.match_token(TokenType::Defer) {
+            self.defer_statement();
+        } else if self.match_token(TokenType::Throw) {
+            self.throw_statement();
+        } else if self.match_token(TokenType::Try) {
+            self.try_statement();
         } else if self.match_token(TokenType::While) {
             self.while_statement();
         } else if self.match_token(TokenType::LeftBrace) {
@@ -524,33 +1413,113 @@ impl CompilerManager {
         }
     }
 
+    /// Compiles a return statement, which at the top level ends the script, giving its value
+    /// to [crate::vm::vm::Interpretation::return_value].
     fn return_statement(&mut self) {
-        if self.current_compiler().function_type == FunctionType::Script {
-            self.error("Can't return from top-level code.");
-        }
-
         if self.match_token(TokenType::Semicolon) {
             self.emit_return();
         } else {
+            match self.current_compiler().function_type {
+                FunctionType::Initializer => self.error(Diagnostic::ReturnFromInitializer),
+                FunctionType::Setter => self.error(Diagnostic::ReturnFromSetter),
+                _ => {}
+            }
             self.expression();
-            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.consume(TokenType::Semicolon, Diagnostic::ExpectSemicolonAfterReturn);
+            self.mark_tail_call();
             self.emit_instruction(Instruction::OpReturn);
         }
     }
 
-    fn if_statement(&mut self) {
-        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+    /// Turns a call whose result is returned, like in `return f(x);`, into an
+    /// [Instruction::OpTailCall], so that deep recursion in tail position does not overflow
+    /// the frames.
+    ///
+    /// The [Instruction::OpReturn] is still emitted after it, for the VM to return through when
+    /// it cannot reuse the frame, and for jumps over the call, like in `return a and f(a);`.
+    ///
+    /// Scripts keep their frame, so that they stay in stack traces.
+    fn mark_tail_call(&mut self) {
+        if self.current_compiler().function_type == FunctionType::Script {
+            return;
+        }
+        let bytecode = &mut self.current_compiler().function.chunk.bytecode;
+        if let Some(Instruction::OpCall(arg_count)) = bytecode.last().copied() {
+            *bytecode.last_mut().unwrap() = Instruction::OpTailCall(arg_count);
+        }
+    }
+
+    /// Compiles the deferred expression in place, behind an [Instruction::OpDefer] that jumps over it.
+    ///
+    /// When the statement is executed, the VM registers the start of the deferred code,
+    /// and runs it when the enclosing function returns.
+    fn defer_statement(&mut self) {
+        let defer_jump = self.emit_jump(Instruction::OpDefer(JUMP_PLACEHOLDER));
+
+        self.current_compiler().compiling_defer = true;
+        self.expression();
+        self.current_compiler().compiling_defer = false;
+        self.consume(TokenType::Semicolon, Diagnostic::ExpectSemicolonAfterDefer);
+        self.emit_instruction(Instruction::OpEndDefer);
+
+        self.patch_jump(defer_jump);
+    }
+
+    fn throw_statement(&mut self) {
         self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        self.consume(TokenType::Semicolon, Diagnostic::ExpectSemicolonAfterThrow);
+        self.emit_instruction(Instruction::OpThrow);
+    }
+
+    /// Compiles the try block between an [Instruction::OpSetupCatch] and an
+    /// [Instruction::OpPopCatch], followed by the catch block, which the try block jumps over.
+    ///
+    /// The catch block starts with the caught value on top of the stack, which becomes the
+    /// local variable of the catch clause.
+    fn try_statement(&mut self) {
+        self.consume(TokenType::LeftBrace, Diagnostic::ExpectBraceAfterTry);
+        let catch_jump = self.emit_jump(Instruction::OpSetupCatch(JUMP_PLACEHOLDER));
+        self.begin_scope();
+        // Without the brace, whatever follows would be parsed as the block.
+        if !self.parser.panic_mode {
+            self.block();
+        }
+        self.end_scope();
+        self.emit_instruction(Instruction::OpPopCatch);
+        let end_jump = self.emit_jump(Instruction::OpJump(JUMP_PLACEHOLDER));
+
+        self.patch_jump(catch_jump);
+        self.consume(TokenType::Catch, Diagnostic::ExpectCatch);
+        self.consume(TokenType::LeftParen, Diagnostic::ExpectParenAfterCatch);
+        self.begin_scope();
+        self.consume(TokenType::Identifier, Diagnostic::ExpectCatchVariable);
+        self.declare_variable();
+        self.mark_initialized();
+        self.consume(
+            TokenType::RightParen,
+            Diagnostic::ExpectParenAfterCatchVariable,
+        );
+        self.consume(TokenType::LeftBrace, Diagnostic::ExpectBraceAfterCatch);
+        if !self.parser.panic_mode {
+            self.block();
+        }
+        self.end_scope();
+        self.patch_jump(end_jump);
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, Diagnostic::ExpectParenAfterIf);
+        self.condition();
+        self.consume(TokenType::RightParen, Diagnostic::ExpectParenAfterCondition);
 
         // Using a placeholder offset for the OpJumpIfFalse instruction.
-        let then_jump = self.emit_jump(Instruction::OpJumpIfFalse(0xffff));
+        let then_jump = self.emit_jump(Instruction::OpJumpIfFalse(JUMP_PLACEHOLDER));
         // Pop the result of the if expression, if it was true, after it has been used by OpJumpIfFalse.
         self.emit_instruction(Instruction::OpPop);
         self.statement();
 
         // Using a placeholder offset for the OpJump instruction.
-        let else_jump = self.emit_jump(Instruction::OpJump(0xffff));
+        let else_jump = self.emit_jump(Instruction::OpJump(JUMP_PLACEHOLDER));
 
         self.patch_jump(then_jump);
         // If the if expression was false, the result of the if expression was not popped earlier.
@@ -567,7 +1536,7 @@ impl CompilerManager {
         // Starting new scope, in case the initializer declares a variable.
         self.begin_scope();
 
-        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        self.consume(TokenType::LeftParen, Diagnostic::ExpectParenAfterFor);
         // Left/Initializer clause.
         if self.match_token(TokenType::Semicolon) {
             // There is no initializer.
@@ -583,20 +1552,26 @@ impl CompilerManager {
         // Middle/Test clause.
         if !self.match_token(TokenType::Semicolon) {
             self.expression();
-            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+            self.consume(
+                TokenType::Semicolon,
+                Diagnostic::ExpectSemicolonAfterLoopCondition,
+            );
 
             // If the middle clause is false exit the for loop.
-            exit_jump = self.emit_jump(Instruction::OpJumpIfFalse(0xffff)) as i32;
+            exit_jump = self.emit_jump(Instruction::OpJumpIfFalse(JUMP_PLACEHOLDER)) as i32;
             self.emit_instruction(Instruction::OpPop);
         }
 
         // Right/Increment clause.
         if !self.match_token(TokenType::RightParen) {
-            let body_jump = self.emit_jump(Instruction::OpJump(0xfff));
+            let body_jump = self.emit_jump(Instruction::OpJump(JUMP_PLACEHOLDER));
             let increment_start = self.current_compiler().function.chunk.bytecode.len();
             self.expression();
             self.emit_instruction(Instruction::OpPop);
-            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+            self.consume(
+                TokenType::RightParen,
+                Diagnostic::ExpectParenAfterForClauses,
+            );
 
             self.emit_loop(loop_start);
             loop_start = increment_start;
@@ -604,7 +1579,9 @@ impl CompilerManager {
         }
 
         // Body
+        self.begin_loop();
         self.statement();
+        self.end_loop();
         self.emit_loop(loop_start);
 
         // A jump instruction only exists if there is a middle clause.
@@ -616,15 +1593,34 @@ impl CompilerManager {
         self.end_scope();
     }
 
+    /// Compiles the condition of an if or while statement.
+    fn condition(&mut self) {
+        let start = self.parser.current;
+        self.last_assignment = None;
+        self.expression();
+
+        // The condition is a bare assignment if it starts with the target of its outermost one.
+        if self.warn_assignment_in_condition && self.last_assignment == Some(start.start) {
+            self.warnings.push(Warning {
+                source_name: self.source_name.to_string(),
+                line: start.line,
+                code: Diagnostic::AssignmentInCondition,
+                message: self.catalog.message(Diagnostic::AssignmentInCondition, &[]),
+            });
+        }
+    }
+
     fn while_statement(&mut self) {
         let loop_start = self.current_compiler().function.chunk.bytecode.len();
-        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
-        self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        self.consume(TokenType::LeftParen, Diagnostic::ExpectParenAfterWhile);
+        self.condition();
+        self.consume(TokenType::RightParen, Diagnostic::ExpectParenAfterCondition);
 
-        let exit_jump = self.emit_jump(Instruction::OpJumpIfFalse(0xffff));
+        let exit_jump = self.emit_jump(Instruction::OpJumpIfFalse(JUMP_PLACEHOLDER));
         self.emit_instruction(Instruction::OpPop);
+        self.begin_loop();
         self.statement();
+        self.end_loop();
         // jump back to the beginning
         self.emit_loop(loop_start);
 
@@ -641,43 +1637,92 @@ impl CompilerManager {
     /// Put the correct number of instructions to jump over, if the if condition is false,
     /// now that the if block has been compiled.
     fn patch_jump(&mut self, offset: usize) {
-        let jump = self.current_compiler().function.chunk.bytecode.len() - offset - 1;
+        let length = self.current_compiler().function.chunk.bytecode.len();
+        if offset >= length {
+            self.internal_error(&format!(
+                "jump offset {} is past the end of the chunk ({} instructions).",
+                offset, length
+            ));
+            return;
+        }
+
+        let jump = length - offset - 1;
         let instruction = match self.current_compiler().function.chunk.bytecode[offset] {
-            Instruction::OpJump(_) => Some(Instruction::OpJump(jump)),
-            Instruction::OpJumpIfFalse(_) => Some(Instruction::OpJumpIfFalse(jump)),
-            _ => None,
+            Instruction::OpJump(JUMP_PLACEHOLDER) => Instruction::OpJump(jump),
+            Instruction::OpJumpIfFalse(JUMP_PLACEHOLDER) => Instruction::OpJumpIfFalse(jump),
+            Instruction::OpJumpIfNil(JUMP_PLACEHOLDER) => Instruction::OpJumpIfNil(jump),
+            Instruction::OpJumpIfNotEqual(JUMP_PLACEHOLDER) => Instruction::OpJumpIfNotEqual(jump),
+            Instruction::OpDefer(JUMP_PLACEHOLDER) => Instruction::OpDefer(jump),
+            Instruction::OpSetupCatch(JUMP_PLACEHOLDER) => Instruction::OpSetupCatch(jump),
+            instruction => {
+                self.internal_error(&format!(
+                    "instruction {:?} at offset {} is not an unpatched jump.",
+                    instruction, offset
+                ));
+                return;
+            }
         };
-        self.current_compiler().function.chunk.bytecode[offset] = instruction.unwrap();
+        self.current_compiler().function.chunk.bytecode[offset] = instruction;
     }
 
     fn emit_loop(&mut self, loop_start: usize) {
-        let offset = self.current_compiler().function.chunk.bytecode.len() - loop_start + 1;
+        let length = self.current_compiler().function.chunk.bytecode.len();
+        if loop_start > length {
+            self.internal_error(&format!(
+                "loop start {} is past the end of the chunk ({} instructions).",
+                loop_start, length
+            ));
+            return;
+        }
+
+        let offset = length - loop_start + 1;
         self.emit_instruction(Instruction::OpLoop(offset));
     }
 
+    /// Reports a bug in the compiler itself, rather than in the compiled program.
+    fn internal_error(&mut self, message: &str) {
+        self.error_with_args_at(
+            self.parser.previous,
+            Diagnostic::InternalCompilerError,
+            &[message],
+        );
+    }
+
     fn function(&mut self, function_type: FunctionType) {
         self.init_compiler(function_type);
 
         self.begin_scope();
 
-        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        self.consume(
+            TokenType::LeftParen,
+            Diagnostic::ExpectParenAfterFunctionName,
+        );
         if !self.check(TokenType::RightParen) {
             loop {
                 self.current_compiler().function.arity += 1;
                 if self.current_compiler().function.arity > 255 {
-                    self.error_at(self.parser.current, "Can't have more than 255 parameters.");
+                    self.error_at(self.parser.current, Diagnostic::TooManyParameters);
                 }
 
-                let constant = self.parse_variable("Expect parameter name.");
+                let constant = self.parse_variable(Diagnostic::ExpectParameterName);
                 self.define_variable(constant);
+                if let Some(parameter) = self.current_compiler().locals.last_mut() {
+                    parameter.used = true;
+                }
 
                 if !self.match_token(TokenType::Comma) {
                     break;
                 }
             }
         }
-        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
-        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.consume(
+            TokenType::RightParen,
+            Diagnostic::ExpectParenAfterParameters,
+        );
+        self.consume(
+            TokenType::LeftBrace,
+            Diagnostic::ExpectBraceBeforeFunctionBody,
+        );
         self.block();
 
         let function = self.end();
@@ -686,10 +1731,138 @@ impl CompilerManager {
 
     /// Compiles a function call.
     fn call(&mut self) {
+        let target = self.call_target.take();
         let arg_count = self.argument_list();
+        if let Some(name) = target {
+            self.global_calls.push(GlobalCall {
+                name,
+                arg_count,
+                source_name: Rc::clone(&self.source_name),
+                line: self.parser.previous.line,
+            });
+        }
         self.emit_instruction(Instruction::OpCall(arg_count));
     }
 
+    /// Compiles a property access, `instance.name`, or an assignment to it.
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, Diagnostic::ExpectPropertyName);
+        let name = self.identifier_constant(self.parser.previous);
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_instruction(Instruction::OpSetProperty(name));
+        } else {
+            self.emit_instruction(Instruction::OpGetProperty(name));
+        }
+    }
+
+    /// Compiles `?.name`, which reads the property like `.name`, unless the receiver is nil.
+    ///
+    /// A nil receiver skips the rest of the chain of property accesses, calls and indexes after
+    /// it, which evaluates to nil: `a?.b.c()` is nil if `a` is.
+    fn optional_dot(&mut self) {
+        let jump = self.emit_jump(Instruction::OpJumpIfNil(JUMP_PLACEHOLDER));
+        self.optional_jumps.push(jump);
+        self.consume(TokenType::Identifier, Diagnostic::ExpectPropertyName);
+        let name = self.identifier_constant(self.parser.previous);
+        self.emit_instruction(Instruction::OpGetProperty(name));
+    }
+
+    /// Lands the jumps of the optional chains since the given number of pending ones, at the end
+    /// of their chain.
+    fn patch_optional_jumps(&mut self, since: usize) {
+        for jump in self.optional_jumps.split_off(since) {
+            self.patch_jump(jump);
+        }
+    }
+
+    /// Compiles `this`, the instance a method was called on, which is in slot 0.
+    ///
+    /// Functions declared inside a method cannot use it, since they do not capture variables.
+    fn this(&mut self) {
+        match self.current_compiler().function_type {
+            FunctionType::Method | FunctionType::Initializer | FunctionType::Setter => {
+                self.emit_instruction(Instruction::OpGetLocal(0))
+            }
+            _ => self.error(Diagnostic::ThisOutsideMethod),
+        }
+    }
+
+    /// Compiles `super.name`, the method of the superclass bound to `this`.
+    fn super_(&mut self) {
+        match self.current_compiler().function_type {
+            FunctionType::Method | FunctionType::Initializer | FunctionType::Setter => {
+                if self.classes.last() != Some(&true) {
+                    self.error(Diagnostic::SuperWithoutSuperclass);
+                }
+            }
+            _ => self.error(Diagnostic::SuperOutsideMethod),
+        }
+        self.consume(TokenType::Dot, Diagnostic::ExpectDotAfterSuper);
+        self.consume(
+            TokenType::Identifier,
+            Diagnostic::ExpectSuperclassMethodName,
+        );
+        let name = self.identifier_constant(self.parser.previous);
+        self.emit_instruction(Instruction::OpGetSuper(name));
+    }
+
+    /// Compiles a slice, `value[start:end]`, where either bound may be omitted,
+    /// or an index, `list[index]`, which can be assigned to.
+    fn subscript(&mut self, can_assign: bool) {
+        if self.check(TokenType::Colon) {
+            self.emit_instruction(Instruction::OpNil);
+        } else {
+            self.expression();
+            if !self.check(TokenType::Colon) {
+                self.consume(TokenType::RightBracket, Diagnostic::ExpectBracketAfterIndex);
+                if can_assign && self.match_token(TokenType::Equal) {
+                    self.expression();
+                    self.emit_instruction(Instruction::OpIndexSet);
+                } else {
+                    self.emit_instruction(Instruction::OpIndexGet);
+                }
+                return;
+            }
+        }
+        self.consume(TokenType::Colon, Diagnostic::ExpectColonInSlice);
+
+        if self.check(TokenType::RightBracket) {
+            self.emit_instruction(Instruction::OpNil);
+        } else {
+            self.expression();
+        }
+        self.consume(TokenType::RightBracket, Diagnostic::ExpectBracketAfterSlice);
+
+        self.emit_instruction(Instruction::OpSlice);
+    }
+
+    /// Compiles a list literal, `[a, b, c]`.
+    fn list(&mut self) {
+        let mut count: usize = 0;
+
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.expression();
+                if count == 255 {
+                    self.error(Diagnostic::TooManyListElements);
+                }
+                count += 1;
+
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            TokenType::RightBracket,
+            Diagnostic::ExpectBracketAfterElements,
+        );
+
+        self.emit_instruction(Instruction::OpBuildList(count));
+    }
+
     fn argument_list(&mut self) -> usize {
         let mut arg_count: usize = 0;
 
@@ -697,7 +1870,7 @@ impl CompilerManager {
             loop {
                 self.expression();
                 if arg_count == 255 {
-                    self.error("Can't have more than 255 arguments.");
+                    self.error(Diagnostic::TooManyArguments);
                 }
                 arg_count += 1;
 
@@ -706,21 +1879,21 @@ impl CompilerManager {
                 }
             }
         }
-        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        self.consume(TokenType::RightParen, Diagnostic::ExpectParenAfterArguments);
 
         arg_count
     }
 
     fn and(&mut self) {
-        let end_jump = self.emit_jump(Instruction::OpJumpIfFalse(0xffff));
+        let end_jump = self.emit_jump(Instruction::OpJumpIfFalse(JUMP_PLACEHOLDER));
         self.emit_instruction(Instruction::OpPop);
         self.parse_precedence(Precedence::And as i32);
         self.patch_jump(end_jump);
     }
 
     fn or(&mut self) {
-        let else_jump = self.emit_jump(Instruction::OpJumpIfFalse(0xffff));
-        let end_jump = self.emit_jump(Instruction::OpJump(0xffff));
+        let else_jump = self.emit_jump(Instruction::OpJumpIfFalse(JUMP_PLACEHOLDER));
+        let end_jump = self.emit_jump(Instruction::OpJump(JUMP_PLACEHOLDER));
 
         self.patch_jump(else_jump);
         self.emit_instruction(Instruction::OpPop);
@@ -730,58 +1903,216 @@ impl CompilerManager {
     }
 
     fn block(&mut self) {
+        if self.current_compiler().scope_depth > MAX_SCOPE_DEPTH {
+            // Already reported, and compiling any deeper could overflow the compiler's own stack.
+            self.skip_block();
+            return;
+        }
         while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
             self.declaration();
         }
-        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+        self.consume(TokenType::RightBrace, Diagnostic::ExpectBraceAfterBlock);
+    }
+
+    /// Skips to the end of the current block without compiling it.
+    fn skip_block(&mut self) {
+        let mut depth = 1;
+        while !self.check(TokenType::Eof) {
+            if self.check(TokenType::LeftBrace) {
+                depth += 1;
+            } else if self.check(TokenType::RightBrace) {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            self.advance();
+        }
+        self.consume(TokenType::RightBrace, Diagnostic::ExpectBraceAfterBlock);
+        // The parser is back in sync at the end of the block, so the enclosing blocks can end
+        // normally instead of being skipped by synchronize().
+        self.parser.panic_mode = false;
+    }
+
+    fn begin_loop(&mut self) {
+        if self.current_compiler().loop_depth == MAX_LOOP_DEPTH {
+            self.error_with_args_at(
+                self.parser.previous,
+                Diagnostic::TooManyLoops,
+                &[&MAX_LOOP_DEPTH.to_string()],
+            );
+        }
+        self.current_compiler().loop_depth += 1;
+    }
+
+    fn end_loop(&mut self) {
+        self.current_compiler().loop_depth -= 1;
     }
 
     fn expression_statement(&mut self) {
+        let start = self.parser.current;
         self.expression();
-        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        if self.parser.current.token_type == TokenType::Semicolon {
+            self.advance();
+        } else {
+            // A lone name followed by more code is usually a misspelled keyword, as in `thorw x;`.
+            let hint = if start.token_type == TokenType::Identifier
+                && self.parser.previous.start == start.start
+            {
+                did_you_mean(&self.lexeme_to_string(start), KEYWORDS, &self.catalog)
+            } else {
+                String::new()
+            };
+            self.error_with_args_at(
+                self.parser.current,
+                Diagnostic::ExpectSemicolonAfterExpression,
+                &[&hint],
+            );
+        }
         self.emit_instruction(Instruction::OpPop);
     }
 
     fn print_statement(&mut self) {
         self.expression();
-        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.consume(TokenType::Semicolon, Diagnostic::ExpectSemicolonAfterValue);
         self.emit_instruction(Instruction::OpPrint);
     }
 
     fn number(&mut self) {
-        // TODO: lexeme handling?
-        let value = self
-            .lexeme_to_string(self.parser.previous)
-            .parse::<f64>()
-            .unwrap();
-        self.emit_constant(Value::Number(value));
+        let lexeme = self.lexeme_to_string(self.parser.previous);
+        match parse_number(&lexeme) {
+            // Common small integers are loaded directly, without using a constant slot.
+            Some(0.0) => self.emit_instruction(Instruction::OpZero),
+            Some(1.0) => self.emit_instruction(Instruction::OpOne),
+            Some(value) if value.fract() == 0.0 && value <= i8::MAX as f64 => {
+                self.emit_instruction(Instruction::OpSmallInt(value as i8))
+            }
+            Some(value) => self.emit_constant(Value::Number(value)),
+            None => self.error(Diagnostic::InvalidNumber),
+        }
     }
 
     fn variable(&mut self, can_assign: bool) {
-        self.named_variable(self.parser.previous, can_assign);
+        let name = self.parser.previous;
+        if let Some(extension) = self.extension(name) {
+            if self.match_token(TokenType::LeftParen) {
+                let arg_count = self.argument_list();
+                if arg_count != extension.arity as usize {
+                    self.error_with_args_at(
+                        self.parser.previous,
+                        Diagnostic::WrongExtensionArgumentCount,
+                        &[&extension.arity.to_string(), &arg_count.to_string()],
+                    );
+                }
+                self.emit_instruction(Instruction::OpExtension(extension.id, extension.arity));
+                return;
+            }
+        }
+        self.named_variable(name, can_assign);
+    }
+
+    /// The extra instruction called by this name, unless a local variable has the name.
+    fn extension(&self, name: Token) -> Option<ExtensionOpcode> {
+        if self.extensions.is_empty() {
+            return None;
+        }
+        let compiler = &self.compilers[self.current as usize];
+        if compiler
+            .locals
+            .iter()
+            .any(|local| self.identifiers_equal(local.name, name))
+        {
+            return None;
+        }
+        self.extensions.get(&self.lexeme_to_string(name)).copied()
     }
 
     fn named_variable(&mut self, name: Token, can_assign: bool) {
+        let (get_op, set_op) = self.variable_instructions(name);
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_instruction(set_op);
+            self.record_assignment(name, set_op);
+            self.last_assignment = Some(name.start);
+        } else if self.match_token(TokenType::PlusPlus) || self.match_token(TokenType::MinusMinus) {
+            self.record_assignment(name, set_op);
+            // The old value stays on the stack, under the incremented copy.
+            self.emit_instruction(get_op);
+            self.emit_instruction(Instruction::OpDup);
+            self.emit_increment(self.parser.previous.token_type, set_op);
+            self.emit_instruction(Instruction::OpPop);
+        } else {
+            self.emit_instruction(get_op);
+        }
+    }
+
+    /// Compiles `++name` or `--name`, which evaluates to the new value.
+    fn prefix_increment(&mut self) {
+        let operator = self.parser.previous;
+        if !self.check(TokenType::Identifier) {
+            let lexeme = self.lexeme_to_string(operator);
+            self.error_with_args_at(
+                self.parser.current,
+                Diagnostic::ExpectIncrementVariable,
+                &[&lexeme],
+            );
+            return;
+        }
+        self.advance();
+        let (get_op, set_op) = self.variable_instructions(self.parser.previous);
+        self.record_assignment(self.parser.previous, set_op);
+        self.emit_instruction(get_op);
+        self.emit_increment(operator.token_type, set_op);
+        // `++a.b` would otherwise increment `a` and then read its property.
+        if self.check(TokenType::Dot)
+            || self.check(TokenType::QuestionDot)
+            || self.check(TokenType::LeftParen)
+            || self.check(TokenType::LeftBracket)
+        {
+            self.error_at(self.parser.current, Diagnostic::InvalidIncrementTarget);
+        }
+    }
+
+    /// Emits the instructions adding 1 to the variable's value on top of the stack for `++`, or
+    /// subtracting 1 for `--`, and assigning the result, which is left on the stack.
+    fn emit_increment(&mut self, operator: TokenType, set_op: Instruction) {
+        self.emit_instruction(Instruction::OpOne);
+        match operator {
+            TokenType::PlusPlus => self.emit_instruction(Instruction::OpAdd),
+            _ => self.emit_instruction(Instruction::OpSubtract),
+        }
+        self.emit_instruction(set_op);
+    }
+
+    /// The instructions reading and assigning the variable with the given name.
+    fn variable_instructions(&mut self, name: Token) -> (Instruction, Instruction) {
         let get_op: Instruction;
         let set_op: Instruction;
-        let mut arg = self.resolve_local(name);
+        let arg = self.resolve_local(name);
         if arg != -1 {
             // If a local variable with the given name exists, this is a local variable.
             get_op = Instruction::OpGetLocal(arg as usize);
             set_op = Instruction::OpSetLocal(arg as usize);
         } else {
             // If it does not exist, it should be a global variable.
-            arg = self.identifier_constant(name) as i32;
-            get_op = Instruction::OpGetGlobal(arg as usize);
-            set_op = Instruction::OpSetGlobal(arg as usize);
+            if self.track_global_uses {
+                let locals = self.current_compiler().locals.clone();
+                self.global_uses.push(GlobalUse {
+                    name: self.lexeme_to_string(name),
+                    source_name: Rc::clone(&self.source_name),
+                    line: name.line,
+                    locals: locals
+                        .iter()
+                        .map(|local| self.lexeme_to_string(local.name))
+                        .collect(),
+                });
+            }
+            let slot = self.global_slot(name);
+            get_op = Instruction::OpGetGlobal(slot);
+            set_op = Instruction::OpSetGlobal(slot);
         };
-
-        if can_assign && self.match_token(TokenType::Equal) {
-            self.expression();
-            self.emit_instruction(set_op);
-        } else {
-            self.emit_instruction(get_op);
-        }
+        (get_op, set_op)
     }
 
     /// Returns the index of the local variable in the locals vector.
@@ -791,17 +2122,92 @@ impl CompilerManager {
             let l = self.current_compiler().locals[i];
             if self.identifiers_equal(l.name, name) {
                 if l.depth == -1 {
-                    self.error("Can't read local variable in its own initializer.");
+                    self.error(Diagnostic::LocalInOwnInitializer);
                 }
+                // Deferred code runs when the function returns, after any nested block has ended,
+                // so only the locals of the function's outermost scope are still in their slots.
+                if self.current_compiler().compiling_defer && l.depth > self.function_scope_depth()
+                {
+                    self.error(Diagnostic::BlockLocalInDefer);
+                }
+                self.current_compiler().locals[i].used = true;
                 return i as i32;
             }
         }
-        return -1;
+        -1
+    }
+
+    /// The scope depth of the outermost locals of the [Function] currently being compiled.
+    fn function_scope_depth(&mut self) -> i32 {
+        match self.current_compiler().function_type {
+            FunctionType::Script => 0,
+            FunctionType::Function
+            | FunctionType::Method
+            | FunctionType::Initializer
+            | FunctionType::Setter => 1,
+        }
     }
 
     fn grouping(&mut self) {
         self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+        self.consume(
+            TokenType::RightParen,
+            Diagnostic::ExpectParenAfterExpression,
+        );
+    }
+
+    /// Compiles a match expression, `match (value) { pattern => result, _ => default }`.
+    ///
+    /// The value stays on the stack while each pattern is compared to it with an
+    /// [Instruction::OpJumpIfNotEqual], which skips to the next arm if they differ. The first
+    /// arm whose pattern is equal replaces the value with its result. Without a matching arm,
+    /// the match is nil.
+    fn match_(&mut self) {
+        self.consume(TokenType::LeftParen, Diagnostic::ExpectParenAfterMatch);
+        self.expression();
+        self.consume(
+            TokenType::RightParen,
+            Diagnostic::ExpectParenAfterMatchValue,
+        );
+        self.consume(TokenType::LeftBrace, Diagnostic::ExpectBraceBeforeMatchArms);
+
+        let mut end_jumps = Vec::new();
+        let mut has_wildcard = false;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            if has_wildcard {
+                self.error_at(self.parser.current, Diagnostic::WildcardArmNotLast);
+                break;
+            }
+            let is_wildcard = self.check(TokenType::Identifier)
+                && self.lexeme_to_string(self.parser.current) == "_";
+            let next_arm = if is_wildcard {
+                self.advance();
+                has_wildcard = true;
+                None
+            } else {
+                self.expression();
+                Some(self.emit_jump(Instruction::OpJumpIfNotEqual(JUMP_PLACEHOLDER)))
+            };
+            self.consume(TokenType::EqualGreater, Diagnostic::ExpectArrowAfterPattern);
+            self.emit_instruction(Instruction::OpPop);
+            self.expression();
+            if let Some(next_arm) = next_arm {
+                end_jumps.push(self.emit_jump(Instruction::OpJump(JUMP_PLACEHOLDER)));
+                self.patch_jump(next_arm);
+            }
+
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBrace, Diagnostic::ExpectBraceAfterMatchArms);
+
+        if !has_wildcard {
+            self.emit_instructions(Instruction::OpPop, Instruction::OpNil);
+        }
+        for end_jump in end_jumps {
+            self.patch_jump(end_jump);
+        }
     }
 
     fn string(&mut self) {
@@ -810,7 +2216,7 @@ impl CompilerManager {
             self.parser.previous.start + 1,
             (self.parser.previous.length - 2) as usize,
         );
-        let v: Value = Value::String(Rc::new(s));
+        let v: Value = Value::String(self.intern(s));
         self.emit_constant(v);
     }
 
@@ -849,7 +2255,8 @@ impl CompilerManager {
             TokenType::Minus => self.emit_instruction(Instruction::OpSubtract),
             TokenType::Star => self.emit_instruction(Instruction::OpMultiply),
             TokenType::Slash => self.emit_instruction(Instruction::OpDivide),
-            _ => return,
+            TokenType::Is => self.emit_instruction(Instruction::OpIs),
+            _ => (),
         }
     }
 
@@ -860,15 +2267,18 @@ impl CompilerManager {
             TokenType::False => self.emit_instruction(Instruction::OpFalse),
             TokenType::Nil => self.emit_instruction(Instruction::OpNil),
             TokenType::True => self.emit_instruction(Instruction::OpTrue),
-            _ => return,
+            _ => (),
         }
     }
 
     fn parse_fn(&mut self, parse_fn: ParseFn, can_assign: bool) {
         match parse_fn {
             ParseFn::Call => self.call(),
+            ParseFn::Subscript => self.subscript(can_assign),
+            ParseFn::List => self.list(),
             ParseFn::Grouping => self.grouping(),
-            // ParseFn::Dot => ,
+            ParseFn::Dot => self.dot(can_assign),
+            ParseFn::OptionalDot => self.optional_dot(),
             ParseFn::Unary => self.unary(),
             ParseFn::Binary => self.binary(),
             ParseFn::Variable => self.variable(can_assign),
@@ -877,15 +2287,18 @@ impl CompilerManager {
             ParseFn::And => self.and(),
             ParseFn::Literal => self.literal(),
             ParseFn::Or => self.or(),
-            // ParseFn::Super => ,
-            // ParseFn::This => ,
+            ParseFn::Super => self.super_(),
+            ParseFn::This => self.this(),
+            ParseFn::Increment => self.prefix_increment(),
+            ParseFn::InvalidIncrement => self.error(Diagnostic::InvalidIncrementTarget),
+            ParseFn::Match => self.match_(),
             // ParseFn::None => ,
             ParseFn::None => (),
         }
     }
 
     fn rules(token_type: TokenType) -> ParseRule {
-        return match token_type {
+        match token_type {
             TokenType::LeftParen => ParseRule {
                 prefix: ParseFn::Grouping,
                 infix: ParseFn::Call,
@@ -906,16 +2319,43 @@ impl CompilerManager {
                 infix: ParseFn::None,
                 precedence: Precedence::None,
             },
-            TokenType::Comma => ParseRule {
+            TokenType::LeftBracket => ParseRule {
+                prefix: ParseFn::List,
+                infix: ParseFn::Subscript,
+                precedence: Precedence::Call,
+            },
+            TokenType::RightBracket => ParseRule {
                 prefix: ParseFn::None,
                 infix: ParseFn::None,
                 precedence: Precedence::None,
             },
-            TokenType::Dot => ParseRule {
+            TokenType::Colon => ParseRule {
                 prefix: ParseFn::None,
                 infix: ParseFn::None,
                 precedence: Precedence::None,
             },
+            TokenType::Comma => ParseRule {
+                prefix: ParseFn::None,
+                infix: ParseFn::None,
+                precedence: Precedence::None,
+            },
+            TokenType::Dot => ParseRule {
+                prefix: ParseFn::None,
+                infix: ParseFn::Dot,
+                precedence: Precedence::Call,
+            },
+            TokenType::QuestionDot => ParseRule {
+                prefix: ParseFn::None,
+                infix: ParseFn::OptionalDot,
+                precedence: Precedence::Call,
+            },
+            TokenType::MinusMinus | TokenType::PlusPlus => ParseRule {
+                prefix: ParseFn::Increment,
+                // Postfix increments of variables are compiled with the variable, any other
+                // operand is an error.
+                infix: ParseFn::InvalidIncrement,
+                precedence: Precedence::Call,
+            },
             TokenType::Minus => ParseRule {
                 prefix: ParseFn::Unary,
                 infix: ParseFn::Binary,
@@ -961,6 +2401,11 @@ impl CompilerManager {
                 infix: ParseFn::Binary,
                 precedence: Precedence::Equality,
             },
+            TokenType::EqualGreater => ParseRule {
+                prefix: ParseFn::None,
+                infix: ParseFn::None,
+                precedence: Precedence::None,
+            },
             TokenType::Greater => ParseRule {
                 prefix: ParseFn::None,
                 infix: ParseFn::Binary,
@@ -1001,11 +2446,21 @@ impl CompilerManager {
                 infix: ParseFn::And,
                 precedence: Precedence::And,
             },
+            TokenType::Catch => ParseRule {
+                prefix: ParseFn::None,
+                infix: ParseFn::None,
+                precedence: Precedence::None,
+            },
             TokenType::Class => ParseRule {
                 prefix: ParseFn::None,
                 infix: ParseFn::None,
                 precedence: Precedence::None,
             },
+            TokenType::Defer => ParseRule {
+                prefix: ParseFn::None,
+                infix: ParseFn::None,
+                precedence: Precedence::None,
+            },
             TokenType::Else => ParseRule {
                 prefix: ParseFn::None,
                 infix: ParseFn::None,
@@ -1031,6 +2486,16 @@ impl CompilerManager {
                 infix: ParseFn::None,
                 precedence: Precedence::None,
             },
+            TokenType::Is => ParseRule {
+                prefix: ParseFn::None,
+                infix: ParseFn::Binary,
+                precedence: Precedence::Comparison,
+            },
+            TokenType::Match => ParseRule {
+                prefix: ParseFn::Match,
+                infix: ParseFn::None,
+                precedence: Precedence::None,
+            },
             TokenType::Nil => ParseRule {
                 prefix: ParseFn::Literal,
                 infix: ParseFn::None,
@@ -1052,12 +2517,12 @@ impl CompilerManager {
                 precedence: Precedence::None,
             },
             TokenType::Super => ParseRule {
-                prefix: ParseFn::None,
+                prefix: ParseFn::Super,
                 infix: ParseFn::None,
                 precedence: Precedence::None,
             },
             TokenType::This => ParseRule {
-                prefix: ParseFn::None,
+                prefix: ParseFn::This,
                 infix: ParseFn::None,
                 precedence: Precedence::None,
             },
@@ -1071,6 +2536,16 @@ impl CompilerManager {
                 infix: ParseFn::None,
                 precedence: Precedence::None,
             },
+            TokenType::Throw => ParseRule {
+                prefix: ParseFn::None,
+                infix: ParseFn::None,
+                precedence: Precedence::None,
+            },
+            TokenType::Try => ParseRule {
+                prefix: ParseFn::None,
+                infix: ParseFn::None,
+                precedence: Precedence::None,
+            },
             TokenType::While => ParseRule {
                 prefix: ParseFn::None,
                 infix: ParseFn::None,
@@ -1086,6 +2561,6 @@ impl CompilerManager {
                 infix: ParseFn::None,
                 precedence: Precedence::None,
             },
-        };
+        }
     }
 }
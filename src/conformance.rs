@@ -0,0 +1,182 @@
+//! Checking a script against the expectations written in its comments, in the format of the
+//! test suite of Crafting Interpreters, to measure how closely rlox follows the reference
+//! implementation:
+//!
+//! - `// expect: text` is a line the script prints, in order;
+//! - `// expect runtime error: message` is the runtime error that stops the script;
+//! - `// Error at 'x': message`, optionally after `[line 3]` or `[c line 3]`, is the compile error
+//!   that stops the script. Only the message is compared, and errors for the Java implementation,
+//!   after `[java line 3]`, are ignored.
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::vm::vm::{VMError, VM};
+
+/// What a script is expected to do.
+#[derive(Debug, Default, PartialEq)]
+pub struct Expectations {
+    /// The printed lines.
+    pub output: Vec<String>,
+    pub runtime_error: Option<String>,
+    /// The message of the first compile error.
+    pub compile_error: Option<String>,
+}
+
+/// Reads the expectations in the comments of a script.
+pub fn expectations(source: &str) -> Expectations {
+    let mut expectations = Expectations::default();
+    for line in source.lines() {
+        if let Some((_, output)) = line.split_once("// expect: ") {
+            expectations.output.push(output.to_string());
+        } else if let Some((_, error)) = line.split_once("// expect runtime error: ") {
+            expectations.runtime_error = Some(error.to_string());
+        } else if let Some(error) = line.split_once("// ").and_then(|(_, c)| compile_error(c)) {
+            expectations.compile_error.get_or_insert(error);
+        }
+    }
+    expectations
+}
+
+/// Reads the message of an expected compile error, like `[line 2] Error at 'a': Message.`.
+fn compile_error(comment: &str) -> Option<String> {
+    let error = match comment.strip_prefix('[') {
+        Some(located) => {
+            let (location, error) = located.split_once("] ")?;
+            if !(location.starts_with("line ") || location.starts_with("c line ")) {
+                return None;
+            }
+            error
+        }
+        None => comment,
+    };
+    if !error.starts_with("Error") {
+        return None;
+    }
+    let (_, message) = error.split_once(": ")?;
+    Some(message.to_string())
+}
+
+/// Collects what a [VM] prints, including the output printed before an error.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs a script in the given [VM], describing how it did not meet its expectations, if it did
+/// not.
+///
+/// Replaces the output of the VM, so that what the script prints can be compared.
+pub fn check(vm: &mut VM, name: &str, source: String) -> Result<(), String> {
+    let expected = expectations(&source);
+    let output = Output::default();
+    vm.set_output(Box::new(output.clone()));
+    let error = vm.interpret_source(name, source).err();
+    let written = String::from_utf8_lossy(&output.0.borrow()).into_owned();
+    let printed: Vec<&str> = written.lines().collect();
+    if printed != expected.output {
+        return Err(format!(
+            "expected output {:?}, got {:?}",
+            expected.output, printed
+        ));
+    }
+
+    let expected_error = match (&expected.compile_error, &expected.runtime_error) {
+        (Some(message), _) => Some((VMError::CompileError, message)),
+        (None, Some(message)) => Some((VMError::RuntimeError, message)),
+        (None, None) => None,
+    };
+    match (expected_error, error) {
+        (None, None) => Ok(()),
+        (Some((kind, message)), Some(error)) if error.kind == kind && &error.message == message => {
+            Ok(())
+        }
+        (Some((kind, message)), error) => Err(format!(
+            "expected {} {:?}, got {}",
+            describe(kind),
+            message,
+            match error {
+                Some(error) => format!("{} {:?}", describe(error.kind), error.message),
+                None => "no error".to_string(),
+            }
+        )),
+        (None, Some(error)) => Err(format!(
+            "unexpected {} {:?}",
+            describe(error.kind),
+            error.message
+        )),
+    }
+}
+
+fn describe(kind: VMError) -> &'static str {
+    match kind {
+        VMError::CompileError => "compile error",
+        VMError::RuntimeError => "runtime error",
+        VMError::Timeout => "timeout",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_expectations() {
+        let source = r#"
+print 1; // expect: 1
+print "a // b"; // expect: a // b
+var a = "unused"; // [line 3] Error at 'a': First.
+// [java line 4] Error at 'b': Java only.
+// [c line 5] Error at end: Second.
+nil(); // expect runtime error: Can only call functions and classes.
+"#;
+        assert_eq!(
+            Expectations {
+                output: vec!["1".to_string(), "a // b".to_string()],
+                runtime_error: Some("Can only call functions and classes.".to_string()),
+                compile_error: Some("First.".to_string()),
+            },
+            expectations(source)
+        );
+    }
+
+    fn check_source(source: &str) -> Result<(), String> {
+        let mut vm = VM::new();
+        vm.trace_execution = false;
+        check(&mut vm, "", source.to_string())
+    }
+
+    #[test]
+    fn checks_output_and_errors() {
+        assert_eq!(Ok(()), check_source("print 1; // expect: 1"));
+        assert_eq!(
+            Err("expected output [\"2\"], got [\"1\"]".to_string()),
+            check_source("print 1; // expect: 2")
+        );
+        let source =
+            "print 1; // expect: 1\nprint -nil; // expect runtime error: Operand must be a number.";
+        assert_eq!(Ok(()), check_source(source));
+        assert_eq!(
+            Ok(()),
+            check_source("print; // Error at ';': Expect expression.")
+        );
+        assert_eq!(
+            Err("expected compile error \"Expect expression.\", got no error".to_string()),
+            check_source("var a; // Error at ';': Expect expression.")
+        );
+        assert_eq!(
+            Err("unexpected runtime error \"Operand must be a number.\"".to_string()),
+            check_source("-nil;")
+        );
+    }
+}
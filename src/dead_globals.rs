@@ -0,0 +1,295 @@
+//! Removal of the global functions a compiled program never uses, before it is serialized.
+//!
+//! A top-level `fun` declaration compiles to an [Instruction::OpConstant] loading the function,
+//! followed by an [Instruction::OpDefineGlobal]. Globals are only ever read through their slot,
+//! with [Instruction::OpGetGlobal], so a function whose slot is never read or assigned by the
+//! script or by any function the script can reach cannot run. Its declaration is removed, along with
+//! the constants nothing refers to anymore.
+
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, Instruction};
+use crate::value::function::Function;
+use crate::value::value::Value;
+
+/// Returns the script without the declarations of the global functions it cannot reach.
+///
+/// The script is the top-level function of a whole program: its globals are assumed not to be
+/// used by any other code.
+pub fn eliminate_dead_globals(script: &Function) -> Function {
+    let chunk = &script.chunk;
+    let declarations = function_declarations(chunk);
+    let declared_at: HashSet<usize> = declarations.iter().map(|(index, _, _)| *index).collect();
+
+    // The names used by the script outside of the declarations, and by the functions it reaches.
+    let mut used = HashSet::new();
+    let mut reached = HashSet::new();
+    for (index, instruction) in chunk.bytecode.iter().enumerate() {
+        if !declared_at.contains(&index) {
+            global_use(chunk, *instruction, &mut used);
+        }
+    }
+    let declared_constants: HashSet<usize> = declarations
+        .iter()
+        .map(|(_, constant, _)| *constant)
+        .collect();
+    for (index, constant) in chunk.constants.iter().enumerate() {
+        if let Value::Function(function) = constant {
+            if !declared_constants.contains(&index) {
+                globals_used_by(function, &mut used);
+            }
+        }
+    }
+    loop {
+        let newly_reached: Vec<&(usize, usize, String)> = declarations
+            .iter()
+            .filter(|(index, _, name)| used.contains(name) && !reached.contains(index))
+            .collect();
+        if newly_reached.is_empty() {
+            break;
+        }
+        for (index, constant, _) in newly_reached {
+            reached.insert(*index);
+            if let Value::Function(function) = &chunk.constants[*constant] {
+                globals_used_by(function, &mut used);
+            }
+        }
+    }
+
+    let removed: HashSet<usize> = declarations
+        .iter()
+        .filter(|(index, _, _)| !reached.contains(index))
+        .flat_map(|(index, _, _)| [*index, index + 1])
+        .collect();
+    let mut stripped = script.clone();
+    stripped.chunk = compact_constants(&remove_instructions(chunk, &removed));
+    stripped
+}
+
+/// Finds the top-level function declarations, as the index of their [Instruction::OpConstant],
+/// the index of the function constant and the global's name.
+fn function_declarations(chunk: &Chunk) -> Vec<(usize, usize, String)> {
+    chunk
+        .bytecode
+        .windows(2)
+        .enumerate()
+        .filter_map(|(index, pair)| match pair {
+            [Instruction::OpConstant(constant), Instruction::OpDefineGlobal(name)]
+                if matches!(chunk.constants[*constant], Value::Function(_)) =>
+            {
+                Some((index, *constant, chunk.globals[*name].clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Adds the names of the globals used by a function and the functions among its constants.
+fn globals_used_by(function: &Function, used: &mut HashSet<String>) {
+    for instruction in &function.chunk.bytecode {
+        global_use(&function.chunk, *instruction, used);
+    }
+    for constant in &function.chunk.constants {
+        if let Value::Function(function) = constant {
+            globals_used_by(function, used);
+        }
+    }
+}
+
+/// Adds the name of the global an instruction reads or assigns, if any.
+fn global_use(chunk: &Chunk, instruction: Instruction, used: &mut HashSet<String>) {
+    if let Instruction::OpGetGlobal(name) | Instruction::OpSetGlobal(name) = instruction {
+        used.insert(chunk.globals[name].clone());
+    }
+}
+
+/// Returns the chunk without the instructions at the given indexes,
+/// with the jumps over them shortened to land on the same instructions.
+pub(crate) fn remove_instructions(chunk: &Chunk, removed: &HashSet<usize>) -> Chunk {
+    // The new index of every instruction, and of the end of the chunk.
+    let mut new_indexes = Vec::with_capacity(chunk.bytecode.len() + 1);
+    let mut kept = 0;
+    for index in 0..=chunk.bytecode.len() {
+        new_indexes.push(kept);
+        if !removed.contains(&index) {
+            kept += 1;
+        }
+    }
+
+    let mut stripped = Chunk {
+        bytecode: Vec::new(),
+        lines: Vec::new(),
+        constants: chunk.constants.clone(),
+        source_name: Rc::clone(&chunk.source_name),
+        globals: Rc::clone(&chunk.globals),
+    };
+    for (index, (instruction, line)) in chunk.bytecode.iter().zip(&chunk.lines).enumerate() {
+        if removed.contains(&index) {
+            continue;
+        }
+        let forward = |offset: usize| new_indexes[index + 1 + offset] - new_indexes[index] - 1;
+        let instruction = match *instruction {
+            Instruction::OpJump(offset) => Instruction::OpJump(forward(offset)),
+            Instruction::OpJumpIfFalse(offset) => Instruction::OpJumpIfFalse(forward(offset)),
+            Instruction::OpJumpIfTrue(offset) => Instruction::OpJumpIfTrue(forward(offset)),
+            Instruction::OpJumpIfNil(offset) => Instruction::OpJumpIfNil(forward(offset)),
+            Instruction::OpJumpIfNotEqual(offset) => Instruction::OpJumpIfNotEqual(forward(offset)),
+            Instruction::OpDefer(offset) => Instruction::OpDefer(forward(offset)),
+            Instruction::OpSetupCatch(offset) => Instruction::OpSetupCatch(forward(offset)),
+            Instruction::OpLoop(offset) => {
+                Instruction::OpLoop(new_indexes[index] + 1 - new_indexes[index + 1 - offset])
+            }
+            instruction => instruction,
+        };
+        stripped.write(instruction, *line);
+    }
+    stripped
+}
+
+/// Returns the chunk without the constants none of its instructions refer to.
+pub(crate) fn compact_constants(chunk: &Chunk) -> Chunk {
+    let mut new_indexes = vec![None; chunk.constants.len()];
+    let mut compacted = Chunk {
+        bytecode: Vec::new(),
+        lines: chunk.lines.clone(),
+        constants: Vec::new(),
+        source_name: Rc::clone(&chunk.source_name),
+        globals: Rc::clone(&chunk.globals),
+    };
+    for instruction in &chunk.bytecode {
+        let instruction = match constant_operand(*instruction) {
+            Some((index, with_index)) => with_index(
+                *new_indexes[index]
+                    .get_or_insert_with(|| compacted.add_constant(chunk.constants[index].clone())),
+            ),
+            None => *instruction,
+        };
+        compacted.bytecode.push(instruction);
+    }
+    compacted
+}
+
+/// Builds an instruction referring to the constant at an index.
+type WithConstant = fn(usize) -> Instruction;
+
+/// The index of the constant an instruction refers to, with the function that builds the same
+/// instruction referring to another index.
+fn constant_operand(instruction: Instruction) -> Option<(usize, WithConstant)> {
+    match instruction {
+        Instruction::OpConstant(index) => Some((index, Instruction::OpConstant)),
+        Instruction::OpClass(index) => Some((index, Instruction::OpClass)),
+        Instruction::OpGetProperty(index) => Some((index, Instruction::OpGetProperty)),
+        Instruction::OpSetProperty(index) => Some((index, Instruction::OpSetProperty)),
+        Instruction::OpMethod(index) => Some((index, Instruction::OpMethod)),
+        Instruction::OpGetter(index) => Some((index, Instruction::OpGetter)),
+        Instruction::OpSetter(index) => Some((index, Instruction::OpSetter)),
+        Instruction::OpGetSuper(index) => Some((index, Instruction::OpGetSuper)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompilerManager;
+    use crate::vm::vm::VM;
+
+    fn compile(source: &str) -> Function {
+        CompilerManager::compile(source.to_string()).unwrap()
+    }
+
+    fn global_functions(script: &Function) -> Vec<String> {
+        script
+            .chunk
+            .constants
+            .iter()
+            .filter_map(|constant| match constant {
+                Value::Function(function) => Some(function.name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn run(script: Function) -> Vec<String> {
+        let mut vm = VM::new();
+        vm.capture_output = true;
+        let result = vm
+            .interpret_program(crate::compiler::Program {
+                scripts: vec![(String::new(), script)],
+                warnings: Vec::new(),
+            })
+            .unwrap();
+        result
+            .printed_values
+            .iter()
+            .map(|value| value.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn unreachable_functions_are_removed() {
+        let script = compile(
+            r#"
+fun unused() { return helper(); }
+fun helper() { return 1; }
+fun leaf() { return 2; }
+fun used() { return leaf(); }
+fun isEven(n) { if (n == 0) return true; return isOdd(n - 1); }
+fun isOdd(n) { if (n == 0) return false; return isEven(n - 1); }
+print used();
+"#,
+        );
+        let stripped = eliminate_dead_globals(&script);
+        assert_eq!(vec!["leaf", "used"], global_functions(&stripped));
+        assert!(!stripped
+            .chunk
+            .constants
+            .iter()
+            .any(|constant| constant.to_string() == "unused"));
+        assert!(stripped.chunk.constants.len() < script.chunk.constants.len());
+        assert_eq!(vec!["2"], run(stripped));
+    }
+
+    #[test]
+    fn functions_used_as_values_are_kept() {
+        let script = compile(
+            r#"
+fun f() { return "f"; }
+fun g() { return "g"; }
+var h = f;
+fun call() { return g(); }
+class C { method() { return call(); } }
+print h();
+print C().method();
+"#,
+        );
+        let stripped = eliminate_dead_globals(&script);
+        assert_eq!(global_functions(&script), global_functions(&stripped));
+        assert_eq!(vec!["f", "g"], run(stripped));
+    }
+
+    #[test]
+    fn jumps_over_removed_instructions() {
+        let mut chunk = Chunk::new();
+        let f = chunk.add_constant(Value::Function(Rc::new(Function::new())));
+        chunk.globals = Rc::new(vec!["f".to_string()]);
+        chunk.write(Instruction::OpJump(3), 1);
+        chunk.write(Instruction::OpConstant(f), 2);
+        chunk.write(Instruction::OpDefineGlobal(0), 2);
+        chunk.write(Instruction::OpNil, 3);
+        chunk.write(Instruction::OpLoop(5), 4);
+
+        let removed = HashSet::from([1, 2]);
+        let stripped = remove_instructions(&chunk, &removed);
+        assert_eq!(
+            vec![
+                Instruction::OpJump(1),
+                Instruction::OpNil,
+                Instruction::OpLoop(3),
+            ],
+            stripped.bytecode
+        );
+        assert_eq!(vec![1, 3, 4], stripped.lines);
+    }
+}
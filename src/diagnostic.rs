@@ -0,0 +1,354 @@
+//! The user-facing messages of the compiler and the VM, identified by stable codes.
+//!
+//! Every diagnostic has an English template, which a [Catalog] can replace with a translation.
+//! Templates refer to their arguments as `{0}`, `{1}` and so on.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+
+/// Declares the [Diagnostic] variants with their codes and English templates.
+macro_rules! diagnostics {
+    ($($variant:ident = $code:literal, $template:literal;)*) => {
+        /// A compile error, runtime error, warning or hint shown to the user.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Diagnostic {
+            $($variant,)*
+        }
+
+        impl Diagnostic {
+            /// Every diagnostic, in the order of their codes.
+            pub const ALL: &'static [Diagnostic] = &[$(Diagnostic::$variant,)*];
+
+            /// The stable code of the diagnostic, which does not change between versions.
+            ///
+            /// Compile errors start with E0, runtime errors with E1, warnings with W and hints with H.
+            pub fn code(self) -> &'static str {
+                match self {
+                    $(Diagnostic::$variant => $code,)*
+                }
+            }
+
+            /// The English template of the diagnostic's message.
+            pub fn template(self) -> &'static str {
+                match self {
+                    $(Diagnostic::$variant => $template,)*
+                }
+            }
+        }
+    };
+}
+
+diagnostics! {
+    InvalidAssignmentTarget = "E001", "Invalid assignment target.";
+    ExpectExpression = "E002", "Expect expression.";
+    UnexpectedCharacter = "E003", "Unexpected character.";
+    UnterminatedString = "E004", "Unterminated string.";
+    UninitializedToken = "E005", "Uninitialized token.";
+    InvalidDirective = "E006", "Invalid directive.";
+    UnmatchedDirective = "E007", "Directive without a matching '#if'.";
+    UnterminatedConditional = "E008", "Expect '#end' after '#if'.";
+    TooManyConstants = "E009", "Too many constants in one chunk.";
+    DuplicateLocal = "E010", "Already variable with this name in this scope.";
+    TooManyLocals = "E011", "Too many local variables in function.";
+    TooManyParameters = "E013", "Can't have more than 255 parameters.";
+    TooManyArguments = "E014", "Can't have more than 255 arguments.";
+    InvalidNumber = "E015", "Invalid number literal.";
+    LocalInOwnInitializer = "E016", "Can't read local variable in its own initializer.";
+    BlockLocalInDefer = "E017", "Can't use a block-scoped local variable in a deferred expression.";
+    SourceTooLarge = "E018", "Source is too large ({0} bytes, the maximum is {1}).";
+    LineTooLong = "E019", "Line is too long ({0} characters, the maximum is {1}).";
+    ExpectVariableName = "E020", "Expect variable name.";
+    ExpectSemicolonAfterVariable = "E021", "Expect ';' after variable declaration.";
+    ExpectFunctionName = "E022", "Expect function name.";
+    ExpectSemicolonAfterReturn = "E023", "Expect ';' after return value.";
+    ExpectSemicolonAfterDefer = "E024", "Expect ';' after deferred expression.";
+    ExpectParenAfterIf = "E025", "Expect '(' after 'if'.";
+    ExpectParenAfterCondition = "E026", "Expect ')' after condition.";
+    ExpectParenAfterFor = "E027", "Expect '(' after 'for'.";
+    ExpectSemicolonAfterLoopCondition = "E028", "Expect ';' after loop condition.";
+    ExpectParenAfterForClauses = "E029", "Expect ')' after for clauses.";
+    ExpectParenAfterWhile = "E030", "Expect '(' after 'while'.";
+    ExpectParenAfterFunctionName = "E031", "Expect '(' after function name.";
+    ExpectParameterName = "E032", "Expect parameter name.";
+    ExpectParenAfterParameters = "E033", "Expect ')' after parameters.";
+    ExpectBraceBeforeFunctionBody = "E034", "Expect '{' before function body.";
+    ExpectColonInSlice = "E035", "Expect ':' in slice.";
+    ExpectBracketAfterSlice = "E036", "Expect ']' after slice.";
+    ExpectParenAfterArguments = "E037", "Expect ')' after arguments.";
+    ExpectBraceAfterBlock = "E038", "Expect '}' after block.";
+    ExpectSemicolonAfterExpression = "E039", "Expect ';' after expression.{0}";
+    ExpectSemicolonAfterValue = "E040", "Expect ';' after value.";
+    ExpectParenAfterExpression = "E041", "Expect ')' after expression.";
+    TooManyScopes = "E042", "Too many nested scopes in function, the maximum is {0}.";
+    TooManyLoops = "E043", "Too many nested loops in function, the maximum is {0}.";
+    WrongExtensionArgumentCount = "E044", "Expected {0} arguments but got {1}.";
+    ExpectClassName = "E045", "Expect class name.";
+    ExpectBraceBeforeClassBody = "E046", "Expect '{' before class body.";
+    ExpectBraceAfterClassBody = "E047", "Expect '}' after class body.";
+    ExpectPropertyName = "E048", "Expect property name after '.'.";
+    ExpectMethodName = "E049", "Expect method name.";
+    ReturnFromInitializer = "E050", "Can't return a value from an initializer.";
+    ThisOutsideMethod = "E051", "Can't use 'this' outside of a method.";
+    InheritFromSelf = "E052", "A class can't inherit from itself.";
+    SuperOutsideMethod = "E053", "Can't use 'super' outside of a method.";
+    SuperWithoutSuperclass = "E054", "Can't use 'super' in a class with no superclass.";
+    ExpectSuperclassName = "E055", "Expect superclass name.";
+    ExpectDotAfterSuper = "E056", "Expect '.' after 'super'.";
+    ExpectSuperclassMethodName = "E057", "Expect superclass method name.";
+    ExpectIncrementVariable = "E058", "Expect variable name after '{0}'.";
+    InvalidIncrementTarget = "E059", "Invalid increment target.";
+    ExpectBracketAfterIndex = "E060", "Expect ']' after index.";
+    TooManyListElements = "E061", "Can't have more than 255 elements in a list literal.";
+    ExpectBracketAfterElements = "E062", "Expect ']' after list elements.";
+    WrongGlobalArgumentCount = "E063", "Expected {0} arguments but got {1}.";
+    ShadowedNative = "E064", "Can't redefine native function '{0}'.";
+    UnknownDirective = "E065", "Unknown directive '{0}'.";
+    InvalidDirectiveValue = "E066", "Invalid value '{0}' for directive '{1}'.";
+    ExpectDirectiveValue = "E067", "Expect 'key = value' after 'rlox:'.";
+    ExpectSemicolonAfterThrow = "E068", "Expect ';' after thrown value.";
+    ExpectBraceAfterTry = "E069", "Expect '{' after 'try'.";
+    ExpectCatch = "E070", "Expect 'catch' after try block.";
+    ExpectParenAfterCatch = "E071", "Expect '(' after 'catch'.";
+    ExpectCatchVariable = "E072", "Expect exception variable name.";
+    ExpectParenAfterCatchVariable = "E073", "Expect ')' after exception variable name.";
+    ExpectBraceAfterCatch = "E074", "Expect '{' after catch clause.";
+    SetterArity = "E075", "A setter must take exactly one parameter.";
+    ReturnFromSetter = "E076", "Can't return a value from a setter.";
+    ExpectSemicolonAfterField = "E077", "Expect ';' after field declaration.";
+    ExpectParenAfterMatch = "E078", "Expect '(' after 'match'.";
+    ExpectParenAfterMatchValue = "E079", "Expect ')' after match value.";
+    ExpectBraceBeforeMatchArms = "E080", "Expect '{' before match arms.";
+    ExpectArrowAfterPattern = "E081", "Expect '=>' after match pattern.";
+    ExpectBraceAfterMatchArms = "E082", "Expect '}' after match arms.";
+    WildcardArmNotLast = "E083", "The '_' arm must be the last arm of a match.";
+    InternalCompilerError = "E099", "Internal compiler error: {0}";
+    StackOverflow = "E101", "Stack overflow.";
+    UndefinedVariable = "E102", "Undefined variable '{0}'.{1}";
+    NotCallable = "E103", "Can only call functions and classes.";
+    OperandNotNumber = "E104", "Operand must be a number.";
+    WrongArgumentCount = "E105", "Expected {0} arguments but got {1}.";
+    OutputFailed = "E106", "Could not write output.";
+    NotSliceable = "E107", "Can only slice strings and lists.";
+    InvalidSliceBound = "E108", "Slice bounds must be integers or nil.";
+    UnknownExtension = "E109", "Unknown extension instruction {0}.";
+    OperandNotVector = "E110", "Operand must be a vector.";
+    OperandNotMatrix = "E111", "Operand must be a matrix.";
+    OperandNotVectorOrMatrix = "E112", "Operand must be a vector or a matrix.";
+    VectorSizeMismatch = "E113", "Vectors must have the same size.";
+    MatrixSizeMismatch = "E114", "Matrix and operand sizes must match.";
+    CrossNotVec3 = "E115", "Cross product is only defined for vec3.";
+    ZeroLengthVector = "E116", "Can't normalize a zero-length vector.";
+    MissingComponent = "E117", "Vector has no {0} component.";
+    EventNotString = "E118", "Event name must be a string.";
+    NotAnInstance = "E119", "Only instances have properties.";
+    UndefinedProperty = "E120", "Undefined property '{0}'.";
+    FieldOnNonInstance = "E121", "Only instances have fields.";
+    SuperclassNotClass = "E122", "Superclass must be a class.";
+    VariableNameNotString = "E123", "Variable name must be a string.";
+    NotWatched = "E124", "Variable '{0}' is not watched.";
+    FrameInstructionLimit = "E125", "'{0}' ran more than {1} instructions without returning.";
+    IndexNotList = "E126", "Can only index lists.";
+    InvalidIndex = "E127", "List index must be an integer.";
+    IndexOutOfBounds = "E128", "List index {0} is out of bounds for a list of length {1}.";
+    ReadOnlyGlobal = "E129", "Cannot assign to read-only global '{0}'.";
+    NotHostObject = "E130", "Operand must be a {0}.";
+    OperandNotString = "E131", "Operand must be a string.";
+    OperandNotBoolean = "E132", "Operand must be a boolean.";
+    UncaughtException = "E133", "Uncaught exception: {0}";
+    IsNotClass = "E134", "Right operand of 'is' must be a class.";
+    OperandsNotNumbers = "E135", "Operands must be numbers.";
+    OperandsNotNumbersOrStrings = "E136", "Operands must be two numbers or two strings.";
+    InstructionLimit = "E137", "The program ran more than {0} instructions.";
+    ToStringNotString = "E140", "toString() must return a string.";
+    ComparisonNotNumber = "E141", "compareTo() and comparators must return a number.";
+    NotComparable = "E142", "Can only compare two numbers, two strings or an instance with compareTo().";
+    OperandNotList = "E143", "Operand must be a list.";
+    FrozenValue = "E144", "Can't change a frozen {0}.";
+    UndefinedGlobal = "W001", "Undefined variable '{0}'.{1}";
+    AssignmentInCondition = "W002", "Assignment used as a condition. Use '==' to compare, or wrap the assignment in parentheses.";
+    ShadowedNativeWarning = "W003", "Global '{0}' replaces the native function of the same name.";
+    UnusedLocal = "W004", "Unused local variable '{0}'.";
+    ShadowedLocal = "W005", "Local variable '{0}' shadows a variable of an enclosing block.";
+    DidYouMean = "H001", "Did you mean '{0}'?";
+}
+
+impl Diagnostic {
+    /// Finds the diagnostic with the given code.
+    pub fn from_code(code: &str) -> Option<Diagnostic> {
+        Diagnostic::ALL
+            .iter()
+            .copied()
+            .find(|diagnostic| diagnostic.code() == code)
+    }
+}
+
+/// The message templates used instead of the English ones, for translated diagnostics.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Catalog {
+    templates: HashMap<Diagnostic, String>,
+}
+
+impl Catalog {
+    /// Reads a catalog with one `CODE = template` line per diagnostic.
+    ///
+    /// Blank lines and lines starting with '#' are skipped. Diagnostics that are left out keep
+    /// their English template.
+    pub fn parse(text: &str) -> Result<Catalog, String> {
+        let mut catalog = Catalog::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (code, template) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Expect '=' after the code on line {}.", index + 1))?;
+            let code = code.trim();
+            let diagnostic = Diagnostic::from_code(code)
+                .ok_or_else(|| format!("Unknown code '{}' on line {}.", code, index + 1))?;
+            catalog.insert(diagnostic, template.trim());
+        }
+        Ok(catalog)
+    }
+
+    /// Replaces the template of a diagnostic.
+    pub fn insert(&mut self, diagnostic: Diagnostic, template: &str) {
+        self.templates.insert(diagnostic, template.to_string());
+    }
+
+    /// Formats the message of a diagnostic, replacing `{i}` in its template with `args[i]`.
+    pub fn message(&self, diagnostic: Diagnostic, args: &[&str]) -> String {
+        let template = self
+            .templates
+            .get(&diagnostic)
+            .map_or(diagnostic.template(), String::as_str);
+        // A single pass, so that arguments containing braces are left alone.
+        let mut message = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            message.push_str(&rest[..open]);
+            rest = &rest[open..];
+            let arg = rest
+                .find('}')
+                .and_then(|close| Some((rest[1..close].parse::<usize>().ok()?, close)))
+                .and_then(|(index, close)| Some((args.get(index)?, close)));
+            match arg {
+                Some((arg, close)) => {
+                    message.push_str(arg);
+                    rest = &rest[close + 1..];
+                }
+                None => {
+                    message.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        message.push_str(rest);
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_unique() {
+        for (i, diagnostic) in Diagnostic::ALL.iter().enumerate() {
+            assert_eq!(Some(*diagnostic), Diagnostic::from_code(diagnostic.code()));
+            assert!(
+                Diagnostic::ALL[..i]
+                    .iter()
+                    .all(|other| other.code() != diagnostic.code()),
+                "{}",
+                diagnostic.code()
+            );
+        }
+    }
+
+    #[test]
+    fn english_messages() {
+        let catalog = Catalog::default();
+        assert_eq!(
+            "Invalid assignment target.",
+            catalog.message(Diagnostic::InvalidAssignmentTarget, &[])
+        );
+        assert_eq!(
+            "Expected 2 arguments but got 3.",
+            catalog.message(Diagnostic::WrongArgumentCount, &["2", "3"])
+        );
+        assert_eq!(
+            "Undefined variable '{1}'.",
+            catalog.message(Diagnostic::UndefinedVariable, &["{1}", ""])
+        );
+    }
+
+    #[test]
+    fn translated_messages() {
+        let catalog = Catalog::parse(
+            "# French\n\nE001 = Cible d'affectation invalide.\nE105 = {1} arguments au lieu de {0}.\n",
+        )
+        .unwrap();
+        assert_eq!(
+            "Cible d'affectation invalide.",
+            catalog.message(Diagnostic::InvalidAssignmentTarget, &[])
+        );
+        assert_eq!(
+            "3 arguments au lieu de 2.",
+            catalog.message(Diagnostic::WrongArgumentCount, &["2", "3"])
+        );
+        assert_eq!(
+            "Stack overflow.",
+            catalog.message(Diagnostic::StackOverflow, &[])
+        );
+    }
+
+    #[test]
+    fn invalid_catalogs() {
+        assert_eq!(
+            Err("Unknown code 'E999' on line 1.".to_string()),
+            Catalog::parse("E999 = Nothing.")
+        );
+        assert_eq!(
+            Err("Expect '=' after the code on line 2.".to_string()),
+            Catalog::parse("E001 = Ok.\nE002")
+        );
+    }
+}
+
+/// Where diagnostics are written to: stderr, unless replaced with [ErrorOutput::new].
+///
+/// Clones share the same writer, so that the compiler and the VM write to it in order.
+#[derive(Clone)]
+pub struct ErrorOutput(Rc<RefCell<Box<dyn Write>>>);
+
+impl ErrorOutput {
+    pub fn new(output: Box<dyn Write>) -> ErrorOutput {
+        ErrorOutput(Rc::new(RefCell::new(output)))
+    }
+}
+
+impl Default for ErrorOutput {
+    fn default() -> Self {
+        ErrorOutput::new(Box::new(std::io::stderr()))
+    }
+}
+
+impl std::fmt::Debug for ErrorOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ErrorOutput")
+    }
+}
+
+/// Writes through a shared reference, like `&File`, so that `writeln!(&output, ...)` works.
+impl Write for &ErrorOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
@@ -0,0 +1,175 @@
+//! Options that a script sets for itself, in a header of `// rlox: key = value` comments.
+//!
+//! The header is the comment and blank lines at the start of the source, and only its lines
+//! starting with `// rlox:` are directives. The directives are:
+//!
+//! - `strict = true`, which reports the problems of every lint as errors;
+//! - `warn = LINT`, `deny = LINT` and `allow = LINT`, which set the level of one lint, like the
+//!   `--warn` and `--deny` options, overriding `strict`;
+//! - `max-frame-instructions = N`, which stops a call that runs more than `N` instructions,
+//!   like [crate::vm::vm::VM::max_frame_instructions];
+//! - `max-instructions = N`, which stops the script once it runs `N` instructions, like
+//!   [crate::vm::vm::VM::max_instructions].
+//!
+//! Numbers are written like Lox number literals, and may have an exponent, like `1e7`.
+//!
+//! Later directives override earlier ones.
+
+use std::io::Write;
+
+use crate::chunk::format_location;
+use crate::compiler::{CompileError, CompileOptions, Lint, LintLevel};
+use crate::diagnostic::Diagnostic;
+use crate::value::number::parse_number;
+
+/// The options set by the directives of a script.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Directives {
+    /// The level of each lint, in the order they were set.
+    pub lints: Vec<(Lint, LintLevel)>,
+    pub max_frame_instructions: Option<usize>,
+    pub max_instructions: Option<usize>,
+}
+
+impl Directives {
+    /// Applies the compile options set by the directives.
+    pub fn apply(&self, options: &mut CompileOptions) {
+        options.lints.extend(self.lints.iter().cloned());
+    }
+}
+
+/// Reads the directives in the header of a script, reporting the first invalid one.
+pub fn parse_directives(
+    source_name: &str,
+    source: &str,
+    options: &CompileOptions,
+) -> Result<Directives, CompileError> {
+    let mut directives = Directives::default();
+    let header = source
+        .lines()
+        .map(str::trim)
+        .take_while(|line| line.is_empty() || line.starts_with("//"));
+    for (index, line) in header.enumerate() {
+        let directive = line
+            .strip_prefix("//")
+            .and_then(|comment| comment.trim_start().strip_prefix("rlox:"));
+        let directive = match directive {
+            Some(directive) => directive,
+            None => continue,
+        };
+        let error = |diagnostic: Diagnostic, args: &[&str]| {
+            let message = options.catalog.message(diagnostic, args);
+            let _ = writeln!(
+                &options.error_output,
+                "[{}] error[{}]: {}",
+                format_location(source_name, index as i32 + 1),
+                diagnostic.code(),
+                message
+            );
+            CompileError {
+                code: diagnostic,
+                message,
+            }
+        };
+        let (key, value) = match directive.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => return Err(error(Diagnostic::ExpectDirectiveValue, &[])),
+        };
+        let invalid = || error(Diagnostic::InvalidDirectiveValue, &[value, key]);
+        match key {
+            "strict" => match value {
+                "true" => directives
+                    .lints
+                    .extend(Lint::ALL.iter().map(|lint| (*lint, LintLevel::Deny))),
+                "false" => {}
+                _ => return Err(invalid()),
+            },
+            "warn" | "deny" | "allow" => {
+                let lint = Lint::from_name(value).ok_or_else(invalid)?;
+                let level = match key {
+                    "warn" => LintLevel::Warn,
+                    "deny" => LintLevel::Deny,
+                    _ => LintLevel::Allow,
+                };
+                directives.lints.push((lint, level));
+            }
+            "max-frame-instructions" => {
+                directives.max_frame_instructions = Some(parse_count(value).ok_or_else(invalid)?)
+            }
+            "max-instructions" => {
+                directives.max_instructions = Some(parse_count(value).ok_or_else(invalid)?)
+            }
+            _ => return Err(error(Diagnostic::UnknownDirective, &[key])),
+        }
+    }
+    Ok(directives)
+}
+
+/// Parses a number of instructions, which must be a whole number that is not negative.
+fn parse_count(value: &str) -> Option<usize> {
+    match parse_number(value) {
+        Some(n) if n >= 0.0 && n.fract() == 0.0 && n <= usize::MAX as f64 => Some(n as usize),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<Directives, String> {
+        parse_directives("", source, &CompileOptions::default()).map_err(|error| error.message)
+    }
+
+    #[test]
+    fn header() {
+        let source = "// A script.\n//rlox: deny = unused-local\n// rlox: max-frame-instructions = 1e7\n// rlox: max-instructions = 2_000\nprint 1;\n// rlox: warn = shadowing\n";
+        assert_eq!(
+            Ok(Directives {
+                lints: vec![(Lint::UnusedLocal, LintLevel::Deny)],
+                max_frame_instructions: Some(10_000_000),
+                max_instructions: Some(2_000),
+            }),
+            parse(source)
+        );
+    }
+
+    #[test]
+    fn strict() {
+        let directives = parse("// rlox: strict = true\n// rlox: allow = shadowing").unwrap();
+        let mut options = CompileOptions::default();
+        directives.apply(&mut options);
+        assert_eq!(LintLevel::Deny, options.lint_level(Lint::UnusedLocal));
+        assert_eq!(LintLevel::Allow, options.lint_level(Lint::Shadowing));
+    }
+
+    #[test]
+    fn invalid_directives() {
+        assert_eq!(
+            Err("Unknown directive 'fast'.".to_string()),
+            parse("// rlox: fast = true")
+        );
+        assert_eq!(
+            Err("Invalid value '1.5' for directive 'max-frame-instructions'.".to_string()),
+            parse("// rlox: max-frame-instructions = 1.5")
+        );
+        // Only numbers that Lox reads are accepted, not everything Rust does.
+        for value in [".5e1", "-1", "inf", "1e400"] {
+            assert_eq!(
+                Err(format!(
+                    "Invalid value '{}' for directive 'max-instructions'.",
+                    value
+                )),
+                parse(&format!("// rlox: max-instructions = {}", value))
+            );
+        }
+        assert_eq!(
+            Err("Invalid value 'typo' for directive 'warn'.".to_string()),
+            parse("// rlox: warn = typo")
+        );
+        assert_eq!(
+            Err("Expect 'key = value' after 'rlox:'.".to_string()),
+            parse("// rlox: strict")
+        );
+    }
+}
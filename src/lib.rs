@@ -0,0 +1,13 @@
+pub mod chunk;
+pub mod compiler;
+pub mod conformance;
+pub mod dead_globals;
+pub mod diagnostic;
+pub mod directives;
+pub mod optimizer;
+pub mod parser;
+pub mod scanner;
+pub mod serializer;
+pub mod suggestion;
+pub mod value;
+pub mod vm;
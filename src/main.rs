@@ -1,20 +1,196 @@
-mod chunk;
-mod compiler;
-mod parser;
-mod scanner;
-mod value;
-mod vm;
-
+use rlox::chunk::format_location;
+use rlox::compiler::{CompilerManager, Lint, LintLevel, Program};
+use rlox::dead_globals::eliminate_dead_globals;
+use rlox::diagnostic::Catalog;
+use rlox::directives::parse_directives;
+use rlox::serializer::{
+    build_id, check_debug_info, deserialize, deserialize_debug_info, serialize, serialize_stripped,
+    DebugInfo,
+};
+use rlox::value::inspect::inspect;
+use rlox::value::number::NumberFormat;
+use rlox::value::value::Value;
+use rlox::vm::vm::*;
 use std::io::Write;
-use vm::vm::*;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+
+/// Options given on the command line, shared by every way of running code.
+#[derive(Clone, Default)]
+struct Options {
+    /// Flush the output after every print statement, for use in pipelines.
+    unbuffered: bool,
+    /// The lints selected with --warn and --deny, in order, so that a later one wins.
+    lints: Vec<(Lint, LintLevel)>,
+    /// Report every warning as an error, with --deny warnings.
+    deny_warnings: bool,
+    /// The names defined for conditional compilation, with their values.
+    defines: Vec<(String, String)>,
+    /// Write numbers with all their digits, without an exponent.
+    plain_numbers: bool,
+    /// Report the peak stack and call depths when the program exits.
+    profile: bool,
+    /// Report the calls to native functions that are not deterministic when the program exits.
+    audit_determinism: bool,
+    /// The most instructions a single call may run.
+    max_frame_instructions: Option<usize>,
+    /// The most instructions a script may run.
+    max_instructions: Option<usize>,
+    /// The messages of diagnostics, translated by the file given with --messages.
+    catalog: Catalog,
+    /// Where run-all writes a JSON report of the results.
+    report: Option<PathBuf>,
+    /// Compile to stripped bytecode, with the names and lines in a separate debug file.
+    strip: bool,
+    /// Optimize the compiled bytecode: fold constants, remove dead code and fuse instructions.
+    optimize: bool,
+}
+
+impl Options {
+    /// Creates a [VM] configured with these options.
+    fn vm(&self) -> VM {
+        let mut vm = VM::new();
+        vm.unbuffered_output = self.unbuffered;
+        vm.profiling = self.profile;
+        if self.plain_numbers {
+            vm.number_format = NumberFormat::PLAIN;
+        }
+        vm.audit_determinism = self.audit_determinism;
+        vm.max_frame_instructions = self.max_frame_instructions;
+        vm.max_instructions = self.max_instructions;
+        vm.catalog = Rc::new(self.catalog.clone());
+        vm.compile_options.catalog = Rc::clone(&vm.catalog);
+        vm.compile_options.lints.extend(self.lints.iter().cloned());
+        vm.compile_options.deny_warnings = self.deny_warnings;
+        vm.compile_options.optimize = self.optimize;
+        vm.compile_options
+            .defines
+            .extend(self.defines.iter().cloned());
+        vm
+    }
+}
 
 fn main() {
-    let args_count = std::env::args().count();
-    match args_count {
-        1 => repl(),
-        2 => run_file(std::env::args().nth(1).unwrap()),
+    let mut options = Options::default();
+    let mut args = Vec::new();
+    let mut arg_iter = std::env::args().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--unbuffered" => options.unbuffered = true,
+            "--profile" => options.profile = true,
+            "--plain-numbers" => options.plain_numbers = true,
+            "--audit-determinism" => options.audit_determinism = true,
+            "--strip" => options.strip = true,
+            "--optimize" => options.optimize = true,
+            "--warn-undefined-globals" => {
+                options.lints.push((Lint::UndefinedGlobal, LintLevel::Warn))
+            }
+            "--warn-assignment-in-condition" => options
+                .lints
+                .push((Lint::AssignmentInCondition, LintLevel::Warn)),
+            "--messages" => match arg_iter.next() {
+                Some(path) => {
+                    let catalog =
+                        load_catalog(Path::new(&path)).unwrap_or_else(|(exit_code, message)| {
+                            eprintln!("{}", message);
+                            std::process::exit(exit_code);
+                        });
+                    options.catalog = catalog;
+                }
+                None => {
+                    eprintln!("Expect a path after --messages.");
+                    std::process::exit(64);
+                }
+            },
+            "--report" => match arg_iter.next() {
+                Some(path) => options.report = Some(PathBuf::from(path)),
+                None => {
+                    eprintln!("Expect a path after --report.");
+                    std::process::exit(64);
+                }
+            },
+            "--max-frame-instructions" => match arg_iter.next().map(|n| n.parse()) {
+                Some(Ok(max)) => options.max_frame_instructions = Some(max),
+                _ => {
+                    eprintln!("Expect a number after --max-frame-instructions.");
+                    std::process::exit(64);
+                }
+            },
+            "--warn" => {
+                let lint = parse_lint(arg_iter.next(), "--warn");
+                options.lints.push((lint, LintLevel::Warn));
+            }
+            "--deny" => match arg_iter.next() {
+                Some(name) if name == "warnings" => options.deny_warnings = true,
+                name => {
+                    let lint = parse_lint(name, "--deny");
+                    options.lints.push((lint, LintLevel::Deny));
+                }
+            },
+            "--max-instructions" => match arg_iter.next().map(|n| n.parse()) {
+                Some(Ok(max)) => options.max_instructions = Some(max),
+                _ => {
+                    eprintln!("Expect a number after --max-instructions.");
+                    std::process::exit(64);
+                }
+            },
+            "--define" => match arg_iter.next() {
+                Some(define) => options.defines.push(parse_define(&define)),
+                None => {
+                    eprintln!("Expect a name after --define.");
+                    std::process::exit(64);
+                }
+            },
+            _ => args.push(arg),
+        }
+    }
+
+    match args.as_slice() {
+        [] => repl(&options),
+        [path] => run_file(path, &options),
+        [command, dir] if command == "run-all" => run_all(dir, &options),
+        [command, dir] if command == "par" => par(dir, &options),
+        [command, dir] if command == "conformance" => conformance(dir, &options),
+        [command, path, output] if command == "compile" => compile_file(path, output, &options),
         _ => {
-            eprintln!("Usage: rlox [path]");
+            eprintln!("Usage: rlox [options] [path]");
+            eprintln!("       rlox [options] run-all <dir>");
+            eprintln!("       rlox [options] par <dir>");
+            eprintln!("       rlox [options] conformance <dir>");
+            eprintln!("       rlox [options] compile <path> <output.rloxc>");
+            eprintln!();
+            eprintln!("Options:");
+            eprintln!("  --unbuffered              Flush the output after every print statement.");
+            eprintln!("  --warn-undefined-globals  Same as '--warn undefined-global'.");
+            eprintln!("  --warn-assignment-in-condition");
+            eprintln!("                            Same as '--warn assignment-in-condition'.");
+            eprintln!("  --warn LINT               Warn about the problems LINT finds.");
+            eprintln!("  --deny LINT               Report the problems LINT finds as errors.");
+            eprintln!(
+                "                            '--deny warnings' reports every warning as an error."
+            );
+            eprintln!("                            Lints: {}.", lint_names());
+            eprintln!("  --define NAME[=value]     Define NAME for '#if NAME' directives.");
+            eprintln!("  --profile                 Report the peak stack and call depths at exit.");
+            eprintln!("  --plain-numbers           Print numbers without an exponent, like 1e21.");
+            eprintln!("  --max-instructions N      Stop the program once it runs N instructions.");
+            eprintln!("  --max-frame-instructions N");
+            eprintln!(
+                "                            Stop a call that runs more than N instructions."
+            );
+            eprintln!(
+                "  --audit-determinism       Report the calls to nondeterministic natives at exit."
+            );
+            eprintln!("  --strip                   Compile without names and lines, writing them");
+            eprintln!("                            to a .rloxdbg file next to the output.");
+            eprintln!("  --optimize                Optimize the compiled bytecode.");
+            eprintln!("  --messages FILE           Read translated diagnostics from FILE.");
+            eprintln!(
+                "  --report FILE             Write a JSON report of run-all's results to FILE."
+            );
             std::process::exit(64);
         }
     }
@@ -25,54 +201,896 @@ fn main() {
     // vm.interpret();
 }
 
-fn repl() {
+/// Reads the lint named after `--warn` or `--deny`, exiting if there is none.
+fn parse_lint(name: Option<String>, option: &str) -> Lint {
+    let name = name.unwrap_or_else(|| {
+        eprintln!("Expect a lint name after {}.", option);
+        std::process::exit(64);
+    });
+    Lint::from_name(&name).unwrap_or_else(|| {
+        eprintln!("Unknown lint '{}'. Lints: {}.", name, lint_names());
+        std::process::exit(64);
+    })
+}
+
+fn lint_names() -> String {
+    let names: Vec<&str> = Lint::ALL.iter().map(|lint| lint.name()).collect();
+    names.join(", ")
+}
+
+/// Splits a `--define` argument into a name and a value, which is `true` if not given.
+fn parse_define(define: &str) -> (String, String) {
+    match define.split_once('=') {
+        Some((name, value)) => (name.to_string(), value.to_string()),
+        None => (define.to_string(), "true".to_string()),
+    }
+}
+
+fn repl(options: &Options) {
+    // One VM for the whole session, so that definitions carry over between lines.
+    // A line only runs once all of it compiled, so a compile error leaves them untouched.
+    let mut vm = options.vm();
+    let mut settings = ReplSettings::default();
+    if let Some(path) = std::env::var_os("HOME").map(|home| Path::new(&home).join(".rloxrc")) {
+        load_rc(&path, &mut vm, &mut settings);
+    }
+    vm.trace_execution = settings.trace;
+
     let mut user_input = String::new();
     loop {
-        print!("> ");
+        print!("{}", settings.prompt);
         std::io::stdout()
             .flush()
             .expect("Failed to write to stdout");
-        std::io::stdin()
+        let read = std::io::stdin()
             .read_line(&mut user_input)
             .expect("Failed to read input");
+        if read == 0 {
+            println!();
+            break;
+        }
 
-        let mut vm = VM::new();
-        #[allow(unused_must_use)]
-        {
-            vm.interpret(user_input.clone());
+        if let Some(name) = user_input.trim().strip_prefix(":inspect") {
+            println!("{}", inspect_global(&vm, name.trim()));
+        } else if user_input.trim() == ":time" {
+            settings.time = !settings.time;
+            println!("// Timing {}.", if settings.time { "on" } else { "off" });
+        } else {
+            let source = if user_input.trim() == ":paste" {
+                println!("// Paste mode. Enter ':end' on a line of its own to run the code.");
+                read_paste(&mut std::io::stdin().lock())
+            } else {
+                user_input.clone()
+            };
+            #[allow(unused_must_use)]
+            {
+                vm.interpret_source("<repl>", source);
+            }
+            if settings.time {
+                println!("{}", format_timing(vm.timing()));
+            }
         }
         user_input.clear();
     }
 }
 
-fn run_file(path: String) {
-    let source = match std::fs::read_to_string(&path) {
+/// Reads the lines of the REPL's `:paste` command up to a line with just `:end`, or the end of
+/// the input, so that they compile together even if a statement spans several lines.
+fn read_paste(input: &mut impl std::io::BufRead) -> String {
+    let mut source = String::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = input.read_line(&mut line).expect("Failed to read input");
+        if read == 0 || line.trim() == ":end" {
+            return source;
+        }
+        source.push_str(&line);
+    }
+}
+
+/// Formats the time a REPL entry took, for the `:time` command.
+fn format_timing(timing: Timing) -> String {
+    format!(
+        "// Compiled in {:.3} ms, ran in {:.3} ms, {} instructions.",
+        timing.compile_time.as_secs_f64() * 1000.0,
+        timing.run_time.as_secs_f64() * 1000.0,
+        timing.instruction_count
+    )
+}
+
+/// Dumps the value of a global for the REPL's `:inspect name` command.
+fn inspect_global(vm: &VM, name: &str) -> String {
+    match vm.global(name) {
+        Some(value) => inspect(value, vm.number_format),
+        None => format!("Undefined variable '{}'.", name),
+    }
+}
+
+/// The REPL's settings, which the rc file can change.
+#[derive(Debug, PartialEq)]
+struct ReplSettings {
+    /// Printed before reading each line.
+    prompt: String,
+    /// Whether the execution of each line is traced, with the `debug_trace_execution` feature.
+    trace: bool,
+    /// Whether the time each entry took is reported after it, toggled with `:time`.
+    time: bool,
+}
+
+impl Default for ReplSettings {
+    fn default() -> Self {
+        ReplSettings {
+            prompt: "> ".to_string(),
+            trace: true,
+            time: false,
+        }
+    }
+}
+
+/// Runs the REPL's rc file, if there is one, into the session's VM and applies its settings.
+///
+/// Errors are reported but do not stop the REPL from starting.
+fn load_rc(path: &Path, vm: &mut VM, settings: &mut ReplSettings) {
+    if !path.exists() {
+        return;
+    }
+    let source = match read_source(path) {
         Ok(source) => source,
-        Err(_) => {
-            eprintln!("Could not read file \"{:?}\".", &path);
-            std::process::exit(74);
+        Err((_, message)) => {
+            eprintln!("{}", message);
+            return;
         }
     };
+    let name = path.display().to_string();
+    let (source, errors) = parse_rc(&name, &source, settings);
+    for error in errors {
+        eprintln!("{}", error);
+    }
+    #[allow(unused_must_use)]
+    {
+        vm.interpret_source(&name, source);
+    }
+}
+
+/// Separates the settings in an rc file from its Lox source, applying them to `settings`.
+///
+/// A setting is a line of the form `set prompt = "lox> "` or `set trace = false`. Its line is left
+/// blank in the returned source, so that errors in the rest of the file have the right line.
+fn parse_rc(name: &str, source: &str, settings: &mut ReplSettings) -> (String, Vec<String>) {
+    let mut lox = String::with_capacity(source.len());
+    let mut errors = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let setting = match line.trim().strip_prefix("set ") {
+            Some(setting) => setting,
+            None => {
+                lox.push_str(line);
+                lox.push('\n');
+                continue;
+            }
+        };
+        lox.push('\n');
+
+        let error = match setting.split_once('=') {
+            Some((key, value)) => apply_setting(key.trim(), value.trim(), settings).err(),
+            None => Some("Expect '=' after the setting name.".to_string()),
+        };
+        if let Some(error) = error {
+            let location = format_location(name, index as i32 + 1);
+            errors.push(format!("[{}] Error: {}", location, error));
+        }
+    }
+    (lox, errors)
+}
 
-    let mut vm = VM::new();
-    let result = vm.interpret(source);
+fn apply_setting(key: &str, value: &str, settings: &mut ReplSettings) -> Result<(), String> {
+    match key {
+        "prompt" => match value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+        {
+            Some(prompt) => settings.prompt = prompt.to_string(),
+            None => return Err("Expect a string for 'prompt'.".to_string()),
+        },
+        "trace" => match value {
+            "true" => settings.trace = true,
+            "false" => settings.trace = false,
+            _ => return Err("Expect true or false for 'trace'.".to_string()),
+        },
+        "time" => match value {
+            "true" => settings.time = true,
+            "false" => settings.time = false,
+            _ => return Err("Expect true or false for 'time'.".to_string()),
+        },
+        _ => return Err(format!("Unknown setting '{}'.", key)),
+    }
+    Ok(())
+}
 
-    match result {
+fn run_file(path: &str, options: &Options) {
+    let mut vm = options.vm();
+    let result = if path.ends_with(".rloxc") {
+        let function = load_compiled(Path::new(path)).unwrap_or_else(|(exit_code, message)| {
+            eprintln!("{}", message);
+            std::process::exit(exit_code);
+        });
+        if function.symbol.is_some() {
+            let path = PathBuf::from(path);
+            vm.set_debug_info_loader(Box::new(move || load_debug_info(&path)));
+        }
+        vm.interpret_program(Program {
+            scripts: vec![(path.to_string(), function)],
+            warnings: Vec::new(),
+        })
+    } else {
+        let source = read_source(Path::new(path)).unwrap_or_else(|(exit_code, message)| {
+            eprintln!("{}", message);
+            std::process::exit(exit_code);
+        });
+        vm.interpret_source(path, source)
+    };
+    if let Some(profile) = vm.profile() {
+        eprintln!("{}", format_profile(&profile, vm.limits()));
+    }
+    if let Some(calls) = vm.nondeterministic_calls() {
+        eprint!("{}", format_nondeterministic_calls(calls));
+    }
+    #[cfg(feature = "opcode_timing")]
+    eprint!("{}", format_opcode_timings(&vm.opcode_timings()));
+    #[cfg(feature = "profile")]
+    eprint!("{}", format_profile_report(&vm.profile_report()));
+
+    match result.map_err(|error| error.kind) {
+        Ok(interpretation) => {
+            if let Some(exit_code) = exit_code(&interpretation.return_value) {
+                std::process::exit(exit_code);
+            }
+        }
         Err(VMError::CompileError) => std::process::exit(65),
-        Err(VMError::RuntimeError) => std::process::exit(70),
-        _ => {}
+        Err(VMError::RuntimeError | VMError::Timeout) => std::process::exit(70),
+    }
+}
+
+/// The exit status a script asks for by returning it from the top level, like `return 3;`.
+///
+/// Only integers from 0 to 255 are exit statuses, and the process exits with 0 after
+/// returning any other value.
+fn exit_code(return_value: &Value) -> Option<i32> {
+    match return_value {
+        Value::Number(n) if n.fract() == 0.0 && (0.0..=255.0).contains(n) => Some(*n as i32),
+        _ => None,
+    }
+}
+
+fn format_profile(profile: &Profile, limits: Limits) -> String {
+    format!(
+        "Peak stack depth: {} of {}\nPeak call depth: {} of {}",
+        profile.peak_stack_depth, limits.stack_max, profile.peak_frame_depth, limits.frames_max
+    )
+}
+
+/// Lists the calls to nondeterministic native functions with where they were called from.
+fn format_nondeterministic_calls(calls: &[NondeterministicCall]) -> String {
+    if calls.is_empty() {
+        return "No calls to nondeterministic functions.\n".to_string();
+    }
+    let mut report = "Calls to nondeterministic functions:\n".to_string();
+    for call in calls {
+        let times = match call.count {
+            1 => "once".to_string(),
+            count => format!("{} times", count),
+        };
+        report.push_str(&format!("[{}] {}(), {}\n", call.location, call.name, times));
+    }
+    report
+}
+
+/// Formats a table of the time spent on each kind of instruction, slowest first.
+#[cfg(feature = "opcode_timing")]
+fn format_opcode_timings(timings: &[OpcodeTiming]) -> String {
+    let total: std::time::Duration = timings.iter().map(|timing| timing.total).sum();
+    let mut table = format!(
+        "{:<16} {:>10} {:>12} {:>10} {:>6}\n",
+        "Instruction", "Count", "Total (us)", "Mean (ns)", "Share"
+    );
+    for timing in timings {
+        let share = if total.is_zero() {
+            0.0
+        } else {
+            100.0 * timing.total.as_secs_f64() / total.as_secs_f64()
+        };
+        table.push_str(&format!(
+            "{:<16} {:>10} {:>12} {:>10} {:>5.1}%\n",
+            timing.name,
+            timing.count,
+            timing.total.as_micros(),
+            timing.total.as_nanos() / timing.count as u128,
+            share
+        ));
+    }
+    table
+}
+
+/// Formats tables of the number of times each kind of instruction ran and of the number of
+/// instructions each function ran, most first.
+#[cfg(feature = "profile")]
+fn format_profile_report(report: &ProfileReport) -> String {
+    let mut table = format!("{:<16} {:>10}\n", "Instruction", "Count");
+    for (name, count) in &report.opcodes {
+        table.push_str(&format!("{:<16} {:>10}\n", name, count));
+    }
+    table.push_str(&format!("\n{:<32} {:>10}\n", "Function", "Instructions"));
+    for (name, count) in &report.functions {
+        table.push_str(&format!("{:<32} {:>10}\n", name, count));
+    }
+    table
+}
+
+/// Compiles a script and writes its bytecode to a file, to be run later without recompiling.
+fn compile_file(path: &str, output: &str, options: &Options) {
+    let source = read_source(Path::new(path)).unwrap_or_else(|(exit_code, message)| {
+        eprintln!("{}", message);
+        std::process::exit(exit_code);
+    });
+    let mut compile_options = options.vm().compile_options;
+    match parse_directives(path, &source, &compile_options) {
+        Ok(directives) => directives.apply(&mut compile_options),
+        Err(_) => std::process::exit(65),
+    }
+    let mut program = match CompilerManager::compile_many(&[(path, &source)], &compile_options) {
+        Ok(program) => program,
+        Err(_) => std::process::exit(65),
+    };
+    let (_, function) = program.scripts.pop().unwrap();
+    let (bytes, debug_info) = compiled_bytes(&function, options.strip).unwrap_or_else(|message| {
+        eprintln!("{}", message);
+        std::process::exit(70);
+    });
+    write_file(Path::new(output), &bytes);
+    if let Some(debug_info) = debug_info {
+        write_file(&Path::new(output).with_extension("rloxdbg"), &debug_info);
+    }
+}
+
+/// Returns the contents of the .rloxc file for a program, and of its debug file if stripped.
+fn compiled_bytes(
+    function: &rlox::value::function::Function,
+    strip: bool,
+) -> Result<(Vec<u8>, Option<Vec<u8>>), String> {
+    // The file holds the whole program, so functions it never calls can be left out.
+    let function = eliminate_dead_globals(function);
+    if strip {
+        serialize_stripped(&function).map(|(bytes, debug_info)| (bytes, Some(debug_info)))
+    } else {
+        serialize(&function).map(|bytes| (bytes, None))
+    }
+}
+
+fn write_file(path: &Path, bytes: &[u8]) {
+    if let Err(e) = std::fs::write(path, bytes) {
+        eprintln!("Could not write file \"{}\": {}", path.display(), e);
+        std::process::exit(74);
+    }
+}
+
+/// Reads a message catalog of `CODE = template` lines,
+/// returning the exit code and message to report if it cannot be loaded.
+fn load_catalog(path: &Path) -> Result<Catalog, (i32, String)> {
+    let source = read_source(path)?;
+    Catalog::parse(&source).map_err(|message| {
+        (
+            65,
+            format!("Could not load file \"{}\": {}", path.display(), message),
+        )
+    })
+}
+
+/// Reads a script compiled with the compile command,
+/// returning the exit code and message to report if it cannot be loaded.
+fn load_compiled(path: &Path) -> Result<rlox::value::function::Function, (i32, String)> {
+    let bytes = std::fs::read(path)
+        .map_err(|_| (74, format!("Could not read file \"{}\".", path.display())))?;
+    deserialize(&bytes).map_err(|message| {
+        (
+            65,
+            format!("Could not load file \"{}\": {}", path.display(), message),
+        )
+    })
+}
+
+/// Reads the debug file written next to a script compiled with --strip,
+/// checking that it belongs to the script.
+fn load_debug_info(path: &Path) -> Result<DebugInfo, String> {
+    let debug_path = path.with_extension("rloxdbg");
+    let bytes =
+        std::fs::read(path).map_err(|_| format!("Could not read file \"{}\".", path.display()))?;
+    let debug_bytes = std::fs::read(&debug_path)
+        .map_err(|_| format!("Could not read file \"{}\".", debug_path.display()))?;
+    let debug_info = deserialize_debug_info(&debug_bytes)
+        .map_err(|message| format!("\"{}\": {}", debug_path.display(), message))?;
+    if debug_info.build_id != build_id(&bytes) {
+        return Err(format!(
+            "\"{}\" was not written for \"{}\".",
+            debug_path.display(),
+            path.display()
+        ));
+    }
+    // A matching build id could still come with a crafted file, so the functions are compared.
+    let function =
+        deserialize(&bytes).map_err(|message| format!("\"{}\": {}", path.display(), message))?;
+    check_debug_info(&function, &debug_info)
+        .map_err(|message| format!("\"{}\": {}", debug_path.display(), message))?;
+    Ok(debug_info)
+}
+
+/// Runs every .lox file in a directory and its subdirectories, each in a fresh [VM],
+/// and prints which of them failed. Exits with 1 if any of them did.
+fn run_all(dir: &str, options: &Options) {
+    let mut paths = Vec::new();
+    if let Err(e) = find_lox_files(Path::new(dir), &mut paths) {
+        eprintln!("Could not read directory \"{:?}\": {}", dir, e);
+        std::process::exit(74);
+    }
+    paths.sort();
+
+    let mut results = Vec::new();
+    for path in &paths {
+        let result = run_script(path, options);
+        print_result(path, &result);
+        results.push((path.as_path(), result));
+    }
+    finish_run(&results, options);
+}
+
+/// Prints whether a script run by run-all or par passed.
+fn print_result(path: &Path, result: &Result<(), Failure>) {
+    match result {
+        Ok(()) => println!("PASS {}", path.display()),
+        Err(failure) => println!("FAIL {} ({})", path.display(), failure),
+    }
+}
+
+/// Writes the report of run-all or par if one was asked for and prints how many scripts
+/// passed. Exits with 1 if any of them failed.
+fn finish_run(results: &[(&Path, Result<(), Failure>)], options: &Options) {
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    if let Some(report) = &options.report {
+        if let Err(e) = std::fs::write(report, json_report(results)) {
+            eprintln!("Could not write file \"{}\": {}", report.display(), e);
+            std::process::exit(74);
+        }
+    }
+
+    println!();
+    println!(
+        "{} passed, {} failed, {} total",
+        results.len() - failed,
+        failed,
+        results.len()
+    );
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// Runs every .lox file in a directory and its subdirectories, each in a fresh [VM], checking
+/// it against the `// expect:` and error comments of the Crafting Interpreters test suite.
+///
+/// Prints the pass rate of each chapter, which is a subdirectory of `dir`. The files listed in
+/// `dir/skip.txt` are skipped. Exits with 1 if any other file failed.
+fn conformance(dir: &str, options: &Options) {
+    let chapters = check_conformance(Path::new(dir), options).unwrap_or_else(|e| {
+        eprintln!("Could not read directory \"{:?}\": {}", dir, e);
+        std::process::exit(74);
+    });
+
+    println!();
+    let mut total = Chapter::default();
+    for (name, chapter) in &chapters {
+        println!("{:<24} {}", name, chapter);
+        total.passed += chapter.passed;
+        total.failed += chapter.failed;
+        total.skipped += chapter.skipped;
+    }
+    println!("{:<24} {}", "total", total);
+    if total.failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// The results of the conformance tests of a chapter.
+#[derive(Debug, Default, PartialEq)]
+struct Chapter {
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+impl std::fmt::Display for Chapter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let run = self.passed + self.failed;
+        let rate = if run == 0 {
+            100.0
+        } else {
+            100.0 * self.passed as f64 / run as f64
+        };
+        write!(
+            f,
+            "{:>4}/{:<4} passed ({:5.1}%), {} skipped",
+            self.passed, run, rate, self.skipped
+        )
+    }
+}
+
+/// Checks every .lox file in a directory against its expectations, printing the result of each
+/// file, and returns the results by chapter.
+///
+/// A file directly in `dir` belongs to the chapter `.`.
+fn check_conformance(
+    dir: &Path,
+    options: &Options,
+) -> std::io::Result<std::collections::BTreeMap<String, Chapter>> {
+    let mut paths = Vec::new();
+    find_lox_files(dir, &mut paths)?;
+    paths.sort();
+    let skipped = match std::fs::read_to_string(dir.join("skip.txt")) {
+        Ok(list) => parse_skip_list(&list),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut chapters = std::collections::BTreeMap::new();
+    for path in &paths {
+        let relative = path.strip_prefix(dir).unwrap_or(path);
+        let name = relative.to_string_lossy().replace('\\', "/");
+        let chapter: &mut Chapter = chapters
+            .entry(match name.split_once('/') {
+                Some((chapter, _)) => chapter.to_string(),
+                None => ".".to_string(),
+            })
+            .or_default();
+        if let Some((_, reason)) = skipped.iter().find(|(skipped, _)| *skipped == name) {
+            chapter.skipped += 1;
+            println!("SKIP {} ({})", name, reason);
+            continue;
+        }
+        let result = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|source| {
+                let mut vm = options.vm();
+                rlox::conformance::check(&mut vm, &path.to_string_lossy(), source)
+            });
+        match result {
+            Ok(()) => {
+                chapter.passed += 1;
+                println!("PASS {}", name);
+            }
+            Err(reason) => {
+                chapter.failed += 1;
+                println!("FAIL {} ({})", name, reason);
+            }
+        }
+    }
+    Ok(chapters)
+}
+
+/// Reads the allowlist of skipped conformance tests: one path relative to the test directory
+/// per line, followed by `#` and the reason it is skipped. Lines starting with `#` are comments.
+fn parse_skip_list(list: &str) -> Vec<(String, String)> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once('#') {
+            Some((path, reason)) => (path.trim().to_string(), reason.trim().to_string()),
+            None => (line.to_string(), "no reason given".to_string()),
+        })
+        .collect()
+}
+
+/// Runs every .lox file in a directory and its subdirectories on a pool of threads, each in a
+/// fresh [VM], and prints which of them failed like run-all. Exits with 1 if any of them did.
+///
+/// Every line a script prints, to stdout or stderr, is prefixed with its path, and printed as
+/// soon as it is complete.
+fn par(dir: &str, options: &Options) {
+    let mut paths = Vec::new();
+    if let Err(e) = find_lox_files(Path::new(dir), &mut paths) {
+        eprintln!("Could not read directory \"{:?}\": {}", dir, e);
+        std::process::exit(74);
+    }
+    paths.sort();
+
+    let results = run_parallel(&paths, options);
+    for (path, result) in &results {
+        print_result(path, result);
+    }
+    finish_run(&results, options);
+}
+
+/// Runs scripts on one thread per available core, returning their results in the order of
+/// the paths.
+fn run_parallel<'a>(
+    paths: &'a [PathBuf],
+    options: &Options,
+) -> Vec<(&'a Path, Result<(), Failure>)> {
+    let workers = std::thread::available_parallelism()
+        .map_or(1, |count| count.get())
+        .min(paths.len());
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            let options = options.clone();
+            let sender = sender.clone();
+            let next = &next;
+            scope.spawn(move || {
+                // Each worker takes the next script that no other worker has taken.
+                while let Some(path) = paths.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        run_prefixed_script(path, &options)
+                    }))
+                    .unwrap_or_else(|_| Err(Failure::Read("The script panicked.".to_string())));
+                    let _ = sender.send((path.as_path(), result));
+                }
+            });
+        }
+    });
+    drop(sender);
+
+    let mut results: Vec<_> = receiver.into_iter().collect();
+    results.sort_by_key(|(path, _)| paths.iter().position(|p| p == path));
+    results
+}
+
+/// Runs a script in a fresh [VM] whose output and errors are prefixed with the script's path.
+fn run_prefixed_script(path: &Path, options: &Options) -> Result<(), Failure> {
+    // The VM is not Send, so each thread creates its own.
+    let mut vm = options.vm();
+    let prefix = format!("[{}] ", path.display());
+    vm.set_output(Box::new(PrefixedOutput::new(
+        prefix.clone(),
+        std::io::stdout(),
+    )));
+    vm.set_error_output(Box::new(PrefixedOutput::new(prefix, std::io::stderr())));
+    run_script_in(path, &mut vm)
+}
+
+/// Writes complete lines to another writer, each prefixed with the same text,
+/// so that lines from scripts running at the same time are not mixed up.
+struct PrefixedOutput<W: Write> {
+    prefix: String,
+    /// The part of the current line written so far.
+    line: Vec<u8>,
+    output: W,
+}
+
+impl<W: Write> PrefixedOutput<W> {
+    fn new(prefix: String, output: W) -> PrefixedOutput<W> {
+        PrefixedOutput {
+            prefix,
+            line: Vec::new(),
+            output,
+        }
+    }
+
+    /// Writes the current line with its prefix in a single write.
+    fn write_line(&mut self) -> std::io::Result<()> {
+        let mut line = self.prefix.clone().into_bytes();
+        line.append(&mut self.line);
+        self.output.write_all(&line)
+    }
+}
+
+impl<W: Write> Write for PrefixedOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for &byte in buf {
+            self.line.push(byte);
+            if byte == b'\n' {
+                self.write_line()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Flushes the complete lines. An incomplete line is kept until it is complete.
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+impl<W: Write> Drop for PrefixedOutput<W> {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            self.line.push(b'\n');
+            let _ = self.write_line();
+        }
+    }
+}
+
+/// Collects the paths of all .lox files in a directory and its subdirectories.
+fn find_lox_files(dir: &Path, paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_lox_files(&path, paths)?;
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Why a script run by run-all failed.
+#[derive(Debug, PartialEq)]
+enum Failure {
+    /// The script could not be read.
+    Read(String),
+    /// The script failed to compile or to run.
+    Lox(LoxError),
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Read(message) => write!(f, "{}", message),
+            Failure::Lox(error) => match error.kind {
+                VMError::CompileError => write!(f, "compile error"),
+                VMError::RuntimeError => write!(f, "runtime error"),
+                VMError::Timeout => write!(f, "timeout"),
+            },
+        }
+    }
+}
+
+/// Runs a script in a fresh [VM], returning why it failed if it did.
+fn run_script(path: &Path, options: &Options) -> Result<(), Failure> {
+    run_script_in(path, &mut options.vm())
+}
+
+/// Runs a script in the given [VM], returning why it failed if it did.
+fn run_script_in(path: &Path, vm: &mut VM) -> Result<(), Failure> {
+    let source = read_source(path).map_err(|(_, message)| Failure::Read(message))?;
+    vm.interpret_source(&path.display().to_string(), source)
+        .map(|_| ())
+        .map_err(Failure::Lox)
+}
+
+/// Formats the results of run-all as JSON, for CI systems to show failures without reading stderr.
+fn json_report(results: &[(&Path, Result<(), Failure>)]) -> String {
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    let scripts: Vec<String> = results
+        .iter()
+        .map(|(path, result)| {
+            let path = json_string(&path.display().to_string());
+            let error = match result {
+                Ok(()) => return format!("{{\"path\": {}, \"status\": \"pass\"}}", path),
+                Err(Failure::Read(message)) => format!(
+                    "{{\"kind\": \"read\", \"code\": null, \"message\": {}, \"stack_trace\": []}}",
+                    json_string(message)
+                ),
+                Err(Failure::Lox(error)) => {
+                    let kind = match error.kind {
+                        VMError::CompileError => "compile",
+                        VMError::RuntimeError => "runtime",
+                        VMError::Timeout => "timeout",
+                    };
+                    let code = error
+                        .code
+                        .map_or("null".to_string(), |code| json_string(code.code()));
+                    let trace: Vec<String> =
+                        error.stack_trace.iter().map(|line| json_string(line)).collect();
+                    format!(
+                        "{{\"kind\": \"{}\", \"code\": {}, \"message\": {}, \"stack_trace\": [{}]}}",
+                        kind,
+                        code,
+                        json_string(&error.message),
+                        trace.join(", ")
+                    )
+                }
+            };
+            format!(
+                "{{\"path\": {}, \"status\": \"fail\", \"error\": {}}}",
+                path, error
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\n  \"passed\": {},\n  \"failed\": {},\n  \"total\": {},\n  \"scripts\": [\n    {}\n  ]\n}}\n",
+        results.len() - failed,
+        failed,
+        results.len(),
+        scripts.join(",\n    ")
+    )
+}
+
+/// Quotes a string for JSON.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Reads a source file, returning the exit code and message to report if it cannot be read.
+fn read_source(path: &Path) -> Result<String, (i32, String)> {
+    let bytes =
+        std::fs::read(path).map_err(|_| (74, format!("Could not read file \"{:?}\".", path)))?;
+    decode_source(bytes).map_err(|message| {
+        (
+            65,
+            format!("Could not read file \"{:?}\": {}", path, message),
+        )
+    })
+}
+
+/// Decodes a source file as UTF-8, skipping a leading byte order mark if present.
+fn decode_source(mut bytes: Vec<u8>) -> Result<String, String> {
+    let mut offset = 0;
+    if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        bytes.drain(..3);
+        offset = 3;
     }
+    String::from_utf8(bytes).map_err(|e| {
+        format!(
+            "invalid UTF-8 at byte offset {}.",
+            offset + e.utf8_error().valid_up_to()
+        )
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rlox::diagnostic::Diagnostic;
+
+    /// The values a program printed, as the print statement showed them.
+    fn printed(result: Interpretation) -> Vec<String> {
+        result
+            .printed_values
+            .iter()
+            .map(|value| value.to_string())
+            .collect()
+    }
+
+    /// Runs a program in a new [VM], returning the values it printed.
+    fn run(source: &str) -> Result<Vec<String>, LoxError> {
+        let mut vm = VM::new();
+        vm.capture_output = true;
+        vm.interpret(source.to_string()).map(printed)
+    }
+
+    /// Collects what a VM writes, including the output written before a runtime error.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
 
     mod expressions {
         use super::*;
 
         #[test]
-        fn evaluate() -> VMResult {
+        fn evaluate() -> Result<(), LoxError> {
             let source = r#"
 // Note: Slightly modified from the original.
 print (5 - (3 - 1)) + -1;
@@ -80,17 +1098,18 @@ print (5 - (3 - 1)) + -1;
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("2", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("2", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
     }
 
     mod assignment {
-        use crate::vm::vm::{VMResult, VM};
+        use rlox::vm::vm::{LoxError, VM};
 
         #[test]
-        fn associativity() -> VMResult {
+        fn associativity() -> Result<(), LoxError> {
             let source = r#"
 var a = "a";
 var b = "b";
@@ -104,15 +1123,16 @@ print c; // expect: c
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("c", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("c", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("c", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("c", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("c", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("c", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn global() -> VMResult {
+        fn global() -> Result<(), LoxError> {
             let source = r#"
 var a = "before";
 print a; // expect: before
@@ -125,32 +1145,30 @@ print a; // expect: arg
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("arg", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("arg", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("after", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("before", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("arg", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("arg", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("after", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("before", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn grouping() -> VMResult {
+        fn grouping() -> Result<(), LoxError> {
             let source = r#"
 var a = "a";
 (a) = "value"; // Error at '=': Invalid assignment target.
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Invalid assignment target.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Invalid assignment target.", error.message);
             Ok(())
         }
 
         #[test]
-        fn infix_operator() -> VMResult {
+        fn infix_operator() -> Result<(), LoxError> {
             let source = r#"
 var a = "a";
 var b = "b";
@@ -158,16 +1176,13 @@ a + b = "value"; // Error at '=': Invalid assignment target.
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Invalid assignment target.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Invalid assignment target.", error.message);
             Ok(())
         }
 
         #[test]
-        fn local() -> VMResult {
+        fn local() -> Result<(), LoxError> {
             let source = r#"
 {
   var a = "before";
@@ -182,32 +1197,30 @@ a + b = "value"; // Error at '=': Invalid assignment target.
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("arg", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("arg", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("after", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("before", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("arg", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("arg", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("after", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("before", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn prefix_operator() -> VMResult {
+        fn prefix_operator() -> Result<(), LoxError> {
             let source = r#"
 var a = "a";
 !a = "value"; // Error at '=': Invalid assignment target.
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Invalid assignment target.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Invalid assignment target.", error.message);
             Ok(())
         }
 
         #[test]
-        fn syntax() -> VMResult {
+        fn syntax() -> Result<(), LoxError> {
             let source = r#"
 // Assignment on RHS of variable.
 var a = "before";
@@ -217,15 +1230,15 @@ print c; // expect: var
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("var", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("var", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("var", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("var", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
-        #[ignore = "class"]
         #[test]
-        fn to_this() -> VMResult {
+        fn to_this() -> Result<(), LoxError> {
             let source = r#"
 class Foo {
   Foo() {
@@ -237,37 +1250,31 @@ Foo();
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Invalid assignment target.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Invalid assignment target.", error.message);
             Ok(())
         }
 
         #[test]
-        fn undefined() -> VMResult {
+        fn undefined() -> Result<(), LoxError> {
             let source = r#"
 unknown = "what"; // expect runtime error: Undefined variable 'unknown'.
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Undefined variable 'unknown'.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Undefined variable 'unknown'.", error.message);
             Ok(())
         }
     }
 
     mod block {
-        use crate::vm::vm::VMResult;
+        use rlox::vm::vm::LoxError;
 
         use super::*;
 
         #[test]
-        fn empty() -> VMResult {
+        fn empty() -> Result<(), LoxError> {
             let source = r#"
 {} // By itself.
 
@@ -279,13 +1286,14 @@ print "ok"; // expect: ok
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("ok", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("ok", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn scope() -> VMResult {
+        fn scope() -> Result<(), LoxError> {
             let source = r#"
 var a = "outer";
 
@@ -298,9 +1306,10 @@ print a; // expect: outer
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("outer", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("inner", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("outer", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("inner", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
     }
@@ -309,7 +1318,7 @@ print a; // expect: outer
         use super::*;
 
         #[test]
-        fn equality() -> VMResult {
+        fn equality() -> Result<(), LoxError> {
             let source = r#"
 print true == true;    // expect: true
 print true == false;   // expect: false
@@ -337,30 +1346,31 @@ print false != "";      // expect: true
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            Ok(())
-        }
-
-        #[test]
-        fn not() -> VMResult {
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn not() -> Result<(), LoxError> {
             let source = r#"
 print !true;    // expect: false
 print !false;   // expect: true
@@ -368,10 +1378,11 @@ print !!true;   // expect: true
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
     }
@@ -380,40 +1391,43 @@ print !!true;   // expect: true
         use super::*;
 
         #[test]
-        fn line_at_eof() -> VMResult {
+        fn line_at_eof() -> Result<(), LoxError> {
             let source = r#"
 print "ok"; // expect: ok
 // comment
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("ok", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("ok", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn only_line_comment() -> VMResult {
+        fn only_line_comment() -> Result<(), LoxError> {
             let source = r#"
 // comment
 "#
             .to_string();
             let mut vm = VM::new();
+            vm.interpret(source)?;
             Ok(())
         }
 
         #[test]
-        fn only_line_comment_and_line() -> VMResult {
+        fn only_line_comment_and_line() -> Result<(), LoxError> {
             let source = r#"
 // comment
 "#
             .to_string();
             let mut vm = VM::new();
+            vm.interpret(source)?;
             Ok(())
         }
 
         #[test]
-        fn unicode() -> VMResult {
+        fn unicode() -> Result<(), LoxError> {
             let source = r#"
 // Unicode characters are allowed in comments.
 //
@@ -427,23 +1441,25 @@ print "ok"; // expect: ok
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("ok", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("ok", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
     }
 
     #[test]
-    fn empty_file() -> VMResult {
+    fn empty_file() -> Result<(), LoxError> {
         let source = r#"
 "#
         .to_string();
         let mut vm = VM::new();
+        vm.interpret(source)?;
         Ok(())
     }
 
     #[test]
-    fn precedence() -> VMResult {
+    fn precedence() -> Result<(), LoxError> {
         let source = r#"
 // * has higher precedence than +.
 print 2 + 3 * 4; // expect: 14
@@ -480,20 +1496,21 @@ print (2 * (6 - (2 + 2))); // expect: 4
 "#
         .to_string();
         let mut vm = VM::new();
-        vm.interpret(source)?;
-        assert_eq!("4", vm.printed_values.pop().unwrap().to_string());
-        assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
-        assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
-        assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
-        assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
-        assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-        assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-        assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-        assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-        assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
-        assert_eq!("4", vm.printed_values.pop().unwrap().to_string());
-        assert_eq!("8", vm.printed_values.pop().unwrap().to_string());
-        assert_eq!("14", vm.printed_values.pop().unwrap().to_string());
+        vm.capture_output = true;
+        let mut result = vm.interpret(source)?;
+        assert_eq!("4", result.printed_values.pop().unwrap().to_string());
+        assert_eq!("0", result.printed_values.pop().unwrap().to_string());
+        assert_eq!("0", result.printed_values.pop().unwrap().to_string());
+        assert_eq!("0", result.printed_values.pop().unwrap().to_string());
+        assert_eq!("0", result.printed_values.pop().unwrap().to_string());
+        assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+        assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+        assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+        assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+        assert_eq!("0", result.printed_values.pop().unwrap().to_string());
+        assert_eq!("4", result.printed_values.pop().unwrap().to_string());
+        assert_eq!("8", result.printed_values.pop().unwrap().to_string());
+        assert_eq!("14", result.printed_values.pop().unwrap().to_string());
         Ok(())
     }
 
@@ -501,18 +1518,15 @@ print (2 * (6 - (2 + 2))); // expect: 4
         use super::*;
 
         #[test]
-        fn missing_argument() -> VMResult {
+        fn missing_argument() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at ';': Expect expression.
 print;
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect expression.", error.message);
             Ok(())
         }
     }
@@ -521,7 +1535,7 @@ print;
         use super::*;
 
         #[test]
-        fn error_after_multiline() -> VMResult {
+        fn error_after_multiline() -> Result<(), LoxError> {
             let source = r#"
 // Tests that we correctly track the line info across multiline strings.
 var a = "1
@@ -533,16 +1547,13 @@ err; // // expect runtime error: Undefined variable 'err'.
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Undefined variable 'err'.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Undefined variable 'err'.", error.message);
             Ok(())
         }
 
         #[test]
-        fn literals() -> VMResult {
+        fn literals() -> Result<(), LoxError> {
             let source = r#"
 print "(" + "" + ")";   // expect: ()
 print "a string"; // expect: a string
@@ -552,16 +1563,17 @@ print "A~¶Þॐஃ"; // expect: A~¶Þॐஃ
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("A~¶Þॐஃ", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("a string", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("()", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("A~¶Þॐஃ", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("a string", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("()", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[ignore = "refactor or remove"]
         #[test]
-        fn multiline() -> VMResult {
+        fn multiline() -> Result<(), LoxError> {
             let source = r#"
 var a = "1
 2
@@ -573,26 +1585,24 @@ print a;
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("3", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("2", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("1", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("3", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("2", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn unterminated() -> VMResult {
+        fn unterminated() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error: Unterminated string.
 "this string has no close quote
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Unterminated string.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Unterminated string.", error.message);
             Ok(())
         }
     }
@@ -601,7 +1611,7 @@ print a;
         use super::*;
 
         #[test]
-        fn collide_with_parameter() -> VMResult {
+        fn collide_with_parameter() -> Result<(), LoxError> {
             let source = r#"
 fun foo(a) {
   var a; // Error at 'a': Already variable with this name in this scope.
@@ -609,19 +1619,16 @@ fun foo(a) {
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
+            let error = vm.interpret(source).unwrap_err();
             assert_eq!(
                 "Already variable with this name in this scope.",
-                vm.latest_error_message
+                error.message
             );
             Ok(())
         }
 
         #[test]
-        fn duplicate_local() -> VMResult {
+        fn duplicate_local() -> Result<(), LoxError> {
             let source = r#"
 {
   var a = "value";
@@ -630,19 +1637,16 @@ fun foo(a) {
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
+            let error = vm.interpret(source).unwrap_err();
             assert_eq!(
                 "Already variable with this name in this scope.",
-                vm.latest_error_message
+                error.message
             );
             Ok(())
         }
 
         #[test]
-        fn duplicate_parameter() -> VMResult {
+        fn duplicate_parameter() -> Result<(), LoxError> {
             let source = r#"
 fun foo(arg,
         arg) { // Error at 'arg': Already variable with this name in this scope.
@@ -651,19 +1655,16 @@ fun foo(arg,
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
+            let error = vm.interpret(source).unwrap_err();
             assert_eq!(
                 "Already variable with this name in this scope.",
-                vm.latest_error_message
+                error.message
             );
             Ok(())
         }
 
         #[test]
-        fn early_bound() -> VMResult {
+        fn early_bound() -> Result<(), LoxError> {
             let source = r#"
 var a = "outer";
 {
@@ -678,14 +1679,15 @@ var a = "outer";
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("outer", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("outer", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("outer", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("outer", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn in_middle_of_block() -> VMResult {
+        fn in_middle_of_block() -> Result<(), LoxError> {
             let source = r#"
 {
   var a = "a";
@@ -700,16 +1702,17 @@ var a = "outer";
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("a b d", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("a c", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("a b", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("a", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("a b d", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("a c", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("a b", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("a", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn in_nested_block() -> VMResult {
+        fn in_nested_block() -> Result<(), LoxError> {
             let source = r#"
 {
   var a = "outer";
@@ -720,14 +1723,14 @@ var a = "outer";
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("outer", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("outer", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
-        #[ignore = "method"]
         #[test]
-        fn local_from_method() -> VMResult {
+        fn local_from_method() -> Result<(), LoxError> {
             let source = r#"
 var foo = "variable";
 
@@ -741,13 +1744,14 @@ Foo().method(); // expect: variable
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("variable", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("variable", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn redeclare_global() -> VMResult {
+        fn redeclare_global() -> Result<(), LoxError> {
             let source = r#"
 var a = "1";
 var a;
@@ -755,13 +1759,14 @@ print a; // expect: nil
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("nil", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("nil", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn redefine_global() -> VMResult {
+        fn redefine_global() -> Result<(), LoxError> {
             let source = r#"
 var a = "1";
 var a = "2";
@@ -769,13 +1774,14 @@ print a; // expect: 2
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("2", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("2", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn scope_reuse_in_different_blocks() -> VMResult {
+        fn scope_reuse_in_different_blocks() -> Result<(), LoxError> {
             let source = r#"
 {
   var a = "first";
@@ -789,14 +1795,15 @@ print a; // expect: 2
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("second", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("first", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("second", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("first", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn shadow_and_local() -> VMResult {
+        fn shadow_and_local() -> Result<(), LoxError> {
             let source = r#"
 {
   var a = "outer";
@@ -809,14 +1816,15 @@ print a; // expect: 2
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("inner", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("outer", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("inner", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("outer", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn shadow_global() -> VMResult {
+        fn shadow_global() -> Result<(), LoxError> {
             let source = r#"
 var a = "global";
 {
@@ -827,14 +1835,15 @@ print a; // expect: global
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("global", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("shadow", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("global", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("shadow", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn shadow_local() -> VMResult {
+        fn shadow_local() -> Result<(), LoxError> {
             let source = r#"
 {
   var a = "local";
@@ -847,29 +1856,27 @@ print a; // expect: global
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("local", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("shadow", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("local", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("shadow", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn undefined_global() -> VMResult {
+        fn undefined_global() -> Result<(), LoxError> {
             let source = r#"
 print notDefined;  // expect runtime error: Undefined variable 'notDefined'.
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Undefined variable 'notDefined'.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Undefined variable 'notDefined'.", error.message);
             Ok(())
         }
 
         #[test]
-        fn undefined_local() -> VMResult {
+        fn undefined_local() -> Result<(), LoxError> {
             let source = r#"
 {
   print notDefined;  // expect runtime error: Undefined variable 'notDefined'.
@@ -877,29 +1884,27 @@ print notDefined;  // expect runtime error: Undefined variable 'notDefined'.
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Undefined variable 'notDefined'.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Undefined variable 'notDefined'.", error.message);
             Ok(())
         }
 
         #[test]
-        fn uninitialized() -> VMResult {
+        fn uninitialized() -> Result<(), LoxError> {
             let source = r#"
 var a;
 print a; // expect: nil
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("nil", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("nil", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn unreached_undefined() -> VMResult {
+        fn unreached_undefined() -> Result<(), LoxError> {
             let source = r#"
 if (false) {
   print notDefined;
@@ -909,29 +1914,27 @@ print "ok"; // expect: ok
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("ok", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("ok", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn use_false_as_var() -> VMResult {
+        fn use_false_as_var() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'false': Expect variable name.
 var false = "value";
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect variable name.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect variable name.", error.message);
             Ok(())
         }
 
         #[test]
-        fn use_global_in_initializer() -> VMResult {
+        fn use_global_in_initializer() -> Result<(), LoxError> {
             let source = r#"
 var a = "value";
 var a = a;
@@ -939,13 +1942,14 @@ print a; // expect: value
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("value", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("value", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn use_local_in_initializer() -> VMResult {
+        fn use_local_in_initializer() -> Result<(), LoxError> {
             let source = r#"
 var a = "outer";
 {
@@ -954,46 +1958,37 @@ var a = "outer";
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
+            let error = vm.interpret(source).unwrap_err();
             assert_eq!(
                 "Can't read local variable in its own initializer.",
-                vm.latest_error_message
+                error.message
             );
             Ok(())
         }
 
         #[test]
-        fn use_nil_as_var() -> VMResult {
+        fn use_nil_as_var() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'nil': Expect variable name.
 var nil = "value";
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect variable name.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect variable name.", error.message);
             Ok(())
         }
 
         #[test]
-        fn use_this_as_var() -> VMResult {
+        fn use_this_as_var() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'this': Expect variable name.
 var this = "value";
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect variable name.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect variable name.", error.message);
             Ok(())
         }
     }
@@ -1002,7 +1997,7 @@ var this = "value";
         use super::*;
 
         #[test]
-        fn and() -> VMResult {
+        fn and() -> Result<(), LoxError> {
             let source = r#"
 // Note: These tests implicitly depend on ints being truthy.
 
@@ -1026,19 +2021,20 @@ print b; // expect: false
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("3", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("1", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("3", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn and_truth() -> VMResult {
+        fn and_truth() -> Result<(), LoxError> {
             let source = r#"
 // False and nil are false.
 print false and "bad"; // expect: false
@@ -1051,17 +2047,18 @@ print "" and "ok"; // expect: ok
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("ok", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("ok", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("ok", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("nil", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("ok", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("ok", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("ok", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("nil", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn or() -> VMResult {
+        fn or() -> Result<(), LoxError> {
             let source = r#"
 // Note: These tests implicitly depend on ints being truthy.
 
@@ -1085,19 +2082,20 @@ print b; // expect: true
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("1", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("1", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn or_truth() -> VMResult {
+        fn or_truth() -> Result<(), LoxError> {
             let source = r#"
 // False and nil are false.
 print false or "ok"; // expect: ok
@@ -1110,12 +2108,13 @@ print "s" or "ok"; // expect: s
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("s", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("ok", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("ok", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("s", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("0", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("ok", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("ok", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
     }
@@ -1124,39 +2123,33 @@ print "s" or "ok"; // expect: s
         use super::*;
 
         #[test]
-        fn class_in_else_test() -> VMResult {
+        fn class_in_else_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'class': Expect expression.
 if (true) "ok"; else class Foo {}
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect expression.", error.message);
             Ok(())
         }
 
         #[test]
-        fn class_in_then_test() -> VMResult {
+        fn class_in_then_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'class': Expect expression.
 if (true) class Foo {}
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect expression.", error.message);
             Ok(())
         }
 
         #[test]
-        fn dangling_else_test() -> VMResult {
+        fn dangling_else_test() -> Result<(), LoxError> {
             let source = r#"
 // A dangling else binds to the right-most if.
 if (true) if (false) print "bad"; else print "good"; // expect: good
@@ -1164,13 +2157,14 @@ if (false) if (true) print "bad"; else print "bad";
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("good", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("good", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn else_test() -> VMResult {
+        fn else_test() -> Result<(), LoxError> {
             let source = r#"
 // Evaluate the 'else' expression if the condition is false.
 if (true) print "good"; else print "bad"; // expect: good
@@ -1181,47 +2175,42 @@ if (false) nil; else { print "block"; } // expect: block
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("block", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("good", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("good", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("block", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("good", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("good", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn fun_in_else_test() -> VMResult {
+        fn fun_in_else_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'fun': Expect expression.
 if (true) "ok"; else fun foo() {}
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect expression.", error.message);
             Ok(())
         }
 
         #[test]
-        fn fun_in_then_test() -> VMResult {
+        fn fun_in_then_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'fun': Expect expression.
 if (true) fun foo() {}
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect expression.", error.message);
             Ok(())
         }
 
         #[test]
-        fn if_test() -> VMResult {
+        fn if_test() -> Result<(), LoxError> {
             let source = r#"
 // Evaluate the 'then' expression if the condition is true.
 if (true) print "good"; // expect: good
@@ -1236,15 +2225,16 @@ if (a = true) print a; // expect: true
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("block", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("good", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("block", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("good", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn truth_test() -> VMResult {
+        fn truth_test() -> Result<(), LoxError> {
             let source = r#"
 // False and nil are false.
 if (false) print "bad"; else print "false"; // expect: false
@@ -1257,50 +2247,45 @@ if ("") print "empty"; // expect: empty
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("empty", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("nil", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("false", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("empty", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("0", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("nil", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("false", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn var_in_else_test() -> VMResult {
+        fn var_in_else_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'var': Expect expression.
 if (true) "ok"; else var foo;
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect expression.", error.message);
             Ok(())
         }
 
         #[test]
-        fn var_in_then_test() -> VMResult {
+        fn var_in_then_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'var': Expect expression.
 if (true) var foo;
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect expression.", error.message);
             Ok(())
         }
     }
 
     #[test]
-    fn unexpected_character() -> VMResult {
+    fn unexpected_character() -> Result<(), LoxError> {
         let source = r#"
 // [line 3] Error: Unexpected character.
 // [java line 3] Error at 'b': Expect ')' after arguments.
@@ -1308,37 +2293,30 @@ foo(a | b);
 "#
         .to_string();
         let mut vm = VM::new();
-        #[allow(unused_must_use)]
-        {
-            vm.interpret(source);
-        }
-        assert_eq!("Unexpected character.", vm.latest_error_message);
+        let error = vm.interpret(source).unwrap_err();
+        assert_eq!("Unexpected character.", error.message);
         Ok(())
     }
 
     mod while_tests {
         use super::*;
 
-        #[ignore = "class"]
         #[test]
-        fn class_in_body_test() -> VMResult {
+        fn class_in_body_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'class': Expect expression.
 while (true) class Foo {}
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect expression.", error.message);
             Ok(())
         }
 
         #[ignore = "closure"]
         #[test]
-        fn closure_in_body_test() -> VMResult {
+        fn closure_in_body_test() -> Result<(), LoxError> {
             let source = r#"
 var f1;
 var f2;
@@ -1362,32 +2340,30 @@ f3(); // expect: 3
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("3", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("2", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("1", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("3", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("2", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn fun_in_body_test() -> VMResult {
+        fn fun_in_body_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'fun': Expect expression.
 while (true) fun foo() {}
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect expression.", error.message);
             Ok(())
         }
 
         #[ignore = "closure"]
         #[test]
-        fn return_closure_test() -> VMResult {
+        fn return_closure_test() -> Result<(), LoxError> {
             let source = r#"
 fun f() {
   while (true) {
@@ -1402,13 +2378,14 @@ h(); // expect: i
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("i", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("i", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn return_inside_test() -> VMResult {
+        fn return_inside_test() -> Result<(), LoxError> {
             let source = r#"
 fun f() {
   while (true) {
@@ -1422,13 +2399,14 @@ print f();
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("i", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("i", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn syntax_test() -> VMResult {
+        fn syntax_test() -> Result<(), LoxError> {
             let source = r#"
 // Single-expression body.
 var c = 0;
@@ -1454,29 +2432,27 @@ while (false) for (;;) 1;
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("2", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("1", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("3", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("2", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("1", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("2", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("0", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("3", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("2", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn var_in_body_test() -> VMResult {
+        fn var_in_body_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'var': Expect expression.
 while (true) var foo;
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect expression.", error.message);
             Ok(())
         }
     }
@@ -1484,26 +2460,22 @@ while (true) var foo;
     mod for_tests {
         use super::*;
 
-        #[ignore = "class"]
         #[test]
-        fn class_in_body_test() -> VMResult {
+        fn class_in_body_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'class': Expect expression.
 for (;;) class Foo {}
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect expression.", error.message);
             Ok(())
         }
 
         #[ignore = "closure"]
         #[test]
-        fn closure_in_body_test() -> VMResult {
+        fn closure_in_body_test() -> Result<(), LoxError> {
             let source = r#"
 var f1;
 var f2;
@@ -1530,35 +2502,33 @@ f3(); // expect: 4
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("3", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("4", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("2", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("4", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("1", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("4", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("3", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("4", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("2", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("4", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("4", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn fun_in_body_test() -> VMResult {
+        fn fun_in_body_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'fun': Expect expression.
 for (;;) fun foo() {}
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect expression.", error.message);
             Ok(())
         }
 
         #[ignore = "closure"]
         #[test]
-        fn return_closure_test() -> VMResult {
+        fn return_closure_test() -> Result<(), LoxError> {
             let source = r#"
 fun f() {
 for (;;) {
@@ -1573,13 +2543,14 @@ h(); // expect: i
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("i", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("i", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn return_inside_test() -> VMResult {
+        fn return_inside_test() -> Result<(), LoxError> {
             let source = r#"
 fun f() {
 for (;;) {
@@ -1593,13 +2564,14 @@ print f();
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("i", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("i", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn scope_test() -> VMResult {
+        fn scope_test() -> Result<(), LoxError> {
             let source = r#"
 {
 var i = "before";
@@ -1630,64 +2602,56 @@ print i; // expect: 0
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("after", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("-1", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("0", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("after", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("-1", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("0", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn statement_condition_test() -> VMResult {
+        fn statement_condition_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 3] Error at ')': Expect ';' after expression.
 for (var a = 1; {}; a = a + 1) {}
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect ';' after expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect ';' after expression.", error.message);
             Ok(())
         }
 
         #[test]
-        fn statement_increment_test() -> VMResult {
+        fn statement_increment_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at '{': Expect expression.
 for (var a = 1; a < 2; {}) {}
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect expression.", error.message);
             Ok(())
         }
 
         #[test]
-        fn statement_initializer_test() -> VMResult {
+        fn statement_initializer_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 3] Error at ')': Expect ';' after expression.
 for ({}; a < 2; a = a + 1) {}
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect ';' after expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect ';' after expression.", error.message);
             Ok(())
         }
 
         #[test]
-        fn syntax_test() -> VMResult {
+        fn syntax_test() -> Result<(), LoxError> {
             let source = r#"
 // Single-expression body.
 for (var c = 0; c < 3;) print c = c + 1;
@@ -1742,37 +2706,35 @@ for (; false;) for (;;) 1;
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("1", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("2", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("1", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("1", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("done", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("2", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("1", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("3", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("2", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("1", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("0", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("2", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("0", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("0", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("done", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("2", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("0", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("3", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("2", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn var_in_body_test() -> VMResult {
+        fn var_in_body_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 2] Error at 'var': Expect expression.
 for (;;) var foo;
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect expression.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect expression.", error.message);
             Ok(())
         }
     }
@@ -1780,7 +2742,7 @@ for (;;) var foo;
         use super::*;
 
         #[test]
-        fn body_must_be_block_test() -> VMResult {
+        fn body_must_be_block_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 3] Error at '123': Expect '{' before function body.
 // [c line 4] Error at end: Expect '}' after block.
@@ -1788,29 +2750,27 @@ fun f() 123;
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect '}' after block.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect '}' after block.", error.message);
             Ok(())
         }
 
         #[test]
-        fn empty_body_test() -> VMResult {
+        fn empty_body_test() -> Result<(), LoxError> {
             let source = r#"
 fun f() {}
 print f(); // expect: nil
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("nil", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("nil", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn extra_arguments_test() -> VMResult {
+        fn extra_arguments_test() -> Result<(), LoxError> {
             let source = r#"
 fun f(a, b) {
 print a;
@@ -1821,16 +2781,13 @@ f(1, 2, 3, 4); // expect runtime error: Expected 2 arguments but got 4.
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expected 2 arguments but got 4.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expected 2 arguments but got 4.", error.message);
             Ok(())
         }
 
         #[test]
-        fn local_mutual_recursion_test() -> VMResult {
+        fn local_mutual_recursion_test() -> Result<(), LoxError> {
             let source = r#"
 {
 fun isEven(n) {
@@ -1848,16 +2805,13 @@ isEven(4);
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Undefined variable 'isOdd'.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Undefined variable 'isOdd'.", error.message);
             Ok(())
         }
 
         #[test]
-        fn local_recursion_test() -> VMResult {
+        fn local_recursion_test() -> Result<(), LoxError> {
             let source = r#"
 {
     fun fib(n) {
@@ -1869,13 +2823,14 @@ isEven(4);
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("21", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("21", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn missing_arguments_test() -> VMResult {
+        fn missing_arguments_test() -> Result<(), LoxError> {
             let source = r#"
 fun f(a, b) {}
 
@@ -1883,16 +2838,13 @@ f(1); // expect runtime error: Expected 2 arguments but got 1.
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expected 2 arguments but got 1.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expected 2 arguments but got 1.", error.message);
             Ok(())
         }
 
         #[test]
-        fn missing_comma_in_parameters_test() -> VMResult {
+        fn missing_comma_in_parameters_test() -> Result<(), LoxError> {
             let source = r#"
 // [line 3] Error at 'c': Expect ')' after parameters.
 // [c line 4] Error at end: Expect '}' after block.
@@ -1900,16 +2852,13 @@ fun foo(a, b c, d, e, f) {}
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!("Expect '}' after block.", vm.latest_error_message);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect '}' after block.", error.message);
             Ok(())
         }
 
         #[test]
-        fn mutual_recursion_test() -> VMResult {
+        fn mutual_recursion_test() -> Result<(), LoxError> {
             let source = r#"
 fun isEven(n) {
 if (n == 0) return true;
@@ -1926,14 +2875,15 @@ print isOdd(3); // expect: true
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("true", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("true", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn nested_call_with_arguments_test() -> VMResult {
+        fn nested_call_with_arguments_test() -> Result<(), LoxError> {
             let source = r#"
 fun returnArg(arg) {
 return arg;
@@ -1951,13 +2901,17 @@ returnFunCallWithArg(printArg, "hello world"); // expect: hello world
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("hello world", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!(
+                "hello world",
+                result.printed_values.pop().unwrap().to_string()
+            );
             Ok(())
         }
 
         #[test]
-        fn parameters_test() -> VMResult {
+        fn parameters_test() -> Result<(), LoxError> {
             let source = r#"
 fun f0() { return 0; }
 print f0(); // expect: 0
@@ -1988,21 +2942,22 @@ print f8(1, 2, 3, 4, 5, 6, 7, 8); // expect: 36
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("36", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("28", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("21", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("15", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("10", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("6", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("3", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("1", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("0", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("36", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("28", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("21", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("15", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("10", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("6", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("3", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("0", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn print_test() -> VMResult {
+        fn print_test() -> Result<(), LoxError> {
             let source = r#"
 fun foo() {}
 print foo; // expect: <fn foo>
@@ -2011,14 +2966,18 @@ print clock; // expect: <native fn>
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("<native fn>", vm.printed_values.pop().unwrap().to_string());
-            assert_eq!("<fn foo>", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!(
+                "<native fn>",
+                result.printed_values.pop().unwrap().to_string()
+            );
+            assert_eq!("<fn foo>", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn recursion_test() -> VMResult {
+        fn recursion_test() -> Result<(), LoxError> {
             let source = r#"
 fun fib(n) {
 if (n < 2) return n;
@@ -2029,13 +2988,14 @@ print fib(8); // expect: 21
 "#
             .to_string();
             let mut vm = VM::new();
-            vm.interpret(source)?;
-            assert_eq!("21", vm.printed_values.pop().unwrap().to_string());
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("21", result.printed_values.pop().unwrap().to_string());
             Ok(())
         }
 
         #[test]
-        fn too_many_arguments_test() -> VMResult {
+        fn too_many_arguments_test() -> Result<(), LoxError> {
             let source = r#"
 fun foo() {}
 {
@@ -2301,19 +3261,13 @@ foo(
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
-            assert_eq!(
-                "Can't have more than 255 arguments.",
-                vm.latest_error_message
-            );
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Can't have more than 255 arguments.", error.message);
             Ok(())
         }
 
         #[test]
-        fn too_many_parameters_test() -> VMResult {
+        fn too_many_parameters_test() -> Result<(), LoxError> {
             let source = r#"
 // 256 parameters.
 fun f(
@@ -2575,15 +3529,5893 @@ a255, a) {} // Error at 'a': Can't have more than 255 parameters.
 "#
             .to_string();
             let mut vm = VM::new();
-            #[allow(unused_must_use)]
-            {
-                vm.interpret(source);
-            }
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Can't have more than 255 parameters.", error.message);
+            Ok(())
+        }
+    }
+
+    mod defer {
+        use super::*;
+
+        #[test]
+        fn lifo_order() -> Result<(), LoxError> {
+            let source = r#"
+fun log(message) {
+  print message;
+}
+
+fun f() {
+  defer log("first");
+  defer log("second");
+  print "body";
+}
+
+f();
+// expect: body
+// expect: second
+// expect: first
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("first", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("second", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("body", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn after_return_value() -> Result<(), LoxError> {
+            let source = r#"
+fun log(message) {
+  print message;
+}
+
+fun f(a) {
+  defer log(a);
+  return a + 1;
+}
+
+print f(1);
+// expect: 1
+// expect: 2
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("2", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn only_when_executed() -> Result<(), LoxError> {
+            let source = r#"
+fun log(message) {
+  print message;
+}
+
+fun f(flag) {
+  if (flag) defer log("deferred");
+  print "body";
+}
+
+f(false);
+f(true);
+// expect: body
+// expect: body
+// expect: deferred
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("deferred", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("body", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("body", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn at_end_of_script() -> Result<(), LoxError> {
+            let source = r#"
+fun log(message) {
+  print message;
+}
+
+defer log("end");
+print "start";
+// expect: start
+// expect: end
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("end", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("start", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn block_scoped_local() -> Result<(), LoxError> {
+            let source = r#"
+fun f() {
+  {
+    var a = 1;
+    defer a; // Error at 'a': Can't use a block-scoped local variable in a deferred expression.
+  }
+}
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
             assert_eq!(
-                "Can't have more than 255 parameters.",
-                vm.latest_error_message
+                "Can't use a block-scoped local variable in a deferred expression.",
+                error.message
             );
             Ok(())
         }
     }
+
+    mod slice {
+        use super::*;
+
+        #[test]
+        fn string() -> Result<(), LoxError> {
+            let source = r#"
+var s = "abcdef";
+print s[1:4]; // expect: bcd
+print s[:2]; // expect: ab
+print s[4:]; // expect: ef
+print s[:]; // expect: abcdef
+print s[-2:]; // expect: ef
+print s[1:-1]; // expect: bcde
+print s[4:2]; // expect:
+print s[-100:100]; // expect: abcdef
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("abcdef", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("bcde", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("ef", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("abcdef", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("ef", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("ab", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("bcd", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn unicode() -> Result<(), LoxError> {
+            let source = r#"
+print "ĐĦŋœ"[1:3]; // expect: Ħŋ
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("Ħŋ", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn list() -> Result<(), LoxError> {
+            let source = r#"
+var l = [1, 2, 3, 4];
+print l[1:3]; // expect: [2, 3]
+print l[-1:]; // expect: [4]
+print l[3:1]; // expect: []
+var copy = l[:];
+copy[0] = 10;
+print l[0]; // expect: 1
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["[2, 3]", "[4]", "[]", "1"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn non_integer_bound() -> Result<(), LoxError> {
+            let source = r#"
+"abc"[0.5:2]; // expect runtime error: Slice bounds must be integers or nil.
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Slice bounds must be integers or nil.", error.message);
+            Ok(())
+        }
+
+        #[test]
+        fn non_string() -> Result<(), LoxError> {
+            let source = r#"
+123[0:1]; // expect runtime error: Can only slice strings and lists.
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Can only slice strings and lists.", error.message);
+            Ok(())
+        }
+
+        #[test]
+        fn missing_colon() -> Result<(), LoxError> {
+            let source = r#"
+"abc"[1 2]; // Error at '2': Expect ']' after index.
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expect ']' after index.", error.message);
+            Ok(())
+        }
+    }
+
+    mod native_reentrancy {
+        use super::*;
+        use rlox::value::value::Value;
+
+        /// Calls its first argument with its second argument.
+        fn apply(vm: &mut VM, args: &[Value]) -> Result<Value, VMError> {
+            vm.call_value(args[0].clone(), &args[1..])
+        }
+
+        /// Raises a runtime error.
+        fn fail(vm: &mut VM, _args: &[Value]) -> Result<Value, VMError> {
+            vm.runtime_error("Native failure.");
+            Err(VMError::RuntimeError)
+        }
+
+        fn vm_with_apply() -> VM {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.define_native("apply", 2, apply);
+            vm.define_native("fail", 0, fail);
+            vm
+        }
+
+        #[test]
+        fn native_in_stack_trace() {
+            let source = r#"
+fun f() {
+  fail();
+}
+
+f();
+"#
+            .to_string();
+            let mut vm = vm_with_apply();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Native failure.", error.message);
+            assert_eq!(
+                vec![
+                    "[line 3] in fail()",
+                    "[line 3] in f()",
+                    "[line 6] in script"
+                ],
+                error.stack_trace
+            );
+        }
+
+        #[test]
+        fn natives_around_callback_in_stack_trace() {
+            let source = r#"
+fun callback(n) {
+  return fail();
+}
+
+var result =
+  apply(callback, 1);
+"#
+            .to_string();
+            let mut vm = vm_with_apply();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!(
+                vec![
+                    "[line 3] in fail()",
+                    "[line 3] in callback()",
+                    "[line 7] in apply()",
+                    "[line 7] in script",
+                ],
+                error.stack_trace
+            );
+
+            // The native calls do not outlive the error.
+            let error = vm.interpret("-nil;".to_string()).unwrap_err();
+            assert_eq!(vec!["[line 1] in script"], error.stack_trace);
+        }
+
+        #[test]
+        fn call_function() -> Result<(), LoxError> {
+            let source = r#"
+fun double(n) {
+  return n * 2;
+}
+
+print apply(double, 21); // expect: 42
+print "after"; // expect: after
+"#
+            .to_string();
+            let mut vm = vm_with_apply();
+            let mut result = vm.interpret(source)?;
+            assert_eq!("after", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("42", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn nested() -> Result<(), LoxError> {
+            let source = r#"
+fun countdown(n) {
+  if (n == 0) return "done";
+  var result = apply(countdown, n - 1);
+  print n;
+  return result;
+}
+
+print countdown(3);
+// expect: 1
+// expect: 2
+// expect: 3
+// expect: done
+"#
+            .to_string();
+            let mut vm = vm_with_apply();
+            let mut result = vm.interpret(source)?;
+            assert_eq!("done", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("3", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("2", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn error_in_callback() -> Result<(), LoxError> {
+            let source = r#"
+fun fail(n) {
+  return -"n"; // expect runtime error: Operand must be a number.
+}
+
+apply(fail, 1);
+print "unreachable";
+"#
+            .to_string();
+            let mut vm = vm_with_apply();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!(VMError::RuntimeError, error.kind);
+            assert_eq!("Operand must be a number.", error.message);
+            Ok(())
+        }
+
+        #[test]
+        fn call_native() -> Result<(), LoxError> {
+            let source = r#"
+apply(clock, 1); // expect runtime error: Expected 0 arguments but got 1.
+"#
+            .to_string();
+            let mut vm = vm_with_apply();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expected 0 arguments but got 1.", error.message);
+            Ok(())
+        }
+
+        #[test]
+        fn call_native_and_interpret_again() -> Result<(), LoxError> {
+            let mut vm = vm_with_apply();
+            let result =
+                vm.interpret("var a = 1; print apply(inspect, 2); print a;".to_string())?;
+            assert_eq!(vec!["2", "1"], printed(result));
+
+            vm.interpret("on(\"tick\", inspect);".to_string())?;
+            vm.dispatch("tick", &[Value::Number(1.0)])?;
+            let result = vm.interpret("print 3;".to_string())?;
+            assert_eq!("3", result.printed_values[0].to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn stack_overflow() -> Result<(), LoxError> {
+            let source = r#"
+fun recurse(n) {
+  return apply(recurse, n);
+}
+
+recurse(1); // expect runtime error: Stack overflow.
+"#
+            .to_string();
+            let mut vm = vm_with_apply();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Stack overflow.", error.message);
+            Ok(())
+        }
+    }
+
+    mod number {
+        use super::*;
+
+        #[test]
+        fn literals() -> Result<(), LoxError> {
+            let source = r#"
+print 123; // expect: 123
+print 987654; // expect: 987654
+print 0; // expect: 0
+print 123.456; // expect: 123.456
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("123.456", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("0", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("987654", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("123", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn underscore() -> Result<(), LoxError> {
+            let source = r#"
+print 1_000_000;
+print -1_0.2_5;
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("-10.25", result.printed_values.pop().unwrap().to_string());
+            assert_eq!("1000000", result.printed_values.pop().unwrap().to_string());
+
+            for source in ["print 1__0;", "print 1_;", "print 1_.5;"] {
+                let error = vm.interpret(source.to_string()).unwrap_err();
+                assert_eq!("Invalid number literal.", error.message, "{}", source);
+            }
+            Ok(())
+        }
+
+        #[test]
+        fn double_dot() -> Result<(), LoxError> {
+            let source = r#"
+print 1..2; // Error at '1..2': Invalid number literal.
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Invalid number literal.", error.message);
+            Ok(())
+        }
+
+        #[test]
+        fn exponent() -> Result<(), LoxError> {
+            assert_eq!(
+                vec!["100000", "0.025", "-2000"],
+                run("print 1e5; print 2.5E-2; print -2e+3;")?
+            );
+            for source in ["print 1e;", "print 1e-;", "print 1e5.0;", "print 1e400;"] {
+                let error = run(source).unwrap_err();
+                assert_eq!("Invalid number literal.", error.message, "{}", source);
+            }
+            Ok(())
+        }
+
+        #[test]
+        fn non_ascii_digit() -> Result<(), LoxError> {
+            let source = r#"
+print 1٢; // Error at '1٢': Invalid number literal.
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Invalid number literal.", error.message);
+            Ok(())
+        }
+
+        #[test]
+        fn too_long() -> Result<(), LoxError> {
+            let source = r#"
+print 1111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111111; // Error: Invalid number literal.
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Invalid number literal.", error.message);
+            Ok(())
+        }
+    }
+
+    mod decode_source {
+        use super::*;
+
+        #[test]
+        fn utf8() {
+            let bytes = "print \"ĐĦŋœ\";".as_bytes().to_vec();
+            assert_eq!(Ok("print \"ĐĦŋœ\";".to_string()), decode_source(bytes));
+        }
+
+        #[test]
+        fn byte_order_mark() {
+            let mut bytes = vec![0xEF, 0xBB, 0xBF];
+            bytes.extend_from_slice(b"print 1;");
+            assert_eq!(Ok("print 1;".to_string()), decode_source(bytes));
+        }
+
+        #[test]
+        fn invalid_utf8() {
+            let bytes = b"print \"\xE9\";".to_vec();
+            assert_eq!(
+                Err("invalid UTF-8 at byte offset 7.".to_string()),
+                decode_source(bytes)
+            );
+        }
+
+        #[test]
+        fn invalid_utf8_after_byte_order_mark() {
+            let mut bytes = vec![0xEF, 0xBB, 0xBF];
+            bytes.extend_from_slice(b"\xFF");
+            assert_eq!(
+                Err("invalid UTF-8 at byte offset 3.".to_string()),
+                decode_source(bytes)
+            );
+        }
+    }
+
+    mod source_limits {
+        use super::*;
+
+        #[test]
+        fn source_too_large() -> Result<(), LoxError> {
+            let source = r#"
+print "this source is larger than the limit";
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.compile_options.max_source_size = 16;
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!(VMError::CompileError, error.kind);
+            assert_eq!(
+                "Source is too large (47 bytes, the maximum is 16).",
+                error.message
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn line_too_long() -> Result<(), LoxError> {
+            let source = r#"
+print 1;
+print "this line is longer than the limit";
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.compile_options.max_line_length = 20;
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!(VMError::CompileError, error.kind);
+            assert_eq!(
+                "Line is too long (43 characters, the maximum is 20).",
+                error.message
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn within_limits() -> Result<(), LoxError> {
+            let source = r#"
+print "ok"; // expect: ok
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.compile_options.max_source_size = source.len();
+            vm.compile_options.max_line_length = 25;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("ok", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+    }
+
+    mod nested_loops {
+        use super::*;
+
+        #[test]
+        fn deeply_nested_for() -> Result<(), LoxError> {
+            let depth = 32;
+            let mut source = String::from("var count = 0;\n");
+            for i in 0..depth {
+                source.push_str(&format!(
+                    "for (var i{0} = 0; i{0} < 2; i{0} = i{0} + 1) {{\n  if (i{0} > 0) {{\n",
+                    i
+                ));
+            }
+            source.push_str("count = count + 1;\n");
+            for _ in 0..depth {
+                source.push_str("}\n}\n");
+            }
+            source.push_str("print count; // expect: 1\n");
+
+            let mut vm = VM::new();
+
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn deeply_nested_while() -> Result<(), LoxError> {
+            let depth = 32;
+            let mut source = String::from("var count = 0;\n");
+            for i in 0..depth {
+                source.push_str(&format!(
+                    "{{\nvar i{0} = 0;\nwhile (i{0} < 1 and true) {{\ni{0} = i{0} + 1;\nif (false or i{0} > 1) print \"unreachable\"; else {{\n",
+                    i
+                ));
+            }
+            source.push_str("count = count + 1;\n");
+            for _ in 0..depth {
+                source.push_str("}\n}\n}\n");
+            }
+            source.push_str("print count; // expect: 1\n");
+
+            let mut vm = VM::new();
+
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!(1, result.printed_values.len());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+    }
+
+    mod run_all {
+        use super::*;
+
+        #[test]
+        fn directory() {
+            let dir = std::env::temp_dir().join(format!("rlox_run_all_{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("nested")).unwrap();
+            std::fs::write(dir.join("pass.lox"), "print 1;").unwrap();
+            std::fs::write(dir.join("runtime.lox"), "print -nil;").unwrap();
+            std::fs::write(dir.join("nested").join("compile.lox"), "print;").unwrap();
+            std::fs::write(dir.join("notes.txt"), "not a script").unwrap();
+
+            let mut paths = Vec::new();
+            find_lox_files(&dir, &mut paths).unwrap();
+            paths.sort();
+            let results: Vec<Result<(), String>> = paths
+                .iter()
+                .map(|p| run_script(p, &Options::default()).map_err(|f| f.to_string()))
+                .collect();
+            std::fs::remove_dir_all(&dir).unwrap();
+
+            assert_eq!(
+                vec![
+                    dir.join("nested").join("compile.lox"),
+                    dir.join("pass.lox"),
+                    dir.join("runtime.lox"),
+                ],
+                paths
+            );
+            assert_eq!(
+                vec![
+                    Err("compile error".to_string()),
+                    Ok(()),
+                    Err("runtime error".to_string()),
+                ],
+                results
+            );
+        }
+    }
+
+    mod interpretation {
+        use super::*;
+        use rlox::value::value::Value;
+
+        #[test]
+        fn globals_snapshot() -> Result<(), LoxError> {
+            let source = r#"
+var a = 1;
+var b = "two";
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let result = vm.interpret(source)?;
+            assert_eq!("1", result.globals["a"].to_string());
+            assert_eq!("two", result.globals["b"].to_string());
+            assert!(matches!(result.globals["clock"], Value::NativeFunction(_)));
+            Ok(())
+        }
+
+        #[test]
+        fn instruction_count() -> Result<(), LoxError> {
+            let source = r#"
+print 1 + 2; // expect: 3
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let result = vm.interpret(source)?;
+            // OpConstant, OpConstant, OpAdd, OpPrint, OpNil, OpReturn
+            assert_eq!(6, result.instruction_count);
+            Ok(())
+        }
+
+        #[test]
+        fn per_call() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let first = vm.interpret("print 1;".to_string())?;
+            let mut second = vm.interpret("print 2;".to_string())?;
+            assert_eq!(1, first.printed_values.len());
+            assert_eq!(1, second.printed_values.len());
+            assert_eq!("2", second.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn output_not_captured_by_default() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            let result = vm.interpret("print 1;".to_string())?;
+            assert!(result.printed_values.is_empty());
+            Ok(())
+        }
+
+        #[test]
+        fn compile_error() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            let error = vm.interpret("print;".to_string()).unwrap_err();
+            assert_eq!(
+                LoxError {
+                    kind: VMError::CompileError,
+                    code: Some(Diagnostic::ExpectExpression),
+                    message: "Expect expression.".to_string(),
+                    stack_trace: Vec::new(),
+                },
+                error
+            );
+            Ok(())
+        }
+    }
+
+    mod output {
+        use super::*;
+        use rlox::value::value::Value;
+        use std::cell::RefCell;
+
+        thread_local! {
+            static WRITTEN: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        }
+
+        /// Collects what the VM writes, so that it can be inspected while the VM runs.
+        struct SharedOutput;
+
+        impl Write for SharedOutput {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                WRITTEN.with(|written| written.borrow_mut().extend_from_slice(buf));
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        fn written() -> String {
+            WRITTEN.with(|written| String::from_utf8(written.borrow().clone()).unwrap())
+        }
+
+        /// Returns how many bytes of output have reached [SharedOutput] so far.
+        fn output_length_native(_vm: &mut VM, _args: &[Value]) -> Result<Value, VMError> {
+            Ok(Value::Number(
+                WRITTEN.with(|written| written.borrow().len()) as f64,
+            ))
+        }
+
+        fn vm_with_shared_output() -> VM {
+            WRITTEN.with(|written| written.borrow_mut().clear());
+            let mut vm = VM::new();
+            vm.set_output(Box::new(SharedOutput));
+            vm.define_native("outputLength", 0, output_length_native);
+            vm
+        }
+
+        #[test]
+        fn flushed_after_interpret() -> Result<(), LoxError> {
+            let source = r#"
+print 1;
+print "two";
+print outputLength(); // expect: 0
+"#
+            .to_string();
+            let mut vm = vm_with_shared_output();
+            vm.interpret(source)?;
+            assert_eq!("1\ntwo\n0\n", written());
+            Ok(())
+        }
+
+        #[test]
+        fn unbuffered() -> Result<(), LoxError> {
+            let source = r#"
+print 1;
+print outputLength(); // expect: 2
+"#
+            .to_string();
+            let mut vm = vm_with_shared_output();
+            vm.unbuffered_output = true;
+            vm.interpret(source)?;
+            assert_eq!("1\n2\n", written());
+            Ok(())
+        }
+
+        #[test]
+        fn flushed_on_runtime_error() {
+            let source = r#"
+print 1;
+print -nil;
+"#
+            .to_string();
+            let mut vm = vm_with_shared_output();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Operand must be a number.", error.message);
+            assert_eq!("1\n", written());
+        }
+    }
+
+    mod undefined_globals {
+        use super::*;
+        use rlox::compiler::Warning;
+
+        fn vm_with_warnings() -> VM {
+            let mut vm = VM::new();
+            vm.compile_options
+                .lints
+                .insert(Lint::UndefinedGlobal, LintLevel::Warn);
+            vm
+        }
+
+        #[test]
+        fn typo() {
+            let source = r#"
+var count = 1;
+print cuont;
+"#
+            .to_string();
+            let mut vm = vm_with_warnings();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!(
+                "Undefined variable 'cuont'. Did you mean 'count'?",
+                error.message
+            );
+        }
+
+        #[test]
+        fn reported_as_warning() -> Result<(), LoxError> {
+            let source = r#"
+fun show() {
+  print missing;
+}
+var unused = clock;
+"#
+            .to_string();
+            let mut vm = vm_with_warnings();
+            let result = vm.interpret(source)?;
+            assert_eq!(
+                vec![Warning {
+                    source_name: String::new(),
+                    line: 3,
+                    code: Diagnostic::UndefinedGlobal,
+                    message: "Undefined variable 'missing'.".to_string()
+                }],
+                result.warnings
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn defined_later() -> Result<(), LoxError> {
+            let source = r#"
+fun first() {
+  return second();
+}
+fun second() {
+  return later;
+}
+var later = 1;
+print first(); // expect: 1
+"#
+            .to_string();
+            let mut vm = vm_with_warnings();
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert!(result.warnings.is_empty());
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn defined_by_earlier_source() -> Result<(), LoxError> {
+            let mut vm = vm_with_warnings();
+            vm.interpret("var a = 1;".to_string())?;
+            let result = vm.interpret("print a;".to_string())?;
+            assert!(result.warnings.is_empty());
+            Ok(())
+        }
+
+        #[test]
+        fn off_by_default() -> Result<(), LoxError> {
+            let source = r#"
+fun show() {
+  print missing;
+}
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let result = vm.interpret(source)?;
+            assert!(result.warnings.is_empty());
+            Ok(())
+        }
+    }
+
+    mod did_you_mean {
+        use super::*;
+
+        #[test]
+        fn keyword() {
+            let source = r#"
+pritn;
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!(
+                "Undefined variable 'pritn'. Did you mean 'print'?",
+                error.message
+            );
+        }
+
+        #[test]
+        fn misspelled_keyword_statement() {
+            let source = r#"
+thorw x;
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!(
+                "Expect ';' after expression. Did you mean 'throw'?",
+                error.message
+            );
+        }
+
+        #[test]
+        fn no_close_name() {
+            let source = r#"
+var count = 1;
+print total;
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Undefined variable 'total'.", error.message);
+        }
+
+        #[test]
+        fn local_in_warning() -> Result<(), LoxError> {
+            let source = r#"
+fun area(width, height) {
+  return width * heigth;
+}
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.compile_options
+                .lints
+                .insert(Lint::UndefinedGlobal, LintLevel::Warn);
+            let result = vm.interpret(source)?;
+            assert_eq!(
+                "Undefined variable 'heigth'. Did you mean 'height'?",
+                result.warnings[0].message
+            );
+            Ok(())
+        }
+    }
+
+    /// The VM asserts that the stack is empty after the script returns, in debug builds.
+    mod stack_balance {
+        use super::*;
+
+        #[test]
+        fn return_from_nested_scopes() -> Result<(), LoxError> {
+            let source = r#"
+fun find(limit) {
+  var i = 0;
+  while (i < limit) {
+    var doubled = i * 2;
+    {
+      var tripled = i * 3;
+      if (doubled + tripled > 10) return i;
+    }
+    i = i + 1;
+  }
+  return nil;
+}
+print find(5) + find(10); // expect: 6
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("6", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn return_with_defer() -> Result<(), LoxError> {
+            let source = r#"
+fun log(value) {
+  print value;
+}
+fun f() {
+  var a = 1;
+  defer log(a);
+  return a + 1;
+}
+print f(); // expect: 2
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("2", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn script_with_locals() -> Result<(), LoxError> {
+            let source = r#"
+{
+  var a = 1;
+  var b = 2;
+  print a + b; // expect: 3
+}
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let mut result = vm.interpret(source)?;
+            assert_eq!("3", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn repeated_interpret() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.interpret("fun f() { return 1; }".to_string())?;
+            vm.interpret("f();".to_string())?;
+            let mut result = vm.interpret("print f(); // expect: 1".to_string())?;
+            assert_eq!("1", result.printed_values.pop().unwrap().to_string());
+            Ok(())
+        }
+    }
+
+    mod compile_many {
+        use super::*;
+        use rlox::compiler::{CompileOptions, CompilerManager};
+        use rlox::value::string::LoxString;
+        use rlox::value::value::Value;
+
+        const LIB: &str = r#"
+fun greet(name) {
+  return "hello " + name;
+}
+"#;
+
+        const MAIN: &str = r#"
+print greet("world"); // expect: hello world
+"#;
+
+        #[test]
+        fn shared_globals() -> Result<(), LoxError> {
+            let program = CompilerManager::compile_many(
+                &[("lib.lox", LIB), ("main.lox", MAIN)],
+                &CompileOptions::default(),
+            )
+            .unwrap();
+            assert_eq!("lib.lox", program.scripts[0].0);
+            assert_eq!("main.lox", program.scripts[1].0);
+
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let mut result = vm.interpret_program(program)?;
+            assert_eq!(
+                "hello world",
+                result.printed_values.pop().unwrap().to_string()
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn shared_strings() {
+            let program = CompilerManager::compile_many(
+                &[
+                    ("lib.lox", "var a = \"shared\";"),
+                    ("main.lox", "var b = \"shared\";"),
+                ],
+                &CompileOptions::default(),
+            )
+            .unwrap();
+
+            let string_constant = |function: &rlox::value::function::Function| {
+                function
+                    .chunk
+                    .constants
+                    .iter()
+                    .find_map(|constant| match constant {
+                        Value::String(s) if &**s == "shared" => Some(s.clone()),
+                        _ => None,
+                    })
+                    .unwrap()
+            };
+            assert!(LoxString::ptr_eq(
+                &string_constant(&program.scripts[0].1),
+                &string_constant(&program.scripts[1].1)
+            ));
+        }
+
+        #[test]
+        fn repeated_names_share_a_slot() {
+            let function = CompilerManager::compile("var a = 1; a = a + a;".to_string()).unwrap();
+            assert_eq!(vec!["a"], *function.chunk.globals);
+            assert!(!function
+                .chunk
+                .constants
+                .iter()
+                .any(|constant| matches!(constant, Value::String(_))));
+        }
+
+        #[test]
+        fn sources_share_global_slots() {
+            let program = CompilerManager::compile_many(
+                &[("lib.lox", LIB), ("main.lox", MAIN)],
+                &CompileOptions::default(),
+            )
+            .unwrap();
+            for (_, script) in &program.scripts {
+                assert_eq!(vec!["greet"], *script.chunk.globals);
+            }
+        }
+
+        #[test]
+        fn globals_defined_in_other_sources() {
+            let options = CompileOptions {
+                lints: std::collections::HashMap::from([(Lint::UndefinedGlobal, LintLevel::Warn)]),
+                known_globals: vec!["clock".to_string()],
+                ..CompileOptions::default()
+            };
+            let program = CompilerManager::compile_many(
+                &[
+                    ("main.lox", MAIN),
+                    ("lib.lox", LIB),
+                    ("typo.lox", "greeet;"),
+                ],
+                &options,
+            )
+            .unwrap();
+            assert_eq!(1, program.warnings.len());
+            assert_eq!(
+                "Undefined variable 'greeet'. Did you mean 'greet'?",
+                program.warnings[0].message
+            );
+        }
+
+        #[test]
+        fn compile_error() {
+            let error = CompilerManager::compile_many(
+                &[("lib.lox", LIB), ("broken.lox", "print;")],
+                &CompileOptions::default(),
+            )
+            .err()
+            .unwrap();
+            assert_eq!("Expect expression.", error.message);
+        }
+    }
+
+    mod source_names {
+        use super::*;
+        use rlox::compiler::{CompileOptions, CompilerManager};
+        use rlox::value::value::Value;
+
+        #[test]
+        fn chunks() {
+            let program = CompilerManager::compile_many(
+                &[("lib.lox", "fun f() { return 1; }"), ("main.lox", "f();")],
+                &CompileOptions::default(),
+            )
+            .unwrap();
+            let lib = &program.scripts[0].1;
+            assert_eq!("lib.lox", &*lib.chunk.source_name);
+            assert_eq!("main.lox", &*program.scripts[1].1.chunk.source_name);
+            match &lib.chunk.constants[0] {
+                Value::Function(f) => assert_eq!("lib.lox", &*f.chunk.source_name),
+                constant => panic!("expected a function, got {:?}", constant),
+            }
+        }
+
+        #[test]
+        fn warnings() {
+            let options = CompileOptions {
+                lints: std::collections::HashMap::from([(Lint::UndefinedGlobal, LintLevel::Warn)]),
+                ..CompileOptions::default()
+            };
+            let program =
+                CompilerManager::compile_many(&[("main.lox", "\nmissing;")], &options).unwrap();
+            assert_eq!(
+                "[main.lox:2] warning[W001]: Undefined variable 'missing'.",
+                program.warnings[0].to_string()
+            );
+        }
+
+        #[test]
+        fn unnamed() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.interpret("fun f() {}".to_string())?;
+            match &vm.interpret("print f;".to_string())?.globals["f"] {
+                Value::Function(f) => assert!(f.chunk.source_name.is_empty()),
+                global => panic!("expected a function, got {:?}", global),
+            }
+            Ok(())
+        }
+    }
+
+    mod conditional_compilation {
+        use super::*;
+
+        fn run(source: &str, defines: &[(&str, &str)]) -> Result<Vec<String>, LoxError> {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            for (name, value) in defines {
+                vm.compile_options
+                    .defines
+                    .insert(name.to_string(), value.to_string());
+            }
+            let result = vm.interpret(source.to_string())?;
+            Ok(printed(result))
+        }
+
+        const SOURCE: &str = r#"
+print "always";
+#if DEBUG
+print "debug";
+#else
+print "release";
+#end
+"#;
+
+        #[test]
+        fn defined() -> Result<(), LoxError> {
+            assert_eq!(vec!["always", "debug"], run(SOURCE, &[("DEBUG", "true")])?);
+            Ok(())
+        }
+
+        #[test]
+        fn undefined() -> Result<(), LoxError> {
+            assert_eq!(vec!["always", "release"], run(SOURCE, &[])?);
+            assert_eq!(vec!["always", "release"], run(SOURCE, &[("DEBUG", "0")])?);
+            Ok(())
+        }
+
+        #[test]
+        fn nested() -> Result<(), LoxError> {
+            let source = r#"
+#if A
+  #if B
+print "a and b";
+  #else
+print "only a";
+  #end
+#else
+  #if B
+print "only b";
+  #end
+#end
+"#;
+            assert_eq!(vec!["only a"], run(source, &[("A", "1")])?);
+            assert_eq!(vec!["only b"], run(source, &[("B", "1")])?);
+            assert_eq!(vec!["a and b"], run(source, &[("A", "1"), ("B", "1")])?);
+            Ok(())
+        }
+
+        #[test]
+        fn skipped_lines_are_counted() {
+            let source = r#"
+#if DEBUG
+print "debug";
+#end
+-nil;
+"#;
+            let options = rlox::compiler::CompileOptions {
+                lints: std::collections::HashMap::from([(Lint::UndefinedGlobal, LintLevel::Warn)]),
+                ..Default::default()
+            };
+            let program = rlox::compiler::CompilerManager::compile_many(
+                &[("", "#if DEBUG\n\n#end\nmissing;")],
+                &options,
+            )
+            .unwrap();
+            assert_eq!(4, program.warnings[0].line);
+            assert_eq!(
+                "Operand must be a number.",
+                run(source, &[]).unwrap_err().message
+            );
+        }
+
+        #[test]
+        fn unterminated() {
+            let error = run("#if DEBUG\nprint 1;\n", &[]).unwrap_err();
+            assert_eq!("Expect '#end' after '#if'.", error.message);
+            let error = run("#if DEBUG\nprint 1;\n", &[("DEBUG", "true")]).unwrap_err();
+            assert_eq!("Expect '#end' after '#if'.", error.message);
+        }
+
+        #[test]
+        fn unmatched() {
+            let error = run("print 1;\n#end\n", &[]).unwrap_err();
+            assert_eq!("Directive without a matching '#if'.", error.message);
+        }
+
+        #[test]
+        fn invalid() {
+            let error = run("#ifdef DEBUG\n#end\n", &[]).unwrap_err();
+            assert_eq!("Invalid directive.", error.message);
+            let error = run("print #if;\n", &[]).unwrap_err();
+            assert_eq!("Unexpected character.", error.message);
+        }
+
+        #[test]
+        fn define_argument() {
+            assert_eq!(
+                ("DEBUG".to_string(), "true".to_string()),
+                parse_define("DEBUG")
+            );
+            assert_eq!(
+                ("LEVEL".to_string(), "2".to_string()),
+                parse_define("LEVEL=2")
+            );
+        }
+    }
+
+    /// Property tests for the serializer, over randomly generated programs.
+    mod serializer {
+        use super::*;
+        use rlox::chunk::Instruction;
+        use rlox::value::function::Function;
+
+        /// Generates random, terminating Lox programs from a seed.
+        struct ProgramGenerator {
+            state: u64,
+            /// The number of globals defined so far, named `g0`, `g1`, ...
+            globals: usize,
+            /// The number of functions defined so far, named `f0`, `f1`, ...
+            functions: usize,
+            /// The number of classes defined so far, named `C0`, `C1`, ...
+            classes: usize,
+            /// Whether statements that fail at runtime are generated too.
+            runtime_errors: bool,
+        }
+
+        impl ProgramGenerator {
+            fn new(seed: u64) -> ProgramGenerator {
+                ProgramGenerator {
+                    state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+                    globals: 0,
+                    functions: 0,
+                    classes: 0,
+                    runtime_errors: false,
+                }
+            }
+
+            /// Also generates statements that fail at runtime, ending the program.
+            fn with_runtime_errors(mut self) -> ProgramGenerator {
+                self.runtime_errors = true;
+                self
+            }
+
+            /// A xorshift step, returning a number below `n`.
+            fn below(&mut self, n: usize) -> usize {
+                self.state ^= self.state << 13;
+                self.state ^= self.state >> 7;
+                self.state ^= self.state << 17;
+                (self.state % n as u64) as usize
+            }
+
+            fn program(&mut self) -> String {
+                let mut source = String::new();
+                for _ in 0..1 + self.below(12) {
+                    source.push_str(&self.statement());
+                    source.push('\n');
+                }
+                source
+            }
+
+            fn statement(&mut self) -> String {
+                if self.runtime_errors && self.below(20) == 0 {
+                    return self.failing_statement();
+                }
+                match self.below(12) {
+                    0 => {
+                        let value = self.number_expression(2);
+                        self.globals += 1;
+                        format!("var g{} = {};", self.globals - 1, value)
+                    }
+                    1 => format!("print {};", self.number_expression(3)),
+                    2 => format!(
+                        "{{ var l = {}; print l * {}; }}",
+                        self.number_expression(2),
+                        self.number_expression(1)
+                    ),
+                    3 => format!(
+                        "if ({} < {}) print {}; else print {};",
+                        self.number_expression(1),
+                        self.number_expression(1),
+                        self.number_expression(1),
+                        self.string_expression()
+                    ),
+                    4 => {
+                        self.functions += 1;
+                        let name = format!("f{}", self.functions - 1);
+                        format!(
+                            "fun {name}(a, b) {{ defer a; return a - b * {}; }} print {name}({}, {});",
+                            self.number_expression(1),
+                            self.number_expression(1),
+                            self.number_expression(1)
+                        )
+                    }
+                    5 => format!(
+                        "{{ var i = 0; while (i < {}) {{ print i; i = i + 1; }} }}",
+                        self.below(4)
+                    ),
+                    6 => format!("print {};", self.string_expression()),
+                    7 => format!(
+                        "print {} == {} or !{};",
+                        self.number_expression(1),
+                        self.number_expression(1),
+                        ["true", "false", "nil"][self.below(3)]
+                    ),
+                    8 => {
+                        self.classes += 1;
+                        let name = format!("C{}", self.classes - 1);
+                        format!(
+                            "class {name} {{ init(x) {{ this.x = x; }} get() {{ return this.x * {}; }} }} print {name}({}).get();",
+                            self.number_expression(1),
+                            self.number_expression(1)
+                        )
+                    }
+                    9 if self.globals > 0 => format!("g{}++;", self.below(self.globals)),
+                    9 | 10 => format!(
+                        "{{ var k = {}; k++; print ++k; print k--; print k; }}",
+                        self.number_expression(1)
+                    ),
+                    11 if self.below(2) == 0 => format!(
+                        "{{ var xs = [{}, {}, {}]; xs[{}] = {}; print xs; print xs[-1]; }}",
+                        self.number_expression(1),
+                        self.string_expression(),
+                        self.number_expression(0),
+                        self.below(3),
+                        self.number_expression(1)
+                    ),
+                    11 => format!(
+                        "print {} < {} and {} or nil;",
+                        self.number_expression(1),
+                        self.number_expression(1),
+                        self.string_expression()
+                    ),
+                    _ => format!(
+                        "for (var j = 0; j < 2; j = j + 1) print j + {};",
+                        self.number_expression(1)
+                    ),
+                }
+            }
+
+            fn failing_statement(&mut self) -> String {
+                match self.below(5) {
+                    0 => format!("print -{};", self.string_expression()),
+                    1 => "print nil.field;".to_string(),
+                    2 if self.below(2) == 0 => format!("print [1, 2][{}];", self.below(4)),
+                    // Called through a local, since direct calls are checked when compiling.
+                    2 if self.functions > 0 => {
+                        format!("{{ var h = f{}; h(1); }}", self.below(self.functions))
+                    }
+                    3 if self.classes > 0 => format!("C{}().get();", self.below(self.classes)),
+                    _ => format!("{}();", self.number_expression(1)),
+                }
+            }
+
+            fn number_expression(&mut self, depth: usize) -> String {
+                if depth == 0 {
+                    return match self.below(3) {
+                        0 if self.globals > 0 => format!("g{}", self.below(self.globals)),
+                        1 => format!("{}.5", self.below(100)),
+                        _ => format!("{}", self.below(1000)),
+                    };
+                }
+                match self.below(4) {
+                    // Spaced, so that two negations do not read as a decrement.
+                    0 => format!("- {}", self.number_expression(depth - 1)),
+                    1 => format!("({})", self.number_expression(depth - 1)),
+                    _ => format!(
+                        "{} {} {}",
+                        self.number_expression(depth - 1),
+                        ["+", "-", "*", "/"][self.below(4)],
+                        self.number_expression(depth - 1)
+                    ),
+                }
+            }
+
+            fn string_expression(&mut self) -> String {
+                let words = ["\"lox\"", "\"\"", "\"héllo\"", "\"a b c\""];
+                match self.below(3) {
+                    0 => words[self.below(words.len())].to_string(),
+                    1 => format!(
+                        "{} + {}",
+                        words[self.below(words.len())],
+                        words[self.below(words.len())]
+                    ),
+                    _ => format!("\"slice me\"[{}:{}]", self.below(4), 4 + self.below(6)),
+                }
+            }
+        }
+
+        fn run(function: Function) -> Result<Vec<String>, LoxError> {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret_program(Program {
+                scripts: vec![("".to_string(), function)],
+                warnings: Vec::new(),
+            })?;
+            Ok(printed(result))
+        }
+
+        #[test]
+        fn load_compiled_file() -> Result<(), LoxError> {
+            let path = std::env::temp_dir().join(format!("rlox_{}.rloxc", std::process::id()));
+            let function = CompilerManager::compile("print 1 + 2;".to_string()).unwrap();
+            std::fs::write(&path, serialize(&function).unwrap()).unwrap();
+            let loaded = load_compiled(&path);
+            std::fs::write(&path, b"not bytecode").unwrap();
+            let broken = load_compiled(&path);
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(vec!["3"], run(loaded.unwrap())?);
+            assert_eq!(65, broken.err().unwrap().0);
+            Ok(())
+        }
+
+        #[test]
+        fn load_file_with_invalid_operand() {
+            let path = std::env::temp_dir().join(format!("rlox_{}_bad.rloxc", std::process::id()));
+            let mut function = Function::new();
+            function.chunk.add_constant(Value::Number(1.0));
+            function.chunk.write(Instruction::OpConstant(9), 1);
+            function.chunk.write(Instruction::OpPrint, 1);
+            function.chunk.write(Instruction::OpReturn, 1);
+            std::fs::write(&path, serialize(&function).unwrap()).unwrap();
+            let loaded = load_compiled(&path);
+            std::fs::remove_file(&path).unwrap();
+
+            let (exit_code, message) = loaded.err().unwrap();
+            assert_eq!(65, exit_code);
+            assert!(
+                message.ends_with(": Unknown constant 9 at byte 40."),
+                "{}",
+                message
+            );
+        }
+
+        #[test]
+        fn round_trip_generated_programs() -> Result<(), LoxError> {
+            for seed in 0..200 {
+                let source = ProgramGenerator::new(seed).program();
+                let function = CompilerManager::compile(source.clone())
+                    .unwrap_or_else(|e| panic!("{}\n{}", e, source));
+
+                let bytes = serialize(&function).unwrap();
+                let decoded = deserialize(&bytes).unwrap();
+                assert_eq!(bytes, serialize(&decoded).unwrap(), "{}", source);
+                assert_eq!(run(function)?, run(decoded)?, "{}", source);
+            }
+            Ok(())
+        }
+
+        /// What a program wrote and how it failed, if it did.
+        type Outcome = (String, Option<(Option<Diagnostic>, String, Vec<String>)>);
+
+        fn outcome(run: impl FnOnce(&mut VM) -> InterpretResult) -> Outcome {
+            let output = SharedOutput::default();
+            let mut vm = VM::new();
+            vm.set_output(Box::new(output.clone()));
+            let error = run(&mut vm)
+                .err()
+                .map(|error| (error.code, error.message, error.stack_trace));
+            let written = String::from_utf8(output.0.take()).unwrap();
+            (written, error)
+        }
+
+        /// Runs generated programs from source and from the .rloxc files `--compile` writes
+        /// for them, plain and stripped, checking that they print the same and fail the same.
+        fn differential(seeds: std::ops::Range<u64>) {
+            let dir = std::env::temp_dir().join(format!("rlox_fuzz_{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("fuzz.rloxc");
+            for seed in seeds {
+                let source = ProgramGenerator::new(seed).with_runtime_errors().program();
+                let interpreted = outcome(|vm| vm.interpret_source("fuzz.lox", source.clone()));
+
+                let mut program = CompilerManager::compile_many(
+                    &[("fuzz.lox", &source)],
+                    &VM::new().compile_options,
+                )
+                .unwrap_or_else(|e| panic!("{:?}\n{}", e, source));
+                let (_, function) = program.scripts.pop().unwrap();
+                for strip in [false, true] {
+                    let (bytes, debug_info) = compiled_bytes(&function, strip).unwrap();
+                    std::fs::write(&path, bytes).unwrap();
+                    if let Some(debug_info) = debug_info {
+                        std::fs::write(path.with_extension("rloxdbg"), debug_info).unwrap();
+                    }
+                    let loaded = outcome(|vm| {
+                        let function = load_compiled(&path).unwrap();
+                        let path = path.clone();
+                        vm.set_debug_info_loader(Box::new(move || load_debug_info(&path)));
+                        vm.interpret_program(Program {
+                            scripts: vec![(String::new(), function)],
+                            warnings: Vec::new(),
+                        })
+                    });
+                    assert_eq!(
+                        interpreted, loaded,
+                        "seed {}, stripped: {}\n{}",
+                        seed, strip, source
+                    );
+                }
+            }
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn interpreted_and_compiled_programs_agree() {
+            differential(0..300);
+        }
+
+        /// Run with `cargo test --release -- --ignored fuzz`.
+        #[ignore = "fuzz"]
+        #[test]
+        fn fuzz_interpreted_and_compiled_programs() {
+            differential(300..20_000);
+        }
+
+        #[test]
+        fn stripped_programs_run_the_same() -> Result<(), LoxError> {
+            for seed in 0..50 {
+                let source = ProgramGenerator::new(seed).program();
+                let function = CompilerManager::compile(source.clone()).unwrap();
+
+                let (bytes, _) = serialize_stripped(&function).unwrap();
+                assert!(bytes.len() < serialize(&function).unwrap().len());
+                let decoded = deserialize(&bytes).unwrap();
+                assert_eq!(run(function)?, run(decoded)?, "{}", source);
+            }
+            Ok(())
+        }
+
+        #[test]
+        fn symbolicate_stripped_stack_trace() {
+            let dir = std::env::temp_dir().join(format!("rlox_strip_{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("crash.rloxc");
+            let source = "fun fail() {\n  return -nil;\n}\nfail();";
+            let mut program =
+                CompilerManager::compile_many(&[("crash.lox", source)], &Default::default())
+                    .unwrap();
+            let (_, function) = program.scripts.pop().unwrap();
+            let (bytes, debug_info) = serialize_stripped(&function).unwrap();
+            std::fs::write(&path, bytes).unwrap();
+
+            let stack_trace = |vm: &mut VM| {
+                let function = load_compiled(&path).unwrap();
+                let path = path.clone();
+                vm.set_debug_info_loader(Box::new(move || load_debug_info(&path)));
+                vm.interpret_program(Program {
+                    scripts: vec![(String::new(), function)],
+                    warnings: Vec::new(),
+                })
+                .unwrap_err()
+                .stack_trace
+            };
+            let without_debug_info = stack_trace(&mut VM::new());
+            std::fs::write(path.with_extension("rloxdbg"), debug_info).unwrap();
+            let with_debug_info = stack_trace(&mut VM::new());
+            std::fs::remove_dir_all(&dir).unwrap();
+
+            assert_eq!(
+                vec!["[instruction 1] in #1()", "[instruction 3] in script"],
+                without_debug_info
+            );
+            assert_eq!(
+                vec!["[crash.lox:2] in fail()", "[crash.lox:4] in script"],
+                with_debug_info
+            );
+        }
+    }
+
+    mod small_ints {
+        use super::*;
+        use rlox::chunk::Instruction;
+        use rlox::compiler::CompilerManager;
+
+        #[test]
+        fn no_constants() {
+            let function =
+                CompilerManager::compile("var i = 0; i = i + 1; print i * 127;".to_string())
+                    .unwrap();
+            // The variable has a global slot rather than a constant for its name.
+            assert!(function.chunk.constants.is_empty());
+            let bytecode = &function.chunk.bytecode;
+            assert!(bytecode.contains(&Instruction::OpZero));
+            assert!(bytecode.contains(&Instruction::OpOne));
+            assert!(bytecode.contains(&Instruction::OpSmallInt(127)));
+        }
+
+        #[test]
+        fn larger_numbers_are_constants() {
+            let function = CompilerManager::compile("print 128 + 2.5;".to_string()).unwrap();
+            assert_eq!(2, function.chunk.constants.len());
+        }
+
+        #[test]
+        fn values() -> Result<(), LoxError> {
+            let source = r#"
+var i = 0;
+while (i < 3) i = i + 1;
+print i; // expect: 3
+print -1 + 0 - 127; // expect: -128
+print 100 / 8; // expect: 12.5
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["3", "-128", "12.5"], printed(result));
+            Ok(())
+        }
+    }
+
+    mod profile {
+        use super::*;
+
+        #[test]
+        fn disabled_by_default() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.interpret("print 1;".to_string())?;
+            assert_eq!(None, vm.profile());
+            Ok(())
+        }
+
+        #[test]
+        fn peak_depths() -> Result<(), LoxError> {
+            let source = r#"
+fun count(n) {
+    if (n > 0) count(n - 1);
+}
+count(10);
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.profiling = true;
+            vm.interpret(source)?;
+            let profile = vm.profile().unwrap();
+            // The script and 11 calls of count.
+            assert_eq!(12, profile.peak_frame_depth);
+            // Each call has the function and its argument on the stack.
+            assert!(profile.peak_stack_depth >= 23, "{:?}", profile);
+            Ok(())
+        }
+
+        #[test]
+        fn reset_between_runs() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.profiling = true;
+            vm.interpret("fun f(a, b, c) {} f(1, 2, 3);".to_string())?;
+            vm.interpret("print 1;".to_string())?;
+            let profile = vm.profile().unwrap();
+            assert_eq!(1, profile.peak_frame_depth);
+            assert!(profile.peak_stack_depth < 4, "{:?}", profile);
+            Ok(())
+        }
+
+        #[test]
+        fn kept_after_runtime_error() {
+            let mut vm = VM::new();
+            vm.profiling = true;
+            let source = "fun f() { f(); } f();".to_string();
+            assert!(vm.interpret(source).is_err());
+            assert_eq!(FRAMES_MAX, vm.profile().unwrap().peak_frame_depth);
+        }
+    }
+
+    mod string_equality {
+        use super::*;
+        use std::time::Instant;
+
+        #[test]
+        fn interned_and_built_strings() -> Result<(), LoxError> {
+            let source = r#"
+var a = "lox";
+var b = "lo" + "x";
+print a == "lox"; // expect: true
+print a == b; // expect: true
+print b == "lo"; // expect: false
+print a != "Lox"; // expect: true
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["true", "true", "false", "true"], printed(result));
+            Ok(())
+        }
+
+        fn time(source: &str) -> std::time::Duration {
+            let mut vm = VM::new();
+            let start = Instant::now();
+            vm.interpret(source.to_string()).unwrap();
+            start.elapsed()
+        }
+
+        /// Compares equality of interned strings, which takes the pointer fast path, against
+        /// equality of strings built at runtime, which compares their contents. Run with
+        /// `cargo test --release --no-default-features -- --ignored --nocapture string_equality`.
+        #[ignore = "benchmark"]
+        #[test]
+        fn benchmark() {
+            let suffix = "x".repeat(1000);
+            let interned = format!(
+                r#"
+var a = "{0}";
+var b = "{0}";
+var i = 0;
+while (i < 100000) {{ a == b; i = i + 1; }}
+"#,
+                suffix
+            );
+            let built = format!(
+                r#"
+var a = "a" + "{0}";
+var b = "a" + "{0}";
+var i = 0;
+while (i < 100000) {{ a == b; i = i + 1; }}
+"#,
+                suffix
+            );
+            println!("interned: {:?}", time(&interned));
+            println!("built:    {:?}", time(&built));
+        }
+    }
+
+    #[cfg(feature = "opcode_timing")]
+    mod opcode_timing {
+        use super::*;
+
+        #[test]
+        fn counts_every_instruction() -> Result<(), LoxError> {
+            let source = "var i = 0; while (i < 10) i = i + 1;".to_string();
+            let mut vm = VM::new();
+            let result = vm.interpret(source)?;
+            let timings = vm.opcode_timings();
+            let count: usize = timings.iter().map(|timing| timing.count).sum();
+            assert_eq!(result.instruction_count, count);
+            let add = timings
+                .iter()
+                .find(|timing| timing.name == "OpAdd")
+                .unwrap();
+            assert_eq!(10, add.count);
+            assert!(timings
+                .windows(2)
+                .all(|pair| pair[0].total >= pair[1].total));
+            Ok(())
+        }
+
+        #[test]
+        fn table() {
+            let mut vm = VM::new();
+            vm.interpret("print 1 + 2;".to_string()).unwrap();
+            let timings = vm.opcode_timings();
+            let table = format_opcode_timings(&timings);
+            assert!(table.starts_with("Instruction"));
+            assert_eq!(timings.len() + 1, table.lines().count(), "{}", table);
+            assert!(table.contains("\nOpAdd "), "{}", table);
+        }
+    }
+
+    mod repl_rc {
+        use super::*;
+
+        #[test]
+        fn settings() {
+            let source =
+                "set prompt = \"lox> \"\nfun twice(n) { return 2 * n; }\n  set trace = false\n";
+            let mut settings = ReplSettings::default();
+            let (lox, errors) = parse_rc("rc", source, &mut settings);
+            assert!(errors.is_empty(), "{:?}", errors);
+            assert_eq!("lox> ", settings.prompt);
+            assert!(!settings.trace);
+            // The settings are blanked, keeping the line numbers.
+            assert_eq!("\nfun twice(n) { return 2 * n; }\n\n", lox);
+        }
+
+        #[test]
+        fn invalid_settings() {
+            let source = "set color = true\nset trace = maybe\nset prompt \"$\"\nset prompt = $\n";
+            let mut settings = ReplSettings::default();
+            let (_, errors) = parse_rc("rc", source, &mut settings);
+            assert_eq!(
+                vec![
+                    "[rc:1] Error: Unknown setting 'color'.",
+                    "[rc:2] Error: Expect true or false for 'trace'.",
+                    "[rc:3] Error: Expect '=' after the setting name.",
+                    "[rc:4] Error: Expect a string for 'prompt'.",
+                ],
+                errors
+            );
+            assert_eq!(ReplSettings::default(), settings);
+        }
+
+        #[test]
+        fn definitions_persist() -> Result<(), LoxError> {
+            let path = std::env::temp_dir().join(format!("rlox_{}.rloxrc", std::process::id()));
+            std::fs::write(
+                &path,
+                "set prompt = \">> \"\nfun twice(n) { return 2 * n; }\n",
+            )
+            .unwrap();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let mut settings = ReplSettings::default();
+            load_rc(&path, &mut vm, &mut settings);
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(">> ", settings.prompt);
+            let result = vm.interpret_source("<repl>", "print twice(21);".to_string())?;
+            assert_eq!("42", result.printed_values[0].to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn missing_file() {
+            let mut vm = VM::new();
+            let mut settings = ReplSettings::default();
+            load_rc(Path::new("/nonexistent/.rloxrc"), &mut vm, &mut settings);
+            assert_eq!(ReplSettings::default(), settings);
+        }
+    }
+
+    mod message_catalog {
+        use super::*;
+
+        fn french() -> Rc<Catalog> {
+            Rc::new(
+                Catalog::parse(
+                    "E001 = Cible d'affectation invalide.\n\
+                     E102 = Variable '{0}' non définie.{1}\n\
+                     E105 = {0} arguments attendus, {1} reçus.\n\
+                     W001 = Variable '{0}' jamais définie.{1}\n\
+                     H001 = Vouliez-vous dire '{0}' ?\n",
+                )
+                .unwrap(),
+            )
+        }
+
+        #[test]
+        fn compile_error() {
+            let mut vm = VM::new();
+            vm.catalog = french();
+            let error = vm.interpret("var a; a + 1 = 2;".to_string()).unwrap_err();
+            assert_eq!("Cible d'affectation invalide.", error.message);
+        }
+
+        #[test]
+        fn runtime_errors() {
+            let mut vm = VM::new();
+            vm.catalog = french();
+            let error = vm
+                .interpret("var count = 1; print cuont;".to_string())
+                .unwrap_err();
+            assert_eq!(
+                "Variable 'cuont' non définie. Vouliez-vous dire 'count' ?",
+                error.message
+            );
+
+            let error = vm
+                .interpret("fun f(a) {} var g = f; g();".to_string())
+                .unwrap_err();
+            assert_eq!("1 arguments attendus, 0 reçus.", error.message);
+        }
+
+        #[test]
+        fn untranslated_messages_stay_english() {
+            let mut vm = VM::new();
+            vm.catalog = french();
+            let error = vm.interpret("print -nil;".to_string()).unwrap_err();
+            assert_eq!("Operand must be a number.", error.message);
+        }
+
+        #[test]
+        fn warnings() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.catalog = french();
+            vm.compile_options
+                .lints
+                .insert(Lint::UndefinedGlobal, LintLevel::Warn);
+            let result = vm.interpret("var count = 1; if (false) print cuont;".to_string())?;
+            assert_eq!(
+                "Variable 'cuont' jamais définie. Vouliez-vous dire 'count' ?",
+                result.warnings[0].message
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn load_catalog_file() {
+            let path = std::env::temp_dir().join(format!("rlox_{}.messages", std::process::id()));
+            std::fs::write(&path, "E101 = Débordement de pile.\n").unwrap();
+            let loaded = load_catalog(&path);
+            std::fs::write(&path, "E101 Débordement de pile.\n").unwrap();
+            let broken = load_catalog(&path);
+            std::fs::remove_file(&path).unwrap();
+
+            let message = loaded.unwrap().message(Diagnostic::StackOverflow, &[]);
+            assert_eq!("Débordement de pile.", message);
+            assert_eq!(65, broken.unwrap_err().0);
+        }
+    }
+
+    mod error_codes {
+        use super::*;
+        use rlox::compiler::Warning;
+        use rlox::value::value::Value;
+
+        fn code(source: &str) -> Option<&'static str> {
+            let mut vm = VM::new();
+            let error = vm.interpret(source.to_string()).unwrap_err();
+            error.code.map(Diagnostic::code)
+        }
+
+        #[test]
+        fn compile_errors() {
+            assert_eq!(Some("E001"), code("var a; a + 1 = 2;"));
+            assert_eq!(Some("E002"), code("print;"));
+            assert_eq!(Some("E003"), code("print @;"));
+            assert_eq!(Some("E040"), code("print 1"));
+            assert_eq!(Some("E063"), code("fun f(a) {} f();"));
+        }
+
+        #[test]
+        fn runtime_errors() {
+            assert_eq!(Some("E101"), code("fun f() { f(); } f();"));
+            assert_eq!(Some("E102"), code("print missing;"));
+            assert_eq!(Some("E103"), code("1();"));
+            assert_eq!(Some("E105"), code("fun f(a) {} var g = f; g();"));
+            assert_eq!(Some("E107"), code("print 1[0:1];"));
+        }
+
+        fn fail(vm: &mut VM, _args: &[Value]) -> Result<Value, VMError> {
+            vm.runtime_error("Native failure.");
+            Err(VMError::RuntimeError)
+        }
+
+        #[test]
+        fn native_errors_have_no_code() {
+            let mut vm = VM::new();
+            vm.define_native("fail", 0, fail);
+            let error = vm.interpret("fail();".to_string()).unwrap_err();
+            assert_eq!(None, error.code);
+            assert_eq!("Native failure.", error.message);
+        }
+
+        #[test]
+        fn compile_error_from_compiler() {
+            let error = CompilerManager::compile("var;".to_string()).unwrap_err();
+            assert_eq!(Diagnostic::ExpectVariableName, error.code);
+        }
+
+        #[test]
+        fn warning_display() {
+            let warning = Warning {
+                source_name: "main.lox".to_string(),
+                line: 2,
+                code: Diagnostic::UndefinedGlobal,
+                message: "Undefined variable 'x'.".to_string(),
+            };
+            assert_eq!(
+                "[main.lox:2] warning[W001]: Undefined variable 'x'.",
+                warning.to_string()
+            );
+        }
+    }
+
+    mod assignment_in_condition {
+        use super::*;
+
+        fn warnings(source: &str) -> Vec<(i32, &'static str)> {
+            let mut vm = VM::new();
+            vm.compile_options
+                .lints
+                .insert(Lint::AssignmentInCondition, LintLevel::Warn);
+            let result = vm.interpret(source.to_string()).unwrap();
+            result
+                .warnings
+                .iter()
+                .map(|warning| (warning.line, warning.code.code()))
+                .collect()
+        }
+
+        #[test]
+        fn bare_assignments() {
+            let source = r#"
+var a = false;
+var b = 1;
+if (a = true) b = 2;
+while (a = false) {}
+fun f(x) {
+  if (x = a = nil) print x;
+}
+f(1);
+"#;
+            assert_eq!(
+                vec![(4, "W002"), (5, "W002"), (7, "W002")],
+                warnings(source)
+            );
+        }
+
+        #[test]
+        fn not_bare_assignments() {
+            let source = r#"
+var a = false;
+var b = false;
+if ((a = true)) b = 1;
+if (a == true) b = 2;
+if (b and (a = false)) {}
+if (a == (b = nil)) {}
+if (a) a = 1;
+while ((a = false)) {}
+"#;
+            assert!(warnings(source).is_empty());
+        }
+
+        #[test]
+        fn off_by_default() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            let result = vm.interpret("var a; if (a = 1) print a;".to_string())?;
+            assert!(result.warnings.is_empty());
+            Ok(())
+        }
+    }
+
+    mod nesting_limits {
+        use super::*;
+        use rlox::compiler::{MAX_LOOP_DEPTH, MAX_SCOPE_DEPTH};
+
+        fn nested_blocks(depth: usize) -> String {
+            format!("{}print 1;{}", "{\n".repeat(depth), "}\n".repeat(depth))
+        }
+
+        fn nested_loops(depth: usize) -> String {
+            "while (false) ".repeat(depth) + "print 1;"
+        }
+
+        #[test]
+        fn scopes_up_to_the_limit() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.interpret(nested_blocks(MAX_SCOPE_DEPTH as usize))?;
+            Ok(())
+        }
+
+        #[test]
+        fn too_many_scopes() {
+            let mut vm = VM::new();
+            let error = vm
+                .interpret(nested_blocks(MAX_SCOPE_DEPTH as usize + 1))
+                .unwrap_err();
+            assert_eq!(Some(Diagnostic::TooManyScopes), error.code);
+            assert_eq!(
+                "Too many nested scopes in function, the maximum is 255.",
+                error.message
+            );
+        }
+
+        #[test]
+        fn deeply_nested_blocks_do_not_overflow() {
+            let mut vm = VM::new();
+            let error = vm.interpret(nested_blocks(100_000)).unwrap_err();
+            assert_eq!(Some(Diagnostic::TooManyScopes), error.code);
+        }
+
+        #[test]
+        fn scopes_are_per_function() -> Result<(), LoxError> {
+            let depth = MAX_SCOPE_DEPTH as usize - 2;
+            let source = format!(
+                "{}fun f() {{ {} }}{}",
+                "{".repeat(depth),
+                nested_blocks(depth),
+                "}".repeat(depth)
+            );
+            let mut vm = VM::new();
+            vm.interpret(source)?;
+            Ok(())
+        }
+
+        #[test]
+        fn loops_up_to_the_limit() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.interpret(nested_loops(MAX_LOOP_DEPTH))?;
+            Ok(())
+        }
+
+        #[test]
+        fn too_many_loops() {
+            let mut vm = VM::new();
+            let error = vm.interpret(nested_loops(MAX_LOOP_DEPTH + 1)).unwrap_err();
+            assert_eq!(Some(Diagnostic::TooManyLoops), error.code);
+            assert_eq!(
+                "Too many nested loops in function, the maximum is 64.",
+                error.message
+            );
+
+            let source = "for (;false;) ".repeat(MAX_LOOP_DEPTH + 1) + "print 1;";
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!(Some(Diagnostic::TooManyLoops), error.code);
+        }
+    }
+
+    mod json_report {
+        use super::*;
+
+        #[test]
+        fn escapes() {
+            assert_eq!(r#""plain""#, json_string("plain"));
+            assert_eq!(
+                r#""a \"quote\"\\ and\nlines\u0001""#,
+                json_string("a \"quote\"\\ and\nlines\u{1}")
+            );
+        }
+
+        #[test]
+        fn report() {
+            let runtime = VM::new()
+                .interpret("\nprint -nil;".to_string())
+                .unwrap_err();
+            let compile = VM::new().interpret("print;".to_string()).unwrap_err();
+            let results = [
+                (Path::new("a.lox"), Ok(())),
+                (Path::new("b.lox"), Err(Failure::Lox(runtime))),
+                (Path::new("c.lox"), Err(Failure::Lox(compile))),
+                (
+                    Path::new("d.lox"),
+                    Err(Failure::Read("Could not read.".to_string())),
+                ),
+            ];
+            let expected = r#"{
+  "passed": 1,
+  "failed": 3,
+  "total": 4,
+  "scripts": [
+    {"path": "a.lox", "status": "pass"},
+    {"path": "b.lox", "status": "fail", "error": {"kind": "runtime", "code": "E104", "message": "Operand must be a number.", "stack_trace": ["[line 2] in script"]}},
+    {"path": "c.lox", "status": "fail", "error": {"kind": "compile", "code": "E002", "message": "Expect expression.", "stack_trace": []}},
+    {"path": "d.lox", "status": "fail", "error": {"kind": "read", "code": null, "message": "Could not read.", "stack_trace": []}}
+  ]
+}
+"#;
+            assert_eq!(expected, json_report(&results));
+        }
+    }
+
+    mod extensions {
+        use super::*;
+        use rlox::chunk::Instruction;
+        use rlox::compiler::{CompileOptions, CompilerManager, ExtensionOpcode};
+        use rlox::value::function::Function;
+        use rlox::value::value::Value;
+
+        fn vm_with_mix() -> VM {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.register_extension("mix", 2, |vm, args| match (&args[0], &args[1]) {
+                (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a * 10.0 + b)),
+                _ => {
+                    vm.runtime_error("Operands must be numbers.");
+                    Err(VMError::RuntimeError)
+                }
+            });
+            vm
+        }
+
+        #[test]
+        fn compiles_to_the_instruction() {
+            let mut options = CompileOptions::default();
+            options
+                .extensions
+                .insert("mix".to_string(), ExtensionOpcode { id: 3, arity: 2 });
+            let function =
+                CompilerManager::compile_with_options("print mix(1, 2);".to_string(), &options)
+                    .unwrap()
+                    .function;
+            assert!(function
+                .chunk
+                .bytecode
+                .contains(&Instruction::OpExtension(3, 2)));
+            assert!(!function
+                .chunk
+                .bytecode
+                .iter()
+                .any(|instruction| matches!(instruction, Instruction::OpCall(_))));
+        }
+
+        #[test]
+        fn runs_the_handler() -> Result<(), LoxError> {
+            let source = r#"
+fun f(x) {
+  return mix(x, mix(2, 3));
+}
+print f(1); // expect: 33
+"#
+            .to_string();
+            let mut vm = vm_with_mix();
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["33"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn locals_shadow_extensions() -> Result<(), LoxError> {
+            let source = r#"
+fun apply(mix) {
+  return mix(1, 2);
+}
+fun add(a, b) {
+  return a + b;
+}
+print apply(add); // expect: 3
+"#
+            .to_string();
+            let mut vm = vm_with_mix();
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["3"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn wrong_argument_count() {
+            let mut vm = vm_with_mix();
+            let error = vm.interpret("print mix(1);".to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::WrongExtensionArgumentCount), error.code);
+            assert_eq!("Expected 2 arguments but got 1.", error.message);
+        }
+
+        #[test]
+        fn handler_error() {
+            let mut vm = vm_with_mix();
+            let error = vm.interpret("print mix(1, nil);".to_string()).unwrap_err();
+            assert_eq!("Operands must be numbers.", error.message);
+        }
+
+        #[test]
+        fn unknown_extension() {
+            let mut script = Function::new();
+            script.chunk.write(Instruction::OpExtension(7, 0), 1);
+            script.chunk.write(Instruction::OpReturn, 1);
+            let mut vm = VM::new();
+            let error = vm
+                .interpret_program(Program {
+                    scripts: vec![(String::new(), script)],
+                    warnings: Vec::new(),
+                })
+                .unwrap_err();
+            assert_eq!(Some(Diagnostic::UnknownExtension), error.code);
+        }
+
+        #[test]
+        fn register_again_replaces() -> Result<(), LoxError> {
+            let mut vm = vm_with_mix();
+            let id = vm.register_extension("mix", 2, |_, _| Ok(Value::Nil));
+            assert_eq!(0, id);
+            let result = vm.interpret("print mix(1, 2);".to_string())?;
+            assert_eq!(vec!["nil"], printed(result));
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "vector_math")]
+    mod vector_math {
+        use super::*;
+
+        #[test]
+        fn vectors() -> Result<(), LoxError> {
+            let source = r#"
+var a = vec3(1, 0, 0);
+var b = vec3(0, 1, 0);
+print cross(a, b); // expect: vec3(0, 0, 1)
+print dot(a, b); // expect: 0
+print normalize(vec2(3, 4)); // expect: vec2(0.6, 0.8)
+print length(vec2(3, 4)); // expect: 5
+print vy(vec2(3, 4)); // expect: 4
+print vec2(1, 2) == vec2(1, 2); // expect: true
+"#;
+            assert_eq!(
+                vec!["vec3(0, 0, 1)", "0", "vec2(0.6, 0.8)", "5", "4", "true"],
+                run(source)?
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn matrices() -> Result<(), LoxError> {
+            let source = r#"
+var rotate = mat2(0, -1, 1, 0);
+print matmul(rotate, vec2(1, 0)); // expect: vec2(0, 1)
+var m = mat2(1, 2, 3, 4);
+print matmul(m, m); // expect: mat2(7, 10, 15, 22)
+"#;
+            assert_eq!(vec!["vec2(0, 1)", "mat2(7, 10, 15, 22)"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn errors() {
+            let cases = [
+                (
+                    "dot(vec2(1, 2), vec3(1, 2, 3));",
+                    Diagnostic::VectorSizeMismatch,
+                ),
+                ("cross(vec2(1, 2), vec2(1, 2));", Diagnostic::CrossNotVec3),
+                ("normalize(vec3(0, 0, 0));", Diagnostic::ZeroLengthVector),
+                ("vz(vec2(1, 2));", Diagnostic::MissingComponent),
+                ("length(1);", Diagnostic::OperandNotVector),
+                (
+                    "matmul(vec2(1, 2), vec2(1, 2));",
+                    Diagnostic::OperandNotMatrix,
+                ),
+                (
+                    "matmul(mat2(1, 0, 0, 1), vec3(1, 2, 3));",
+                    Diagnostic::MatrixSizeMismatch,
+                ),
+                ("vec2(1, nil);", Diagnostic::OperandNotNumber),
+            ];
+            for (source, diagnostic) in cases {
+                let error = run(source).unwrap_err();
+                assert_eq!(Some(diagnostic), error.code, "{}", source);
+            }
+        }
+    }
+
+    mod events {
+        use super::*;
+        use rlox::value::string::LoxString;
+        use rlox::value::value::Value;
+
+        #[test]
+        fn dispatch() -> Result<(), LoxError> {
+            let source = r#"
+var ticks = 0;
+fun count(dt) {
+  ticks = ticks + 1;
+  print ticks;
+}
+fun show(dt) {
+  print dt;
+}
+on("tick", count);
+on("tick", show);
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.interpret(source)?;
+
+            let result = vm.dispatch("tick", &[Value::Number(0.5)])?;
+            assert_eq!(vec!["1", "0.5"], printed(result));
+            let result = vm.dispatch("tick", &[Value::Number(0.25)])?;
+            assert_eq!(vec!["2", "0.25"], printed(result));
+            // Events without handlers are ignored.
+            let result = vm.dispatch("draw", &[])?;
+            assert!(result.printed_values.is_empty());
+            Ok(())
+        }
+
+        #[test]
+        fn handlers_registered_while_dispatching() -> Result<(), LoxError> {
+            let source = r#"
+fun later(name) {
+  print "later " + name;
+}
+fun first(name) {
+  print "first " + name;
+  on("key", later);
+}
+on("key", first);
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.interpret(source)?;
+
+            let key = Value::String(LoxString::from("a"));
+            let result = vm.dispatch("key", std::slice::from_ref(&key))?;
+            assert_eq!(vec!["first a"], printed(result));
+            let result = vm.dispatch("key", &[key])?;
+            assert_eq!(vec!["first a", "later a"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn handler_error() -> Result<(), LoxError> {
+            let source = r#"
+fun fail(n) {
+  return -nil;
+}
+fun never(n) {
+  print n;
+}
+on("tick", fail);
+on("tick", never);
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.interpret(source)?;
+
+            let error = vm.dispatch("tick", &[Value::Nil]).unwrap_err();
+            assert_eq!(Some(Diagnostic::OperandNotNumber), error.code);
+            assert_eq!(vec!["[line 3] in fail()"], error.stack_trace);
+            // The VM can still run after the error.
+            vm.interpret("print 1;".to_string())?;
+            Ok(())
+        }
+
+        #[test]
+        fn methods_and_classes() -> Result<(), LoxError> {
+            let source = r#"
+class Counter {
+  init() { this.count = 0; }
+  tick(dt) {
+    this.count = this.count + 1;
+    print this.count;
+  }
+}
+class Event {
+  init(dt) { print "event"; }
+}
+var counter = Counter();
+on("tick", counter.tick);
+on("tick", Event);
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.interpret(source)?;
+
+            let result = vm.dispatch("tick", &[Value::Number(0.5)])?;
+            assert_eq!(vec!["1", "event"], printed(result));
+            let result = vm.dispatch("tick", &[Value::Number(0.5)])?;
+            assert_eq!(vec!["2", "event"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn wrong_arguments() {
+            let mut vm = VM::new();
+            let error = vm.interpret("on(1, clock);".to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::EventNotString), error.code);
+            let error = vm.interpret("on(\"tick\", 1);".to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::NotCallable), error.code);
+        }
+    }
+
+    mod class_tests {
+        use super::*;
+
+        #[test]
+        fn empty_test() -> Result<(), LoxError> {
+            let source = r#"
+class Foo {}
+
+print Foo; // expect: Foo
+print Foo(); // expect: Foo instance
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["Foo", "Foo instance"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn fields_test() -> Result<(), LoxError> {
+            let source = r#"
+class Foo {}
+var foo = Foo();
+
+print foo.bar = "bar value"; // expect: bar value
+print foo.baz = "baz value"; // expect: baz value
+foo.bar = foo.bar + "!";
+print foo.bar; // expect: bar value!
+print foo.baz; // expect: baz value
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(
+                vec!["bar value", "baz value", "bar value!", "baz value"],
+                printed(result)
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn local_class_test() -> Result<(), LoxError> {
+            let source = r#"
+{
+  class Foo {}
+  var a = Foo();
+  var b = Foo();
+  a.other = b;
+  b.value = "b";
+  print a.other.value; // expect: b
+  print a == b; // expect: false
+  print a == a; // expect: true
+}
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["b", "false", "true"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn undefined_property_test() {
+            let source = r#"
+class Foo {}
+var foo = Foo();
+
+foo.bar; // expect runtime error: Undefined property 'bar'.
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!(Some(Diagnostic::UndefinedProperty), error.code);
+            assert_eq!("Undefined property 'bar'.", error.message);
+        }
+
+        #[test]
+        fn properties_on_non_instances_test() {
+            let mut vm = VM::new();
+            let error = vm.interpret("true.foo;".to_string()).unwrap_err();
+            assert_eq!("Only instances have properties.", error.message);
+            let error = vm
+                .interpret("class Foo {} Foo.bar = 1;".to_string())
+                .unwrap_err();
+            assert_eq!("Only instances have fields.", error.message);
+        }
+
+        #[test]
+        fn arguments_without_initializer_test() {
+            let source = r#"
+class Foo {}
+Foo(1, 2); // expect runtime error: Expected 0 arguments but got 2.
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expected 0 arguments but got 2.", error.message);
+        }
+
+        #[test]
+        fn missing_class_body_test() {
+            let mut vm = VM::new();
+            let error = vm.interpret("class Foo;".to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::ExpectBraceBeforeClassBody), error.code);
+            let error = vm.interpret("var a = 1; a.;".to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::ExpectPropertyName), error.code);
+        }
+    }
+
+    mod par {
+        use super::*;
+
+        #[test]
+        fn scripts_run_in_parallel() {
+            let dir = std::env::temp_dir().join(format!("rlox_par_{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("a.lox"), "var a = 0; while (a < 100) a = a + 1;").unwrap();
+            std::fs::write(dir.join("b.lox"), "print -nil;").unwrap();
+            std::fs::write(dir.join("c.lox"), "print;").unwrap();
+
+            let mut paths = Vec::new();
+            find_lox_files(&dir, &mut paths).unwrap();
+            paths.sort();
+            let results = run_parallel(&paths, &Options::default());
+            std::fs::remove_dir_all(&dir).unwrap();
+
+            let failures: Vec<String> = results
+                .iter()
+                .map(|(_, result)| {
+                    result
+                        .as_ref()
+                        .map_or_else(Failure::to_string, |_| "pass".to_string())
+                })
+                .collect();
+            assert_eq!(vec!["pass", "runtime error", "compile error"], failures);
+            assert_eq!(dir.join("b.lox"), results[1].0);
+        }
+
+        #[test]
+        fn errors_are_prefixed() {
+            let errors = SharedOutput::default();
+            let mut vm = VM::new();
+            vm.set_output(Box::new(PrefixedOutput::new(
+                "[a.lox] ".to_string(),
+                Vec::new(),
+            )));
+            vm.set_error_output(Box::new(PrefixedOutput::new(
+                "[a.lox] ".to_string(),
+                errors.clone(),
+            )));
+            vm.interpret_source("a.lox", "print;".to_string())
+                .unwrap_err();
+            vm.interpret_source("a.lox", "print -nil;".to_string())
+                .unwrap_err();
+            drop(vm);
+
+            let written = String::from_utf8(errors.0.take()).unwrap();
+            let lines: Vec<&str> = written.lines().collect();
+            assert_eq!(3, lines.len(), "{}", written);
+            assert!(lines.iter().all(|line| line.starts_with("[a.lox] ")));
+            assert!(lines[0].contains("error[E0"), "{}", lines[0]);
+            assert!(lines[1].contains("error[E1"), "{}", lines[1]);
+            assert_eq!("[a.lox] [a.lox:1] in script", lines[2]);
+        }
+
+        #[test]
+        fn prefixed_lines() {
+            let mut bytes = Vec::new();
+            {
+                let mut output = PrefixedOutput::new("[a.lox] ".to_string(), &mut bytes);
+                output.write_all(b"one\ntw").unwrap();
+                output.flush().unwrap();
+                output.write_all(b"o\nthree").unwrap();
+            }
+            assert_eq!(
+                "[a.lox] one\n[a.lox] two\n[a.lox] three\n",
+                String::from_utf8(bytes).unwrap()
+            );
+        }
+    }
+
+    mod initializer_tests {
+        use super::*;
+
+        #[test]
+        fn arguments_test() -> Result<(), LoxError> {
+            let source = r#"
+class Foo {
+  init(a, b) {
+    print "init"; // expect: init
+    this.a = a;
+    this.b = b;
+  }
+}
+
+var foo = Foo(1, 2);
+print foo.a; // expect: 1
+print foo.b; // expect: 2
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["init", "1", "2"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn methods_test() -> Result<(), LoxError> {
+            let source = r#"
+class Counter {
+  init() {
+    this.count = 0;
+  }
+  increment() {
+    this.count = this.count + 1;
+    return this;
+  }
+}
+
+var counter = Counter();
+print counter.increment().increment().count; // expect: 2
+var increment = counter.increment;
+print increment; // expect: <fn increment>
+increment();
+print counter.count; // expect: 3
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["2", "<fn increment>", "3"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn call_init_explicitly_test() -> Result<(), LoxError> {
+            let source = r#"
+class Foo {
+  init(arg) {
+    print "Foo.init(" + arg + ")";
+    this.field = "init";
+  }
+}
+
+var foo = Foo("one"); // expect: Foo.init(one)
+foo.field = "field";
+
+var foo2 = foo.init("two"); // expect: Foo.init(two)
+print foo2; // expect: Foo instance
+
+// Make sure init() doesn't create a fresh instance.
+print foo.field; // expect: init
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(
+                vec!["Foo.init(one)", "Foo.init(two)", "Foo instance", "init"],
+                printed(result)
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn early_return_test() -> Result<(), LoxError> {
+            let source = r#"
+class Foo {
+  init() {
+    print "init";
+    return;
+    print "nope";
+  }
+}
+
+var foo = Foo(); // expect: init
+print foo; // expect: Foo instance
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["init", "Foo instance"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn return_value_test() {
+            let source = r#"
+class Foo {
+  init() {
+    return "result"; // Error at 'return': Can't return a value from an initializer.
+  }
+}
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!(Some(Diagnostic::ReturnFromInitializer), error.code);
+        }
+
+        #[test]
+        fn arity_test() {
+            let source = r#"
+class Foo {
+  init(a, b) {}
+}
+
+var foo = Foo(1); // expect runtime error: Expected 2 arguments but got 1.
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Expected 2 arguments but got 1.", error.message);
+        }
+
+        #[test]
+        fn this_outside_method_test() {
+            let mut vm = VM::new();
+            let error = vm.interpret("print this;".to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::ThisOutsideMethod), error.code);
+            let error = vm
+                .interpret("fun notMethod() { print this; }".to_string())
+                .unwrap_err();
+            assert_eq!(Some(Diagnostic::ThisOutsideMethod), error.code);
+        }
+
+        #[test]
+        fn called_from_native_test() -> Result<(), LoxError> {
+            fn apply(
+                vm: &mut VM,
+                args: &[rlox::value::value::Value],
+            ) -> Result<rlox::value::value::Value, VMError> {
+                vm.call_value(args[0].clone(), &args[1..])
+            }
+            let source = r#"
+class Box {
+  init(value) {
+    this.value = value;
+  }
+  get() {
+    return this.value;
+  }
+}
+
+var box = apply(Box, 4);
+print apply(box.get, nil) == nil; // expect: false
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.define_native("apply", 2, apply);
+            let error = vm.interpret(source).unwrap_err();
+            // box.get takes no arguments, but apply passes one.
+            assert_eq!("Expected 0 arguments but got 1.", error.message);
+
+            let source = "var box = apply(Box, 4); print box.value; // expect: 4".to_string();
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["4"], printed(result));
+            Ok(())
+        }
+    }
+
+    mod inheritance_tests {
+        use super::*;
+
+        #[test]
+        fn inherit_methods_test() -> Result<(), LoxError> {
+            let source = r#"
+class Foo {
+  methodOnFoo() { print "foo"; }
+  override() { print "foo"; }
+}
+
+class Bar < Foo {
+  methodOnBar() { print "bar"; }
+  override() { print "bar"; }
+}
+
+var bar = Bar();
+bar.methodOnFoo(); // expect: foo
+bar.methodOnBar(); // expect: bar
+bar.override(); // expect: bar
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["foo", "bar", "bar"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn inherited_initializer_test() -> Result<(), LoxError> {
+            let source = r#"
+class A {
+  init(param) {
+    this.field = param;
+  }
+
+  test() {
+    print this.field;
+  }
+}
+
+class B < A {}
+
+var b = B("value");
+b.test(); // expect: value
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["value"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn super_calls_test() -> Result<(), LoxError> {
+            let source = r#"
+class A {
+  method() {
+    print "A method";
+  }
+}
+
+class B < A {
+  method() {
+    print "B method";
+  }
+
+  test() {
+    super.method();
+  }
+}
+
+class C < B {}
+
+C().test(); // expect: A method
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["A method"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn super_in_inherited_method_test() -> Result<(), LoxError> {
+            let source = r#"
+class A {
+  say() {
+    print "A";
+  }
+}
+
+class B < A {
+  test() {
+    super.say();
+  }
+
+  say() {
+    print "B";
+  }
+}
+
+class C < B {
+  say() {
+    print "C";
+  }
+}
+
+C().test(); // expect: A
+var bound = C().test;
+bound(); // expect: A
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["A", "A"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn local_classes_test() -> Result<(), LoxError> {
+            let source = r#"
+{
+  class Base {
+    init(a) { this.a = a; }
+  }
+  class Derived < Base {
+    init(a, b) {
+      super.init(a);
+      this.b = b;
+    }
+  }
+  var d = Derived(1, 2);
+  print d.a + d.b; // expect: 3
+}
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["3"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn inherit_from_non_class_test() {
+            let source = r#"
+var Number = 123;
+class Foo < Number {} // expect runtime error: Superclass must be a class.
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!(Some(Diagnostic::SuperclassNotClass), error.code);
+        }
+
+        #[test]
+        fn undefined_super_method_test() {
+            let source = r#"
+class Base {}
+
+class Derived < Base {
+  foo() {
+    super.doesNotExist(1); // expect runtime error: Undefined property 'doesNotExist'.
+  }
+}
+
+Derived().foo();
+"#
+            .to_string();
+            let mut vm = VM::new();
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Undefined property 'doesNotExist'.", error.message);
+        }
+
+        #[test]
+        fn compile_errors_test() {
+            let cases = [
+                ("class Foo < Foo {}", Diagnostic::InheritFromSelf),
+                (
+                    "class Foo { method() { super.method(); } }",
+                    Diagnostic::SuperWithoutSuperclass,
+                ),
+                ("super.foo();", Diagnostic::SuperOutsideMethod),
+                (
+                    "class A {} class B < A { method() { super; } }",
+                    Diagnostic::ExpectDotAfterSuper,
+                ),
+                ("class Foo < {}", Diagnostic::ExpectSuperclassName),
+            ];
+            for (source, diagnostic) in cases {
+                let mut vm = VM::new();
+                let error = vm.interpret(source.to_string()).unwrap_err();
+                assert_eq!(Some(diagnostic), error.code, "{}", source);
+            }
+        }
+    }
+
+    mod determinism_audit {
+        use super::*;
+        use rlox::value::value::Value;
+
+        #[test]
+        fn disabled_by_default() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.interpret("clock();".to_string())?;
+            assert_eq!(None, vm.nondeterministic_calls());
+            Ok(())
+        }
+
+        #[test]
+        fn calls_with_call_sites() -> Result<(), LoxError> {
+            let source = r#"
+fun elapsed(start) {
+  return clock() - start;
+}
+var start = clock();
+for (var i = 0; i < 3; i = i + 1) elapsed(start);
+print vec2(1, 2);
+"#;
+            let mut vm = VM::new();
+            vm.audit_determinism = true;
+            vm.define_native("vec2", 2, |_, args| Ok(args[0].clone()));
+            vm.interpret_source("timer.lox", source.to_string())?;
+            let calls = vm.nondeterministic_calls().unwrap();
+            assert_eq!(
+                "Calls to nondeterministic functions:\n\
+                 [timer.lox:5] clock(), once\n\
+                 [timer.lox:3] clock(), 3 times\n",
+                format_nondeterministic_calls(calls)
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn reset_between_runs() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.audit_determinism = true;
+            vm.interpret("clock();".to_string())?;
+            vm.interpret("print 1;".to_string())?;
+            let calls = vm.nondeterministic_calls().unwrap();
+            assert_eq!(
+                "No calls to nondeterministic functions.\n",
+                format_nondeterministic_calls(calls)
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn kept_after_runtime_error() {
+            let mut vm = VM::new();
+            vm.audit_determinism = true;
+            vm.define_nondeterministic_native("random", 0, |_, _| Ok(Value::Number(4.0)));
+            assert!(vm
+                .interpret("random(); clock(); -nil;".to_string())
+                .is_err());
+            let calls = vm.nondeterministic_calls().unwrap();
+            assert_eq!(
+                vec!["random", "clock"],
+                calls
+                    .iter()
+                    .map(|call| call.name.as_str())
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    mod repl_session {
+        use super::*;
+
+        /// Interprets lines one by one on the same VM, like the REPL does,
+        /// returning the values printed by the last line.
+        fn session(vm: &mut VM, lines: &[&str]) -> Result<Vec<String>, LoxError> {
+            let mut last = Vec::new();
+            for line in lines {
+                last = printed(vm.interpret_source("<repl>", line.to_string())?);
+            }
+            Ok(last)
+        }
+
+        fn repl_vm() -> VM {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            session(
+                &mut vm,
+                &[
+                    "var count = 1;",
+                    "fun twice(n) { return 2 * n; }",
+                    "class Point { init(x) { this.x = x; } }",
+                ],
+            )
+            .unwrap();
+            vm
+        }
+
+        #[test]
+        fn compile_error_keeps_definitions() -> Result<(), LoxError> {
+            let mut vm = repl_vm();
+            for line in [
+                "var count = ;",
+                "fun twice(n { return n; }",
+                "class Point {",
+                "print",
+            ] {
+                let error = vm.interpret_source("<repl>", line.to_string()).unwrap_err();
+                assert_eq!(VMError::CompileError, error.kind, "{}", line);
+            }
+            let printed = session(&mut vm, &["print twice(count) + Point(3).x;"])?;
+            assert_eq!(vec!["5"], printed);
+            Ok(())
+        }
+
+        #[test]
+        fn line_with_compile_error_does_not_run() -> Result<(), LoxError> {
+            let mut vm = repl_vm();
+            let source = "count = 10; fun twice(n) { return n; } var fresh = 1; print;";
+            assert!(vm.interpret_source("<repl>", source.to_string()).is_err());
+            let printed = session(&mut vm, &["print twice(count);"])?;
+            assert_eq!(vec!["2"], printed);
+            let error = vm
+                .interpret_source("<repl>", "print fresh;".to_string())
+                .unwrap_err();
+            assert_eq!(Some(Diagnostic::UndefinedVariable), error.code);
+            Ok(())
+        }
+
+        #[test]
+        fn runtime_error_keeps_earlier_statements() -> Result<(), LoxError> {
+            let mut vm = repl_vm();
+            let source = "count = 2; fun fail() { return -nil; } twice(fail()); count = 3;";
+            assert!(vm.interpret_source("<repl>", source.to_string()).is_err());
+            let printed = session(&mut vm, &["print twice(count);", "print fail;"])?;
+            assert_eq!(vec!["<fn fail>"], printed);
+            let printed = session(&mut vm, &["print twice(count);"])?;
+            assert_eq!(vec!["4"], printed);
+            Ok(())
+        }
+
+        #[test]
+        fn redefined_function_is_used_by_callers() -> Result<(), LoxError> {
+            let mut vm = repl_vm();
+            session(
+                &mut vm,
+                &[
+                    "fun quadruple(n) { return twice(twice(n)); }",
+                    "var f = twice;",
+                ],
+            )?;
+            let printed = session(
+                &mut vm,
+                &["fun twice(n) { return n + n + 1; }", "print quadruple(1);"],
+            )?;
+            assert_eq!(vec!["7"], printed);
+            // Values taken before the redefinition keep the old function.
+            let printed = session(&mut vm, &["print f(1);"])?;
+            assert_eq!(vec!["2"], printed);
+            Ok(())
+        }
+
+        #[test]
+        fn redefined_class_is_used_by_new_instances() -> Result<(), LoxError> {
+            let mut vm = repl_vm();
+            session(
+                &mut vm,
+                &[
+                    "var old = Point(1);",
+                    "fun make(x) { return Point(x); }",
+                    "class Point { init(x) { this.x = x * 10; } }",
+                ],
+            )?;
+            let printed = session(&mut vm, &["print make(2).x + old.x;"])?;
+            assert_eq!(vec!["21"], printed);
+            Ok(())
+        }
+    }
+
+    mod watch {
+        use super::*;
+
+        #[test]
+        fn history_of_assignments() -> Result<(), LoxError> {
+            let source = r#"
+watch("total");
+var total = 0;
+var other = 0;
+for (var i = 1; i <= 3; i = i + 1) {
+  total = total + i;
+  other = other + 1;
+}
+history("total");
+"#;
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret_source("sum.lox", source.to_string())?;
+            assert_eq!(
+                vec![
+                    "[sum.lox:3] total = 0",
+                    "[sum.lox:6] total = 1",
+                    "[sum.lox:6] total = 3",
+                    "[sum.lox:6] total = 6",
+                ],
+                printed(result)
+            );
+            assert_eq!(None, vm.history("other").map(|writes| writes.len()));
+            Ok(())
+        }
+
+        #[test]
+        fn kept_between_repl_lines() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.interpret_source("<repl>", "var name = \"a\";".to_string())?;
+            vm.interpret_source("<repl>", "watch(\"name\");".to_string())?;
+            vm.interpret_source("<repl>", "fun rename() { name = \"b\"; }".to_string())?;
+            vm.interpret_source("<repl>", "rename();".to_string())?;
+            vm.interpret_source("<repl>", "watch(\"name\");".to_string())?;
+            let result = vm.interpret_source("<repl>", "history(\"name\");".to_string())?;
+            assert_eq!(vec!["[<repl>:1] name = b"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn errors() {
+            let mut vm = VM::new();
+            let error = vm.interpret("watch(1);".to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::VariableNameNotString), error.code);
+            let error = vm.interpret("history(\"x\");".to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::NotWatched), error.code);
+            assert_eq!("Variable 'x' is not watched.", error.message);
+        }
+    }
+
+    mod frame_instruction_limit {
+        use super::*;
+
+        #[test]
+        fn runaway_loop_in_function() {
+            let source = r#"
+fun spin() {
+  var i = 0;
+  while (i >= 0) i = i + 1;
+}
+spin();
+"#;
+            let mut vm = VM::new();
+            vm.max_frame_instructions = Some(100);
+            let error = vm
+                .interpret_source("spin.lox", source.to_string())
+                .unwrap_err();
+            assert_eq!(Some(Diagnostic::FrameInstructionLimit), error.code);
+            assert_eq!(
+                "'spin' ran more than 100 instructions without returning.",
+                error.message
+            );
+            assert!(error.stack_trace[0].ends_with("in spin()"));
+        }
+
+        #[test]
+        fn calls_count_separately() -> Result<(), LoxError> {
+            let source = r#"
+fun count(n) {
+  var i = 0;
+  while (i < n) i = i + 1;
+  return i;
+}
+var total = 0;
+for (var j = 0; j < 20; j = j + 1) total = total + count(30);
+print total;
+"#;
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.max_frame_instructions = Some(500);
+            let result = vm.interpret(source.to_string())?;
+            assert_eq!("600", result.printed_values[0].to_string());
+            assert!(result.instruction_count > 500);
+            Ok(())
+        }
+
+        #[test]
+        fn runaway_script() {
+            let mut vm = VM::new();
+            vm.max_frame_instructions = Some(50);
+            let error = vm.interpret("for (;;) {}".to_string()).unwrap_err();
+            assert_eq!(
+                "'script' ran more than 50 instructions without returning.",
+                error.message
+            );
+        }
+    }
+
+    mod increment {
+        use super::*;
+
+        #[test]
+        fn globals() -> Result<(), LoxError> {
+            let source = r#"
+var a = 1;
+print a++; // expect: 1
+print a; // expect: 2
+print ++a; // expect: 3
+print a--; // expect: 3
+print --a; // expect: 1
+print 2 * a++ - -a; // expect: 4
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["1", "2", "3", "3", "1", "4"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn locals() -> Result<(), LoxError> {
+            let source = r#"
+fun sum(n) {
+  var total = 0;
+  for (var i = 1; i <= n; i++) total = total + i;
+  return total;
+}
+print sum(4); // expect: 10
+{
+  var b = 5;
+  print b++ + ++b; // expect: 12
+  print b; // expect: 7
+}
+"#
+            .to_string();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!(vec!["10", "12", "7"], printed(result));
+            Ok(())
+        }
+
+        #[test]
+        fn non_number() {
+            let mut vm = VM::new();
+            let error = vm.interpret("var s = \"a\"; s++;".to_string()).unwrap_err();
+            assert_eq!(VMError::RuntimeError, error.kind);
+        }
+
+        #[test]
+        fn invalid_targets() {
+            let cases = [
+                ("++1;", Diagnostic::ExpectIncrementVariable),
+                ("var a = 1; a-- = 2;", Diagnostic::InvalidAssignmentTarget),
+                ("1++;", Diagnostic::InvalidIncrementTarget),
+                ("var o; o.field++;", Diagnostic::InvalidIncrementTarget),
+                ("var o; ++o.field;", Diagnostic::InvalidIncrementTarget),
+                ("fun f() {} f()--;", Diagnostic::InvalidIncrementTarget),
+            ];
+            for (source, diagnostic) in cases {
+                let mut vm = VM::new();
+                let error = vm.interpret(source.to_string()).unwrap_err();
+                assert_eq!(Some(diagnostic), error.code, "{}", source);
+            }
+        }
+    }
+
+    mod inspect {
+        use super::*;
+
+        #[test]
+        fn inspect_native() -> Result<(), LoxError> {
+            let source = r#"
+class Shape {
+  area() { return 0; }
+}
+class Square < Shape {
+  init(side) { this.side = side; }
+  area() { return this.side * this.side; }
+}
+var square = Square(2);
+square.label = "unit";
+square.self = square;
+print inspect(square);
+print inspect(Square);
+print inspect(3);
+"#;
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source.to_string())?;
+            let printed = printed(result);
+            // Reference counts are only shown in debug builds.
+            let without_counts: Vec<String> = printed
+                .iter()
+                .map(|dump| {
+                    dump.lines()
+                        .map(|line| match line.find(" (rc ") {
+                            Some(index) => &line[..index],
+                            None => line,
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                })
+                .collect();
+            assert_eq!(
+                vec![
+                    "Square instance\n  label: \"unit\"\n  self: <cycle: Square instance>\n  side: 2",
+                    "class Square < Shape\n  area()\n  init()",
+                    "3",
+                ],
+                without_counts
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn repl_command() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.interpret("var answer = 42;".to_string())?;
+            assert_eq!("42", inspect_global(&vm, "answer"));
+            assert_eq!(
+                "Undefined variable 'question'.",
+                inspect_global(&vm, "question")
+            );
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "template_jit")]
+    mod template_jit {
+        use super::*;
+        use std::time::Instant;
+
+        fn run(source: &str, jit_threshold: usize) -> Result<Vec<String>, LoxError> {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.trace_execution = false;
+            vm.jit_threshold = jit_threshold;
+            let result = vm.interpret(source.to_string())?;
+            Ok(printed(result))
+        }
+
+        #[test]
+        fn same_results_as_the_interpreter() -> Result<(), LoxError> {
+            let source = r#"
+fun sum(n) {
+  var total = 0;
+  for (var i = 1; i <= n; i = i + 1) total = total + i;
+  return total;
+}
+fun describe(a, b) {
+  if (a == b) return "same";
+  return !(a < b);
+}
+var total = 0;
+for (var i = 0; i < 50; i = i + 1) total = total + sum(i);
+print total;
+print describe(1, 1);
+print describe(1, 2);
+print describe("a", "a");
+"#;
+            let interpreted = run(source, usize::MAX)?;
+            assert_eq!(vec!["20825", "same", "false", "same"], interpreted);
+            assert_eq!(interpreted, run(source, 1)?);
+            Ok(())
+        }
+
+        #[test]
+        fn falls_back_on_other_operands() -> Result<(), LoxError> {
+            let source = r#"
+fun add(a, b) { return a + b; }
+print add(1, 2);
+print add("a", "b");
+"#;
+            assert_eq!(vec!["3", "ab"], run(source, 1)?);
+
+            let mut vm = VM::new();
+            vm.trace_execution = false;
+            vm.jit_threshold = 1;
+            let error = vm
+                .interpret("fun neg(a) { return -a; } neg(1); neg(nil);".to_string())
+                .unwrap_err();
+            assert_eq!(Some(Diagnostic::OperandNotNumber), error.code);
+            assert!(error.stack_trace[0].ends_with("in neg()"));
+            Ok(())
+        }
+
+        #[test]
+        fn counts_instructions() -> Result<(), LoxError> {
+            let source = r#"
+fun count(n) {
+  var i = 0;
+  while (i < n) i = i + 1;
+  return i;
+}
+count(30);
+count(30);
+"#;
+            let mut interpreted = VM::new();
+            interpreted.trace_execution = false;
+            interpreted.jit_threshold = usize::MAX;
+            let mut compiled = VM::new();
+            compiled.trace_execution = false;
+            compiled.jit_threshold = 1;
+            assert_eq!(
+                interpreted.interpret(source.to_string())?.instruction_count,
+                compiled.interpret(source.to_string())?.instruction_count
+            );
+
+            compiled.max_frame_instructions = Some(100);
+            let error = compiled.interpret(source.to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::FrameInstructionLimit), error.code);
+            Ok(())
+        }
+
+        fn time(source: &str, jit_threshold: usize) -> std::time::Duration {
+            let mut vm = VM::new();
+            vm.trace_execution = false;
+            vm.jit_threshold = jit_threshold;
+            let start = Instant::now();
+            vm.interpret(source.to_string()).unwrap();
+            start.elapsed()
+        }
+
+        /// Compares a hot numeric function interpreted and compiled by the template backend,
+        /// which only stays if it is clearly faster.
+        #[ignore = "benchmark"]
+        #[test]
+        fn benchmark() {
+            let source = r#"
+fun work(n) {
+  var x = 0;
+  for (var i = 0; i < n; i = i + 1) x = x * 0.5 + i;
+  return x;
+}
+var total = 0;
+for (var i = 0; i < 2000; i = i + 1) total = total + work(1000);
+"#;
+            println!("interpreted: {:?}", time(source, usize::MAX));
+            println!("compiled:    {:?}", time(source, 100));
+        }
+    }
+
+    mod lists {
+        use super::*;
+
+        fn error(source: &str) -> LoxError {
+            VM::new().interpret(source.to_string()).unwrap_err()
+        }
+
+        #[test]
+        fn literals_and_indexing() -> Result<(), LoxError> {
+            let source = r#"
+var xs = [1, "two", nil, [3, 4]];
+print xs; // expect: [1, two, nil, [3, 4]]
+print xs[1]; // expect: two
+print xs[-1][0]; // expect: 3
+print []; // expect: []
+print [1 + 2, xs[0]][1]; // expect: 1
+"#;
+            assert_eq!(
+                vec!["[1, two, nil, [3, 4]]", "two", "3", "[]", "1"],
+                run(source)?
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn assignment() -> Result<(), LoxError> {
+            let source = r#"
+var xs = [1, 2, 3];
+xs[0] = xs[0] + 10;
+print xs[-1] = "last"; // expect: last
+var ys = xs;
+ys[1] = nil;
+print xs[0]; // expect: 11
+print xs[1]; // expect: nil
+fun fill(list, value) { for (var i = 0; i < 3; i = i + 1) list[i] = value; }
+fill(xs, 0);
+print ys[2]; // expect: 0
+"#;
+            assert_eq!(vec!["last", "11", "nil", "0"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn equality_and_cycles() -> Result<(), LoxError> {
+            let source = r#"
+var xs = [1];
+var ys = xs;
+print xs == ys; // expect: true
+print [1] == [1]; // expect: false
+xs[0] = xs;
+print xs; // expect: [[...]]
+"#;
+            assert_eq!(vec!["true", "false", "[[...]]"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn runtime_errors() {
+            let e = error("print \"abc\"[0];");
+            assert_eq!(Some(Diagnostic::IndexNotList), e.code);
+            let e = error("print [1, 2][0.5];");
+            assert_eq!(Some(Diagnostic::InvalidIndex), e.code);
+            let e = error("var xs = [1, 2]; xs[2] = 3;");
+            assert_eq!(
+                "List index 2 is out of bounds for a list of length 2.",
+                e.message
+            );
+            let e = error("print [1][-2];");
+            assert_eq!(
+                "List index -2 is out of bounds for a list of length 1.",
+                e.message
+            );
+        }
+
+        #[test]
+        fn compile_errors() {
+            assert_eq!(
+                "Expect ']' after list elements.",
+                error("print [1 2];").message
+            );
+            let elements = vec!["nil"; 256].join(", ");
+            assert_eq!(
+                "Can't have more than 255 elements in a list literal.",
+                error(&format!("print [{}];", elements)).message
+            );
+            assert_eq!(
+                "Invalid assignment target.",
+                error("var xs = [1]; xs[0] + 1 = 2;").message
+            );
+            assert_eq!(
+                "Invalid increment target.",
+                error("var xs = [1]; xs[0]++;").message
+            );
+        }
+    }
+
+    mod global_arity {
+        use super::*;
+
+        fn error(source: &str) -> LoxError {
+            VM::new().interpret(source.to_string()).unwrap_err()
+        }
+
+        #[test]
+        fn direct_calls_are_checked_when_compiling() {
+            for source in [
+                "fun f(a, b) {} f(1);",
+                "fun g() { return f(1, 2, 3); } fun f(a) {}",
+                "fun f(a) {} if (false) print f();",
+            ] {
+                let error = error(source);
+                assert_eq!(VMError::CompileError, error.kind, "{}", source);
+                assert_eq!(Some(Diagnostic::WrongGlobalArgumentCount), error.code);
+            }
+            assert_eq!(
+                "Expected 2 arguments but got 1.",
+                error("fun f(a, b) {} f(1);").message
+            );
+        }
+
+        #[test]
+        fn across_sources() {
+            let program = CompilerManager::compile_many(
+                &[("a.lox", "fun f(a) {}"), ("b.lox", "f(1, 2);")],
+                &Default::default(),
+            );
+            assert_eq!(
+                Diagnostic::WrongGlobalArgumentCount,
+                program.err().unwrap().code
+            );
+        }
+
+        #[test]
+        fn other_calls_are_checked_when_running() {
+            for source in [
+                "fun f(a) {} var g = f; g();",
+                "fun f(a) {} print (f)();",
+                "fun f(a) {} fun f(a, b) {} f(1);",
+                "fun f(a) {} f = clock; f(1);",
+                "var f = clock; fun f(a) {} f();",
+                "{ fun f(a) {} f(); }",
+                "class C { init(a) {} } C();",
+            ] {
+                let error = error(source);
+                assert_eq!(VMError::RuntimeError, error.kind, "{}", source);
+                assert_eq!(Some(Diagnostic::WrongArgumentCount), error.code);
+            }
+        }
+    }
+
+    mod shadowed_natives {
+        use super::*;
+        use rlox::value::value::Value;
+
+        fn vm(level: LintLevel) -> VM {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.compile_options.lints.insert(Lint::ShadowedNative, level);
+            vm
+        }
+
+        #[test]
+        fn allowed_by_default() -> Result<(), LoxError> {
+            let result = VM::new().interpret("var clock = 1;".to_string())?;
+            assert!(result.warnings.is_empty());
+            Ok(())
+        }
+
+        #[test]
+        fn warn() -> Result<(), LoxError> {
+            let source = r#"
+var clock = 1;
+fun inspect(value) { return value; }
+clock = 2;
+clock++;
+{ var clock = 3; }
+fun f(clock) { return clock; }
+print inspect(clock);
+"#;
+            let result = vm(LintLevel::Warn).interpret(source.to_string())?;
+            let lines: Vec<i32> = result.warnings.iter().map(|w| w.line).collect();
+            assert_eq!(vec![2, 3, 4, 5], lines);
+            assert_eq!(
+                Some(Diagnostic::ShadowedNativeWarning),
+                result.warnings.first().map(|w| w.code)
+            );
+            assert_eq!(
+                "Global 'clock' replaces the native function of the same name.",
+                result.warnings[0].message
+            );
+            assert_eq!("3", result.printed_values[0].to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn forbid() {
+            let mut vm = vm(LintLevel::Deny);
+            for source in [
+                "var clock = 1;",
+                "fun clock() {}",
+                "class clock {}",
+                "clock = nil;",
+            ] {
+                let error = vm.interpret(source.to_string()).unwrap_err();
+                assert_eq!(Some(Diagnostic::ShadowedNative), error.code, "{}", source);
+                assert_eq!("Can't redefine native function 'clock'.", error.message);
+            }
+            assert!(vm.interpret("{ var clock = 1; }".to_string()).is_ok());
+            assert!(matches!(vm.global("clock"), Some(Value::NativeFunction(_))));
+        }
+
+        #[test]
+        fn natives_defined_by_embedders() {
+            let mut vm = vm(LintLevel::Deny);
+            vm.define_native("config", 0, |_, _| Ok(Value::Nil));
+            let error = vm.interpret("var config = 1;".to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::ShadowedNative), error.code);
+        }
+    }
+
+    mod read_only_globals {
+        use super::*;
+        use rlox::value::value::Value;
+
+        fn vm() -> VM {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.define_read_only_global("VERSION", Value::Number(3.0));
+            vm
+        }
+
+        #[test]
+        fn readable() -> Result<(), LoxError> {
+            let result =
+                vm().interpret("fun f() { return VERSION + 1; } print f();".to_string())?;
+            assert_eq!("4", result.printed_values[0].to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn not_assignable() {
+            let mut vm = vm();
+            for source in [
+                "VERSION = 4;",
+                "var VERSION = 4;",
+                "VERSION++;",
+                "fun VERSION() {}",
+                "fun f() { VERSION = nil; } f();",
+            ] {
+                let error = vm.interpret(source.to_string()).unwrap_err();
+                assert_eq!(Some(Diagnostic::ReadOnlyGlobal), error.code, "{}", source);
+                assert_eq!(
+                    "Cannot assign to read-only global 'VERSION'.",
+                    error.message
+                );
+            }
+            assert_eq!("3", vm.global("VERSION").unwrap().to_string());
+        }
+
+        #[test]
+        fn replaced_by_the_host() {
+            let mut vm = vm();
+            vm.define_read_only_global("VERSION", Value::Number(4.0));
+            assert_eq!("4", vm.global("VERSION").unwrap().to_string());
+        }
+    }
+
+    mod host_objects {
+        use super::*;
+        use rlox::value::host_object::HostObject;
+        use rlox::value::value::Value;
+        use std::cell::Cell;
+
+        thread_local! {
+            static CLOSED: Cell<usize> = const { Cell::new(0) };
+        }
+
+        /// Stands for a resource of the host, like a file, counting how many were released.
+        struct Counter {
+            count: Cell<u32>,
+        }
+
+        impl Drop for Counter {
+            fn drop(&mut self) {
+                CLOSED.with(|closed| closed.set(closed.get() + 1));
+            }
+        }
+
+        fn open(_vm: &mut VM, _args: &[Value]) -> Result<Value, VMError> {
+            let counter = Counter {
+                count: Cell::new(0),
+            };
+            Ok(Value::HostObject(Rc::new(HostObject::new(
+                "Counter", counter,
+            ))))
+        }
+
+        fn increment(vm: &mut VM, args: &[Value]) -> Result<Value, VMError> {
+            let counter = vm.host_object::<Counter>(&args[0], "Counter")?;
+            counter.count.set(counter.count.get() + 1);
+            Ok(Value::Number(counter.count.get() as f64))
+        }
+
+        fn vm() -> VM {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.define_native("open", 0, open);
+            vm.define_native("increment", 1, increment);
+            vm
+        }
+
+        #[test]
+        fn passed_back_to_natives() -> Result<(), LoxError> {
+            let source = r#"
+var a = open();
+var b = open();
+increment(a);
+fun twice(counter) { increment(counter); return increment(counter); }
+print twice(a); // expect: 3
+print increment(b); // expect: 1
+print a; // expect: <Counter>
+print a == a; // expect: true
+print a == b; // expect: false
+"#;
+            let result = vm().interpret(source.to_string())?;
+            assert_eq!(
+                vec!["3", "1", "<Counter>", "true", "false"],
+                printed(result)
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn released_when_unreferenced() -> Result<(), LoxError> {
+            CLOSED.with(|closed| closed.set(0));
+            let mut vm = vm();
+            vm.interpret(
+                "{ var local = open(); increment(local); } var global = open();".to_string(),
+            )?;
+            assert_eq!(1, CLOSED.with(Cell::get));
+            vm.interpret("global = nil;".to_string())?;
+            assert_eq!(2, CLOSED.with(Cell::get));
+            Ok(())
+        }
+
+        #[test]
+        fn wrong_argument() {
+            let mut vm = vm();
+            vm.define_native("other", 0, |_, _| {
+                Ok(Value::HostObject(Rc::new(HostObject::new("Other", ()))))
+            });
+            for source in ["increment(1);", "increment(other());"] {
+                let error = vm.interpret(source.to_string()).unwrap_err();
+                assert_eq!(Some(Diagnostic::NotHostObject), error.code);
+                assert_eq!("Operand must be a Counter.", error.message);
+            }
+        }
+    }
+
+    mod typed_natives {
+        use super::*;
+        use rlox::native_fn;
+        use rlox::value::value::Value;
+
+        fn vm() -> VM {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            native_fn!(vm, "hypot", |a: f64, b: f64| a.hypot(b));
+            native_fn!(vm, "repeat", |text: &str, times: f64| text
+                .repeat(times as usize));
+            native_fn!(vm, "choose", |condition: bool, a: &Value, b: &Value| {
+                if condition {
+                    a.clone()
+                } else {
+                    b.clone()
+                }
+            });
+            native_fn!(vm, "nothing", || ());
+            native_fn!(vm, "positive", |n: f64| (n > 0.0).then_some(n));
+            native_fn!(vm, "check", |vm; n: f64| {
+                if n < 0.0 {
+                    vm.runtime_error("Negative.");
+                    return Err(VMError::RuntimeError);
+                }
+                Ok(n)
+            });
+            vm
+        }
+
+        #[test]
+        fn converts_arguments_and_results() -> Result<(), LoxError> {
+            let source = r#"
+print hypot(3, 4); // expect: 5
+print repeat("ab", 2); // expect: abab
+print choose(false, 1, "two"); // expect: two
+print nothing(); // expect: nil
+print positive(-1); // expect: nil
+print check(2); // expect: 2
+print inspect(1); // expect: 1
+"#;
+            let result = vm().interpret(source.to_string())?;
+            assert_eq!(
+                vec!["5", "abab", "two", "nil", "nil", "2", "1"],
+                printed(result)
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn checks_arguments() {
+            let mut vm = vm();
+            let cases = [
+                ("hypot(1);", None, "Expected 2 arguments but got 1."),
+                (
+                    "hypot(1, \"2\");",
+                    Some(Diagnostic::OperandNotNumber),
+                    "Operand must be a number.",
+                ),
+                (
+                    "repeat(1, 2);",
+                    Some(Diagnostic::OperandNotString),
+                    "Operand must be a string.",
+                ),
+                (
+                    "choose(nil, 1, 2);",
+                    Some(Diagnostic::OperandNotBoolean),
+                    "Operand must be a boolean.",
+                ),
+                ("check(-1);", None, "Negative."),
+            ];
+            for (source, code, message) in cases {
+                let error = vm.interpret(source.to_string()).unwrap_err();
+                if code.is_some() {
+                    assert_eq!(code, error.code);
+                }
+                assert_eq!(message, error.message);
+            }
+        }
+    }
+
+    mod lints {
+        use super::*;
+        use rlox::compiler::CompileOptions;
+        use rlox::diagnostic::Diagnostic;
+
+        fn compile(source: &str, lints: &[(Lint, LintLevel)]) -> Result<Vec<String>, String> {
+            let mut options = CompileOptions::default();
+            options.lints.extend(lints.iter().cloned());
+            match CompilerManager::compile_many(&[("main.lox", source)], &options) {
+                Ok(program) => Ok(program
+                    .warnings
+                    .iter()
+                    .map(|warning| warning.to_string())
+                    .collect()),
+                Err(error) => Err(format!("{}: {}", error.code.code(), error.message)),
+            }
+        }
+
+        #[test]
+        fn unused_locals() {
+            let source = r#"
+fun f(unused) {
+  var a = 1;
+  var _b = 2;
+  { var c; var d; d = 3; }
+}
+"#;
+            assert_eq!(Ok(Vec::new()), compile(source, &[]));
+            assert_eq!(
+                Ok(vec![
+                    "[main.lox:5] warning[W004]: Unused local variable 'c'.".to_string(),
+                    "[main.lox:3] warning[W004]: Unused local variable 'a'.".to_string(),
+                ]),
+                compile(source, &[(Lint::UnusedLocal, LintLevel::Warn)])
+            );
+        }
+
+        #[test]
+        fn shadowing() {
+            let source = r#"
+fun f(x) {
+  { var x = 1; print x; }
+  { var y = 1; print y; }
+  { var y = 2; print y; }
+}
+"#;
+            assert_eq!(
+                Ok(vec![
+                    "[main.lox:3] warning[W005]: Local variable 'x' shadows a variable of an enclosing block."
+                        .to_string()
+                ]),
+                compile(source, &[(Lint::Shadowing, LintLevel::Warn)])
+            );
+        }
+
+        #[test]
+        fn denied_lints_are_errors() {
+            let source = "if (a = 1) { var b; }";
+            assert_eq!(
+                Err("W004: Unused local variable 'b'.".to_string()),
+                compile(
+                    source,
+                    &[
+                        (Lint::UnusedLocal, LintLevel::Deny),
+                        (Lint::AssignmentInCondition, LintLevel::Warn)
+                    ]
+                )
+            );
+        }
+
+        #[test]
+        fn denied_shadowed_natives_are_forbidden() {
+            let mut vm = VM::new();
+            vm.compile_options
+                .lints
+                .insert(Lint::ShadowedNative, LintLevel::Deny);
+            let error = vm.interpret("var clock;".to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::ShadowedNative), error.code);
+        }
+
+        #[test]
+        fn deny_warnings() {
+            let mut vm = VM::new();
+            vm.compile_options
+                .lints
+                .insert(Lint::UndefinedGlobal, LintLevel::Warn);
+            vm.compile_options.deny_warnings = true;
+            let error = vm.interpret("missing;".to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::UndefinedGlobal), error.code);
+            vm.compile_options
+                .lints
+                .insert(Lint::UndefinedGlobal, LintLevel::Allow);
+            assert_eq!(
+                Some(Diagnostic::UndefinedVariable),
+                vm.interpret("missing;".to_string()).unwrap_err().code
+            );
+        }
+
+        #[test]
+        fn names() {
+            for lint in Lint::ALL {
+                assert_eq!(Some(lint), Lint::from_name(lint.name()));
+            }
+            assert_eq!(None, Lint::from_name("warnings"));
+        }
+    }
+
+    mod directives {
+        use super::*;
+        use rlox::diagnostic::Diagnostic;
+
+        #[test]
+        fn lints() {
+            let mut vm = VM::new();
+            let source = "// rlox: deny = unused-local\n{ var unused; }";
+            let error = vm.interpret(source.to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::UnusedLocal), error.code);
+            // The directives of a script do not carry over to the next one.
+            assert!(vm.interpret("{ var unused; }".to_string()).is_ok());
+        }
+
+        #[test]
+        fn max_frame_instructions() {
+            let mut vm = VM::new();
+            vm.trace_execution = false;
+            let source = r#"
+// Loops for a while.
+// rlox: max-frame-instructions = 1e3
+fun spin() { for (var i = 0; i < 1000; i = i + 1) {} }
+spin();
+"#;
+            let error = vm.interpret(source.to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::FrameInstructionLimit), error.code);
+            assert_eq!(None, vm.max_frame_instructions);
+        }
+
+        #[test]
+        fn max_instructions() {
+            let mut vm = VM::new();
+            vm.trace_execution = false;
+            vm.max_instructions = Some(1_000_000);
+            let source = "// rlox: max-instructions = 1e2\nwhile (true) {}";
+            let error = vm.interpret(source.to_string()).unwrap_err();
+            assert_eq!(VMError::Timeout, error.kind);
+            assert_eq!(Some(Diagnostic::InstructionLimit), error.code);
+            assert_eq!(Some(1_000_000), vm.max_instructions);
+        }
+
+        #[test]
+        fn invalid_directive() {
+            let mut vm = VM::new();
+            let error = vm
+                .interpret("// rlox: max-frame-instructions = lots\nprint 1;".to_string())
+                .unwrap_err();
+            assert_eq!(VMError::CompileError, error.kind);
+            assert_eq!(Some(Diagnostic::InvalidDirectiveValue), error.code);
+        }
+    }
+
+    mod repl_paste {
+        use super::*;
+
+        #[test]
+        fn reads_up_to_end() -> Result<(), LoxError> {
+            let mut input =
+                "fun twice(n) {\n  return n * 2;\n}\nprint twice(21);\n  :end\nprint 1;\n"
+                    .as_bytes();
+            let source = read_paste(&mut input);
+            assert_eq!("print 1;\n", std::str::from_utf8(input).unwrap());
+
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let result = vm.interpret(source)?;
+            assert_eq!("42", result.printed_values[0].to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn reads_up_to_end_of_input() {
+            let mut input = "var a = 1;\nvar b = 2;".as_bytes();
+            assert_eq!("var a = 1;\nvar b = 2;", read_paste(&mut input));
+        }
+    }
+
+    mod repl_time {
+        use super::*;
+        use std::time::Duration;
+
+        #[test]
+        fn format() {
+            let timing = Timing {
+                compile_time: Duration::from_micros(250),
+                run_time: Duration::from_millis(12),
+                instruction_count: 3400,
+            };
+            assert_eq!(
+                "// Compiled in 0.250 ms, ran in 12.000 ms, 3400 instructions.",
+                format_timing(timing)
+            );
+        }
+
+        #[test]
+        fn latest_entry() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.trace_execution = false;
+            vm.interpret("for (var i = 0; i < 100; i = i + 1) {}".to_string())?;
+            let looped = vm.timing();
+            assert!(looped.instruction_count > 100);
+
+            vm.interpret("1;".to_string())?;
+            assert!(vm.timing().instruction_count < looped.instruction_count);
+
+            // Failed entries are timed up to the error, here the read of the undefined global.
+            assert!(vm.interpret("print missing;".to_string()).is_err());
+            assert_eq!(1, vm.timing().instruction_count);
+            Ok(())
+        }
+
+        #[test]
+        fn setting() {
+            let mut settings = ReplSettings::default();
+            let (_, errors) = parse_rc("", "set time = true\n", &mut settings);
+            assert!(errors.is_empty());
+            assert!(settings.time);
+        }
+    }
+
+    mod number_exponents {
+        use super::*;
+
+        #[test]
+        fn printed_beyond_thresholds() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let source = r#"
+print 1000000000 * 1000000000 * 1000; // expect: 1e21
+print -1000000000 * 1000000000 * 100; // expect: -100000000000000000000
+print 1 / 100000000; // expect: 1e-8
+print 1 / 10000000; // expect: 0.0000001
+"#;
+            let result = vm.interpret(source.to_string())?;
+            let printed = printed(result);
+            assert_eq!(
+                vec!["1e21", "-100000000000000000000", "1e-8", "0.0000001"],
+                printed
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn read_back() -> Result<(), LoxError> {
+            let source = "print 1e21 * 7; print -1 / 3e9; print 5e-324; print 1.5e300;";
+            let printed = run(source)?;
+            let again: Vec<String> = printed.iter().map(|n| format!("print {};", n)).collect();
+            assert_eq!(printed, run(&again.concat())?);
+            Ok(())
+        }
+
+        /// Runs a program, returning what it wrote, errors included.
+        fn written(vm: &mut VM, source: &str) -> String {
+            let output = SharedOutput::default();
+            vm.set_output(Box::new(output.clone()));
+            vm.set_error_output(Box::new(output.clone()));
+            let _ = vm.interpret(source.to_string());
+            let written = String::from_utf8(output.0.borrow().clone()).unwrap();
+            written
+        }
+
+        #[test]
+        fn plain() {
+            let mut vm = VM::new();
+            vm.number_format = NumberFormat::PLAIN;
+            assert_eq!(
+                "1000000000000000000000\n-0.00000001\n[1000000000000000000000]\n",
+                written(&mut vm, "print 1e21; print -1e-8; print [1e21];")
+            );
+            assert!(written(&mut vm, "print inspect(1e21);").starts_with("1000000000000000000000"));
+            assert!(written(&mut vm, "var l = [1]; print l[1e21];")
+                .contains("List index 1000000000000000000000 is out of bounds"));
+        }
+
+        #[test]
+        fn per_vm() {
+            let mut plain = VM::new();
+            plain.number_format = NumberFormat::PLAIN;
+            let mut default = VM::new();
+            assert_eq!(
+                "1000000000000000000000\n",
+                written(&mut plain, "print 1e21;")
+            );
+            assert_eq!("1e21\n", written(&mut default, "print 1e21;"));
+            assert_eq!(
+                "1000000000000000000000\n",
+                written(&mut plain, "print 1e21;")
+            );
+            // Values shown outside of a VM use the default format.
+            assert_eq!("1e21", Value::Number(1e21).to_string());
+        }
+    }
+
+    mod optional_chaining {
+        use super::*;
+        use rlox::diagnostic::Diagnostic;
+
+        #[test]
+        fn nil_receivers() -> Result<(), LoxError> {
+            let source = r#"
+class Node {
+  init(value) { this.value = value; this.next = nil; }
+  describe() { return "node " + this.next?.describe(); }
+}
+var node = Node(1);
+var none = nil;
+print none?.value; // expect: nil
+print node?.value; // expect: 1
+print none?.describe(); // expect: nil
+print node.next?.value; // expect: nil
+print none?.next.value; // expect: nil
+print none?.value == nil; // expect: true
+print [node?.value, none?.value]; // expect: [1, nil]
+"#;
+            assert_eq!(
+                vec!["nil", "1", "nil", "nil", "nil", "true", "[1, nil]"],
+                run(source)?
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn other_receivers() {
+            let error = run("var a = 1; print a?.b;").unwrap_err();
+            assert_eq!(Some(Diagnostic::NotAnInstance), error.code);
+        }
+
+        #[test]
+        fn no_assignment() {
+            let error = run("var a; a?.b = 1;").unwrap_err();
+            assert_eq!(Some(Diagnostic::InvalidAssignmentTarget), error.code);
+            let error = run("var a; a?.b.c = 1;").unwrap_err();
+            assert_eq!(Some(Diagnostic::InvalidAssignmentTarget), error.code);
+        }
+    }
+
+    mod conformance_suite {
+        use super::*;
+
+        #[test]
+        fn chapters_and_skip_list() {
+            let dir = std::env::temp_dir().join(format!("rlox_conformance_{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("class")).unwrap();
+            std::fs::write(dir.join("top.lox"), "print 1; // expect: 1").unwrap();
+            std::fs::write(dir.join("class").join("pass.lox"), "print 2; // expect: 2").unwrap();
+            std::fs::write(dir.join("class").join("fail.lox"), "print 2; // expect: 3").unwrap();
+            std::fs::write(dir.join("class").join("skip.lox"), "print;").unwrap();
+            std::fs::write(
+                dir.join("skip.txt"),
+                "# Skipped.\nclass/skip.lox  # Not yet.\n",
+            )
+            .unwrap();
+
+            let chapters = check_conformance(&dir, &Options::default()).unwrap();
+            std::fs::remove_dir_all(&dir).unwrap();
+
+            let chapter = |passed, failed, skipped| Chapter {
+                passed,
+                failed,
+                skipped,
+            };
+            let expected: Vec<(String, Chapter)> = vec![
+                (".".to_string(), chapter(1, 0, 0)),
+                ("class".to_string(), chapter(1, 1, 1)),
+            ];
+            assert_eq!(expected, chapters.into_iter().collect::<Vec<_>>());
+            assert_eq!(
+                "   1/2    passed ( 50.0%), 1 skipped",
+                chapter(1, 1, 1).to_string()
+            );
+        }
+
+        #[test]
+        fn skip_list() {
+            assert_eq!(
+                vec![
+                    ("a.lox".to_string(), "Why.".to_string()),
+                    ("b.lox".to_string(), "no reason given".to_string()),
+                ],
+                parse_skip_list("# Comment.\n\na.lox  # Why.\nb.lox\n")
+            );
+        }
+
+        /// Runs the conformance tests in tests/lox, which all pass but the skipped ones.
+        #[test]
+        fn in_tree_suite() {
+            let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("lox");
+            let chapters = check_conformance(&dir, &Options::default()).unwrap();
+            let failed: usize = chapters.values().map(|chapter| chapter.failed).sum();
+            assert_eq!(0, failed);
+        }
+    }
+
+    mod exceptions {
+        use super::*;
+        use rlox::diagnostic::Diagnostic;
+
+        #[test]
+        fn unwinds_calls() -> Result<(), LoxError> {
+            let source = r#"
+fun log(value) { print value; }
+fun check(n) {
+  defer log("unwound");
+  if (n > 2) throw n * 10;
+  return n;
+}
+fun middle(n) { var unused = "local"; return 1 + check(n); }
+var kept = "kept";
+try {
+  print middle(1); // expect: 2
+  print 100 + middle(5);
+  print "unreached";
+} catch (error) {
+  print error; // expect: 50
+  print kept; // expect: kept
+}
+"#;
+            // The unwound call runs its deferred code before the catch block, like a return.
+            assert_eq!(vec!["unwound", "2", "unwound", "50", "kept"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn deferred_code_of_unwound_calls() -> Result<(), LoxError> {
+            let source = r#"
+fun log(value) { print value; }
+fun inner() {
+  defer log("inner first");
+  defer log("inner second");
+  throw "thrown";
+}
+fun outer() {
+  var local = "outer";
+  defer log(local);
+  inner();
+}
+fun rethrow() {
+  defer thrower();
+  throw "replaced";
+}
+fun thrower() { throw "from defer"; }
+try { outer(); } catch (error) { print error; }
+try { rethrow(); } catch (error) { print error; }
+"#;
+            assert_eq!(
+                vec![
+                    "inner second",
+                    "inner first",
+                    "outer",
+                    "thrown",
+                    "from defer"
+                ],
+                run(source)?
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn uncaught_at_throw_line() {
+            let source = "fun fail() {\n  throw \"boom\";\n\n}\nfail();\n";
+            let error = run(source).unwrap_err();
+            assert_eq!(Some(Diagnostic::UncaughtException), error.code);
+            assert_eq!(
+                vec!["[line 2] in fail()", "[line 5] in script"],
+                error.stack_trace
+            );
+        }
+
+        #[test]
+        fn nested_and_rethrown() -> Result<(), LoxError> {
+            let source = r#"
+{
+  var a = 1;
+  try {
+    var b = 2;
+    try { throw a + b; } catch (inner) { throw inner * 2; }
+  } catch (outer) {
+    print outer; // expect: 6
+    print a; // expect: 1
+  }
+}
+try { print "no throw"; } catch (unused) { print "unreached"; }
+"#;
+            assert_eq!(vec!["6", "1", "no throw"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn return_ends_try_block() {
+            let source = r#"
+fun f() {
+  try { return "returned"; } catch (error) { print "caught in f"; }
+}
+print f();
+throw "outside";
+"#;
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let error = vm.interpret(source.to_string()).unwrap_err();
+            assert_eq!(Some(Diagnostic::UncaughtException), error.code);
+            assert_eq!("Uncaught exception: outside", error.message);
+        }
+
+        #[test]
+        fn compile_errors() {
+            let error = run("try { } print 1;").unwrap_err();
+            assert_eq!(Some(Diagnostic::ExpectCatch), error.code);
+            let error = run("try { } catch e { }").unwrap_err();
+            assert_eq!(Some(Diagnostic::ExpectParenAfterCatch), error.code);
+            let error = run("throw 1").unwrap_err();
+            assert_eq!(Some(Diagnostic::ExpectSemicolonAfterThrow), error.code);
+        }
+    }
+
+    mod accessors {
+        use super::*;
+        use rlox::diagnostic::Diagnostic;
+
+        #[test]
+        fn getters_and_setters() -> Result<(), LoxError> {
+            let source = r#"
+class Rect {
+  init(width, height) { this.width = width; this.height = height; }
+  get area { return this.width * this.height; }
+  set size(value) { this.width = value; this.height = value; }
+}
+var rect = Rect(2, 3);
+print rect.area; // expect: 6
+print rect.size = 4; // expect: 4
+print rect.area; // expect: 16
+print rect?.area; // expect: 16
+"#;
+            assert_eq!(vec!["6", "4", "16", "16"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn inherited() -> Result<(), LoxError> {
+            let source = r#"
+class Named {
+  get label { return "<" + this.name + ">"; }
+  set label(value) { this.name = value; }
+}
+class Item < Named {
+  init(name) { this.name = name; }
+}
+var item = Item("a");
+print item.label; // expect: <a>
+item.label = "b";
+print item.label; // expect: <b>
+"#;
+            assert_eq!(vec!["<a>", "<b>"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn fields_shadow_getters() -> Result<(), LoxError> {
+            let source = r#"
+class Box {
+  get value { return "getter"; }
+  get() { return "method"; }
+  set(value) { return value; }
+}
+var box = Box();
+print box.value; // expect: getter
+box.value = "field";
+print box.value; // expect: field
+print box.get(); // expect: method
+print box.set(1); // expect: 1
+"#;
+            assert_eq!(vec!["getter", "field", "method", "1"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn compile_errors() {
+            let error = run("class A { set a() {} }").unwrap_err();
+            assert_eq!(Some(Diagnostic::SetterArity), error.code);
+            let error = run("class A { set a(v) { return v; } }").unwrap_err();
+            assert_eq!(Some(Diagnostic::ReturnFromSetter), error.code);
+        }
+    }
+
+    mod field_initializers {
+        use super::*;
+        use rlox::diagnostic::Diagnostic;
+
+        #[test]
+        fn run_before_init() -> Result<(), LoxError> {
+            let source = r#"
+var created = 0;
+fun next() { created = created + 1; return created; }
+class Point {
+  x = 0;
+  y = this.x + 1;
+  id = next();
+  label;
+  init(x) { print this.y; this.x = x; }
+}
+var point = Point(5);
+print point.x; // expect: 5
+print point.id; // expect: 1
+print point.label; // expect: nil
+print Point(1).id; // expect: 2
+"#;
+            assert_eq!(vec!["1", "5", "1", "nil", "1", "2"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn inherited() -> Result<(), LoxError> {
+            let source = r#"
+class Base { kind = "base"; size = 1; }
+class Derived < Base { size = 2; extra = this.kind + "!"; }
+var derived = Derived();
+print derived.kind; // expect: base
+print derived.size; // expect: 2
+print derived.extra; // expect: base!
+"#;
+            assert_eq!(vec!["base", "2", "base!"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn errors() {
+            let error = run("class A { x = 1 }").unwrap_err();
+            assert_eq!(Some(Diagnostic::ExpectSemicolonAfterField), error.code);
+            let error = run("class A { x = nil.y; } A();").unwrap_err();
+            assert_eq!(Some(Diagnostic::NotAnInstance), error.code);
+            assert_eq!(
+                vec!["[line 1] in A()", "[line 1] in script"],
+                error.stack_trace
+            );
+        }
+    }
+
+    mod is_operator {
+        use super::*;
+        use rlox::diagnostic::Diagnostic;
+
+        #[test]
+        fn class_chain() -> Result<(), LoxError> {
+            let source = r#"
+class Shape {}
+class Circle < Shape {}
+class Other {}
+var circle = Circle();
+print circle is Circle; // expect: true
+print circle is Shape; // expect: true
+print circle is Other; // expect: false
+print Shape() is Circle; // expect: false
+print circle is Shape == true; // expect: true
+"#;
+            assert_eq!(vec!["true", "true", "false", "false", "true"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn other_values() -> Result<(), LoxError> {
+            let source = r#"
+class Shape {}
+print 1 is Shape; // expect: false
+print nil is Shape; // expect: false
+print Shape is Shape; // expect: false
+"#;
+            assert_eq!(vec!["false", "false", "false"], run(source)?);
+            let error = run("class A {} print A() is 1;").unwrap_err();
+            assert_eq!(Some(Diagnostic::IsNotClass), error.code);
+            Ok(())
+        }
+    }
+
+    mod tail_calls {
+        use super::*;
+        use rlox::chunk::Instruction;
+        use rlox::diagnostic::Diagnostic;
+        use rlox::value::value::Value;
+
+        #[test]
+        fn deep_recursion() -> Result<(), LoxError> {
+            let source = r#"
+fun count(n, total) {
+  if (n == 0) return total;
+  return count(n - 1, total + 2);
+}
+print count(10000, 0); // expect: 20000
+
+fun isEven(n) {
+  if (n == 0) return true;
+  return isOdd(n - 1);
+}
+fun isOdd(n) {
+  if (n == 0) return false;
+  return isEven(n - 1);
+}
+print isEven(1001); // expect: false
+
+class Countdown {
+  init(n) { this.n = n; }
+  next() {
+    if (this.n == 0) return "liftoff";
+    return Countdown(this.n - 1).next();
+  }
+}
+print Countdown(1000).next(); // expect: liftoff
+"#;
+            assert_eq!(vec!["20000", "false", "liftoff"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn only_calls_in_tail_position() {
+            let script = CompilerManager::compile(
+                "fun f(n) { if (n) return f(n - 1) + 1; return f(n - 1); }".to_string(),
+            )
+            .unwrap();
+            let function = script
+                .chunk
+                .constants
+                .iter()
+                .find_map(|constant| match constant {
+                    Value::Function(function) => Some(function.clone()),
+                    _ => None,
+                })
+                .unwrap();
+            let calls: Vec<Instruction> = function
+                .chunk
+                .bytecode
+                .iter()
+                .copied()
+                .filter(|i| matches!(i, Instruction::OpCall(_) | Instruction::OpTailCall(_)))
+                .collect();
+            assert_eq!(
+                vec![Instruction::OpCall(1), Instruction::OpTailCall(1)],
+                calls
+            );
+
+            let error = run("fun f(n) { if (n == 0) return 0; return f(n - 1) + 1; } f(1000);")
+                .unwrap_err();
+            assert_eq!(Some(Diagnostic::StackOverflow), error.code);
+        }
+
+        #[test]
+        fn keeps_frames_with_work_left() -> Result<(), LoxError> {
+            let source = r#"
+fun fail() { throw "failed"; }
+fun attempt() {
+  try {
+    return fail();
+  } catch (error) {
+    return "caught " + error;
+  }
+}
+print attempt(); // expect: caught failed
+
+fun log(message) { print message; }
+fun inner() { log("inner"); return "result"; }
+fun outer() {
+  defer log("deferred");
+  return inner();
+}
+print outer(); // expect: inner, deferred, result
+"#;
+            assert_eq!(
+                vec!["caught failed", "inner", "deferred", "result"],
+                run(source)?
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn wrong_arity() {
+            let error = run("fun f(a) { var g = f; return g(); } f(1);").unwrap_err();
+            assert_eq!(Some(Diagnostic::WrongArgumentCount), error.code);
+        }
+
+        #[test]
+        fn runaway_tail_recursion() {
+            let mut vm = VM::new();
+            vm.max_frame_instructions = Some(1000);
+            let error = vm
+                .interpret("fun spin() { return spin(); } spin();".to_string())
+                .unwrap_err();
+            assert_eq!(Some(Diagnostic::FrameInstructionLimit), error.code);
+        }
+    }
+
+    mod match_expression {
+        use super::*;
+        use rlox::diagnostic::Diagnostic;
+
+        #[test]
+        fn arms() -> Result<(), LoxError> {
+            let source = r#"
+fun describe(x) {
+  return match (x) {
+    1 => "one",
+    "x" => "ex",
+    nil => "nothing",
+    _ => "something " + "else",
+  };
+}
+print describe(1); // expect: one
+print describe("x"); // expect: ex
+print describe(nil); // expect: nothing
+print describe(false); // expect: something else
+"#;
+            assert_eq!(vec!["one", "ex", "nothing", "something else"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn expressions() -> Result<(), LoxError> {
+            let source = r#"
+var two = 2;
+print 10 + match (1 + 1) { 1 => 1, two => 20, }; // expect: 30
+print match (3) { 1 => "a" }; // expect: nil
+print match (3) {}; // expect: nil
+var arms = 0;
+fun pattern(x) { arms = arms + 1; return x; }
+print match (2) { pattern(1) => "a", pattern(2) => "b", pattern(3) => "c" }; // expect: b
+print arms; // expect: 2
+"#;
+            assert_eq!(vec!["30", "nil", "nil", "b", "2"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn wildcard_must_be_last() {
+            let error = run("print match (1) { _ => 1, 2 => 3 };").unwrap_err();
+            assert_eq!(Some(Diagnostic::WildcardArmNotLast), error.code);
+            let error = run("print match (1) { 1: 2 };").unwrap_err();
+            assert_eq!(Some(Diagnostic::ExpectArrowAfterPattern), error.code);
+        }
+    }
+
+    mod operator_overloading {
+        use super::*;
+        use rlox::diagnostic::Diagnostic;
+
+        const VECTOR: &str = r#"
+class Vector {
+  init(x, y) { this.x = x; this.y = y; }
+  plus(other) { return Vector(this.x + other.x, this.y + other.y); }
+  minus(other) { return Vector(this.x - other.x, this.y - other.y); }
+  times(k) { return Vector(this.x * k, this.y * k); }
+  divide(k) { return Vector(this.x / k, this.y / k); }
+  negate() { return Vector(-this.x, -this.y); }
+  equals(other) { return other is Vector and this.x == other.x and this.y == other.y; }
+  less(other) { return this.x < other.x; }
+  greater(other) { return this.x > other.x; }
+}
+"#;
+
+        #[test]
+        fn arithmetic() -> Result<(), LoxError> {
+            let source = r#"
+var a = Vector(1, 2);
+var b = Vector(3, 4);
+var c = (a + b - Vector(1, 1)) * 2 / 4;
+print c.x; // expect: 1.5
+print c.y; // expect: 2.5
+print (-a).y; // expect: -2
+"#;
+            assert_eq!(
+                vec!["1.5", "2.5", "-2"],
+                run(&(VECTOR.to_string() + source))?
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn comparisons() -> Result<(), LoxError> {
+            let source = r#"
+var a = Vector(1, 2);
+print a == Vector(1, 2); // expect: true
+print a != Vector(1, 2); // expect: false
+print a == 1; // expect: false
+print a < Vector(3, 0); // expect: true
+print a >= Vector(3, 0); // expect: false
+print match (Vector(1, 2)) { Vector(0, 0) => "origin", a => "a" }; // expect: a
+"#;
+            assert_eq!(
+                vec!["true", "false", "false", "true", "false", "a"],
+                run(&(VECTOR.to_string() + source))?
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn inherited_methods() -> Result<(), LoxError> {
+            let source = r#"
+class Base { plus(other) { return "added " + other; } }
+class Derived < Base {}
+print Derived() + "x"; // expect: added x
+class Plain {}
+var p = Plain();
+print p == p; // expect: true
+print p == Plain(); // expect: false
+"#;
+            assert_eq!(vec!["added x", "true", "false"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn only_the_left_operand() {
+            let source = VECTOR.to_string() + "print 1 + Vector(1, 2);";
+            assert!(run(&source).is_err());
+
+            let error = run("class A { plus() { return 1; } } print A() + 1;").unwrap_err();
+            assert_eq!(Some(Diagnostic::WrongArgumentCount), error.code);
+        }
+    }
+
+    mod top_level_return {
+        use super::*;
+
+        fn run(source: &str) -> Result<Interpretation, LoxError> {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.interpret(source.to_string())
+        }
+
+        #[test]
+        fn ends_the_script() -> Result<(), LoxError> {
+            let source = r#"
+fun log(message) { print message; }
+defer log("deferred");
+print "before";
+{
+  var local = 3;
+  if (local > 2) return local;
+}
+print "after";
+"#;
+            let result = run(source)?;
+            assert!(matches!(result.return_value, Value::Number(n) if n == 3.0));
+            assert_eq!(vec!["before", "deferred"], printed(result));
+
+            assert!(matches!(run("return;")?.return_value, Value::Nil));
+            assert!(matches!(run("print 1;")?.return_value, Value::Nil));
+            Ok(())
+        }
+
+        #[test]
+        fn only_ends_its_own_script() -> Result<(), LoxError> {
+            let program = CompilerManager::compile_many(
+                &[
+                    ("a.lox", "var a = 1; return; a = 2;"),
+                    ("b.lox", "return a;"),
+                ],
+                &Default::default(),
+            )
+            .unwrap();
+            let mut vm = VM::new();
+            let result = vm.interpret_program(program)?;
+            assert!(matches!(result.return_value, Value::Number(n) if n == 1.0));
+            Ok(())
+        }
+
+        #[test]
+        fn exit_codes() {
+            assert_eq!(Some(3), exit_code(&Value::Number(3.0)));
+            assert_eq!(Some(0), exit_code(&Value::Number(0.0)));
+            assert_eq!(None, exit_code(&Value::Number(256.0)));
+            assert_eq!(None, exit_code(&Value::Number(1.5)));
+            assert_eq!(None, exit_code(&Value::Number(-1.0)));
+            assert_eq!(None, exit_code(&Value::Nil));
+        }
+    }
+
+    mod binary_operator_errors {
+        use super::*;
+        use rlox::diagnostic::Diagnostic;
+
+        fn error(source: &str) -> LoxError {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.interpret(source.to_string()).unwrap_err()
+        }
+
+        #[test]
+        fn type_errors() {
+            for source in [
+                "1 - nil;",
+                "\"a\" * 2;",
+                "nil / 1;",
+                "true < 1;",
+                "1 > \"a\";",
+            ] {
+                let error = error(source);
+                assert_eq!(
+                    Some(Diagnostic::OperandsNotNumbers),
+                    error.code,
+                    "{}",
+                    source
+                );
+                assert_eq!("Operands must be numbers.", error.message);
+            }
+            for source in ["true + nil;", "\"a\" + 1;", "1 + \"a\";"] {
+                let error = error(source);
+                assert_eq!(
+                    Some(Diagnostic::OperandsNotNumbersOrStrings),
+                    error.code,
+                    "{}",
+                    source
+                );
+                assert_eq!(
+                    "Operands must be two numbers or two strings.",
+                    error.message
+                );
+            }
+            assert_eq!("Operand must be a number.", error("-\"a\";").message);
+        }
+
+        #[test]
+        fn valid_operands() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let source = "print 7 - 2 * 3; print \"a\" + \"b\"; print -(1 + 2) < 0;";
+            assert_eq!(
+                vec!["1", "ab", "true"],
+                printed(vm.interpret(source.to_string())?)
+            );
+            Ok(())
+        }
+    }
+
+    mod global_slots {
+        use super::*;
+        use rlox::compiler::{CompileOptions, CompilerManager};
+
+        #[test]
+        fn links_programs_compiled_without_the_vm() -> Result<(), LoxError> {
+            // The program numbers its globals from 0, while the VM already has natives and
+            // the read-only global in its slots.
+            let program = CompilerManager::compile_many(
+                &[
+                    ("lib.lox", "var a = 1; fun f() { return a + limit; }"),
+                    ("main.lox", "a = 2; print f(); print clock() >= 0;"),
+                ],
+                &CompileOptions::default(),
+            )
+            .unwrap();
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.define_read_only_global("limit", Value::Number(5.0));
+            let result = vm.interpret_program(program)?;
+            assert_eq!(vec!["7", "true"], printed(result));
+            assert!(matches!(vm.global("a"), Some(Value::Number(n)) if *n == 2.0));
+            Ok(())
+        }
+
+        #[test]
+        fn later_scripts_share_the_slots() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.interpret("var a = 1;".to_string())?;
+            let error = vm.interpret("print b;".to_string()).unwrap_err();
+            assert_eq!("Undefined variable 'b'.", error.message);
+            let result = vm.interpret("var b = a + 1; a = 3; print a + b;".to_string())?;
+            assert_eq!(vec!["5"], printed(result));
+            Ok(())
+        }
+    }
+
+    mod optimizations {
+        use super::*;
+
+        fn run(source: &str, optimize: bool) -> (Vec<String>, Option<(String, Vec<String>)>) {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.compile_options.optimize = optimize;
+            match vm.interpret(source.to_string()) {
+                Ok(result) => (printed(result), None),
+                Err(error) => (Vec::new(), Some((error.message, error.stack_trace))),
+            }
+        }
+
+        #[test]
+        fn same_results_when_optimized() {
+            let sources = [
+                "print 2 + 3 * 4; print (1 - 0.5) / 4; print 1 / 0; print -0; print -0 == 0;",
+                "print !true; print !!nil; print - -3; print \"a\" + \"b\" == \"ab\";",
+                "var x = 4; print 10 - x * 2; print -(-x); fun f() { return 1 < 2 and 3 > 4; } print f();",
+                "print 1 + nil;",
+                "print -\"text\";",
+                "fun done() { print \"done\"; } fun f(n) { defer done(); try { if (n) throw n; return 1; print 0; } catch (e) { return e; } } print f(nil); print f(2);",
+                "class V { init(x) { this.x = x; } plus(o) { return V(this.x + o.x); } } fun add(a, b) { var c = a + b; return c.x; } print add(V(1), V(2));",
+                "fun cat(a, b) { if (!a) return b; 1; return a + b; } print cat(\"a\", \"b\"); print cat(nil, 2); print cat(1, nil);",
+                "fun g() { while (false) print 1; if (2 > 1) return \"a\"; else return \"b\"; } print g(); print true and 1 < 2 or nil;",
+            ];
+            for source in sources {
+                assert_eq!(run(source, false), run(source, true), "{}", source);
+            }
+        }
+    }
+
+    mod limits {
+        use super::*;
+
+        const RECURSION: &str =
+            "fun depth(n) { if (n == 0) return 0; return 1 + depth(n - 1); } print depth(300);";
+
+        #[test]
+        fn default_limits() {
+            let vm = VM::new();
+            assert_eq!(
+                Limits {
+                    frames_max: FRAMES_MAX,
+                    stack_max: STACK_MAX,
+                },
+                vm.limits()
+            );
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let error = vm.interpret(RECURSION.to_string()).unwrap_err();
+            assert_eq!("Stack overflow.", error.message);
+        }
+
+        #[test]
+        fn deeper_recursion() -> Result<(), LoxError> {
+            let mut vm = VM::with_limits(Limits {
+                frames_max: 1000,
+                stack_max: 1000 * FRAME_SLOTS,
+            });
+            vm.capture_output = true;
+            let result = vm.interpret(RECURSION.to_string())?;
+            assert_eq!("300", result.printed_values[0].to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn smaller_stack() {
+            let mut vm = VM::with_limits(Limits {
+                frames_max: 1000,
+                stack_max: 4 * FRAME_SLOTS,
+            });
+            vm.capture_output = true;
+            vm.profiling = true;
+            let error = vm.interpret(RECURSION.to_string()).unwrap_err();
+            assert_eq!("Stack overflow.", error.message);
+            let profile = vm.profile().unwrap();
+            assert!(profile.peak_stack_depth < 4 * FRAME_SLOTS, "{:?}", profile);
+            assert!(profile.peak_frame_depth < 1000, "{:?}", profile);
+        }
+
+        #[test]
+        fn too_small_limits_are_raised() -> Result<(), LoxError> {
+            let mut vm = VM::with_limits(Limits {
+                frames_max: 0,
+                stack_max: 0,
+            });
+            assert_eq!(
+                Limits {
+                    frames_max: 1,
+                    stack_max: FRAME_SLOTS + 1,
+                },
+                vm.limits()
+            );
+            vm.capture_output = true;
+            let result = vm.interpret("print 1 + 2;".to_string())?;
+            assert_eq!("3", result.printed_values[0].to_string());
+            Ok(())
+        }
+
+        #[test]
+        fn temporaries_overflow_the_stack() {
+            let mut vm = VM::with_limits(Limits {
+                frames_max: 1,
+                stack_max: FRAME_SLOTS + 1,
+            });
+            vm.capture_output = true;
+            // The outer list's elements stay on the stack while the inner list is built.
+            let elements = vec!["1"; 200].join(", ");
+            let source = format!("print [{}, [{}]];", elements, elements);
+            let error = vm.interpret(source).unwrap_err();
+            assert_eq!("Stack overflow.", error.message);
+        }
+
+        #[test]
+        fn locals_fit_in_a_frame() {
+            let declare = |count: usize| {
+                let locals: String = (0..count).map(|i| format!("var v{} = {};", i, i)).collect();
+                format!("fun f() {{ {} return v{}; }} print f();", locals, count - 1)
+            };
+            assert_eq!(
+                vec![(FRAME_SLOTS - 2).to_string()],
+                run(&declare(FRAME_SLOTS - 1)).unwrap()
+            );
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            let error = vm.interpret(declare(FRAME_SLOTS)).unwrap_err();
+            assert_eq!("Too many local variables in function.", error.message);
+        }
+    }
+
+    mod stack_instructions {
+        use super::*;
+        use rlox::chunk::Instruction;
+        use rlox::compiler::CompilerManager;
+
+        #[test]
+        fn scopes_pop_their_locals_at_once() -> Result<(), LoxError> {
+            let source =
+                "{ var a = 1; { var b = 2; print a + b; } var c = 3; var d = a + c; print d; }";
+            let script = CompilerManager::compile(source.to_string()).unwrap();
+            let pops: Vec<&Instruction> = script
+                .chunk
+                .bytecode
+                .iter()
+                .filter(|i| matches!(i, Instruction::OpPop | Instruction::OpPopN(_)))
+                .collect();
+            assert_eq!(vec![&Instruction::OpPop, &Instruction::OpPopN(3)], pops);
+            assert_eq!(vec!["3", "4"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn postfix_increments_copy_the_old_value() -> Result<(), LoxError> {
+            let source = "var g = 1; print g++; print g; fun f() { var l = 5; print l--; return l; } print f();";
+            let script = CompilerManager::compile(source.to_string()).unwrap();
+            let reads = script
+                .chunk
+                .bytecode
+                .iter()
+                .filter(|i| matches!(i, Instruction::OpGetGlobal(0)))
+                .count();
+            // One read for `g++` and one for `print g`.
+            assert_eq!(2, reads);
+            assert!(script.chunk.bytecode.contains(&Instruction::OpDup));
+            assert_eq!(vec!["1", "2", "5", "4"], run(source)?);
+            Ok(())
+        }
+    }
+
+    mod instruction_budget {
+        use super::*;
+        use rlox::diagnostic::Diagnostic;
+
+        fn vm(max_instructions: usize) -> VM {
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            vm.max_instructions = Some(max_instructions);
+            vm
+        }
+
+        #[test]
+        fn stops_endless_loops() {
+            let mut vm = vm(1000);
+            let error = vm
+                .interpret("var i = 0; while (true) { i = i + 1; }".to_string())
+                .unwrap_err();
+            assert_eq!(VMError::Timeout, error.kind);
+            assert_eq!(Some(Diagnostic::InstructionLimit), error.code);
+            assert_eq!(
+                "The program ran more than 1000 instructions.",
+                error.message
+            );
+            assert_eq!(vec!["[line 1] in script"], error.stack_trace);
+            assert_eq!(1001, vm.timing().instruction_count);
+        }
+
+        #[test]
+        fn counts_every_call() {
+            // No single call runs long, but together they run out of instructions.
+            let source = "fun f(n) { if (n > 0) { f(n - 1); f(n - 1); } } f(20);";
+            let mut vm = vm(10_000);
+            vm.max_frame_instructions = Some(100);
+            let error = vm.interpret(source.to_string()).unwrap_err();
+            assert_eq!(VMError::Timeout, error.kind);
+        }
+
+        #[test]
+        fn each_interpretation_has_the_whole_budget() -> Result<(), LoxError> {
+            let mut vm = vm(50);
+            for _ in 0..3 {
+                let result = vm.interpret("var a = 1; print a + 2;".to_string())?;
+                assert_eq!("3", result.printed_values[0].to_string());
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "profile")]
+    mod profile_report {
+        use super::*;
+
+        #[test]
+        fn counts_opcodes_and_functions() -> Result<(), LoxError> {
+            let source = r#"
+fun add(a, b) { return a + b; }
+var i = 0;
+while (i < 10) i = add(i, 1);
+"#;
+            let mut vm = VM::new();
+            vm.trace_execution = false;
+            let result = vm.interpret_source("main.lox", source.to_string())?;
+            let report = vm.profile_report();
+
+            let total: usize = report.opcodes.iter().map(|(_, count)| count).sum();
+            assert_eq!(result.instruction_count, total);
+            assert!(report.opcodes.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+            assert!(report.opcodes.contains(&("OpCall", 10)), "{:?}", report);
+            // The function pushes its arguments, adds them and returns, and its implicit return
+            // is never reached.
+            assert_eq!(
+                vec![
+                    ("script at main.lox:2".to_string(), total - 40),
+                    ("add() at main.lox:2".to_string(), 40),
+                ],
+                report.functions
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn starts_over_with_each_interpretation() -> Result<(), LoxError> {
+            let mut vm = VM::new();
+            vm.trace_execution = false;
+            vm.interpret("var a = 1 + 2;".to_string())?;
+            let first = vm.profile_report();
+            vm.interpret("var b = 1 + 2;".to_string())?;
+            assert_eq!(first, vm.profile_report());
+
+            let table = format_profile_report(&first);
+            assert!(table.starts_with("Instruction"));
+            assert!(table.contains("\nOpAdd "), "{}", table);
+            assert!(table.contains("\nscript at line 1 "), "{}", table);
+            Ok(())
+        }
+    }
+
+    mod string_handles {
+        use super::*;
+
+        #[test]
+        fn strings() {
+            let source = r#"
+var s = "ab" + "cd";
+print s;
+print s[1:3];
+print s == "abcd";
+"#;
+            assert_eq!(vec!["abcd", "bc", "true"], run(source).unwrap());
+        }
+
+        #[test]
+        fn fields_are_updated_in_place() {
+            let source = r#"
+class Counter {}
+var c = Counter();
+c.count = 0;
+for (var i = 0; i < 3; i = i + 1) c.count = c.count + 1;
+print c.count;
+"#;
+            assert_eq!(vec!["3"], run(source).unwrap());
+        }
+    }
+
+    mod to_string {
+        use super::*;
+
+        #[test]
+        fn print_and_concatenation() -> Result<(), LoxError> {
+            let source = r#"
+class Point {
+  init(x, y) { this.x = x; this.y = y; }
+  toString() { return "(" + "x" + ", " + "y" + ")"; }
+}
+class Plain {}
+var p = Point(1, 2);
+print p;
+print "p = " + p;
+print p + "!";
+print Plain();
+print "plain: " + Plain();
+"#;
+            assert_eq!(
+                vec![
+                    "(x, y)",
+                    "p = (x, y)",
+                    "(x, y)!",
+                    "Plain instance",
+                    "plain: Plain instance"
+                ],
+                run(source)?
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn inherited_and_using_fields() -> Result<(), LoxError> {
+            let source = r#"
+class Named {
+  toString() { return "<" + this.name + ">"; }
+}
+class Dog < Named {
+  init(name) { this.name = name; }
+}
+print Dog("Rex");
+"#;
+            assert_eq!(vec!["<Rex>"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn errors() {
+            let source = "class A { toString() { return 1; } } print A();";
+            let error = run(source).unwrap_err();
+            assert_eq!(Some(Diagnostic::ToStringNotString), error.code);
+            assert_eq!("toString() must return a string.", error.message);
+
+            let source = "class A { toString() { return nil.x; } } print \"a\" + A();";
+            let error = run(source).unwrap_err();
+            assert_eq!("Only instances have properties.", error.message);
+            // Numbers are still not converted.
+            let error = run("print \"a\" + 1;").unwrap_err();
+            assert_eq!(Some(Diagnostic::OperandsNotNumbersOrStrings), error.code);
+        }
+    }
+
+    mod compare_to {
+        use super::*;
+
+        const VERSION: &str = r#"
+class Version {
+  init(major, minor) { this.major = major; this.minor = minor; }
+  compareTo(other) {
+    if (this.major != other.major) return this.major - other.major;
+    return this.minor - other.minor;
+  }
+  toString() { return "v" + this.name(); }
+  name() { if (this.major == 1) return "1"; return "2"; }
+}
+"#;
+
+        #[test]
+        fn comparison_operators() -> Result<(), LoxError> {
+            let source = format!(
+                "{}{}",
+                VERSION,
+                r#"
+var a = Version(1, 2);
+var b = Version(2, 0);
+print a < b;
+print a > b;
+print a <= Version(1, 2);
+print a >= b;
+"#
+            );
+            assert_eq!(vec!["true", "false", "true", "false"], run(&source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn sort() -> Result<(), LoxError> {
+            let source = format!(
+                "{}{}",
+                VERSION,
+                r#"
+var versions = [Version(2, 1), Version(1, 0), Version(2, 0)];
+sort(versions, nil);
+print versions[0].major + versions[0].minor;
+print versions[1].major + versions[1].minor;
+print versions[2].major + versions[2].minor;
+fun descending(a, b) { return b - a; }
+var numbers = [3, 1, 2];
+print sort(numbers, descending);
+print numbers;
+print sort(["b", "c", "a"], nil);
+"#
+            );
+            assert_eq!(
+                vec!["1", "2", "3", "[3, 2, 1]", "[3, 2, 1]", "[a, b, c]"],
+                run(&source)?
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn stable() -> Result<(), LoxError> {
+            let source = r#"
+fun byFirst(a, b) { return a[0] - b[0]; }
+var pairs = [[1, "a"], [0, "b"], [1, "c"], [0, "d"]];
+sort(pairs, byFirst);
+print pairs;
+"#;
+            assert_eq!(vec!["[[0, b], [0, d], [1, a], [1, c]]"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn errors() {
+            let error =
+                run("class A { compareTo(o) { return nil; } } print A() < A();").unwrap_err();
+            assert_eq!(Some(Diagnostic::ComparisonNotNumber), error.code);
+            let error = run("fun f(a, b) { return true; } sort([1, 2], f);").unwrap_err();
+            assert_eq!(Some(Diagnostic::ComparisonNotNumber), error.code);
+            let error = run("sort([1, \"a\"], nil);").unwrap_err();
+            assert_eq!(Some(Diagnostic::NotComparable), error.code);
+            let error = run("sort(1, nil);").unwrap_err();
+            assert_eq!(Some(Diagnostic::OperandNotList), error.code);
+            // Without compareTo, instances still can't be compared.
+            let error = run("class A {} print A() < A();").unwrap_err();
+            assert_eq!(Some(Diagnostic::OperandsNotNumbers), error.code);
+        }
+    }
+
+    mod list_natives {
+        use super::*;
+
+        #[test]
+        fn sorting() -> Result<(), LoxError> {
+            let source = r#"
+fun descending(a, b) { return b - a; }
+fun second(pair) { return pair[1]; }
+print sort([3, 1, 2]);
+print sort([3, 1, 2], descending);
+var pairs = [["c", 2], ["a", 3], ["b", 1], ["d", 2]];
+sortBy(pairs, second);
+print pairs;
+"#;
+            assert_eq!(
+                vec!["[1, 2, 3]", "[3, 2, 1]", "[[b, 1], [c, 2], [d, 2], [a, 3]]"],
+                run(source)?
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn searching() -> Result<(), LoxError> {
+            let source = r#"
+class Point {
+  init(x) { this.x = x; }
+  equals(other) { return other is Point and this.x == other.x; }
+}
+var list = [1, "two", Point(3), 1];
+print indexOf(list, 1);
+print indexOf(list, "two");
+print indexOf(list, Point(3));
+print indexOf(list, 4);
+print contains(list, "two");
+print contains(list, nil);
+"#;
+            assert_eq!(vec!["0", "1", "2", "nil", "true", "false"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn reversing() -> Result<(), LoxError> {
+            let source = r#"
+var list = [1, 2, 3];
+var same = reverse(list);
+print list;
+print same == list;
+print reverse([]);
+"#;
+            assert_eq!(vec!["[3, 2, 1]", "true", "[]"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn errors() {
+            let error = run("reverse(\"abc\");").unwrap_err();
+            assert_eq!(Some(Diagnostic::OperandNotList), error.code);
+            let error = run("sort();").unwrap_err();
+            assert_eq!("Expected 2 arguments but got 0.", error.message);
+            let error = run("sort([], nil, nil);").unwrap_err();
+            assert_eq!("Expected 2 arguments but got 3.", error.message);
+            let error = run("fun key(x) { return nil; } sortBy([1, 2], key);").unwrap_err();
+            assert_eq!(Some(Diagnostic::NotComparable), error.code);
+        }
+    }
+
+    mod higher_order_natives {
+        use super::*;
+
+        #[test]
+        fn map_filter_reduce() -> Result<(), LoxError> {
+            let source = r#"
+fun square(x) { return x * x; }
+fun big(x) { return x > 2; }
+fun add(a, b) { return a + b; }
+var numbers = [1, 2, 3, 4];
+print map(numbers, square);
+print filter(numbers, big);
+print reduce(numbers, add, 10);
+print reduce([], add, "empty");
+print numbers;
+"#;
+            assert_eq!(
+                vec!["[1, 4, 9, 16]", "[3, 4]", "20", "empty", "[1, 2, 3, 4]"],
+                run(source)?
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn callables() -> Result<(), LoxError> {
+            let source = r#"
+class Box {
+  init(value) { this.value = value; }
+  get() { return this.value; }
+}
+class Scale {
+  init(factor) { this.factor = factor; }
+  apply(x) { return x * this.factor; }
+}
+fun unbox(box) { return box.get(); }
+var boxes = map([1, 2], Box);
+print map(boxes, unbox);
+print map([1, 2], Scale(10).apply);
+fun sum(list) {
+  fun add(a, b) { return a + b; }
+  return reduce(list, add, 0);
+}
+print map([[1, 2], [3]], sum);
+"#;
+            assert_eq!(vec!["[1, 2]", "[10, 20]", "[3, 3]"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn callback_errors() {
+            let source = "fun f(x) { return x.y; } map([1], f);";
+            let error = run(source).unwrap_err();
+            assert_eq!(Some(Diagnostic::NotAnInstance), error.code);
+            let error = run("fun f(a, b) { return a; } map([1], f);").unwrap_err();
+            assert_eq!("Expected 2 arguments but got 1.", error.message);
+            let error = run("filter([1], nil);").unwrap_err();
+            assert_eq!(Some(Diagnostic::NotCallable), error.code);
+            // The VM is still usable after an error in a callback.
+            let mut vm = VM::new();
+            vm.capture_output = true;
+            assert!(vm
+                .interpret("fun f(x) { return x.y; } map([1], f);".to_string())
+                .is_err());
+            let result = vm
+                .interpret("fun g(x) { return x + 1; } print map([1], g);".to_string())
+                .unwrap();
+            assert_eq!("[2]", result.printed_values[0].to_string());
+        }
+    }
+
+    mod freeze {
+        use super::*;
+
+        #[test]
+        fn frozen_values_can_be_read() -> Result<(), LoxError> {
+            let source = r#"
+class Point { init(x) { this.x = x; } }
+var point = freeze(Point(1));
+var list = freeze([1, [2]]);
+print point.x;
+print list[0];
+// Freezing is shallow.
+list[1][0] = 3;
+print list;
+print freeze(1);
+print freeze("text");
+"#;
+            assert_eq!(vec!["1", "1", "[1, [3]]", "1", "text"], run(source)?);
+            Ok(())
+        }
+
+        #[test]
+        fn assignments_are_errors() {
+            let error = run("class A {} var a = A(); a.x = 1; freeze(a); a.x = 2;").unwrap_err();
+            assert_eq!(Some(Diagnostic::FrozenValue), error.code);
+            assert_eq!("Can't change a frozen instance.", error.message);
+
+            // Setters don't get around it either.
+            let source = "class A { set x(v) { print v; } } var a = freeze(A()); a.x = 1;";
+            let error = run(source).unwrap_err();
+            assert_eq!(Some(Diagnostic::FrozenValue), error.code);
+
+            let error = run("var l = freeze([1, 2]); l[0] = 3;").unwrap_err();
+            assert_eq!("Can't change a frozen list.", error.message);
+        }
+
+        #[test]
+        fn natives_changing_lists() {
+            for source in [
+                "reverse(freeze([1, 2]));",
+                "sort(freeze([2, 1]));",
+                "fun key(x) { return x; } sortBy(freeze([2, 1]), key);",
+            ] {
+                let error = run(source).unwrap_err();
+                assert_eq!("Can't change a frozen list.", error.message, "{}", source);
+            }
+            // Natives returning new lists work on frozen ones.
+            let source = "fun double(x) { return x * 2; } print map(freeze([1, 2]), double);";
+            assert_eq!(vec!["[2, 4]"], run(source).unwrap());
+        }
+
+        #[test]
+        fn frozen_while_sorting() {
+            let source = r#"
+var list = [2, 1];
+fun compare(a, b) { freeze(list); return a - b; }
+sort(list, compare);
+"#;
+            let error = run(source).unwrap_err();
+            assert_eq!(Some(Diagnostic::FrozenValue), error.code);
+        }
+    }
+
+    mod stack_slots {
+        use super::*;
+        use std::time::Instant;
+
+        /// Times a loop that pushes strings and numbers, to compare the stack slots with and
+        /// without NaN boxing. Run with
+        /// `cargo test --release -- --ignored --nocapture stack_slots`, then again with
+        /// `--features nan_boxing`.
+        #[ignore = "benchmark"]
+        #[test]
+        fn benchmark() {
+            let source = r#"
+var name = "a string";
+var count = 0;
+for (var i = 0; i < 1000000; i = i + 1) {
+  var copy = name;
+  if (copy == name) count = count + 1;
+}
+"#;
+            let mut vm = VM::new();
+            vm.trace_execution = false;
+            let start = Instant::now();
+            vm.interpret(source.to_string()).unwrap();
+            println!("{:?}", start.elapsed());
+        }
+    }
 }
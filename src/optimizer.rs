@@ -0,0 +1,569 @@
+//! Optional passes over compiled [Function]s, making their bytecode shorter without changing
+//! what it does, enabled with [crate::compiler::CompileOptions::optimize].
+//!
+//! Constant folding computes the arithmetic, comparisons and negations whose operands are all
+//! literals, like `2 + 3 * 4`, `1 < 2` or `!true`, replacing them with the loading of their
+//! result. Operations on variables are left alone, even `-(-x)`: `x` may not be a number, or may
+//! be an instance overloading the operator. So are operations that would fail at runtime,
+//! like `1 + nil`, so that the script still reports the error.
+//!
+//! Dead code elimination then removes the instructions that can never run, like those after a
+//! `return` or in the body of a `while (false)`. A conditional jump on a constant, which constant
+//! folding leaves behind for conditions like `1 > 2`, is either always taken, and becomes an
+//! [Instruction::OpJump], or never taken, and is removed, before following the jumps from the
+//! start of the chunk to find the instructions it reaches.
+//!
+//! Finally, a peephole pass replaces common sequences of instructions with shorter ones:
+//!
+//! - a constant that is popped right away, as in the expression statement `1;`, is removed,
+//!   along with jumps to the next instruction;
+//! - [Instruction::OpNot] followed by [Instruction::OpJumpIfFalse] becomes
+//!   [Instruction::OpJumpIfTrue], when both branches pop the condition, as in
+//!   `if (!done)` or `while (!done)`;
+//! - two [Instruction::OpGetLocal]s followed by [Instruction::OpAdd] become
+//!   [Instruction::OpAddLocals].
+
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, Instruction};
+use crate::dead_globals::{compact_constants, remove_instructions};
+use crate::value::function::Function;
+use crate::value::string::LoxString;
+use crate::value::value::Value;
+use crate::vm::vm::is_falsey;
+
+/// Returns the function with its bytecode, and that of the functions among its constants,
+/// optimized.
+pub fn optimize(function: &Function) -> Function {
+    let mut optimized = function.clone();
+    for constant in &mut optimized.chunk.constants {
+        if let Value::Function(function) = constant {
+            *function = Rc::new(optimize(function));
+        }
+    }
+    optimized.chunk = peephole(&eliminate_dead_code(&fold_constants(&optimized.chunk)));
+    optimized
+}
+
+/// Returns the chunk with the operations on constants replaced by their result.
+fn fold_constants(chunk: &Chunk) -> Chunk {
+    let targets = jump_targets(chunk);
+    let mut folded = chunk.clone();
+    let mut removed = HashSet::new();
+    // The instructions that pushed the values on top of the stack, if they are constants,
+    // since the last instruction that did anything else.
+    let mut constants: Vec<usize> = Vec::new();
+    for index in 0..folded.bytecode.len() {
+        // The constants before a jump target are not necessarily what is on the stack there.
+        if targets.contains(&index) {
+            constants.clear();
+        }
+        let instruction = folded.bytecode[index];
+        if constant_value(&folded, instruction).is_some() {
+            constants.push(index);
+            continue;
+        }
+        let operand_count = match instruction {
+            Instruction::OpNot | Instruction::OpNegate => 1,
+            Instruction::OpAdd
+            | Instruction::OpSubtract
+            | Instruction::OpMultiply
+            | Instruction::OpDivide
+            | Instruction::OpEqual
+            | Instruction::OpLess
+            | Instruction::OpGreater => 2,
+            _ => {
+                constants.clear();
+                continue;
+            }
+        };
+        if constants.len() < operand_count {
+            constants.clear();
+            continue;
+        }
+        let operands = &constants[constants.len() - operand_count..];
+        let values: Vec<Value> = operands
+            .iter()
+            .map(|&operand| constant_value(&folded, folded.bytecode[operand]).unwrap())
+            .collect();
+        match fold(instruction, &values) {
+            Some(result) => {
+                removed.extend(operands.iter().copied());
+                constants.truncate(constants.len() - operand_count);
+                folded.bytecode[index] = load(&mut folded, result);
+                // The result can be an operand of the next operation, as in `2 + 3 * 4`.
+                constants.push(index);
+            }
+            None => constants.clear(),
+        }
+    }
+    if removed.is_empty() {
+        return folded;
+    }
+    compact_constants(&remove_instructions(&folded, &removed))
+}
+
+/// Returns the chunk without the instructions that can never run.
+fn eliminate_dead_code(chunk: &Chunk) -> Chunk {
+    let resolved = resolve_constant_branches(chunk);
+    let reachable = reachable_instructions(&resolved);
+    let removed: HashSet<usize> = (0..resolved.bytecode.len())
+        .filter(|index| !reachable.contains(index))
+        .collect();
+    if removed.is_empty() {
+        return resolved;
+    }
+    compact_constants(&remove_instructions(&resolved, &removed))
+}
+
+/// Returns the chunk with the conditional jumps right after a constant either made
+/// unconditional or removed, depending on the constant.
+fn resolve_constant_branches(chunk: &Chunk) -> Chunk {
+    let targets = jump_targets(chunk);
+    let mut resolved = chunk.clone();
+    let mut removed = HashSet::new();
+    for index in 1..chunk.bytecode.len() {
+        // The jump can be reached with another value on the stack.
+        if targets.contains(&index) {
+            continue;
+        }
+        let condition = match constant_value(chunk, chunk.bytecode[index - 1]) {
+            Some(condition) => condition,
+            None => continue,
+        };
+        // The jumps leave the condition on the stack, like the constant does on its own.
+        let (taken, offset) = match chunk.bytecode[index] {
+            Instruction::OpJumpIfFalse(offset) => (is_falsey(&condition), offset),
+            Instruction::OpJumpIfTrue(offset) => (!is_falsey(&condition), offset),
+            Instruction::OpJumpIfNil(offset) => (matches!(condition, Value::Nil), offset),
+            _ => continue,
+        };
+        if taken {
+            resolved.bytecode[index] = Instruction::OpJump(offset);
+        } else {
+            removed.insert(index);
+        }
+    }
+    if removed.is_empty() {
+        return resolved;
+    }
+    remove_instructions(&resolved, &removed)
+}
+
+/// The indexes of the instructions that execution can reach from the start of the chunk.
+fn reachable_instructions(chunk: &Chunk) -> HashSet<usize> {
+    let mut reachable = HashSet::new();
+    let mut pending = vec![0];
+    while let Some(index) = pending.pop() {
+        if index >= chunk.bytecode.len() || !reachable.insert(index) {
+            continue;
+        }
+        let next = index + 1;
+        match chunk.bytecode[index] {
+            Instruction::OpJump(offset) => pending.push(next + offset),
+            Instruction::OpLoop(offset) => pending.push(next - offset),
+            Instruction::OpReturn | Instruction::OpEndDefer | Instruction::OpThrow => {}
+            // The deferred code right after an OpDefer runs when the function returns, and the
+            // catch block an OpSetupCatch jumps over to when a value is thrown.
+            Instruction::OpJumpIfFalse(offset)
+            | Instruction::OpJumpIfTrue(offset)
+            | Instruction::OpJumpIfNil(offset)
+            | Instruction::OpJumpIfNotEqual(offset)
+            | Instruction::OpDefer(offset)
+            | Instruction::OpSetupCatch(offset) => {
+                pending.push(next);
+                pending.push(next + offset);
+            }
+            _ => pending.push(next),
+        }
+    }
+    reachable
+}
+
+/// Returns the chunk with common sequences of instructions replaced by shorter ones, until
+/// there are none left.
+fn peephole(chunk: &Chunk) -> Chunk {
+    let mut optimized = chunk.clone();
+    loop {
+        let targets = jump_targets(&optimized);
+        let bytecode = &optimized.bytecode;
+        let mut replaced = optimized.clone();
+        let mut removed = HashSet::new();
+        let mut index = 0;
+        while index < bytecode.len() {
+            // Execution can only reach the later instructions of a sequence from the first one.
+            let sequence = |length: usize| {
+                index + length <= bytecode.len()
+                    && (index + 1..index + length).all(|later| !targets.contains(&later))
+            };
+            let length = match bytecode[index..] {
+                [Instruction::OpJump(0), ..] => {
+                    removed.insert(index);
+                    1
+                }
+                [load, Instruction::OpPop, ..] if is_load(load) && sequence(2) => {
+                    removed.extend([index, index + 1]);
+                    2
+                }
+                // The condition is left on the stack, so its negation only goes unnoticed when
+                // both branches pop it.
+                [Instruction::OpNot, Instruction::OpJumpIfFalse(offset), Instruction::OpPop, ..]
+                    if sequence(2)
+                        && bytecode.get(index + 2 + offset) == Some(&Instruction::OpPop) =>
+                {
+                    removed.insert(index);
+                    replaced.bytecode[index + 1] = Instruction::OpJumpIfTrue(offset);
+                    2
+                }
+                [Instruction::OpGetLocal(a), Instruction::OpGetLocal(b), Instruction::OpAdd, ..]
+                    if sequence(3) =>
+                {
+                    removed.extend([index, index + 1]);
+                    replaced.bytecode[index + 2] = Instruction::OpAddLocals(a, b);
+                    3
+                }
+                _ => 1,
+            };
+            index += length;
+        }
+        if removed.is_empty() {
+            return compact_constants(&optimized);
+        }
+        optimized = remove_instructions(&replaced, &removed);
+    }
+}
+
+/// Whether an instruction only pushes a constant.
+fn is_load(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::OpConstant(_)
+            | Instruction::OpNil
+            | Instruction::OpTrue
+            | Instruction::OpFalse
+            | Instruction::OpZero
+            | Instruction::OpOne
+            | Instruction::OpSmallInt(_)
+    )
+}
+
+/// The value an instruction pushes, if it always pushes the same one.
+fn constant_value(chunk: &Chunk, instruction: Instruction) -> Option<Value> {
+    match instruction {
+        Instruction::OpNil => Some(Value::Nil),
+        Instruction::OpTrue => Some(Value::Boolean(true)),
+        Instruction::OpFalse => Some(Value::Boolean(false)),
+        Instruction::OpZero => Some(Value::Number(0.0)),
+        Instruction::OpOne => Some(Value::Number(1.0)),
+        Instruction::OpSmallInt(n) => Some(Value::Number(n as f64)),
+        Instruction::OpConstant(index) => match &chunk.constants[index] {
+            value @ (Value::Number(_) | Value::String(_)) => Some(value.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The result of an operation on constants, unless it fails at runtime.
+fn fold(instruction: Instruction, operands: &[Value]) -> Option<Value> {
+    let value = match (instruction, operands) {
+        (Instruction::OpNot, [value]) => Value::Boolean(is_falsey(value)),
+        (Instruction::OpNegate, [Value::Number(n)]) => Value::Number(-n),
+        (Instruction::OpEqual, [a, b]) => Value::Boolean(Value::equals(a.clone(), b.clone())),
+        (Instruction::OpAdd, [Value::String(a), Value::String(b)]) => {
+            Value::String(LoxString::from(format!("{}{}", a, b)))
+        }
+        (_, [Value::Number(a), Value::Number(b)]) => match instruction {
+            Instruction::OpAdd => Value::Number(a + b),
+            Instruction::OpSubtract => Value::Number(a - b),
+            Instruction::OpMultiply => Value::Number(a * b),
+            Instruction::OpDivide => Value::Number(a / b),
+            Instruction::OpLess => Value::Boolean(a < b),
+            Instruction::OpGreater => Value::Boolean(a > b),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// The instruction pushing a value, like the compiler would write it, adding a constant if needed.
+fn load(chunk: &mut Chunk, value: Value) -> Instruction {
+    match value {
+        Value::Nil => Instruction::OpNil,
+        Value::Boolean(true) => Instruction::OpTrue,
+        Value::Boolean(false) => Instruction::OpFalse,
+        Value::Number(n) if n == 0.0 && n.is_sign_positive() => Instruction::OpZero,
+        Value::Number(1.0) => Instruction::OpOne,
+        // Negative zero keeps its sign in a constant.
+        Value::Number(n)
+            if n != 0.0 && n.fract() == 0.0 && n >= i8::MIN as f64 && n <= i8::MAX as f64 =>
+        {
+            Instruction::OpSmallInt(n as i8)
+        }
+        value => Instruction::OpConstant(chunk.add_constant(value)),
+    }
+}
+
+/// The indexes of the instructions that execution can continue at other than from the
+/// instruction before them.
+fn jump_targets(chunk: &Chunk) -> HashSet<usize> {
+    let mut targets = HashSet::new();
+    for (index, instruction) in chunk.bytecode.iter().enumerate() {
+        match *instruction {
+            Instruction::OpJump(offset)
+            | Instruction::OpJumpIfFalse(offset)
+            | Instruction::OpJumpIfTrue(offset)
+            | Instruction::OpJumpIfNil(offset)
+            | Instruction::OpJumpIfNotEqual(offset)
+            | Instruction::OpSetupCatch(offset) => {
+                targets.insert(index + 1 + offset);
+            }
+            // The deferred code right after the instruction runs when the function returns.
+            Instruction::OpDefer(offset) => {
+                targets.insert(index + 1);
+                targets.insert(index + 1 + offset);
+            }
+            Instruction::OpLoop(offset) => {
+                targets.insert(index + 1 - offset);
+            }
+            _ => {}
+        }
+    }
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompilerManager;
+    use crate::vm::vm::VM;
+
+    fn compile(source: &str) -> Function {
+        CompilerManager::compile(source.to_string()).unwrap()
+    }
+
+    fn run(function: Function) -> Vec<String> {
+        let mut vm = VM::new();
+        vm.capture_output = true;
+        let result = vm
+            .interpret_program(crate::compiler::Program {
+                scripts: vec![(String::new(), function)],
+                warnings: Vec::new(),
+            })
+            .unwrap();
+        result
+            .printed_values
+            .iter()
+            .map(|v| v.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn folds_arithmetic() {
+        let optimized = optimize(&compile("print 2 + 3 * 4;"));
+        assert_eq!(
+            vec![
+                Instruction::OpSmallInt(14),
+                Instruction::OpPrint,
+                Instruction::OpNil,
+                Instruction::OpReturn,
+            ],
+            optimized.chunk.bytecode
+        );
+        assert!(optimized.chunk.constants.is_empty());
+
+        let optimized = optimize(&compile("print -(1.5 / 2) < 0 == !nil;"));
+        assert_eq!(Instruction::OpTrue, optimized.chunk.bytecode[0]);
+        assert_eq!(
+            vec!["hello world"],
+            run(optimize(&compile("print \"hello \" + \"world\";")))
+        );
+    }
+
+    #[test]
+    fn folds_negations() {
+        let optimized = optimize(&compile("print !true; print - -3; print -0;"));
+        assert_eq!(Instruction::OpFalse, optimized.chunk.bytecode[0]);
+        assert_eq!(Instruction::OpSmallInt(3), optimized.chunk.bytecode[2]);
+        assert_eq!(Instruction::OpConstant(0), optimized.chunk.bytecode[4]);
+        assert!(
+            matches!(optimized.chunk.constants[0], Value::Number(n) if n == 0.0 && n.is_sign_negative())
+        );
+    }
+
+    #[test]
+    fn keeps_what_is_not_constant() {
+        let source = "var x = 2; print x * 3 + 1; print - -x; print 1 + nil;";
+        let script = compile(source);
+        assert_eq!(script.chunk.bytecode, optimize(&script).chunk.bytecode);
+    }
+
+    #[test]
+    fn folds_inside_functions_around_jumps() {
+        let source = r#"
+fun f(flag) {
+  var result = 10 * 10;
+  while (flag and result > 2 * 50) { result = result - 1; }
+  return flag and 1 + 1 or -(4 / 2);
+}
+print f(true);
+print f(false);
+"#;
+        let script = compile(source);
+        let optimized = optimize(&script);
+        let chunk = match &script.chunk.constants[0] {
+            Value::Function(f) => &f.chunk,
+            constant => panic!("expected a function, got {:?}", constant),
+        };
+        assert_eq!(
+            chunk.bytecode.len() - 9,
+            fold_constants(chunk).bytecode.len()
+        );
+        assert_eq!(run(script), run(optimized));
+    }
+
+    #[test]
+    fn keeps_sequences_split_by_a_jump_target() {
+        let mut chunk = Chunk::new();
+        chunk.write(Instruction::OpOne, 1);
+        chunk.write(Instruction::OpJumpIfFalse(2), 1);
+        chunk.write(Instruction::OpOne, 1);
+        chunk.write(Instruction::OpNegate, 1);
+        // The addition is the target of the jump, which leaves a single value on the stack.
+        chunk.write(Instruction::OpAdd, 1);
+        assert_eq!(
+            vec![
+                Instruction::OpOne,
+                Instruction::OpJumpIfFalse(1),
+                Instruction::OpSmallInt(-1),
+                Instruction::OpAdd,
+            ],
+            fold_constants(&chunk).bytecode
+        );
+    }
+
+    #[test]
+    fn removes_code_after_returns_and_jumps() {
+        let source = r#"
+fun f(n) {
+  if (n > 0) { return "positive"; } else { return "other"; }
+  print "unreachable";
+}
+fun g() {
+  while (false) { print "never"; }
+  if (1 < 2) { return "taken"; }
+  return "not taken";
+}
+print f(1);
+print f(0);
+print g();
+"#;
+        let script = compile(source);
+        let optimized = optimize(&script);
+        let function = |script: &Function, index: usize| match &script.chunk.constants[index] {
+            Value::Function(f) => Rc::clone(f),
+            constant => panic!("expected a function, got {:?}", constant),
+        };
+        // Only the branches and their returns are left in `f`.
+        let f = function(&optimized, 0);
+        assert!(!f.chunk.bytecode.contains(&Instruction::OpPrint));
+        assert_eq!(
+            Some(&Instruction::OpReturn),
+            f.chunk.bytecode.last(),
+            "{:?}",
+            f.chunk.bytecode
+        );
+        assert!(!f
+            .chunk
+            .constants
+            .iter()
+            .any(|constant| matches!(constant, Value::String(s) if &**s == "unreachable")));
+        // The loop and the `if` are gone from `g`, which only returns.
+        let g = function(&optimized, 1);
+        assert_eq!(
+            vec![Instruction::OpConstant(0), Instruction::OpReturn],
+            g.chunk.bytecode
+        );
+        assert_eq!(run(script), run(optimized));
+    }
+
+    #[test]
+    fn keeps_deferred_code_and_catch_blocks() {
+        let mut chunk = Chunk::new();
+        chunk.write(Instruction::OpSetupCatch(4), 1);
+        chunk.write(Instruction::OpDefer(2), 1);
+        chunk.write(Instruction::OpNil, 1);
+        chunk.write(Instruction::OpEndDefer, 1);
+        chunk.write(Instruction::OpThrow, 1);
+        chunk.write(Instruction::OpPop, 1);
+        chunk.write(Instruction::OpNil, 1);
+        chunk.write(Instruction::OpReturn, 1);
+        chunk.write(Instruction::OpPop, 2);
+        assert_eq!(
+            chunk.bytecode[..8].to_vec(),
+            eliminate_dead_code(&chunk).bytecode
+        );
+    }
+
+    #[test]
+    fn rewrites_common_sequences() {
+        let source = r#"
+fun f(a, b) {
+  1;
+  if (!a) { return b + b; }
+  while (!b) { b = true; }
+  return !a and b;
+}
+print f(false, 2);
+print f(1, false);
+"#;
+        let script = compile(source);
+        let optimized = optimize(&script);
+        let f = match &optimized.chunk.constants[0] {
+            Value::Function(f) => Rc::clone(f),
+            constant => panic!("expected a function, got {:?}", constant),
+        };
+        let count = |expected: fn(&Instruction) -> bool| {
+            f.chunk.bytecode.iter().filter(|i| expected(i)).count()
+        };
+        assert_eq!(
+            2,
+            count(|i| matches!(i, Instruction::OpJumpIfTrue(_))),
+            "{:?}",
+            f.chunk.bytecode
+        );
+        assert_eq!(1, count(|i| *i == Instruction::OpAddLocals(2, 2)));
+        // The value of `!a` is the result of the `and`, so its OpNot stays.
+        assert_eq!(1, count(|i| *i == Instruction::OpNot));
+        assert_eq!(Instruction::OpGetLocal(1), f.chunk.bytecode[0]);
+        assert_eq!(run(script), run(optimized));
+    }
+
+    #[test]
+    fn keeps_sequences_split_by_a_jump_target_in_the_peephole() {
+        let mut chunk = Chunk::new();
+        chunk.write(Instruction::OpGetLocal(1), 1);
+        chunk.write(Instruction::OpJumpIfFalse(1), 1);
+        chunk.write(Instruction::OpGetLocal(1), 1);
+        // The jump lands between the two locals.
+        chunk.write(Instruction::OpGetLocal(2), 1);
+        chunk.write(Instruction::OpAdd, 1);
+        chunk.write(Instruction::OpOne, 1);
+        chunk.write(Instruction::OpJump(0), 1);
+        chunk.write(Instruction::OpPop, 1);
+        chunk.write(Instruction::OpReturn, 1);
+        assert_eq!(
+            vec![
+                Instruction::OpGetLocal(1),
+                Instruction::OpJumpIfFalse(1),
+                Instruction::OpGetLocal(1),
+                Instruction::OpGetLocal(2),
+                Instruction::OpAdd,
+                Instruction::OpReturn,
+            ],
+            peephole(&chunk).bytecode
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use super::compiler::CompileError;
 use super::scanner::{ScannerError, Token, TokenType};
 
 pub struct Parser {
@@ -5,7 +6,8 @@ pub struct Parser {
     pub previous: Token,
     pub had_error: bool,
     pub panic_mode: bool,
-    pub error_message: String,
+    /// The latest error reported.
+    pub error: Option<CompileError>,
 }
 
 impl Parser {
@@ -21,7 +23,7 @@ impl Parser {
             previous: placeholder_token,
             had_error: false,
             panic_mode: false,
-            error_message: String::new(),
+            error: None,
         }
     }
 }
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 pub struct Scanner {
     /// The source input, as a [Vec] of [char]s.
     pub source: Vec<char>,
@@ -7,6 +9,13 @@ pub struct Scanner {
     pub current: usize,
     /// The number of the line currently being scanned.
     pub line: i32,
+    /// The names defined for conditional compilation, with their values.
+    ///
+    /// Code between `#if NAME` and the matching `#else` or `#end` is only scanned
+    /// if NAME is defined with a value other than `false` or `0`.
+    pub defines: HashMap<String, String>,
+    /// The number of `#if` directives whose `#end` has not been reached yet.
+    open_conditionals: usize,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -16,6 +25,9 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -24,10 +36,14 @@ pub enum TokenType {
     Slash,
     Star,
     // One or two character tokens.
+    QuestionDot,
+    MinusMinus,
+    PlusPlus,
     Bang,
     BangEqual,
     Equal,
     EqualEqual,
+    EqualGreater,
     Greater,
     GreaterEqual,
     Less,
@@ -38,19 +54,25 @@ pub enum TokenType {
     Number,
     // Keywords.
     And,
+    Catch,
     Class,
+    Defer,
     Else,
     False,
     For,
     Fun,
     If,
+    Is,
+    Match,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
@@ -66,6 +88,9 @@ pub enum ScannerError {
     UnexpectedCharacter,
     UnterminatedString,
     UninitializedToken,
+    InvalidDirective,
+    UnmatchedDirective,
+    UnterminatedConditional,
 }
 
 #[derive(Clone, Copy)]
@@ -86,6 +111,8 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            defines: HashMap::new(),
+            open_conditionals: 0,
         }
     }
 
@@ -94,20 +121,47 @@ impl Scanner {
         self.start = self.current;
 
         if self.is_at_end() {
+            if self.open_conditionals > 0 {
+                self.open_conditionals = 0;
+                return self.make_token(TokenType::Error(ScannerError::UnterminatedConditional));
+            }
             return self.make_token(TokenType::Eof);
         }
 
+        if self.peek() == '#' && self.at_line_start() {
+            if let Err(error) = self.directive() {
+                return self.make_token(TokenType::Error(error));
+            }
+            return self.scan_token();
+        }
+
         let c = self.advance();
-        return match c {
+        match c {
             '(' => self.make_token(TokenType::LeftParen),
             ')' => self.make_token(TokenType::RightParen),
             '{' => self.make_token(TokenType::LeftBrace),
             '}' => self.make_token(TokenType::RightBrace),
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
+            ':' => self.make_token(TokenType::Colon),
             ';' => self.make_token(TokenType::Semicolon),
             ',' => self.make_token(TokenType::Comma),
             '.' => self.make_token(TokenType::Dot),
-            '-' => self.make_token(TokenType::Minus),
-            '+' => self.make_token(TokenType::Plus),
+            '?' if self.match_char('.') => self.make_token(TokenType::QuestionDot),
+            '-' => {
+                if self.match_char('-') {
+                    self.make_token(TokenType::MinusMinus)
+                } else {
+                    self.make_token(TokenType::Minus)
+                }
+            }
+            '+' => {
+                if self.match_char('+') {
+                    self.make_token(TokenType::PlusPlus)
+                } else {
+                    self.make_token(TokenType::Plus)
+                }
+            }
             '/' => self.make_token(TokenType::Slash),
             '*' => self.make_token(TokenType::Star),
             '!' => {
@@ -120,6 +174,8 @@ impl Scanner {
             '=' => {
                 if self.match_char('=') {
                     self.make_token(TokenType::EqualEqual)
+                } else if self.match_char('>') {
+                    self.make_token(TokenType::EqualGreater)
                 } else {
                     self.make_token(TokenType::Equal)
                 }
@@ -143,7 +199,7 @@ impl Scanner {
             c if is_alpha(c) => self.identifier(),
 
             _ => self.make_token(TokenType::Error(ScannerError::UnexpectedCharacter)),
-        };
+        }
     }
 
     fn make_token(&self, token_type: TokenType) -> Token {
@@ -165,11 +221,15 @@ impl Scanner {
     }
 
     fn peek_next(&self) -> char {
-        return if self.is_at_end() {
+        if self.is_at_end() {
             '\0'
         } else {
             self.source[self.current + 1]
-        };
+        }
+    }
+
+    fn peek_after_next(&self) -> char {
+        self.source.get(self.current + 2).copied().unwrap_or('\0')
     }
 
     fn match_char(&mut self, expected: char) -> bool {
@@ -196,13 +256,9 @@ impl Scanner {
                     self.line += 1;
                     self.advance();
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while !self.is_at_end() && self.peek() != '\n' {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '/' => {
+                    while !self.is_at_end() && self.peek() != '\n' {
+                        self.advance();
                     }
                 }
                 _ => return,
@@ -234,21 +290,137 @@ impl Scanner {
     }
 
     fn number(&mut self) -> Token {
-        while is_digit(self.peek()) {
-            self.advance();
-        }
+        self.digits();
 
         if self.peek() == '.' && is_digit(self.peek_next()) {
             self.advance();
+            self.digits();
+        }
 
-            while is_digit(self.peek()) {
+        // An exponent, like in `1e7` or `2.5e-8`. A sign is only part of it when a digit follows.
+        let signed = matches!(self.peek_next(), '+' | '-') && is_digit(self.peek_after_next());
+        if matches!(self.peek(), 'e' | 'E') && (is_digit(self.peek_next()) || signed) {
+            self.advance();
+            if signed {
                 self.advance();
             }
+            self.digits();
+        }
+
+        // A number directly followed by more letters, digits or dots, like `1__0`, `1a` or `1..2`,
+        // is scanned as a single malformed literal, so that the compiler can report it as a whole.
+        while self.peek().is_alphanumeric()
+            || self.peek() == '_'
+            || (self.peek() == '.' && (is_digit(self.peek_next()) || self.peek_next() == '.'))
+        {
+            self.advance();
         }
 
         self.make_token(TokenType::Number)
     }
 
+    /// Skips digits, and the underscores separating them, like in `1_000`.
+    fn digits(&mut self) {
+        while is_digit(self.peek()) || (self.peek() == '_' && is_digit(self.peek_next())) {
+            self.advance();
+        }
+    }
+
+    /// Whether only whitespace precedes the current character on its line.
+    fn at_line_start(&self) -> bool {
+        self.source[..self.current]
+            .iter()
+            .rev()
+            .take_while(|&&c| c != '\n')
+            .all(|&c| c == ' ' || c == '\r' || c == '\t')
+    }
+
+    /// Handles a conditional compilation directive, skipping the code that is not compiled.
+    fn directive(&mut self) -> Result<(), ScannerError> {
+        let (name, argument) = self.read_directive()?;
+        match (name.as_str(), argument) {
+            ("if", Some(define)) => {
+                self.open_conditionals += 1;
+                let active = self
+                    .defines
+                    .get(&define)
+                    .is_some_and(|value| value != "false" && value != "0");
+                if !active && self.skip_conditional(true)? == "end" {
+                    self.open_conditionals -= 1;
+                }
+                Ok(())
+            }
+            // Reaching an `#else` means the `#if` branch was compiled, so the rest is skipped.
+            ("else", None) if self.open_conditionals > 0 => {
+                self.skip_conditional(false)?;
+                self.open_conditionals -= 1;
+                Ok(())
+            }
+            ("end", None) if self.open_conditionals > 0 => {
+                self.open_conditionals -= 1;
+                Ok(())
+            }
+            ("else", None) | ("end", None) => Err(ScannerError::UnmatchedDirective),
+            _ => Err(ScannerError::InvalidDirective),
+        }
+    }
+
+    /// Reads a directive line, like `#if DEBUG`, returning the directive's name and argument.
+    fn read_directive(&mut self) -> Result<(String, Option<String>), ScannerError> {
+        let mut line = String::new();
+        while !self.is_at_end() && self.peek() != '\n' {
+            line.push(self.advance());
+        }
+
+        let line = match line.find("//") {
+            Some(comment) => &line[..comment],
+            None => &line[..],
+        };
+        let mut words = line[1..].split_whitespace();
+        let name = words.next().ok_or(ScannerError::InvalidDirective)?;
+        let argument = words.next();
+        let is_name = |word: &str| word.chars().all(|c| is_alpha(c) || is_digit(c));
+        if words.next().is_some() || !argument.is_none_or(is_name) {
+            return Err(ScannerError::InvalidDirective);
+        }
+        Ok((name.to_string(), argument.map(str::to_string)))
+    }
+
+    /// Skips source code up to the `#end` matching the current `#if`, or up to its `#else`
+    /// if `stop_at_else` is set. Returns the name of the directive it stopped at.
+    fn skip_conditional(&mut self, stop_at_else: bool) -> Result<&'static str, ScannerError> {
+        let mut depth = 0;
+        loop {
+            // Move to the first character of the next line that is not whitespace.
+            while !self.is_at_end() && self.peek() != '\n' {
+                self.advance();
+            }
+            loop {
+                match self.peek() {
+                    '\n' => self.line += 1,
+                    ' ' | '\r' | '\t' => {}
+                    _ => break,
+                }
+                self.advance();
+            }
+            if self.is_at_end() {
+                return Err(ScannerError::UnterminatedConditional);
+            }
+            if self.peek() != '#' {
+                continue;
+            }
+
+            match self.read_directive()? {
+                (name, Some(_)) if name == "if" => depth += 1,
+                (name, None) if name == "end" && depth > 0 => depth -= 1,
+                (name, None) if name == "end" => return Ok("end"),
+                (name, None) if name == "else" && depth == 0 && stop_at_else => return Ok("else"),
+                (name, None) if name == "else" => {}
+                _ => return Err(ScannerError::InvalidDirective),
+            }
+        }
+    }
+
     fn identifier(&mut self) -> Token {
         while is_alpha(self.peek()) || is_digit(self.peek()) {
             self.advance();
@@ -258,11 +430,21 @@ impl Scanner {
     }
 
     fn identifier_type(&self) -> TokenType {
-        return match self.source[self.start] {
+        match self.source[self.start] {
             'a' => self.check_keyword(1, 2, "nd", TokenType::And),
-            'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
+            'c' if self.current - self.start > 1usize => match self.source[self.start + 1] {
+                'a' => self.check_keyword(2, 3, "tch", TokenType::Catch),
+                'l' => self.check_keyword(2, 3, "ass", TokenType::Class),
+                _ => TokenType::Identifier,
+            },
+            'd' => self.check_keyword(1, 4, "efer", TokenType::Defer),
             'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
-            'i' => self.check_keyword(1, 1, "f", TokenType::If),
+            'i' if self.current - self.start > 1usize => match self.source[self.start + 1] {
+                'f' => self.check_keyword(2, 0, "", TokenType::If),
+                's' => self.check_keyword(2, 0, "", TokenType::Is),
+                _ => TokenType::Identifier,
+            },
+            'm' => self.check_keyword(1, 4, "atch", TokenType::Match),
             'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
             'o' => self.check_keyword(1, 1, "r", TokenType::Or),
             'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
@@ -277,12 +459,20 @@ impl Scanner {
                 _ => TokenType::Identifier,
             },
             't' if self.current - self.start > 1usize => match self.source[self.start + 1] {
-                'h' => self.check_keyword(2, 2, "is", TokenType::This),
-                'r' => self.check_keyword(2, 2, "ue", TokenType::True),
+                'h' if self.current - self.start > 2usize => match self.source[self.start + 2] {
+                    'i' => self.check_keyword(3, 1, "s", TokenType::This),
+                    'r' => self.check_keyword(3, 2, "ow", TokenType::Throw),
+                    _ => TokenType::Identifier,
+                },
+                'r' if self.current - self.start > 2usize => match self.source[self.start + 2] {
+                    'u' => self.check_keyword(3, 1, "e", TokenType::True),
+                    'y' => self.check_keyword(3, 0, "", TokenType::Try),
+                    _ => TokenType::Identifier,
+                },
                 _ => TokenType::Identifier,
             },
             _ => TokenType::Identifier,
-        };
+        }
     }
 
     fn check_keyword(
@@ -308,11 +498,11 @@ impl Scanner {
 }
 
 fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
 fn is_alpha(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+    c.is_ascii_lowercase() || c.is_ascii_uppercase() || c == '_'
 }
 
 #[cfg(test)]
@@ -327,6 +517,26 @@ mod tests {
         assert_eq!(TokenType::Number, t.token_type);
     }
 
+    #[test]
+    fn scan_malformed_number() {
+        let source = "1..2;".chars().collect();
+        let mut sc = Scanner::init(source);
+        let t = sc.scan_token();
+        assert_eq!(TokenType::Number, t.token_type);
+        assert_eq!(4, t.length);
+        assert_eq!(TokenType::Semicolon, sc.scan_token().token_type);
+    }
+
+    #[test]
+    fn scan_exponent() {
+        let source = "2.5e-8-1e+x".chars().collect();
+        let mut sc = Scanner::init(source);
+        assert_eq!(6, sc.scan_token().length);
+        assert_eq!(TokenType::Minus, sc.scan_token().token_type);
+        assert_eq!(2, sc.scan_token().length);
+        assert_eq!(TokenType::Plus, sc.scan_token().token_type);
+    }
+
     #[test]
     fn scan_true_keyword() {
         let source = "true;".chars().collect();
@@ -343,6 +553,96 @@ mod tests {
         assert_eq!(TokenType::EqualEqual, t.token_type);
     }
 
+    #[test]
+    fn scan_increment_and_decrement() {
+        let source = "i++ - --j".chars().collect();
+        let mut sc = Scanner::init(source);
+        let types: Vec<TokenType> = (0..5).map(|_| sc.scan_token().token_type).collect();
+        assert_eq!(
+            vec![
+                TokenType::Identifier,
+                TokenType::PlusPlus,
+                TokenType::Minus,
+                TokenType::MinusMinus,
+                TokenType::Identifier,
+            ],
+            types
+        );
+    }
+
+    #[test]
+    fn scan_optional_dot() {
+        let source = "a?.b ?".chars().collect();
+        let mut sc = Scanner::init(source);
+        let types: Vec<TokenType> = (0..4).map(|_| sc.scan_token().token_type).collect();
+        assert_eq!(
+            vec![
+                TokenType::Identifier,
+                TokenType::QuestionDot,
+                TokenType::Identifier,
+                TokenType::Error(ScannerError::UnexpectedCharacter),
+            ],
+            types
+        );
+    }
+
+    #[test]
+    fn scan_exception_keywords() {
+        let source = "try throw catch this true tr throws catcher class"
+            .chars()
+            .collect();
+        let mut sc = Scanner::init(source);
+        let types: Vec<TokenType> = (0..9).map(|_| sc.scan_token().token_type).collect();
+        assert_eq!(
+            vec![
+                TokenType::Try,
+                TokenType::Throw,
+                TokenType::Catch,
+                TokenType::This,
+                TokenType::True,
+                TokenType::Identifier,
+                TokenType::Identifier,
+                TokenType::Identifier,
+                TokenType::Class,
+            ],
+            types
+        );
+    }
+
+    #[test]
+    fn scan_is() {
+        let source = "is if i isa".chars().collect();
+        let mut sc = Scanner::init(source);
+        let types: Vec<TokenType> = (0..4).map(|_| sc.scan_token().token_type).collect();
+        assert_eq!(
+            vec![
+                TokenType::Is,
+                TokenType::If,
+                TokenType::Identifier,
+                TokenType::Identifier,
+            ],
+            types
+        );
+    }
+
+    #[test]
+    fn scan_match() {
+        let source = "match _ => == = matches".chars().collect();
+        let mut sc = Scanner::init(source);
+        let types: Vec<TokenType> = (0..6).map(|_| sc.scan_token().token_type).collect();
+        assert_eq!(
+            vec![
+                TokenType::Match,
+                TokenType::Identifier,
+                TokenType::EqualGreater,
+                TokenType::EqualEqual,
+                TokenType::Equal,
+                TokenType::Identifier,
+            ],
+            types
+        );
+    }
+
     #[test]
     fn scan_string() {
         let source = "\"asda\"".chars().collect();
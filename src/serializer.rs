@@ -0,0 +1,1421 @@
+//! Serialization of compiled [Function]s, so that scripts can be compiled ahead of time.
+//!
+//! The data starts with a header: the [MAGIC] bytes, the format's major and minor version,
+//! and a bitset of the optional features the code uses, so that a reader can reject data it
+//! cannot run. Then comes the string pool, holding every distinct string the code uses,
+//! like global names, once for the whole program. Then comes the number of global variable slots
+//! and the name of each, shared by all the chunks. Then comes the top-level function,
+//! in which strings are indexes into the pool.
+//!
+//! All numbers are little-endian. A function is its name, its arity and its [Chunk].
+//! A chunk is its source name, its instructions with their lines, and its constants.
+//! Instructions are an opcode byte followed by a `u32` operand if they have one.
+//!
+//! Stripped data, written by [serialize_stripped], leaves out the names of functions, the source
+//! names of chunks and the lines of instructions. They go to a separate debug file instead, which
+//! only needs to be read to show the stack trace of a runtime error. The debug file starts with
+//! the [DEBUG_MAGIC] bytes, the format's version and the [build_id] of the stripped data it
+//! belongs to. Then comes, for every function in the order they appear in the stripped data,
+//! its name, its source name and the lines of its instructions.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, Instruction};
+use crate::value::function::Function;
+use crate::value::string::LoxString;
+use crate::value::value::Value;
+use crate::vm::vm::FRAME_SLOTS;
+
+/// The bytes every serialized file starts with.
+pub const MAGIC: [u8; 4] = *b"RLXC";
+/// The major version of the format. Data with a different major version cannot be read.
+pub const MAJOR_VERSION: u16 = 3;
+/// The minor version of the format. Data with a newer minor version cannot be read,
+/// while older minor versions only lack features that newer ones added.
+pub const MINOR_VERSION: u16 = 0;
+
+/// The code uses `defer`.
+pub const FEATURE_DEFER: u32 = 1 << 0;
+/// The code uses string slicing.
+pub const FEATURE_SLICE: u32 = 1 << 1;
+/// The code uses instructions registered as extensions, which the VM running it must register too.
+pub const FEATURE_EXTENSIONS: u32 = 1 << 2;
+/// The code declares classes or uses properties.
+pub const FEATURE_CLASSES: u32 = 1 << 3;
+/// The data is stripped: functions have no names and instructions have no lines.
+pub const FEATURE_STRIPPED: u32 = 1 << 4;
+/// The code builds or indexes lists.
+pub const FEATURE_LISTS: u32 = 1 << 5;
+/// The code uses optional chaining, `?.`.
+pub const FEATURE_OPTIONAL_CHAINING: u32 = 1 << 6;
+/// The code throws or catches values.
+pub const FEATURE_EXCEPTIONS: u32 = 1 << 7;
+/// The code declares getters or setters.
+pub const FEATURE_ACCESSORS: u32 = 1 << 8;
+/// The code declares fields with initializers.
+pub const FEATURE_FIELDS: u32 = 1 << 9;
+/// The code checks the classes of instances, with `is`.
+pub const FEATURE_IS: u32 = 1 << 10;
+/// The code reuses call frames for calls in `return` statements.
+pub const FEATURE_TAIL_CALLS: u32 = 1 << 11;
+/// The code uses `match` expressions.
+pub const FEATURE_MATCH: u32 = 1 << 12;
+/// The code uses the instructions that only the optimizer writes.
+pub const FEATURE_OPTIMIZED: u32 = 1 << 13;
+/// The code duplicates the value on top of the stack or pops several values at once.
+pub const FEATURE_STACK_OPS: u32 = 1 << 14;
+/// All the features this version can run.
+const KNOWN_FEATURES: u32 = FEATURE_DEFER
+    | FEATURE_SLICE
+    | FEATURE_EXTENSIONS
+    | FEATURE_CLASSES
+    | FEATURE_STRIPPED
+    | FEATURE_LISTS
+    | FEATURE_OPTIONAL_CHAINING
+    | FEATURE_EXCEPTIONS
+    | FEATURE_ACCESSORS
+    | FEATURE_FIELDS
+    | FEATURE_IS
+    | FEATURE_TAIL_CALLS
+    | FEATURE_MATCH
+    | FEATURE_OPTIMIZED
+    | FEATURE_STACK_OPS;
+
+/// The bytes every debug file starts with.
+pub const DEBUG_MAGIC: [u8; 4] = *b"RLXD";
+
+const NIL_TAG: u8 = 0;
+const FALSE_TAG: u8 = 1;
+const TRUE_TAG: u8 = 2;
+const NUMBER_TAG: u8 = 3;
+const STRING_TAG: u8 = 4;
+const FUNCTION_TAG: u8 = 5;
+
+/// Encodes a compiled [Function], including the functions among its constants.
+///
+/// Fails if a constant cannot be serialized, which the compiler never produces.
+pub fn serialize(function: &Function) -> Result<Vec<u8>, String> {
+    let mut writer = Writer::new(false);
+    writer.function(function)?;
+    Ok(writer.finish(features(function), &function.chunk.globals))
+}
+
+/// Encodes a compiled [Function] without its names and lines,
+/// returning the stripped data and the debug file holding what was left out.
+pub fn serialize_stripped(function: &Function) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let mut writer = Writer::new(true);
+    writer.function(function)?;
+    let symbols = std::mem::take(&mut writer.symbols);
+    let bytes = writer.finish(
+        features(function) | FEATURE_STRIPPED,
+        &function.chunk.globals,
+    );
+
+    let mut debug = DEBUG_MAGIC.to_vec();
+    debug.extend_from_slice(&MAJOR_VERSION.to_le_bytes());
+    debug.extend_from_slice(&MINOR_VERSION.to_le_bytes());
+    debug.extend_from_slice(&build_id(&bytes).to_le_bytes());
+    write_u32(&mut debug, symbols.len());
+    for symbols in &symbols {
+        for string in [symbols.name.as_str(), &symbols.source_name] {
+            write_u32(&mut debug, string.len());
+            debug.extend_from_slice(string.as_bytes());
+        }
+        write_u32(&mut debug, symbols.lines.len());
+        for line in &symbols.lines {
+            debug.extend_from_slice(&line.to_le_bytes());
+        }
+    }
+    Ok((bytes, debug))
+}
+
+/// Identifies serialized data, so that a debug file is only used with the data it was written for.
+///
+/// This is the 64-bit FNV-1a hash of the data.
+pub fn build_id(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The optional features used by a function and the functions among its constants.
+pub fn features(function: &Function) -> u32 {
+    let mut features = 0;
+    for instruction in &function.chunk.bytecode {
+        features |= match instruction {
+            Instruction::OpDefer(_) | Instruction::OpEndDefer => FEATURE_DEFER,
+            Instruction::OpSlice => FEATURE_SLICE,
+            Instruction::OpExtension(_, _) => FEATURE_EXTENSIONS,
+            Instruction::OpClass(_)
+            | Instruction::OpGetProperty(_)
+            | Instruction::OpSetProperty(_)
+            | Instruction::OpMethod(_)
+            | Instruction::OpInherit
+            | Instruction::OpGetSuper(_) => FEATURE_CLASSES,
+            Instruction::OpBuildList(_) | Instruction::OpIndexGet | Instruction::OpIndexSet => {
+                FEATURE_LISTS
+            }
+            Instruction::OpJumpIfNil(_) => FEATURE_OPTIONAL_CHAINING,
+            Instruction::OpSetupCatch(_) | Instruction::OpPopCatch | Instruction::OpThrow => {
+                FEATURE_EXCEPTIONS
+            }
+            Instruction::OpGetter(_) | Instruction::OpSetter(_) => FEATURE_ACCESSORS,
+            Instruction::OpFields => FEATURE_FIELDS,
+            Instruction::OpIs => FEATURE_IS,
+            Instruction::OpTailCall(_) => FEATURE_TAIL_CALLS,
+            Instruction::OpJumpIfNotEqual(_) => FEATURE_MATCH,
+            Instruction::OpJumpIfTrue(_) | Instruction::OpAddLocals(_, _) => FEATURE_OPTIMIZED,
+            Instruction::OpDup | Instruction::OpPopN(_) => FEATURE_STACK_OPS,
+            _ => 0,
+        };
+    }
+    for constant in &function.chunk.constants {
+        if let Value::Function(f) = constant {
+            features |= self::features(f);
+        }
+    }
+    features
+}
+
+/// Decodes a [Function] encoded by [serialize].
+///
+/// Data from an incompatible version of the format, or using unknown features,
+/// is rejected with an "Incompatible bytecode" error before any of the code is read. Code that
+/// would read outside of its constants, its code or its call frame when run, or pop more values
+/// than its call frame holds, is rejected too.
+pub fn deserialize(bytes: &[u8]) -> Result<Function, String> {
+    let mut reader = Reader {
+        bytes,
+        position: 0,
+        pool: Vec::new(),
+        globals: Rc::new(Vec::new()),
+        stripped: false,
+        function_count: 0,
+    };
+    reader.header()?;
+    reader.pool()?;
+    reader.globals()?;
+    let function = reader.function()?;
+    if reader.position != bytes.len() {
+        return Err(format!(
+            "Unexpected data after the function at byte {}.",
+            reader.position
+        ));
+    }
+    Ok(function)
+}
+
+/// The debug information of the functions of stripped data, read from its debug file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebugInfo {
+    /// The [build_id] of the stripped data.
+    pub build_id: u64,
+    /// The names and lines of the functions, indexed by [Function::symbol].
+    pub functions: Vec<FunctionSymbols>,
+}
+
+/// What stripping leaves out of a function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionSymbols {
+    pub name: String,
+    pub source_name: Rc<str>,
+    pub lines: Vec<i32>,
+}
+
+/// Decodes a debug file written by [serialize_stripped].
+pub fn deserialize_debug_info(bytes: &[u8]) -> Result<DebugInfo, String> {
+    let mut reader = Reader {
+        bytes,
+        position: 0,
+        pool: Vec::new(),
+        globals: Rc::new(Vec::new()),
+        stripped: false,
+        function_count: 0,
+    };
+    if reader.take::<4>().ok() != Some(DEBUG_MAGIC) {
+        return Err("Incompatible debug file: the data is not rlox debug information.".to_string());
+    }
+    let major = u16::from_le_bytes(reader.take()?);
+    let minor = u16::from_le_bytes(reader.take()?);
+    if major != MAJOR_VERSION || minor > MINOR_VERSION {
+        return Err(format!(
+            "Incompatible debug file: format version {}.{} is not supported, the latest is {}.{}.",
+            major, minor, MAJOR_VERSION, MINOR_VERSION
+        ));
+    }
+    let build_id = u64::from_le_bytes(reader.take()?);
+
+    let mut functions = Vec::new();
+    for _ in 0..reader.u32()? {
+        let name = reader.inline_string()?;
+        let source_name = Rc::from(reader.inline_string()?);
+        let mut lines = Vec::new();
+        for _ in 0..reader.u32()? {
+            lines.push(i32::from_le_bytes(reader.take()?));
+        }
+        functions.push(FunctionSymbols {
+            name,
+            source_name,
+            lines,
+        });
+    }
+    if reader.position != bytes.len() {
+        return Err(format!(
+            "Unexpected data after the functions at byte {}.",
+            reader.position
+        ));
+    }
+    Ok(DebugInfo {
+        build_id,
+        functions,
+    })
+}
+
+/// Checks that a debug file describes the functions of the stripped data it was read for, with a
+/// line for each of their instructions.
+pub fn check_debug_info(function: &Function, debug_info: &DebugInfo) -> Result<(), String> {
+    let mut count = 0;
+    let mut functions = vec![function];
+    while let Some(function) = functions.pop() {
+        count += 1;
+        let symbols = function
+            .symbol
+            .and_then(|symbol| debug_info.functions.get(symbol));
+        let lines = symbols.map_or(0, |symbols| symbols.lines.len());
+        if lines != function.chunk.bytecode.len() {
+            return Err(format!(
+                "Incompatible debug file: function {} has {} lines for {} instructions.",
+                function.symbol.unwrap_or(0),
+                lines,
+                function.chunk.bytecode.len()
+            ));
+        }
+        functions.extend(
+            function
+                .chunk
+                .constants
+                .iter()
+                .filter_map(|constant| match constant {
+                    Value::Function(function) => Some(function.as_ref()),
+                    _ => None,
+                }),
+        );
+    }
+    if count != debug_info.functions.len() {
+        return Err(format!(
+            "Incompatible debug file: it describes {} functions instead of {}.",
+            debug_info.functions.len(),
+            count
+        ));
+    }
+    Ok(())
+}
+
+/// Encodes functions, collecting the strings they use in the string pool.
+struct Writer {
+    bytes: Vec<u8>,
+    /// The distinct strings used so far, in the order they were first used.
+    pool: Vec<LoxString>,
+    /// The index of each string in the pool.
+    pool_indexes: HashMap<LoxString, usize>,
+    /// Whether names and lines are left out and collected in [Writer::symbols] instead.
+    strip: bool,
+    /// The names and lines of the functions encoded so far, if stripping.
+    symbols: Vec<FunctionSymbols>,
+}
+
+impl Writer {
+    fn new(strip: bool) -> Writer {
+        Writer {
+            bytes: Vec::new(),
+            pool: Vec::new(),
+            pool_indexes: HashMap::new(),
+            strip,
+            symbols: Vec::new(),
+        }
+    }
+
+    /// Returns the header, the string pool, the global slots and the encoded functions.
+    fn finish(mut self, features: u32, globals: &[String]) -> Vec<u8> {
+        let globals: Vec<usize> = globals
+            .iter()
+            .map(|name| self.intern(&LoxString::from(name.as_str())))
+            .collect();
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&MAJOR_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&MINOR_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&features.to_le_bytes());
+        write_u32(&mut bytes, self.pool.len());
+        for string in &self.pool {
+            write_u32(&mut bytes, string.len());
+            bytes.extend_from_slice(string.as_bytes());
+        }
+        write_u32(&mut bytes, globals.len());
+        for name in globals {
+            write_u32(&mut bytes, name);
+        }
+        bytes.extend_from_slice(&self.bytes);
+        bytes
+    }
+
+    fn function(&mut self, function: &Function) -> Result<(), String> {
+        if self.strip {
+            self.symbols.push(FunctionSymbols {
+                name: function.name.clone(),
+                source_name: Rc::clone(&function.chunk.source_name),
+                lines: function.chunk.lines.clone(),
+            });
+        } else {
+            self.string(&LoxString::from(function.name.as_str()));
+        }
+        write_u32(&mut self.bytes, function.arity);
+        self.chunk(&function.chunk)
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) -> Result<(), String> {
+        if !self.strip {
+            self.string(&LoxString::from(&*chunk.source_name));
+        }
+
+        write_u32(&mut self.bytes, chunk.bytecode.len());
+        for (instruction, line) in chunk.bytecode.iter().zip(&chunk.lines) {
+            let (opcode, operand) = encode_instruction(*instruction);
+            self.bytes.push(opcode);
+            if let Some(operand) = operand {
+                write_u32(&mut self.bytes, operand);
+            }
+            if !self.strip {
+                self.bytes.extend_from_slice(&line.to_le_bytes());
+            }
+        }
+
+        write_u32(&mut self.bytes, chunk.constants.len());
+        for constant in &chunk.constants {
+            match constant {
+                Value::Nil => self.bytes.push(NIL_TAG),
+                Value::Boolean(false) => self.bytes.push(FALSE_TAG),
+                Value::Boolean(true) => self.bytes.push(TRUE_TAG),
+                Value::Number(n) => {
+                    self.bytes.push(NUMBER_TAG);
+                    self.bytes.extend_from_slice(&n.to_bits().to_le_bytes());
+                }
+                Value::String(s) => {
+                    self.bytes.push(STRING_TAG);
+                    self.string(s);
+                }
+                Value::Function(f) => {
+                    self.bytes.push(FUNCTION_TAG);
+                    self.function(f)?;
+                }
+                Value::NativeFunction(f) => {
+                    return Err(format!("Cannot serialize native function '{}'.", f.name));
+                }
+                Value::Class(_)
+                | Value::Instance(_)
+                | Value::BoundMethod(_)
+                | Value::List(_)
+                | Value::HostObject(_) => {
+                    return Err(format!("Cannot serialize {}.", constant));
+                }
+                #[cfg(feature = "vector_math")]
+                Value::Vector(_) | Value::Matrix(_) => {
+                    return Err(format!("Cannot serialize {}.", constant));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the pool index of a string, adding it to the pool if it is new.
+    fn string(&mut self, string: &LoxString) {
+        let index = self.intern(string);
+        write_u32(&mut self.bytes, index);
+    }
+
+    /// Returns the pool index of a string, adding it to the pool if it is new.
+    fn intern(&mut self, string: &LoxString) -> usize {
+        match self.pool_indexes.get(string) {
+            Some(&index) => index,
+            None => {
+                self.pool.push(string.clone());
+                self.pool_indexes
+                    .insert(string.clone(), self.pool.len() - 1);
+                self.pool.len() - 1
+            }
+        }
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    bytes.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+/// The opcode of an instruction, and its operand if it has one.
+///
+/// The opcodes are part of the format: new instructions get new opcodes,
+/// and existing ones must never change. Signed operands are stored as their bits.
+fn encode_instruction(instruction: Instruction) -> (u8, Option<usize>) {
+    match instruction {
+        Instruction::OpCall(n) => (0, Some(n)),
+        Instruction::OpConstant(n) => (1, Some(n)),
+        Instruction::OpNil => (2, None),
+        Instruction::OpTrue => (3, None),
+        Instruction::OpDefineGlobal(n) => (4, Some(n)),
+        Instruction::OpDefer(n) => (5, Some(n)),
+        Instruction::OpEndDefer => (6, None),
+        Instruction::OpEqual => (7, None),
+        Instruction::OpFalse => (8, None),
+        Instruction::OpGetGlobal(n) => (9, Some(n)),
+        Instruction::OpSetGlobal(n) => (10, Some(n)),
+        Instruction::OpGetLocal(n) => (11, Some(n)),
+        Instruction::OpSetLocal(n) => (12, Some(n)),
+        Instruction::OpGreater => (13, None),
+        Instruction::OpJump(n) => (14, Some(n)),
+        Instruction::OpJumpIfFalse(n) => (15, Some(n)),
+        Instruction::OpLess => (16, None),
+        Instruction::OpLoop(n) => (17, Some(n)),
+        Instruction::OpAdd => (18, None),
+        Instruction::OpSubtract => (19, None),
+        Instruction::OpMultiply => (20, None),
+        Instruction::OpDivide => (21, None),
+        Instruction::OpPop => (22, None),
+        Instruction::OpNot => (23, None),
+        Instruction::OpNegate => (24, None),
+        Instruction::OpPrint => (25, None),
+        Instruction::OpReturn => (26, None),
+        Instruction::OpSlice => (27, None),
+        Instruction::OpZero => (28, None),
+        Instruction::OpOne => (29, None),
+        Instruction::OpSmallInt(n) => (30, Some(n as u8 as usize)),
+        Instruction::OpExtension(id, arg_count) => {
+            (31, Some(id as usize | (arg_count as usize) << 8))
+        }
+        Instruction::OpClass(n) => (32, Some(n)),
+        Instruction::OpGetProperty(n) => (33, Some(n)),
+        Instruction::OpSetProperty(n) => (34, Some(n)),
+        Instruction::OpMethod(n) => (35, Some(n)),
+        Instruction::OpInherit => (36, None),
+        Instruction::OpGetSuper(n) => (37, Some(n)),
+        Instruction::OpBuildList(n) => (38, Some(n)),
+        Instruction::OpIndexGet => (39, None),
+        Instruction::OpIndexSet => (40, None),
+        Instruction::OpJumpIfNil(n) => (41, Some(n)),
+        Instruction::OpSetupCatch(n) => (42, Some(n)),
+        Instruction::OpPopCatch => (43, None),
+        Instruction::OpThrow => (44, None),
+        Instruction::OpGetter(n) => (45, Some(n)),
+        Instruction::OpSetter(n) => (46, Some(n)),
+        Instruction::OpFields => (47, None),
+        Instruction::OpIs => (48, None),
+        Instruction::OpTailCall(n) => (49, Some(n)),
+        Instruction::OpJumpIfNotEqual(n) => (50, Some(n)),
+        Instruction::OpJumpIfTrue(n) => (51, Some(n)),
+        // The compiler allows fewer than 2^16 locals.
+        Instruction::OpAddLocals(a, b) => (52, Some(a | b << 16)),
+        Instruction::OpDup => (53, None),
+        Instruction::OpPopN(n) => (54, Some(n)),
+    }
+}
+
+/// Reads the encoded data, keeping track of the position for error messages.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    /// The string pool, shared by all the decoded chunks.
+    pool: Vec<LoxString>,
+    /// The names of the global slots, shared by all the decoded chunks.
+    globals: Rc<Vec<String>>,
+    /// Whether the header has [FEATURE_STRIPPED].
+    stripped: bool,
+    /// The number of functions decoded so far.
+    function_count: usize,
+}
+
+impl Reader<'_> {
+    fn header(&mut self) -> Result<(), String> {
+        if self.take::<4>().ok() != Some(MAGIC) {
+            return Err("Incompatible bytecode: the data is not rlox bytecode.".to_string());
+        }
+
+        let major = u16::from_le_bytes(self.take()?);
+        let minor = u16::from_le_bytes(self.take()?);
+        if major != MAJOR_VERSION || minor > MINOR_VERSION {
+            return Err(format!(
+                "Incompatible bytecode: format version {}.{} is not supported, the latest is {}.{}.",
+                major, minor, MAJOR_VERSION, MINOR_VERSION
+            ));
+        }
+
+        let features = u32::from_le_bytes(self.take()?);
+        if features & !KNOWN_FEATURES != 0 {
+            return Err(format!(
+                "Incompatible bytecode: unknown features {:#x}.",
+                features & !KNOWN_FEATURES
+            ));
+        }
+        self.stripped = features & FEATURE_STRIPPED != 0;
+        Ok(())
+    }
+
+    fn pool(&mut self) -> Result<(), String> {
+        let length = self.u32()?;
+        for _ in 0..length {
+            let string = self.inline_string()?;
+            self.pool.push(LoxString::from(string));
+        }
+        Ok(())
+    }
+
+    fn globals(&mut self) -> Result<(), String> {
+        let length = self.u32()?;
+        let mut globals = Vec::new();
+        for _ in 0..length {
+            globals.push(self.string()?.to_string());
+        }
+        self.globals = Rc::new(globals);
+        Ok(())
+    }
+
+    fn function(&mut self) -> Result<Function, String> {
+        let symbol = self.function_count;
+        self.function_count += 1;
+        // Stripped functions are named after their symbol, except for the top-level function.
+        let (name, symbol) = match (self.stripped, symbol) {
+            (false, _) => (self.string()?.to_string(), None),
+            (true, 0) => (String::new(), Some(symbol)),
+            (true, _) => (format!("#{}", symbol), Some(symbol)),
+        };
+        let arity = self.u32()?;
+        let chunk = self.chunk(arity)?;
+        Ok(Function {
+            arity,
+            chunk,
+            name,
+            symbol,
+        })
+    }
+
+    fn chunk(&mut self, arity: usize) -> Result<Chunk, String> {
+        let mut chunk = Chunk::new();
+        chunk.globals = Rc::clone(&self.globals);
+        if !self.stripped {
+            chunk.source_name = Rc::from(&*self.string()?);
+        }
+
+        let code_start = self.position;
+        let length = self.u32()?;
+        let mut starts = Vec::new();
+        for _ in 0..length {
+            starts.push(self.position);
+            let instruction = self.instruction()?;
+            let line = if self.stripped {
+                0
+            } else {
+                i32::from_le_bytes(self.take()?)
+            };
+            chunk.write(instruction, line);
+        }
+
+        let length = self.u32()?;
+        for _ in 0..length {
+            let constant = match self.byte()? {
+                NIL_TAG => Value::Nil,
+                FALSE_TAG => Value::Boolean(false),
+                TRUE_TAG => Value::Boolean(true),
+                NUMBER_TAG => Value::Number(f64::from_bits(u64::from_le_bytes(self.take()?))),
+                STRING_TAG => Value::String(self.string()?),
+                FUNCTION_TAG => Value::Function(Rc::new(self.function()?)),
+                tag => return Err(self.error(&format!("Unknown constant tag {}", tag))),
+            };
+            chunk.add_constant(constant);
+        }
+        check_operands(&chunk, code_start, &starts)?;
+        check_stack(&chunk, arity, &starts)?;
+        Ok(chunk)
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, String> {
+        let start = self.position;
+        let opcode = self.byte()?;
+        let instruction = match opcode {
+            2 => Instruction::OpNil,
+            3 => Instruction::OpTrue,
+            6 => Instruction::OpEndDefer,
+            7 => Instruction::OpEqual,
+            8 => Instruction::OpFalse,
+            13 => Instruction::OpGreater,
+            16 => Instruction::OpLess,
+            18 => Instruction::OpAdd,
+            19 => Instruction::OpSubtract,
+            20 => Instruction::OpMultiply,
+            21 => Instruction::OpDivide,
+            22 => Instruction::OpPop,
+            23 => Instruction::OpNot,
+            24 => Instruction::OpNegate,
+            25 => Instruction::OpPrint,
+            26 => Instruction::OpReturn,
+            27 => Instruction::OpSlice,
+            28 => Instruction::OpZero,
+            29 => Instruction::OpOne,
+            30 => Instruction::OpSmallInt(self.u32()? as u8 as i8),
+            31 => {
+                let operand = self.u32()?;
+                Instruction::OpExtension(operand as u8, (operand >> 8) as u8)
+            }
+            36 => Instruction::OpInherit,
+            39 => Instruction::OpIndexGet,
+            40 => Instruction::OpIndexSet,
+            43 => Instruction::OpPopCatch,
+            44 => Instruction::OpThrow,
+            47 => Instruction::OpFields,
+            48 => Instruction::OpIs,
+            53 => Instruction::OpDup,
+            52 => {
+                let operand = self.u32()?;
+                Instruction::OpAddLocals(operand & 0xffff, operand >> 16)
+            }
+            _ => {
+                let with_operand: fn(usize) -> Instruction = match opcode {
+                    0 => Instruction::OpCall,
+                    1 => Instruction::OpConstant,
+                    4 => Instruction::OpDefineGlobal,
+                    5 => Instruction::OpDefer,
+                    9 => Instruction::OpGetGlobal,
+                    10 => Instruction::OpSetGlobal,
+                    11 => Instruction::OpGetLocal,
+                    12 => Instruction::OpSetLocal,
+                    14 => Instruction::OpJump,
+                    15 => Instruction::OpJumpIfFalse,
+                    17 => Instruction::OpLoop,
+                    32 => Instruction::OpClass,
+                    33 => Instruction::OpGetProperty,
+                    34 => Instruction::OpSetProperty,
+                    35 => Instruction::OpMethod,
+                    37 => Instruction::OpGetSuper,
+                    38 => Instruction::OpBuildList,
+                    41 => Instruction::OpJumpIfNil,
+                    42 => Instruction::OpSetupCatch,
+                    45 => Instruction::OpGetter,
+                    46 => Instruction::OpSetter,
+                    49 => Instruction::OpTailCall,
+                    50 => Instruction::OpJumpIfNotEqual,
+                    51 => Instruction::OpJumpIfTrue,
+                    54 => Instruction::OpPopN,
+                    _ => return Err(self.error(&format!("Unknown opcode {}", opcode))),
+                };
+                with_operand(self.u32()?)
+            }
+        };
+        if let Instruction::OpDefineGlobal(slot)
+        | Instruction::OpGetGlobal(slot)
+        | Instruction::OpSetGlobal(slot) = instruction
+        {
+            if slot >= self.globals.len() {
+                return Err(format!("Unknown global {} at byte {}.", slot, start));
+            }
+        }
+        Ok(instruction)
+    }
+
+    /// Reads a string as an index into the pool.
+    fn string(&mut self) -> Result<LoxString, String> {
+        let start = self.position;
+        let index = self.u32()?;
+        match self.pool.get(index) {
+            Some(string) => Ok(string.clone()),
+            None => Err(format!("Unknown string {} at byte {}.", index, start)),
+        }
+    }
+
+    /// Reads a string stored with its length rather than as an index into the pool.
+    fn inline_string(&mut self) -> Result<String, String> {
+        let length = self.u32()?;
+        let start = self.position;
+        let bytes = self.slice(length)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| format!("Invalid UTF-8 in string at byte {}.", start))
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        Ok(u32::from_le_bytes(self.take()?) as usize)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.slice(N)?);
+        Ok(array)
+    }
+
+    fn slice(&mut self, length: usize) -> Result<&[u8], String> {
+        if self.bytes.len() - self.position < length {
+            return Err("Unexpected end of data.".to_string());
+        }
+        let slice = &self.bytes[self.position..self.position + length];
+        self.position += length;
+        Ok(slice)
+    }
+
+    /// Formats an error about the data just before the current position.
+    fn error(&self, message: &str) -> String {
+        format!("{} at byte {}.", message, self.position - 1)
+    }
+}
+
+/// Checks that the operands of a chunk's instructions are in range, so that running it
+/// can't read past its constants, its code or the [FRAME_SLOTS] of its call frame, and that
+/// it ends with a return instead of running past the end of its code.
+///
+/// `code_start` is the position of the code in the data, and `starts` that of each instruction.
+fn check_operands(chunk: &Chunk, code_start: usize, starts: &[usize]) -> Result<(), String> {
+    let length = chunk.bytecode.len();
+    for (ip, (&instruction, &start)) in chunk.bytecode.iter().zip(starts).enumerate() {
+        // Jumps are relative to the next instruction.
+        let next = ip + 1;
+        let error = match instruction {
+            Instruction::OpConstant(index) if index >= chunk.constants.len() => {
+                format!("Unknown constant {}", index)
+            }
+            Instruction::OpClass(index)
+            | Instruction::OpGetProperty(index)
+            | Instruction::OpSetProperty(index)
+            | Instruction::OpMethod(index)
+            | Instruction::OpGetSuper(index)
+            | Instruction::OpGetter(index)
+            | Instruction::OpSetter(index)
+                if !matches!(chunk.constants.get(index), Some(Value::String(_))) =>
+            {
+                format!("Constant {} is not a name", index)
+            }
+            Instruction::OpJump(offset)
+            | Instruction::OpJumpIfFalse(offset)
+            | Instruction::OpJumpIfNil(offset)
+            | Instruction::OpJumpIfTrue(offset)
+            | Instruction::OpJumpIfNotEqual(offset)
+            | Instruction::OpSetupCatch(offset)
+            | Instruction::OpDefer(offset)
+                if next + offset >= length =>
+            {
+                format!("Jump past the end of the code by {}", offset)
+            }
+            Instruction::OpLoop(offset) if offset > next => {
+                format!("Loop before the start of the code by {}", offset)
+            }
+            Instruction::OpGetLocal(slot) | Instruction::OpSetLocal(slot)
+                if slot >= FRAME_SLOTS =>
+            {
+                format!("Unknown local {}", slot)
+            }
+            Instruction::OpAddLocals(a, b) if a.max(b) >= FRAME_SLOTS => {
+                format!("Unknown local {}", a.max(b))
+            }
+            _ => continue,
+        };
+        return Err(format!("{} at byte {}.", error, start));
+    }
+    if chunk.bytecode.last() != Some(&Instruction::OpReturn) {
+        return Err(format!(
+            "Missing return at the end of the code at byte {}.",
+            starts.last().copied().unwrap_or(code_start)
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that no instruction of a chunk, whose operands are in range, pops more values than
+/// its call frame holds, which starts with the function and its `arity` arguments.
+///
+/// Follows every path through the code, keeping the fewest values the frame can hold when each
+/// instruction runs. Deferred code starts with only the return value or the thrown value
+/// known to be there, and a catch block with the values there when its try block started and
+/// the thrown value.
+fn check_stack(chunk: &Chunk, arity: usize, starts: &[usize]) -> Result<(), String> {
+    let mut heights: Vec<Option<usize>> = vec![None; chunk.bytecode.len()];
+    let mut pending = vec![(0, arity + 1)];
+    while let Some((ip, height)) = pending.pop() {
+        if heights[ip].is_some_and(|known| known <= height) {
+            continue;
+        }
+        heights[ip] = Some(height);
+
+        let instruction = chunk.bytecode[ip];
+        // Jumps are relative to the next instruction.
+        let next = ip + 1;
+        let (pops, successors) = match instruction {
+            Instruction::OpJump(offset) => (0, vec![(next + offset, height)]),
+            Instruction::OpLoop(offset) => (0, vec![(next - offset, height)]),
+            Instruction::OpJumpIfFalse(offset)
+            | Instruction::OpJumpIfTrue(offset)
+            | Instruction::OpJumpIfNil(offset) => {
+                (1, vec![(next, height), (next + offset, height)])
+            }
+            Instruction::OpJumpIfNotEqual(offset) => {
+                let height = height.saturating_sub(1);
+                (2, vec![(next, height), (next + offset, height)])
+            }
+            Instruction::OpDefer(offset) => (0, vec![(next, 1), (next + offset, height)]),
+            Instruction::OpSetupCatch(offset) => {
+                (0, vec![(next, height), (next + offset, height + 1)])
+            }
+            Instruction::OpPopCatch => (0, vec![(next, height)]),
+            Instruction::OpThrow | Instruction::OpReturn => (1, Vec::new()),
+            // The deferred value, and the return value or the thrown value under it.
+            Instruction::OpEndDefer => (2, Vec::new()),
+            instruction => {
+                let (pops, pushes) = instruction
+                    .stack_effect()
+                    .expect("only control flow has no stack effect");
+                (pops, vec![(next, (height + pushes).saturating_sub(pops))])
+            }
+        };
+        if pops > height {
+            return Err(format!(
+                "Stack underflow by {} at byte {}.",
+                pops - height,
+                starts[ip]
+            ));
+        }
+        pending.extend(successors);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every instruction, with distinct operands. Adding an instruction without
+    /// giving it an opcode fails to compile in [encode_instruction].
+    fn all_instructions() -> Vec<Instruction> {
+        vec![
+            Instruction::OpCall(1),
+            Instruction::OpConstant(2),
+            Instruction::OpTrue,
+            Instruction::OpDefineGlobal(3),
+            Instruction::OpDefer(4),
+            // The deferred code starts with only the return value on the stack.
+            Instruction::OpNil,
+            Instruction::OpEndDefer,
+            Instruction::OpEqual,
+            Instruction::OpFalse,
+            Instruction::OpGetGlobal(5),
+            Instruction::OpSetGlobal(6),
+            Instruction::OpGetLocal(7),
+            Instruction::OpSetLocal(8),
+            Instruction::OpGreater,
+            Instruction::OpJump(9),
+            Instruction::OpJumpIfFalse(10),
+            Instruction::OpLess,
+            Instruction::OpLoop(11),
+            Instruction::OpAdd,
+            Instruction::OpSubtract,
+            Instruction::OpMultiply,
+            Instruction::OpDivide,
+            Instruction::OpPop,
+            Instruction::OpNot,
+            Instruction::OpNegate,
+            Instruction::OpPrint,
+            Instruction::OpReturn,
+            Instruction::OpSlice,
+            Instruction::OpZero,
+            Instruction::OpOne,
+            Instruction::OpSmallInt(-12),
+            Instruction::OpExtension(13, 14),
+            Instruction::OpClass(15),
+            Instruction::OpGetProperty(16),
+            Instruction::OpSetProperty(17),
+            Instruction::OpMethod(18),
+            Instruction::OpInherit,
+            Instruction::OpGetSuper(19),
+            Instruction::OpBuildList(20),
+            Instruction::OpIndexGet,
+            Instruction::OpIndexSet,
+            Instruction::OpJumpIfNil(21),
+            Instruction::OpSetupCatch(22),
+            Instruction::OpPopCatch,
+            Instruction::OpThrow,
+            Instruction::OpGetter(23),
+            Instruction::OpSetter(24),
+            Instruction::OpFields,
+            Instruction::OpIs,
+            Instruction::OpTailCall(25),
+            Instruction::OpJumpIfNotEqual(26),
+            Instruction::OpJumpIfTrue(27),
+            Instruction::OpAddLocals(28, 29),
+            Instruction::OpDup,
+            Instruction::OpPopN(30),
+        ]
+    }
+
+    /// A function that only returns, the shortest valid code.
+    fn empty() -> Function {
+        let mut function = Function::new();
+        function.chunk.write(Instruction::OpReturn, 1);
+        function
+    }
+
+    #[test]
+    fn every_instruction_round_trips() {
+        let mut function = Function::new();
+        // Enough arguments that no instruction pops more values than the frame holds.
+        function.arity = 200;
+        function.chunk.globals = Rc::new((0..7).map(|slot| format!("g{}", slot)).collect());
+        for index in 0..25 {
+            function
+                .chunk
+                .add_constant(Value::String(LoxString::from(format!("c{}", index))));
+        }
+        for (line, instruction) in all_instructions().into_iter().enumerate() {
+            function.chunk.write(instruction, line as i32);
+        }
+        // The last jumps land on these.
+        for _ in 0..30 {
+            function.chunk.write(Instruction::OpReturn, 0);
+        }
+        let decoded = deserialize(&serialize(&function).unwrap()).unwrap();
+        assert_eq!(function.chunk.bytecode, decoded.chunk.bytecode);
+        assert_eq!(function.chunk.lines, decoded.chunk.lines);
+    }
+
+    #[test]
+    fn opcodes_are_unique() {
+        let mut opcodes: Vec<u8> = all_instructions()
+            .into_iter()
+            .map(|instruction| encode_instruction(instruction).0)
+            .collect();
+        opcodes.sort();
+        opcodes.dedup();
+        assert_eq!(all_instructions().len(), opcodes.len());
+    }
+
+    #[test]
+    fn constants_round_trip() {
+        let mut inner = Function::new();
+        inner.name = "inner".to_string();
+        inner.arity = 2;
+        inner.chunk.add_constant(Value::Number(-0.5));
+        inner.chunk.write(Instruction::OpReturn, 1);
+
+        let mut function = empty();
+        function.chunk.source_name = Rc::from("main.lox");
+        for constant in [
+            Value::Nil,
+            Value::Boolean(true),
+            Value::Boolean(false),
+            Value::Number(0.25),
+            Value::String(LoxString::from("héllo")),
+            Value::Function(Rc::new(inner)),
+        ] {
+            function.chunk.add_constant(constant);
+        }
+
+        let bytes = serialize(&function).unwrap();
+        let decoded = deserialize(&bytes).unwrap();
+        assert_eq!("main.lox", &*decoded.chunk.source_name);
+        assert_eq!(
+            "[nil, true, false, 0.25, héllo, <fn inner>]",
+            format!(
+                "[{}]",
+                decoded
+                    .chunk
+                    .constants
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        );
+        assert_eq!(bytes, serialize(&decoded).unwrap());
+    }
+
+    #[test]
+    fn malformed_data() {
+        let bytes = serialize(&empty()).unwrap();
+        assert_eq!(
+            Err("Unexpected end of data.".to_string()),
+            deserialize(&bytes[..bytes.len() - 1]).map(|_| ())
+        );
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            Err(format!(
+                "Unexpected data after the function at byte {}.",
+                bytes.len()
+            )),
+            deserialize(&trailing).map(|_| ())
+        );
+
+        let mut function = Function::new();
+        function.chunk.write(Instruction::OpNil, 1);
+        function.chunk.write(Instruction::OpReturn, 1);
+        let mut bytes = serialize(&function).unwrap();
+        // The opcode follows the header, the pool with the empty string, the empty global
+        // slots, the name, the arity, the source name and the length.
+        bytes[40] = 200;
+        assert_eq!(
+            Err("Unknown opcode 200 at byte 40.".to_string()),
+            deserialize(&bytes).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn invalid_operands() {
+        let check = |instructions: &[Instruction]| {
+            let mut function = Function::new();
+            function.chunk.add_constant(Value::Number(1.0));
+            function
+                .chunk
+                .add_constant(Value::String(LoxString::from("name")));
+            for &instruction in instructions {
+                function.chunk.write(instruction, 1);
+            }
+            deserialize(&serialize(&function).unwrap()).map(|_| ())
+        };
+
+        // The first instruction follows the header, the pool with the empty string and "name",
+        // the empty global slots, the name, the arity, the source name and the length. Each
+        // instruction takes 5 bytes with its line, and 4 more with an operand.
+        let valid = [Instruction::OpConstant(0), Instruction::OpReturn];
+        assert_eq!(Ok(()), check(&valid));
+        assert_eq!(
+            Err("Unknown constant 9 at byte 48.".to_string()),
+            check(&[Instruction::OpConstant(9), Instruction::OpReturn])
+        );
+        assert_eq!(
+            Err("Constant 0 is not a name at byte 48.".to_string()),
+            check(&[Instruction::OpGetProperty(0), Instruction::OpReturn])
+        );
+        assert_eq!(
+            Err("Jump past the end of the code by 1 at byte 53.".to_string()),
+            check(&[
+                Instruction::OpNil,
+                Instruction::OpJump(1),
+                Instruction::OpReturn
+            ])
+        );
+        assert_eq!(
+            Err("Loop before the start of the code by 3 at byte 53.".to_string()),
+            check(&[
+                Instruction::OpNil,
+                Instruction::OpLoop(3),
+                Instruction::OpReturn
+            ])
+        );
+        assert_eq!(
+            Err("Unknown local 256 at byte 48.".to_string()),
+            check(&[Instruction::OpGetLocal(256), Instruction::OpReturn])
+        );
+        assert_eq!(
+            Err("Unknown local 300 at byte 48.".to_string()),
+            check(&[Instruction::OpAddLocals(1, 300), Instruction::OpReturn])
+        );
+        assert_eq!(
+            Err("Missing return at the end of the code at byte 48.".to_string()),
+            check(&[Instruction::OpNil])
+        );
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let check = |instructions: &[Instruction]| {
+            let mut function = Function::new();
+            for &instruction in instructions {
+                function.chunk.write(instruction, 1);
+            }
+            deserialize(&serialize(&function).unwrap()).map(|_| ())
+        };
+
+        // The first instruction follows the header, the pool with the empty string, the empty
+        // global slots, the name, the arity, the source name and the length. Each instruction
+        // takes 5 bytes with its line, and 4 more with an operand. The frame starts with the
+        // function.
+        let valid = [
+            Instruction::OpNil,
+            Instruction::OpPopN(2),
+            Instruction::OpNil,
+            Instruction::OpReturn,
+        ];
+        assert_eq!(Ok(()), check(&valid));
+        assert_eq!(
+            Err("Stack underflow by 999 at byte 40.".to_string()),
+            check(&[
+                Instruction::OpPopN(1000),
+                Instruction::OpNil,
+                Instruction::OpReturn
+            ])
+        );
+        assert_eq!(
+            Err("Stack underflow by 1 at byte 45.".to_string()),
+            check(&[
+                Instruction::OpPop,
+                Instruction::OpPop,
+                Instruction::OpNil,
+                Instruction::OpReturn
+            ])
+        );
+        assert_eq!(
+            Err("Stack underflow by 3 at byte 45.".to_string()),
+            check(&[
+                Instruction::OpNil,
+                Instruction::OpCall(4),
+                Instruction::OpReturn
+            ])
+        );
+        // Only the path that skips the push underflows.
+        assert_eq!(
+            Err("Stack underflow by 1 at byte 59.".to_string()),
+            check(&[
+                Instruction::OpTrue,
+                Instruction::OpJumpIfFalse(1),
+                Instruction::OpNil,
+                Instruction::OpPopN(3),
+                Instruction::OpNil,
+                Instruction::OpReturn
+            ])
+        );
+    }
+
+    #[test]
+    fn header() {
+        let mut function = Function::new();
+        function.chunk.write(Instruction::OpSlice, 1);
+        let bytes = serialize(&function).unwrap();
+        assert_eq!(b"RLXC", &bytes[..4]);
+        assert_eq!([3, 0, 0, 0], bytes[4..8]);
+        assert_eq!(FEATURE_SLICE.to_le_bytes(), bytes[8..12]);
+    }
+
+    #[test]
+    fn nested_features() {
+        let mut inner = Function::new();
+        inner.chunk.write(Instruction::OpDefer(0), 1);
+        let mut function = Function::new();
+        function.chunk.add_constant(Value::Function(Rc::new(inner)));
+        assert_eq!(FEATURE_DEFER, features(&function));
+        assert_eq!(0, features(&Function::new()));
+    }
+
+    #[test]
+    fn incompatible() {
+        let bytes = serialize(&empty()).unwrap();
+        let with = |index: usize, byte: u8| {
+            let mut bytes = bytes.clone();
+            bytes[index] = byte;
+            deserialize(&bytes).map(|_| ())
+        };
+
+        assert_eq!(
+            Err("Incompatible bytecode: the data is not rlox bytecode.".to_string()),
+            with(0, b'X')
+        );
+        assert_eq!(
+            Err("Incompatible bytecode: the data is not rlox bytecode.".to_string()),
+            deserialize(b"RL").map(|_| ())
+        );
+        assert_eq!(
+            Err(
+                "Incompatible bytecode: format version 4.0 is not supported, the latest is 3.0."
+                    .to_string()
+            ),
+            with(4, 4)
+        );
+        assert_eq!(
+            Err(
+                "Incompatible bytecode: format version 3.1 is not supported, the latest is 3.0."
+                    .to_string()
+            ),
+            with(6, 1)
+        );
+        assert_eq!(
+            Err("Incompatible bytecode: unknown features 0x80000000.".to_string()),
+            with(11, 0x80)
+        );
+    }
+
+    #[test]
+    fn global_slots() {
+        let globals = Rc::new(vec!["counter".to_string(), "step".to_string()]);
+        let mut inner = Function::new();
+        inner.chunk.globals = Rc::clone(&globals);
+        inner.chunk.write(Instruction::OpGetGlobal(1), 1);
+        inner.chunk.write(Instruction::OpReturn, 1);
+        let mut function = Function::new();
+        function.chunk.globals = globals;
+        function
+            .chunk
+            .add_constant(Value::String(LoxString::from("counter")));
+        function.chunk.add_constant(Value::Function(Rc::new(inner)));
+        function.chunk.write(Instruction::OpGetGlobal(0), 1);
+        function.chunk.write(Instruction::OpReturn, 1);
+
+        let bytes = serialize(&function).unwrap();
+        assert_eq!(1, bytes.windows(7).filter(|w| *w == b"counter").count());
+        let decoded = deserialize(&bytes).unwrap();
+        assert_eq!(vec!["counter", "step"], *decoded.chunk.globals);
+        match &decoded.chunk.constants[1] {
+            Value::Function(f) => assert!(Rc::ptr_eq(&decoded.chunk.globals, &f.chunk.globals)),
+            constant => panic!("expected a function, got {:?}", constant),
+        }
+
+        let mut function = Function::new();
+        function.chunk.write(Instruction::OpSetGlobal(0), 1);
+        let bytes = serialize(&function).unwrap();
+        // The instruction follows the header, the pool with the empty string, the empty global
+        // slots, the name, the arity, the source name and the length.
+        assert_eq!(
+            Err("Unknown global 0 at byte 40.".to_string()),
+            deserialize(&bytes).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn strings_are_pooled() {
+        let name = LoxString::from("counter");
+        let mut inner = empty();
+        inner.name = "counter".to_string();
+        inner.chunk.add_constant(Value::String(name.clone()));
+        let mut function = empty();
+        function.chunk.add_constant(Value::String(name.clone()));
+        function.chunk.add_constant(Value::Function(Rc::new(inner)));
+
+        let bytes = serialize(&function).unwrap();
+        let occurrences = bytes.windows(7).filter(|w| *w == b"counter").count();
+        assert_eq!(1, occurrences);
+
+        let decoded = deserialize(&bytes).unwrap();
+        let inner = match &decoded.chunk.constants[1] {
+            Value::Function(f) => f,
+            constant => panic!("expected a function, got {:?}", constant),
+        };
+        match (&decoded.chunk.constants[0], &inner.chunk.constants[0]) {
+            (Value::String(a), Value::String(b)) => assert!(LoxString::ptr_eq(a, b)),
+            constants => panic!("expected strings, got {:?}", constants),
+        }
+    }
+
+    #[test]
+    fn stripped() {
+        let mut inner = Function::new();
+        inner.name = "inner".to_string();
+        inner.chunk.source_name = Rc::from("lib.lox");
+        inner.chunk.write(Instruction::OpReturn, 7);
+        let mut function = Function::new();
+        function.chunk.source_name = Rc::from("main.lox");
+        function.chunk.add_constant(Value::Function(Rc::new(inner)));
+        function.chunk.write(Instruction::OpConstant(0), 3);
+        function.chunk.write(Instruction::OpReturn, 4);
+
+        let (bytes, debug) = serialize_stripped(&function).unwrap();
+        assert_eq!(FEATURE_STRIPPED.to_le_bytes(), bytes[8..12]);
+        assert_eq!(0, bytes.windows(5).filter(|w| *w == b"inner").count());
+
+        let decoded = deserialize(&bytes).unwrap();
+        assert_eq!(Some(0), decoded.symbol);
+        assert_eq!("", decoded.name);
+        assert_eq!(function.chunk.bytecode, decoded.chunk.bytecode);
+        assert_eq!(vec![0, 0], decoded.chunk.lines);
+        match &decoded.chunk.constants[0] {
+            Value::Function(f) => {
+                assert_eq!(Some(1), f.symbol);
+                assert_eq!("#1", f.name);
+            }
+            constant => panic!("expected a function, got {:?}", constant),
+        }
+
+        let debug_info = deserialize_debug_info(&debug).unwrap();
+        assert_eq!(build_id(&bytes), debug_info.build_id);
+        assert_eq!(
+            vec![
+                FunctionSymbols {
+                    name: String::new(),
+                    source_name: Rc::from("main.lox"),
+                    lines: vec![3, 4],
+                },
+                FunctionSymbols {
+                    name: "inner".to_string(),
+                    source_name: Rc::from("lib.lox"),
+                    lines: vec![7],
+                },
+            ],
+            debug_info.functions
+        );
+    }
+
+    #[test]
+    fn debug_info_must_match() {
+        let mut inner = empty();
+        inner.name = "inner".to_string();
+        let mut function = empty();
+        function.chunk.add_constant(Value::Function(Rc::new(inner)));
+        let (bytes, debug) = serialize_stripped(&function).unwrap();
+        let decoded = deserialize(&bytes).unwrap();
+        let debug_info = deserialize_debug_info(&debug).unwrap();
+        assert_eq!(Ok(()), check_debug_info(&decoded, &debug_info));
+
+        let mut missing = debug_info.clone();
+        missing.functions.pop();
+        assert_eq!(
+            Err("Incompatible debug file: function 1 has 0 lines for 1 instructions.".to_string()),
+            check_debug_info(&decoded, &missing)
+        );
+        let mut extra = debug_info.clone();
+        extra.functions.push(debug_info.functions[1].clone());
+        assert_eq!(
+            Err("Incompatible debug file: it describes 3 functions instead of 2.".to_string()),
+            check_debug_info(&decoded, &extra)
+        );
+        let mut short = debug_info;
+        short.functions[0].lines.clear();
+        assert_eq!(
+            Err("Incompatible debug file: function 0 has 0 lines for 1 instructions.".to_string()),
+            check_debug_info(&decoded, &short)
+        );
+    }
+
+    #[test]
+    fn stripped_operands_are_checked() {
+        let mut function = Function::new();
+        function
+            .chunk
+            .write(Instruction::OpGetLocal(FRAME_SLOTS), 1);
+        function.chunk.write(Instruction::OpReturn, 1);
+        let (bytes, _) = serialize_stripped(&function).unwrap();
+        // Stripped data has no name, source name or lines, so the instruction follows the
+        // header, the pool with the empty string, the empty global slots, the arity and the
+        // length.
+        assert_eq!(
+            Err("Unknown local 256 at byte 28.".to_string()),
+            deserialize(&bytes).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn malformed_debug_info() {
+        let (_, debug) = serialize_stripped(&empty()).unwrap();
+        assert_eq!(
+            Err("Unexpected end of data.".to_string()),
+            deserialize_debug_info(&debug[..debug.len() - 1]).map(|_| ())
+        );
+        assert_eq!(
+            Err("Incompatible debug file: the data is not rlox debug information.".to_string()),
+            deserialize_debug_info(b"RLXC").map(|_| ())
+        );
+    }
+
+    #[test]
+    fn unknown_string() {
+        let mut bytes = serialize(&empty()).unwrap();
+        // The name's index follows the header, the pool with the empty string and the empty
+        // global slots.
+        bytes[24] = 9;
+        assert_eq!(
+            Err("Unknown string 9 at byte 24.".to_string()),
+            deserialize(&bytes).map(|_| ())
+        );
+    }
+}
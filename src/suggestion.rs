@@ -0,0 +1,137 @@
+use crate::diagnostic::{Catalog, Diagnostic};
+
+/// The reserved words of Lox, suggested when a misspelled one is read as a name.
+pub const KEYWORDS: [&str; 22] = [
+    "and", "catch", "class", "defer", "else", "false", "for", "fun", "if", "is", "match", "nil",
+    "or", "print", "return", "super", "this", "throw", "true", "try", "var", "while",
+];
+
+/// Finds the candidate closest to a name that could not be resolved, to suggest as a correction.
+///
+/// Names shorter than 3 characters get no suggestion, since nearly everything is close to them.
+/// Longer names allow one edit per 3 characters. Ties go to the alphabetically first candidate,
+/// so that the suggestion does not depend on the order of the candidates.
+pub fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let length = name.chars().count();
+    if length < 3 {
+        return None;
+    }
+    let max_distance = (length / 3).max(1);
+
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Formats the hint appended to a diagnostic about an unresolved name, if there is a suggestion.
+pub fn did_you_mean<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+    catalog: &Catalog,
+) -> String {
+    match suggest(name, candidates) {
+        Some(suggestion) => format!(
+            " {}",
+            catalog.message(Diagnostic::DidYouMean, &[suggestion])
+        ),
+        None => String::new(),
+    }
+}
+
+/// The number of single character insertions, deletions, substitutions and
+/// transpositions of adjacent characters needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // distances[i][j] is the distance between the first i characters of a and the first j of b.
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::{Scanner, TokenType};
+
+    #[test]
+    fn distance() {
+        assert_eq!(0, edit_distance("print", "print"));
+        assert_eq!(1, edit_distance("pritn", "print"));
+        assert_eq!(1, edit_distance("count", "coun"));
+        assert_eq!(3, edit_distance("abc", "xyz"));
+    }
+
+    #[test]
+    fn suggests_closest() {
+        assert_eq!(Some("print"), suggest("pritn", KEYWORDS));
+        assert_eq!(Some("count"), suggest("cuont", ["amount", "count"]));
+        assert_eq!(None, suggest("banana", ["count"]));
+    }
+
+    /// The keywords are those of the scanner, in the order of the keyword section of [TokenType].
+    #[test]
+    fn keywords_match_scanner() {
+        let mut scanner = Scanner::init(KEYWORDS.join(" ").chars().collect());
+        let types: Vec<TokenType> = KEYWORDS
+            .iter()
+            .map(|_| scanner.scan_token().token_type)
+            .collect();
+        assert_eq!(
+            vec![
+                TokenType::And,
+                TokenType::Catch,
+                TokenType::Class,
+                TokenType::Defer,
+                TokenType::Else,
+                TokenType::False,
+                TokenType::For,
+                TokenType::Fun,
+                TokenType::If,
+                TokenType::Is,
+                TokenType::Match,
+                TokenType::Nil,
+                TokenType::Or,
+                TokenType::Print,
+                TokenType::Return,
+                TokenType::Super,
+                TokenType::This,
+                TokenType::Throw,
+                TokenType::True,
+                TokenType::Try,
+                TokenType::Var,
+                TokenType::While,
+            ],
+            types
+        );
+        assert_eq!(TokenType::Eof, scanner.scan_token().token_type);
+    }
+
+    #[test]
+    fn no_suggestion_for_short_names() {
+        assert_eq!(None, suggest("b", ["a"]));
+    }
+}
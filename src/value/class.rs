@@ -0,0 +1,88 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::{function::Function, string::LoxString, value::Value};
+
+/// The runtime representation of a class, created by a class declaration.
+#[derive(Debug, Clone)]
+pub struct Class {
+    /// The class's name.
+    pub name: LoxString,
+    /// The class's methods, by name, added by [crate::chunk::Instruction::OpMethod]
+    /// right after the class is created, including the ones inherited from its superclass.
+    pub methods: RefCell<HashMap<LoxString, Method>>,
+    /// The class's getters, by property name, added by [crate::chunk::Instruction::OpGetter],
+    /// including the inherited ones like [Class::methods].
+    pub getters: RefCell<HashMap<LoxString, Method>>,
+    /// The class's setters, by property name, added by [crate::chunk::Instruction::OpSetter],
+    /// including the inherited ones like [Class::methods].
+    pub setters: RefCell<HashMap<LoxString, Method>>,
+    /// The functions initializing the declared fields of new instances, added by
+    /// [crate::chunk::Instruction::OpFields], which run before `init`. The ones of the
+    /// superclasses come first.
+    pub field_initializers: RefCell<Vec<Method>>,
+    /// The class's superclass, set by [crate::chunk::Instruction::OpInherit]
+    /// before the class's own methods are added.
+    pub superclass: RefCell<Option<Rc<Class>>>,
+}
+
+impl Class {
+    pub fn new(name: LoxString) -> Class {
+        Class {
+            name,
+            methods: RefCell::new(HashMap::new()),
+            getters: RefCell::new(HashMap::new()),
+            setters: RefCell::new(HashMap::new()),
+            field_initializers: RefCell::new(Vec::new()),
+            superclass: RefCell::new(None),
+        }
+    }
+
+    /// Whether this class is the given class or one of its subclasses.
+    pub fn inherits_from(&self, class: &Rc<Class>) -> bool {
+        if std::ptr::eq(self, Rc::as_ptr(class)) {
+            return true;
+        }
+        match &*self.superclass.borrow() {
+            Some(superclass) => superclass.inherits_from(class),
+            None => false,
+        }
+    }
+}
+
+/// A method, with the superclass of the class that declared it,
+/// which `super` refers to in the method's body.
+#[derive(Debug, Clone)]
+pub struct Method {
+    pub function: Rc<Function>,
+    pub superclass: Option<Rc<Class>>,
+}
+
+/// An instance of a [Class], created by calling the class.
+#[derive(Debug, Clone)]
+pub struct Instance {
+    /// The class the instance was created from.
+    pub class: Rc<Class>,
+    /// The instance's fields, added by assigning to them.
+    pub fields: HashMap<LoxString, Value>,
+    /// Set by `freeze`, after which the fields can't be assigned.
+    pub frozen: bool,
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Instance {
+        Instance {
+            class,
+            fields: HashMap::new(),
+            frozen: false,
+        }
+    }
+}
+
+/// A method together with the instance it was accessed on, which it receives as `this`.
+#[derive(Debug, Clone)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Method,
+}
@@ -3,11 +3,18 @@ use crate::chunk::Chunk;
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FunctionType {
     Function,
+    /// A method of a class, which receives the instance it is called on as `this`.
+    Method,
+    /// The `init` method of a class, which returns the new instance.
+    Initializer,
+    /// A setter of a class, which receives the instance as `this` like a method, and returns
+    /// the assigned value.
+    Setter,
     Script,
 }
 
 /// The runtime representation of a function.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Function {
     /// The function' number of parameters.
     pub arity: usize,
@@ -15,6 +22,9 @@ pub struct Function {
     pub chunk: Chunk,
     /// The function's name.
     pub name: String,
+    /// For a function loaded from stripped bytecode, its index in the debug information
+    /// holding its name and lines, see [crate::serializer::DebugInfo].
+    pub symbol: Option<usize>,
 }
 
 impl Function {
@@ -23,6 +33,7 @@ impl Function {
             arity: 0,
             name: String::new(),
             chunk: Chunk::new(),
+            symbol: None,
         }
     }
 }
@@ -0,0 +1,47 @@
+//! Opaque references to Rust values, which native functions hand to scripts and get back
+//! as arguments, like file handles or sockets.
+//!
+//! Scripts can store, pass and compare host objects, but not look inside them. A host object
+//! is dropped once no value refers to it anymore, so the [Drop] implementation of the wrapped
+//! type is where its resources are released.
+
+use std::any::Any;
+use std::fmt::Debug;
+
+pub struct HostObject {
+    /// The name shown when the object is printed, like `File`.
+    pub type_name: String,
+    object: Box<dyn Any>,
+}
+
+impl HostObject {
+    pub fn new<T: Any>(type_name: &str, object: T) -> HostObject {
+        HostObject {
+            type_name: type_name.to_string(),
+            object: Box::new(object),
+        }
+    }
+
+    /// The wrapped value, if it is a `T`.
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.object.downcast_ref()
+    }
+}
+
+impl Debug for HostObject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HostObject {{ type_name: {} }}", self.type_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downcast() {
+        let object = HostObject::new("Counter", 3_u32);
+        assert_eq!(Some(&3), object.downcast_ref::<u32>());
+        assert_eq!(None, object.downcast_ref::<i32>());
+    }
+}
@@ -0,0 +1,219 @@
+//! A multi-line dump of a value, showing the fields of instances and the methods of classes,
+//! for the `inspect` native and the REPL's `:inspect` command.
+//!
+//! In debug builds, values held behind an [Rc] show its strong count. The string constants of a
+//! script are interned by the compiler, so equal strings from the source share one count.
+
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::rc::Rc;
+
+use super::class::Instance;
+use super::number::{format_number_with, NumberFormat};
+use super::string::LoxString;
+use super::value::Value;
+
+/// Returns the dump of a value, with reference counts in debug builds, writing numbers with the
+/// given [NumberFormat].
+pub fn inspect(value: &Value, format: NumberFormat) -> String {
+    inspect_with(value, cfg!(debug_assertions), format)
+}
+
+/// Returns the dump of a value, with reference counts if `counts` is set.
+pub fn inspect_with(value: &Value, counts: bool, format: NumberFormat) -> String {
+    let mut inspector = Inspector {
+        output: String::new(),
+        counts,
+        format,
+        instances: Vec::new(),
+    };
+    inspector.value(value, 0);
+    inspector.output
+}
+
+struct Inspector {
+    output: String,
+    counts: bool,
+    format: NumberFormat,
+    /// The instances being dumped, outermost first, to show a cycle instead of recursing forever.
+    instances: Vec<*const RefCell<Instance>>,
+}
+
+impl Inspector {
+    /// Writes a value, continuing the current line, with its nested values on the next lines
+    /// indented one level deeper than `depth`.
+    fn value(&mut self, value: &Value, depth: usize) {
+        match value {
+            Value::String(s) => {
+                let _ = write!(self.output, "{:?}", &**s);
+                self.count(LoxString::strong_count(s));
+            }
+            Value::Number(n) => self.output.push_str(&format_number_with(*n, self.format)),
+            Value::Function(f) => {
+                let _ = write!(self.output, "{} (arity {})", value, f.arity);
+                self.count(Rc::strong_count(f));
+            }
+            Value::NativeFunction(f) => {
+                let _ = write!(self.output, "<native fn {}> (arity {})", f.name, f.arity);
+                self.count(Rc::strong_count(f));
+            }
+            Value::Class(class) => {
+                let _ = write!(self.output, "class {}", class.name);
+                if let Some(superclass) = &*class.superclass.borrow() {
+                    let _ = write!(self.output, " < {}", superclass.name);
+                }
+                self.count(Rc::strong_count(class));
+                let mut methods: Vec<LoxString> = class.methods.borrow().keys().cloned().collect();
+                methods.sort();
+                for method in methods {
+                    self.line(depth + 1);
+                    let _ = write!(self.output, "{}()", method);
+                }
+                for (kind, accessors) in [("get", &class.getters), ("set", &class.setters)] {
+                    let mut names: Vec<LoxString> = accessors.borrow().keys().cloned().collect();
+                    names.sort();
+                    for name in names {
+                        self.line(depth + 1);
+                        let _ = write!(self.output, "{} {}", kind, name);
+                    }
+                }
+            }
+            Value::Instance(instance) => {
+                let pointer = Rc::as_ptr(instance);
+                if self.instances.contains(&pointer) {
+                    let _ = write!(self.output, "<cycle: {}>", value);
+                    return;
+                }
+                let _ = write!(self.output, "{}", value);
+                self.count(Rc::strong_count(instance));
+                self.instances.push(pointer);
+                let instance = instance.borrow();
+                let mut fields: Vec<(&LoxString, &Value)> = instance.fields.iter().collect();
+                fields.sort_by(|a, b| a.0.cmp(b.0));
+                for (name, field) in fields {
+                    self.line(depth + 1);
+                    let _ = write!(self.output, "{}: ", name);
+                    self.value(field, depth + 1);
+                }
+                self.instances.pop();
+            }
+            Value::BoundMethod(bound) => {
+                let _ = write!(self.output, "{} bound to ", value);
+                self.count(Rc::strong_count(bound));
+                self.value(&bound.receiver, depth);
+            }
+            _ => {
+                let _ = write!(self.output, "{}", value.with_format(self.format));
+            }
+        }
+    }
+
+    /// Starts a new line at the given depth.
+    fn line(&mut self, depth: usize) {
+        self.output.push('\n');
+        self.output.push_str(&"  ".repeat(depth));
+    }
+
+    fn count(&mut self, count: usize) {
+        if self.counts {
+            let _ = write!(self.output, " (rc {})", count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::class::{Class, Method};
+    use crate::value::function::Function;
+
+    fn instance(class: &Rc<Class>) -> Rc<RefCell<Instance>> {
+        Rc::new(RefCell::new(Instance::new(Rc::clone(class))))
+    }
+
+    #[test]
+    fn nested_instances() {
+        let point = Rc::new(Class::new(LoxString::from("Point")));
+        let inner = instance(&point);
+        inner
+            .borrow_mut()
+            .fields
+            .insert(LoxString::from("x"), Value::Number(1.5));
+        let outer = instance(&point);
+        let name = Value::String(LoxString::from("origin"));
+        outer
+            .borrow_mut()
+            .fields
+            .insert(LoxString::from("name"), name);
+        outer
+            .borrow_mut()
+            .fields
+            .insert(LoxString::from("inner"), Value::Instance(Rc::clone(&inner)));
+
+        let value = Value::Instance(outer);
+        assert_eq!(
+            "Point instance\n  inner: Point instance\n    x: 1.5\n  name: \"origin\"",
+            inspect_with(&value, false, NumberFormat::default())
+        );
+        assert_eq!(
+            "Point instance (rc 1)\n  inner: Point instance (rc 2)\n    x: 1.5\n  name: \"origin\" (rc 1)",
+            inspect_with(&value, true, NumberFormat::default())
+        );
+    }
+
+    #[test]
+    fn cycles() {
+        let node = Rc::new(Class::new(LoxString::from("Node")));
+        let a = instance(&node);
+        let b = instance(&node);
+        a.borrow_mut()
+            .fields
+            .insert(LoxString::from("next"), Value::Instance(Rc::clone(&b)));
+        b.borrow_mut()
+            .fields
+            .insert(LoxString::from("next"), Value::Instance(Rc::clone(&a)));
+        assert_eq!(
+            "Node instance\n  next: Node instance\n    next: <cycle: Node instance>",
+            inspect_with(
+                &Value::Instance(Rc::clone(&a)),
+                false,
+                NumberFormat::default()
+            )
+        );
+        // Break the cycle so that the instances are freed.
+        a.borrow_mut().fields.clear();
+    }
+
+    #[test]
+    fn classes() {
+        let base = Rc::new(Class::new(LoxString::from("Base")));
+        let class = Class::new(LoxString::from("Derived"));
+        *class.superclass.borrow_mut() = Some(base);
+        let method = || Method {
+            function: Rc::new(Function::new()),
+            superclass: None,
+        };
+        for name in ["speak", "init"] {
+            class
+                .methods
+                .borrow_mut()
+                .insert(LoxString::from(name), method());
+        }
+        class
+            .getters
+            .borrow_mut()
+            .insert(LoxString::from("area"), method());
+        class
+            .setters
+            .borrow_mut()
+            .insert(LoxString::from("area"), method());
+        assert_eq!(
+            "class Derived < Base\n  init()\n  speak()\n  get area\n  set area",
+            inspect_with(
+                &Value::Class(Rc::new(class)),
+                false,
+                NumberFormat::default()
+            )
+        );
+    }
+}
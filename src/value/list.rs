@@ -0,0 +1,162 @@
+//! Native functions on lists.
+
+use std::{cmp::Ordering, rc::Rc};
+
+use crate::{
+    diagnostic::Diagnostic,
+    vm::vm::{is_falsey, VMError, VM},
+};
+
+use super::{
+    native_function::FromArgument,
+    value::{List, Value},
+};
+
+/// Defines the list native functions as globals.
+///
+/// `sort`, `sortBy` and `reverse` change the list they are given and return it, while `map` and
+/// `filter` return new lists.
+pub(crate) fn define_natives(vm: &mut VM) {
+    vm.define_native_with_optional("sort", 2, 1, sort_native);
+    crate::native_fn!(vm, "sortBy", |vm; list: Rc<List>, function: &Value| {
+        sort_by(vm, list, function)
+    });
+    crate::native_fn!(vm, "reverse", |vm; list: Rc<List>| {
+        check_not_frozen(vm, &list)?;
+        list.elements.borrow_mut().reverse();
+        Ok(list)
+    });
+    crate::native_fn!(vm, "indexOf", |vm; list: Rc<List>, value: &Value| {
+        let index = position(vm, &list, value)?;
+        Ok(index.map(|index| index as f64))
+    });
+    crate::native_fn!(vm, "contains", |vm; list: Rc<List>, value: &Value| {
+        Ok(position(vm, &list, value)?.is_some())
+    });
+    crate::native_fn!(vm, "map", |vm; list: Rc<List>, function: &Value| {
+        let mapped = elements(&list)
+            .iter()
+            .map(|element| vm.call_value(function.clone(), std::slice::from_ref(element)))
+            .collect::<Result<Vec<_>, VMError>>()?;
+        Ok(Rc::new(List::new(mapped)))
+    });
+    crate::native_fn!(vm, "filter", |vm; list: Rc<List>, function: &Value| {
+        filter(vm, &list, function)
+    });
+    crate::native_fn!(vm, "reduce", |vm; list: Rc<List>, function: &Value, initial: &Value| {
+        elements(&list)
+            .into_iter()
+            .try_fold(initial.clone(), |accumulator, element| {
+                vm.call_value(function.clone(), &[accumulator, element])
+            })
+    });
+}
+
+/// Reports the error of changing a list that was frozen with `freeze`.
+fn check_not_frozen(vm: &mut VM, list: &List) -> Result<(), VMError> {
+    if list.frozen.get() {
+        vm.report(Diagnostic::FrozenValue, &["list"]);
+        return Err(VMError::RuntimeError);
+    }
+    Ok(())
+}
+
+/// Replaces the elements of a list, unless it is frozen, which callbacks may have done.
+fn set_elements(vm: &mut VM, list: &List, elements: Vec<Value>) -> Result<(), VMError> {
+    check_not_frozen(vm, list)?;
+    *list.elements.borrow_mut() = elements;
+    Ok(())
+}
+
+/// A copy of the elements of a list, which callbacks can then change while they are visited.
+fn elements(list: &List) -> Vec<Value> {
+    list.elements.borrow().clone()
+}
+
+/// Returns a new list of the elements for which a function returns a truthy value.
+fn filter(vm: &mut VM, list: &List, function: &Value) -> Result<Rc<List>, VMError> {
+    let mut kept = Vec::new();
+    for element in elements(list) {
+        let keep = vm.call_value(function.clone(), std::slice::from_ref(&element))?;
+        if !is_falsey(&keep) {
+            kept.push(element);
+        }
+    }
+    Ok(Rc::new(List::new(kept)))
+}
+
+/// Sorts a list in place and returns it, keeping equal elements in order.
+///
+/// The optional comparator is called with two elements and returns a number like `compareTo`.
+/// Without one, or with `nil`, the elements are compared by their value or their class's
+/// `compareTo`.
+fn sort_native(vm: &mut VM, args: &[Value]) -> Result<Value, VMError> {
+    let list = Rc::<List>::from_argument(vm, &args[0])?;
+    let comparator = &args[1];
+    // The comparator may change the list, so it sorts a copy.
+    let elements = list.elements.borrow().clone();
+    let sorted = merge_sort(vm, elements, &mut |vm, a, b| {
+        vm.compare_with(comparator, a, b)
+    })?;
+    set_elements(vm, &list, sorted)?;
+    Ok(args[0].clone())
+}
+
+/// Sorts a list in place by the keys a function returns for its elements, compared like the
+/// elements themselves by `sort`, and returns it.
+///
+/// The function is called once for each element.
+fn sort_by(vm: &mut VM, list: Rc<List>, function: &Value) -> Result<Rc<List>, VMError> {
+    let keyed = elements(&list)
+        .into_iter()
+        .map(|element| {
+            Ok((
+                vm.call_value(function.clone(), std::slice::from_ref(&element))?,
+                element,
+            ))
+        })
+        .collect::<Result<Vec<_>, VMError>>()?;
+    let sorted = merge_sort(vm, keyed, &mut |vm, (a, _), (b, _)| vm.compare(a, b))?;
+    let sorted = sorted.into_iter().map(|(_, element)| element).collect();
+    set_elements(vm, &list, sorted)?;
+    Ok(list)
+}
+
+/// The index of the first element of a list equal to a value, like with `==`.
+fn position(vm: &mut VM, list: &List, value: &Value) -> Result<Option<usize>, VMError> {
+    for (index, element) in elements(list).iter().enumerate() {
+        if vm.values_equal(element, value)? {
+            return Ok(Some(index));
+        }
+    }
+    Ok(None)
+}
+
+/// Sorts values with a comparison that can fail, like one calling back into Lox, keeping equal
+/// values in order.
+///
+/// Unlike `slice::sort_by`, it doesn't need the comparison to be a total order, which a
+/// comparator written in Lox may not be.
+fn merge_sort<T>(
+    vm: &mut VM,
+    mut values: Vec<T>,
+    compare: &mut dyn FnMut(&mut VM, &T, &T) -> Result<Ordering, VMError>,
+) -> Result<Vec<T>, VMError> {
+    if values.len() <= 1 {
+        return Ok(values);
+    }
+    let right = values.split_off(values.len() / 2);
+    let mut left = merge_sort(vm, values, compare)?.into_iter().peekable();
+    let mut right = merge_sort(vm, right, compare)?.into_iter().peekable();
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        if compare(vm, r, l)? == Ordering::Less {
+            merged.extend(right.next());
+        } else {
+            merged.extend(left.next());
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
@@ -1,3 +1,12 @@
-pub mod value;
+pub mod class;
 pub mod function;
-pub mod native_function;
\ No newline at end of file
+pub mod host_object;
+pub mod inspect;
+pub mod list;
+pub mod native_function;
+pub mod number;
+pub mod string;
+#[allow(clippy::module_inception)]
+pub mod value;
+#[cfg(feature = "vector_math")]
+pub mod vector;
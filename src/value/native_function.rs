@@ -1,17 +1,36 @@
 use std::fmt::Debug;
+use std::rc::Rc;
 
-use super::value::Value;
+use crate::diagnostic::Diagnostic;
+use crate::vm::vm::{VMError, VM};
+
+use super::string::LoxString;
+use super::value::{List, Value};
+#[cfg(feature = "vector_math")]
+use super::vector::{Matrix, Vector};
+
+/// The signature of a native function.
+///
+/// It receives the [VM], so that it can call back into Lox functions with [VM::call_value],
+/// and its arguments. Errors must be reported with [VM::runtime_error] before returning them.
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, VMError>;
 
 #[derive(Clone)]
 pub struct NativeFunction {
     /// The function' number of parameters.
     pub arity: usize,
+    /// How many of the last parameters can be left out. The function receives `nil` for them.
+    pub optional: usize,
     /// The function's name.
     pub name: String,
     /// The native function.
     //
     // TODO: variable number of args.
-    pub function: fn() -> Value,
+    pub function: NativeFn,
+    /// Whether the function returns the same result for the same arguments and program state,
+    /// unlike reading the clock. Calls to other functions are reported by
+    /// [VM::audit_determinism].
+    pub deterministic: bool,
 }
 
 impl Debug for NativeFunction {
@@ -23,3 +42,201 @@ impl Debug for NativeFunction {
         )
     }
 }
+
+/// Defines a native function from a closure with typed parameters, converting the arguments
+/// and the result.
+///
+/// The arity is the number of parameters, and an argument of the wrong type is reported as a
+/// runtime error, see [FromArgument]. The body returns anything that converts to a [Value], see
+/// [IntoValue], or a `Result` of it, like `native_fn!(vm, "hypot", |a: f64, b: f64| a.hypot(b))`.
+/// Naming the VM before the parameters, like `|vm; name: &str|`, gives the body access to it.
+#[macro_export]
+macro_rules! native_fn {
+    ($vm:expr, $name:expr, || $body:expr) => {
+        $crate::native_fn!($vm, $name, |_vm;| $body)
+    };
+    ($vm:expr, $name:expr, |$($arg:ident: $ty:ty),*| $body:expr) => {
+        $crate::native_fn!($vm, $name, |_vm; $($arg: $ty),*| $body)
+    };
+    ($vm:expr, $name:expr, |$native_vm:ident; $($arg:ident: $ty:ty),*| $body:expr) => {
+        $vm.define_native(
+            $name,
+            <[&str]>::len(&[$(stringify!($arg)),*]),
+            |$native_vm: &mut $crate::vm::vm::VM, args: &[$crate::value::value::Value]| {
+                #[allow(unused_mut, unused_variables)]
+                let mut args = args.iter();
+                $(
+                    let $arg: $ty = $crate::value::native_function::FromArgument::from_argument(
+                        $native_vm,
+                        args.next().unwrap(),
+                    )?;
+                )*
+                $crate::value::native_function::IntoResult::into_result($body)
+            },
+        )
+    };
+}
+
+/// A type that a native function defined with [native_fn] can take as a parameter.
+pub trait FromArgument<'a>: Sized {
+    /// Converts an argument, reporting the error if it has another type.
+    fn from_argument(vm: &mut VM, value: &'a Value) -> Result<Self, VMError>;
+}
+
+/// Reports that an argument has the wrong type.
+fn wrong_type<T>(vm: &mut VM, diagnostic: Diagnostic) -> Result<T, VMError> {
+    vm.report(diagnostic, &[]);
+    Err(VMError::RuntimeError)
+}
+
+impl<'a> FromArgument<'a> for f64 {
+    fn from_argument(vm: &mut VM, value: &'a Value) -> Result<Self, VMError> {
+        match value {
+            Value::Number(n) => Ok(*n),
+            _ => wrong_type(vm, Diagnostic::OperandNotNumber),
+        }
+    }
+}
+
+impl<'a> FromArgument<'a> for bool {
+    fn from_argument(vm: &mut VM, value: &'a Value) -> Result<Self, VMError> {
+        match value {
+            Value::Boolean(b) => Ok(*b),
+            _ => wrong_type(vm, Diagnostic::OperandNotBoolean),
+        }
+    }
+}
+
+impl<'a> FromArgument<'a> for &'a str {
+    fn from_argument(vm: &mut VM, value: &'a Value) -> Result<Self, VMError> {
+        match value {
+            Value::String(s) => Ok(s),
+            _ => wrong_type(vm, Diagnostic::OperandNotString),
+        }
+    }
+}
+
+#[cfg(feature = "vector_math")]
+impl<'a> FromArgument<'a> for &'a Vector {
+    fn from_argument(vm: &mut VM, value: &'a Value) -> Result<Self, VMError> {
+        match value {
+            Value::Vector(v) => Ok(v),
+            _ => wrong_type(vm, Diagnostic::OperandNotVector),
+        }
+    }
+}
+
+#[cfg(feature = "vector_math")]
+impl<'a> FromArgument<'a> for &'a Matrix {
+    fn from_argument(vm: &mut VM, value: &'a Value) -> Result<Self, VMError> {
+        match value {
+            Value::Matrix(m) => Ok(m),
+            _ => wrong_type(vm, Diagnostic::OperandNotMatrix),
+        }
+    }
+}
+
+/// A list, shared with the caller, so that the function can change it while calling back into
+/// Lox.
+impl<'a> FromArgument<'a> for Rc<List> {
+    fn from_argument(vm: &mut VM, value: &'a Value) -> Result<Self, VMError> {
+        match value {
+            Value::List(list) => Ok(Rc::clone(list)),
+            _ => wrong_type(vm, Diagnostic::OperandNotList),
+        }
+    }
+}
+
+/// Any value, unconverted.
+impl<'a> FromArgument<'a> for &'a Value {
+    fn from_argument(_vm: &mut VM, value: &'a Value) -> Result<Self, VMError> {
+        Ok(value)
+    }
+}
+
+/// A type that a native function defined with [native_fn] can return.
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Boolean(self)
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::String(LoxString::from(self))
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        self.to_string().into_value()
+    }
+}
+
+#[cfg(feature = "vector_math")]
+impl IntoValue for Vector {
+    fn into_value(self) -> Value {
+        Value::Vector(Rc::new(self))
+    }
+}
+
+#[cfg(feature = "vector_math")]
+impl IntoValue for Matrix {
+    fn into_value(self) -> Value {
+        Value::Matrix(Rc::new(self))
+    }
+}
+
+impl IntoValue for Rc<List> {
+    fn into_value(self) -> Value {
+        Value::List(self)
+    }
+}
+
+/// `nil`.
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::Nil
+    }
+}
+
+/// `nil` for `None`.
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        self.map_or(Value::Nil, IntoValue::into_value)
+    }
+}
+
+/// The result of the body of a native function defined with [native_fn]: a value, or a
+/// `Result` of a value for a body that can fail.
+pub trait IntoResult {
+    fn into_result(self) -> Result<Value, VMError>;
+}
+
+impl<T: IntoValue> IntoResult for T {
+    fn into_result(self) -> Result<Value, VMError> {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: IntoValue> IntoResult for Result<T, VMError> {
+    fn into_result(self) -> Result<Value, VMError> {
+        self.map(IntoValue::into_value)
+    }
+}
@@ -0,0 +1,166 @@
+//! Parsing and formatting of Lox numbers.
+//!
+//! Every conversion between numbers and text goes through here, so that the decimal separator
+//! is always '.', whatever the platform or locale.
+
+/// When numbers are written with an exponent, like `1e21`, instead of all their digits.
+///
+/// The thresholds apply to the magnitude of the number, so negative numbers are written the
+/// same way as positive ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NumberFormat {
+    /// Numbers at least this large are written with an exponent.
+    pub large: f64,
+    /// Numbers other than zero that are smaller than this are written with an exponent.
+    pub small: f64,
+}
+
+impl NumberFormat {
+    /// Never write an exponent.
+    pub const PLAIN: NumberFormat = NumberFormat {
+        large: f64::INFINITY,
+        small: 0.0,
+    };
+}
+
+/// The same thresholds as JavaScript's.
+impl Default for NumberFormat {
+    fn default() -> Self {
+        NumberFormat {
+            large: 1e21,
+            small: 1e-7,
+        }
+    }
+}
+
+/// Parses a number written as ASCII digits, with an optional fractional part after a '.',
+/// an optional exponent after an 'e' or 'E', like in `1.5e-7`, and an optional leading '-'.
+/// Single underscores between digits separate them, like in `1_000_000`, and are ignored.
+/// Other separators and non-finite values are rejected.
+pub fn parse_number(text: &str) -> Option<f64> {
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    let (mantissa, exponent) = match digits.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => {
+            let exponent = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+            (mantissa, Some(exponent))
+        }
+        None => (digits, None),
+    };
+    let (integer, fraction) = match mantissa.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (mantissa, None),
+    };
+
+    let is_digits = |part: &str| {
+        part.split('_')
+            .all(|group| !group.is_empty() && group.bytes().all(|b| b.is_ascii_digit()))
+    };
+    if !is_digits(integer) || !fraction.is_none_or(is_digits) || !exponent.is_none_or(is_digits) {
+        return None;
+    }
+
+    // Without its separators, the text is now known to be in a form that `parse` reads the same
+    // on every platform.
+    match text.replace('_', "").parse::<f64>() {
+        Ok(value) if value.is_finite() => Some(value),
+        _ => None,
+    }
+}
+
+/// Formats a number the way `print` shows it by default: the shortest text that parses back to
+/// the same value, without a fractional part for integers, and with an exponent only beyond the
+/// thresholds of the default [NumberFormat].
+pub fn format_number(value: f64) -> String {
+    format_number_with(value, NumberFormat::default())
+}
+
+/// Formats a number with the given thresholds for writing an exponent.
+pub fn format_number_with(value: f64, format: NumberFormat) -> String {
+    let magnitude = value.abs();
+    if value.is_finite()
+        && (magnitude >= format.large || (value != 0.0 && magnitude < format.small))
+    {
+        format!("{:e}", value)
+    } else {
+        format!("{}", value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let values = [
+            0.0,
+            -0.0,
+            1.0,
+            -1.5,
+            0.1 + 0.2,
+            1.0 / 3.0,
+            123456789.125,
+            1e21,
+            1e-7,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            5e-324,
+        ];
+        // Printed numbers are read back the same by Lox, with or without an exponent.
+        for value in values {
+            for format in [NumberFormat::PLAIN, NumberFormat::default()] {
+                let text = format_number_with(value, format);
+                let parsed = parse_number(&text).unwrap();
+                assert_eq!(value.to_bits(), parsed.to_bits(), "{}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!("3", format_number(3.0));
+        assert_eq!("0.5", format_number(0.5));
+        assert_eq!("100000000000000000000", format_number(1e20));
+        assert_eq!("0.30000000000000004", format_number(0.1 + 0.2));
+    }
+
+    #[test]
+    fn exponents() {
+        assert_eq!("1e21", format_number(1e21));
+        assert_eq!("-1.5e21", format_number(-1.5e21));
+        assert_eq!("0.0000001", format_number(1e-7));
+        assert_eq!("2.5e-8", format_number(2.5e-8));
+        assert_eq!("0", format_number(0.0));
+        assert_eq!("inf", format_number(f64::INFINITY));
+
+        let format = NumberFormat {
+            large: 1e6,
+            small: 0.001,
+        };
+        assert_eq!("999999", format_number_with(999999.0, format));
+        assert_eq!("1.234e6", format_number_with(1234000.0, format));
+        assert_eq!("5e-4", format_number_with(0.0005, format));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Some(12.5), parse_number("12.5"));
+        assert_eq!(Some(-3.0), parse_number("-3"));
+        assert_eq!(Some(1_000_000.0), parse_number("1_000_000"));
+        assert_eq!(Some(-1_000.002_5), parse_number("-1_000.002_5"));
+        assert_eq!(Some(1e7), parse_number("1e7"));
+        assert_eq!(Some(-2.5e-8), parse_number("-2.5E-8"));
+        assert_eq!(Some(1e21), parse_number("1e+2_1"));
+        for text in [
+            "", "-", ".5", "1.", "1..2", "1.2.3", "1,5", "inf", "NaN", "_1", "1_", "1__0", "1_.5",
+            "1._5", "-_1", "e5", "1e", "1e+", "1e--5", "1e5.0", "1e5e5", "1.e5", "1e_5", "1e400",
+        ] {
+            assert_eq!(None, parse_number(text), "{}", text);
+        }
+    }
+
+    #[test]
+    fn parse_out_of_range() {
+        assert_eq!(None, parse_number(&"9".repeat(400)));
+    }
+}
@@ -0,0 +1,97 @@
+//! The strings of Lox values.
+
+use std::borrow::Borrow;
+use std::fmt::{self, Debug, Display};
+use std::ops::Deref;
+use std::rc::Rc;
+
+/// An immutable string, shared by the values holding it.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LoxString(Text);
+
+/// The text of a [LoxString], with its length next to the pointer.
+#[cfg(not(feature = "nan_boxing"))]
+type Text = Rc<str>;
+
+/// The text of a [LoxString], boxed behind the [Rc] so that a string is a single pointer like
+/// the other shared values, which the `nan_boxing` feature packs into a stack slot as it is.
+/// Reading the text takes one more indirection.
+#[cfg(feature = "nan_boxing")]
+type Text = Rc<Box<str>>;
+
+impl LoxString {
+    /// Whether both strings are the same allocation, like [Rc::ptr_eq].
+    pub fn ptr_eq(a: &LoxString, b: &LoxString) -> bool {
+        Rc::ptr_eq(&a.0, &b.0)
+    }
+
+    /// The number of values holding the string, like [Rc::strong_count].
+    pub fn strong_count(string: &LoxString) -> usize {
+        Rc::strong_count(&string.0)
+    }
+
+    /// Gives up the string for a thin pointer, like [Rc::into_raw].
+    #[cfg(feature = "nan_boxing")]
+    pub(crate) fn into_raw(string: LoxString) -> *const Box<str> {
+        Rc::into_raw(string.0)
+    }
+
+    /// Takes back a string given up with [LoxString::into_raw].
+    ///
+    /// Safety: the pointer must come from [LoxString::into_raw], and be taken back only once.
+    #[cfg(feature = "nan_boxing")]
+    pub(crate) unsafe fn from_raw(pointer: *const Box<str>) -> LoxString {
+        LoxString(Rc::from_raw(pointer))
+    }
+}
+
+impl Deref for LoxString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Lets maps keyed by strings be searched with a `&str`.
+impl Borrow<str> for LoxString {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for LoxString {
+    #[cfg(not(feature = "nan_boxing"))]
+    fn from(string: &str) -> Self {
+        LoxString(Rc::from(string))
+    }
+
+    #[cfg(feature = "nan_boxing")]
+    fn from(string: &str) -> Self {
+        LoxString(Rc::new(Box::from(string)))
+    }
+}
+
+impl From<String> for LoxString {
+    #[cfg(not(feature = "nan_boxing"))]
+    fn from(string: String) -> Self {
+        LoxString(Rc::from(string))
+    }
+
+    #[cfg(feature = "nan_boxing")]
+    fn from(string: String) -> Self {
+        LoxString(Rc::new(string.into_boxed_str()))
+    }
+}
+
+impl Display for LoxString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&**self, f)
+    }
+}
+
+impl Debug for LoxString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&**self, f)
+    }
+}
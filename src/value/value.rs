@@ -1,15 +1,58 @@
-use std::{fmt::Display, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Display,
+    rc::Rc,
+};
 
-use super::{function::Function, native_function::NativeFunction};
+use crate::diagnostic::Diagnostic;
 
-#[derive(Debug, Clone)]
+#[cfg(feature = "vector_math")]
+use super::vector::{Matrix, Vector};
+use super::{
+    class::{BoundMethod, Class, Instance},
+    function::Function,
+    host_object::HostObject,
+    native_function::NativeFunction,
+    number::{format_number_with, NumberFormat},
+    string::LoxString,
+};
+
+/// A list, shared by every value referring to it.
+#[derive(Debug, Default)]
+pub struct List {
+    pub elements: RefCell<Vec<Value>>,
+    /// Set by `freeze`, after which the elements can't be changed.
+    pub frozen: Cell<bool>,
+}
+
+impl List {
+    pub fn new(elements: Vec<Value>) -> List {
+        List {
+            elements: RefCell::new(elements),
+            frozen: Cell::new(false),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub enum Value {
     Boolean(bool),
     Number(f64),
+    #[default]
     Nil,
-    String(Rc<String>),
+    String(LoxString),
     Function(Rc<Function>),
     NativeFunction(Rc<NativeFunction>),
+    Class(Rc<Class>),
+    Instance(Rc<RefCell<Instance>>),
+    BoundMethod(Rc<BoundMethod>),
+    List(Rc<List>),
+    /// A reference to a Rust value, given to the script by a native function.
+    HostObject(Rc<HostObject>),
+    #[cfg(feature = "vector_math")]
+    Vector(Rc<Vector>),
+    #[cfg(feature = "vector_math")]
+    Matrix(Rc<Matrix>),
 }
 
 #[macro_export]
@@ -44,15 +87,59 @@ impl Value {
     pub fn concatenate_strings(v1: &Value, v2: &Value) -> Result<Value, &'static str> {
         match (v1, v2) {
             (Value::String(s1), Value::String(s2)) => {
-                let mut s1 = String::clone(s1);
-                let s2 = String::clone(s2);
-                s1.push_str(&s2);
-                return Ok(Value::String(Rc::new(s1)));
+                let mut s = String::with_capacity(s1.len() + s2.len());
+                s.push_str(s1);
+                s.push_str(s2);
+                Ok(Value::String(LoxString::from(s)))
             }
             _ => Err("values must both be either strings or numbers"),
         }
     }
 
+    /// Returns the characters of a string, or the elements of a list as a new list, between the
+    /// start and end bounds.
+    ///
+    /// A nil bound stands for the start or the end of the string or list, and negative bounds
+    /// count from the end. Bounds outside of the string or list are clamped to it.
+    pub fn slice(v: &Value, start: &Value, end: &Value) -> Result<Value, Diagnostic> {
+        let length = match v {
+            Value::String(s) => s.chars().count(),
+            Value::List(list) => list.elements.borrow().len(),
+            _ => return Err(Diagnostic::NotSliceable),
+        } as i64;
+        let start = Value::slice_bound(start, 0, length)?;
+        let end = Value::slice_bound(end, length, length)?.max(start);
+
+        match v {
+            Value::List(list) => {
+                let sliced = list.elements.borrow()[start as usize..end as usize].to_vec();
+                Ok(Value::List(Rc::new(List::new(sliced))))
+            }
+            Value::String(s) => {
+                let sliced: String = s
+                    .chars()
+                    .skip(start as usize)
+                    .take((end - start) as usize)
+                    .collect();
+                Ok(Value::String(LoxString::from(sliced)))
+            }
+            _ => unreachable!("only strings and lists have a length"),
+        }
+    }
+
+    fn slice_bound(bound: &Value, default: i64, length: i64) -> Result<i64, Diagnostic> {
+        let bound = match bound {
+            Value::Nil => return Ok(default),
+            Value::Number(n) if n.fract() == 0.0 => *n as i64,
+            _ => return Err(Diagnostic::InvalidSliceBound),
+        };
+        if bound < 0 {
+            Ok((length + bound).max(0))
+        } else {
+            Ok(bound.min(length))
+        }
+    }
+
     // TODO implement PartialEq for Value instead
     pub fn equals(v1: Value, v2: Value) -> bool {
         match v1 {
@@ -64,49 +151,106 @@ impl Value {
                 Value::Number(n2) => n1 == n2,
                 _ => false,
             },
-            Value::Nil => match v2 {
-                Value::Nil => true,
-                _ => false,
-            },
+            Value::Nil => matches!(v2, Value::Nil),
+            // Literals and names are interned by the compiler, so equal strings are usually
+            // the same allocation. Strings built at runtime are compared by content.
             Value::String(s1) => match v2 {
-                Value::String(s2) => s1.eq(&s2),
+                Value::String(s2) => LoxString::ptr_eq(&s1, &s2) || s1 == s2,
                 _ => false,
             },
+            #[cfg(feature = "vector_math")]
+            Value::Vector(v1) => matches!(v2, Value::Vector(v2) if v1 == v2),
+            #[cfg(feature = "vector_math")]
+            Value::Matrix(m1) => matches!(v2, Value::Matrix(m2) if m1 == m2),
+            // Classes and instances are equal only to themselves.
+            Value::Class(c1) => matches!(v2, Value::Class(c2) if Rc::ptr_eq(&c1, &c2)),
+            Value::Instance(i1) => matches!(v2, Value::Instance(i2) if Rc::ptr_eq(&i1, &i2)),
+            // Lists are mutable, so like instances they are equal only to themselves.
+            Value::List(l1) => matches!(v2, Value::List(l2) if Rc::ptr_eq(&l1, &l2)),
+            Value::HostObject(o1) => matches!(v2, Value::HostObject(o2) if Rc::ptr_eq(&o1, &o2)),
             // TODO: equality for other heap allocated values.
             _ => false,
         }
     }
 
     pub fn is_string(v: &Value) -> bool {
-        if let Value::String(_) = v {
-            true
-        } else {
-            false
+        matches!(v, Value::String(_))
+    }
+
+    /// Shows the value the way `print` does, with the given thresholds for writing its numbers,
+    /// including the ones inside it, with an exponent.
+    pub fn with_format(&self, format: NumberFormat) -> FormattedValue<'_> {
+        FormattedValue {
+            value: self,
+            format,
         }
     }
 }
 
+/// Shows values the way `print` does with the default [NumberFormat].
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
+        self.with_format(NumberFormat::default()).fmt(f)
+    }
+}
+
+/// A value shown with the given [NumberFormat] for its numbers, made by [Value::with_format].
+pub struct FormattedValue<'a> {
+    value: &'a Value,
+    format: NumberFormat,
+}
+
+impl Display for FormattedValue<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.value {
             Value::Boolean(b) => write!(f, "{}", b),
-            Value::Number(n) => write!(f, "{}", n),
-            Value::Nil => write!(f, "{}", "nil"),
+            Value::Number(n) => write!(f, "{}", format_number_with(*n, self.format)),
+            Value::Nil => write!(f, "nil"),
             Value::String(s) => write!(f, "{}", s),
             Value::Function(func) => {
                 let name = &func.name;
                 if name.is_empty() {
-                    return write!(f, "{}", "<script>");
+                    return write!(f, "<script>");
                 }
-                return write!(f, "<fn {}>", name);
+                write!(f, "<fn {}>", name)
             }
             Value::NativeFunction(_) => write!(f, "<native fn>"),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.borrow().class.name),
+            Value::BoundMethod(bound) => write!(f, "<fn {}>", bound.method.function.name),
+            Value::List(list) => write_list(f, list, self.format, &mut Vec::new()),
+            Value::HostObject(object) => write!(f, "<{}>", object.type_name),
+            #[cfg(feature = "vector_math")]
+            Value::Vector(v) => v.write(f, self.format),
+            #[cfg(feature = "vector_math")]
+            Value::Matrix(m) => m.write(f, self.format),
         }
     }
 }
 
-impl Default for Value {
-    fn default() -> Self {
-        Value::Nil
+/// Writes a list as `[a, b, c]`, with `[...]` for a list inside itself.
+///
+/// `outer` holds the lists being written around this one.
+fn write_list(
+    f: &mut std::fmt::Formatter<'_>,
+    list: &Rc<List>,
+    format: NumberFormat,
+    outer: &mut Vec<*const List>,
+) -> std::fmt::Result {
+    if outer.contains(&Rc::as_ptr(list)) {
+        return write!(f, "[...]");
+    }
+    outer.push(Rc::as_ptr(list));
+    write!(f, "[")?;
+    for (i, element) in list.elements.borrow().iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        match element {
+            Value::List(inner) => write_list(f, inner, format, outer)?,
+            _ => write!(f, "{}", element.with_format(format))?,
+        }
     }
+    outer.pop();
+    write!(f, "]")
 }
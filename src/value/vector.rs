@@ -0,0 +1,273 @@
+//! Vectors and square matrices of numbers, with native functions for small graphics and game
+//! scripts.
+//!
+//! Vectors have 2 or 3 components and matrices are 2x2 or 3x3. Both are immutable: every
+//! operation returns a new value.
+
+use std::{fmt::Display, rc::Rc};
+
+use crate::{
+    diagnostic::Diagnostic,
+    vm::vm::{VMError, VM},
+};
+
+use super::{
+    number::{format_number_with, NumberFormat},
+    value::Value,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vector {
+    /// The x, y and, for a vec3, z components.
+    pub components: Vec<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matrix {
+    /// The number of rows, which is also the number of columns.
+    pub size: usize,
+    /// The elements, row by row.
+    pub elements: Vec<f64>,
+}
+
+impl Vector {
+    pub fn dot(&self, other: &Vector) -> Result<f64, Diagnostic> {
+        if self.components.len() != other.components.len() {
+            return Err(Diagnostic::VectorSizeMismatch);
+        }
+        Ok(self
+            .components
+            .iter()
+            .zip(&other.components)
+            .map(|(a, b)| a * b)
+            .sum())
+    }
+
+    pub fn cross(&self, other: &Vector) -> Result<Vector, Diagnostic> {
+        match (&self.components[..], &other.components[..]) {
+            ([ax, ay, az], [bx, by, bz]) => Ok(Vector {
+                components: vec![ay * bz - az * by, az * bx - ax * bz, ax * by - ay * bx],
+            }),
+            _ => Err(Diagnostic::CrossNotVec3),
+        }
+    }
+
+    pub fn length(&self) -> f64 {
+        self.components.iter().map(|c| c * c).sum::<f64>().sqrt()
+    }
+
+    /// Returns the vector with the same direction and a length of 1.
+    pub fn normalize(&self) -> Result<Vector, Diagnostic> {
+        let length = self.length();
+        if length == 0.0 {
+            return Err(Diagnostic::ZeroLengthVector);
+        }
+        Ok(Vector {
+            components: self.components.iter().map(|c| c / length).collect(),
+        })
+    }
+}
+
+impl Matrix {
+    fn get(&self, row: usize, column: usize) -> f64 {
+        self.elements[row * self.size + column]
+    }
+
+    pub fn multiply(&self, other: &Matrix) -> Result<Matrix, Diagnostic> {
+        if self.size != other.size {
+            return Err(Diagnostic::MatrixSizeMismatch);
+        }
+        let n = self.size;
+        let elements = (0..n * n)
+            .map(|i| {
+                (0..n)
+                    .map(|k| self.get(i / n, k) * other.get(k, i % n))
+                    .sum()
+            })
+            .collect();
+        Ok(Matrix { size: n, elements })
+    }
+
+    pub fn transform(&self, vector: &Vector) -> Result<Vector, Diagnostic> {
+        if self.size != vector.components.len() {
+            return Err(Diagnostic::MatrixSizeMismatch);
+        }
+        let components = (0..self.size)
+            .map(|row| {
+                (0..self.size)
+                    .map(|column| self.get(row, column) * vector.components[column])
+                    .sum()
+            })
+            .collect();
+        Ok(Vector { components })
+    }
+}
+
+/// Writes `name(a, b, ...)`, the call that builds the value.
+fn write_call(
+    f: &mut std::fmt::Formatter<'_>,
+    name: &str,
+    numbers: &[f64],
+    format: NumberFormat,
+) -> std::fmt::Result {
+    let numbers: Vec<String> = numbers
+        .iter()
+        .map(|n| format_number_with(*n, format))
+        .collect();
+    write!(f, "{}({})", name, numbers.join(", "))
+}
+
+impl Vector {
+    /// Writes the vector with the given [NumberFormat] for its components.
+    pub(crate) fn write(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        format: NumberFormat,
+    ) -> std::fmt::Result {
+        let name = format!("vec{}", self.components.len());
+        write_call(f, &name, &self.components, format)
+    }
+}
+
+impl Matrix {
+    /// Writes the matrix with the given [NumberFormat] for its elements.
+    pub(crate) fn write(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        format: NumberFormat,
+    ) -> std::fmt::Result {
+        let name = format!("mat{}", self.size);
+        write_call(f, &name, &self.elements, format)
+    }
+}
+
+impl Display for Vector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(f, NumberFormat::default())
+    }
+}
+
+impl Display for Matrix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(f, NumberFormat::default())
+    }
+}
+
+/// Defines the vector and matrix native functions as globals.
+pub(crate) fn define_natives(vm: &mut VM) {
+    crate::native_fn!(vm, "vec2", |x: f64, y: f64| Vector {
+        components: vec![x, y],
+    });
+    crate::native_fn!(vm, "vec3", |x: f64, y: f64, z: f64| Vector {
+        components: vec![x, y, z],
+    });
+    crate::native_fn!(vm, "mat2", |m00: f64, m01: f64, m10: f64, m11: f64| {
+        Matrix {
+            size: 2,
+            elements: vec![m00, m01, m10, m11],
+        }
+    });
+    crate::native_fn!(vm, "mat3", |m00: f64,
+                                   m01: f64,
+                                   m02: f64,
+                                   m10: f64,
+                                   m11: f64,
+                                   m12: f64,
+                                   m20: f64,
+                                   m21: f64,
+                                   m22: f64| {
+        Matrix {
+            size: 3,
+            elements: vec![m00, m01, m02, m10, m11, m12, m20, m21, m22],
+        }
+    });
+    crate::native_fn!(vm, "vx", |vm; v: &Vector| component(vm, v, 0, "x"));
+    crate::native_fn!(vm, "vy", |vm; v: &Vector| component(vm, v, 1, "y"));
+    crate::native_fn!(vm, "vz", |vm; v: &Vector| component(vm, v, 2, "z"));
+    crate::native_fn!(vm, "dot", |vm; a: &Vector, b: &Vector| check(vm, a.dot(b)));
+    crate::native_fn!(vm, "cross", |vm; a: &Vector, b: &Vector| check(vm, a.cross(b)));
+    crate::native_fn!(vm, "length", |v: &Vector| v.length());
+    crate::native_fn!(vm, "normalize", |vm; v: &Vector| check(vm, v.normalize()));
+    crate::native_fn!(vm, "matmul", |vm; matrix: &Matrix, other: &Value| {
+        matmul(vm, matrix, other)
+    });
+}
+
+/// Reports the error of a vector operation, if any.
+fn check<T>(vm: &mut VM, result: Result<T, Diagnostic>) -> Result<T, VMError> {
+    result.map_err(|diagnostic| {
+        vm.report(diagnostic, &[]);
+        VMError::RuntimeError
+    })
+}
+
+fn component(vm: &mut VM, vector: &Vector, index: usize, name: &str) -> Result<f64, VMError> {
+    match vector.components.get(index) {
+        Some(c) => Ok(*c),
+        None => {
+            vm.report(Diagnostic::MissingComponent, &[name]);
+            Err(VMError::RuntimeError)
+        }
+    }
+}
+
+/// Multiplies a matrix by a matrix or by a vector.
+fn matmul(vm: &mut VM, matrix: &Matrix, other: &Value) -> Result<Value, VMError> {
+    let product = match other {
+        Value::Matrix(other) => matrix.multiply(other).map(|m| Value::Matrix(Rc::new(m))),
+        Value::Vector(vector) => matrix.transform(vector).map(|v| Value::Vector(Rc::new(v))),
+        _ => Err(Diagnostic::OperandNotVectorOrMatrix),
+    };
+    check(vm, product)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vector(components: &[f64]) -> Vector {
+        Vector {
+            components: components.to_vec(),
+        }
+    }
+
+    #[test]
+    fn vector_operations() {
+        let x = vector(&[1.0, 0.0, 0.0]);
+        let y = vector(&[0.0, 1.0, 0.0]);
+        assert_eq!(Ok(0.0), x.dot(&y));
+        assert_eq!(Ok(vector(&[0.0, 0.0, 1.0])), x.cross(&y));
+        assert_eq!(5.0, vector(&[3.0, 4.0]).length());
+        assert_eq!(Ok(vector(&[0.6, 0.8])), vector(&[3.0, 4.0]).normalize());
+    }
+
+    #[test]
+    fn vector_errors() {
+        let v2 = vector(&[1.0, 2.0]);
+        let v3 = vector(&[1.0, 2.0, 3.0]);
+        assert_eq!(Err(Diagnostic::VectorSizeMismatch), v2.dot(&v3));
+        assert_eq!(Err(Diagnostic::CrossNotVec3), v2.cross(&v2));
+        assert_eq!(
+            Err(Diagnostic::ZeroLengthVector),
+            vector(&[0.0, 0.0]).normalize()
+        );
+    }
+
+    #[test]
+    fn matrix_operations() {
+        let a = Matrix {
+            size: 2,
+            elements: vec![1.0, 2.0, 3.0, 4.0],
+        };
+        let b = Matrix {
+            size: 2,
+            elements: vec![0.0, 1.0, 1.0, 0.0],
+        };
+        assert_eq!(vec![2.0, 1.0, 4.0, 3.0], a.multiply(&b).unwrap().elements);
+        assert_eq!(Ok(vector(&[5.0, 11.0])), a.transform(&vector(&[1.0, 2.0])));
+        assert_eq!(
+            Err(Diagnostic::MatrixSizeMismatch),
+            a.transform(&vector(&[1.0, 2.0, 3.0]))
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use std::rc::Rc;
 
-use crate::value::function::Function;
+use crate::value::{class::Class, function::Function};
 
 /// Represents a single ongoing function call.
 pub struct CallFrame {
@@ -13,25 +13,8 @@ pub struct CallFrame {
     pub ip: usize,
     /// The index of the first slot this [CallFrame] can use, in the VM's value stack.
     pub stack_index: usize,
-}
-
-impl CallFrame {
-    pub fn new() -> CallFrame {
-        CallFrame {
-            function: Rc::new(Function::new()),
-            ip: 0,
-            stack_index: 0,
-        }
-    }
-}
-
-// TODO: is there a better choice? Is it the same as the default Clone implementation?
-impl Clone for CallFrame {
-    fn clone(&self) -> Self {
-        CallFrame {
-            function: Rc::clone(&self.function),
-            ip: self.ip,
-            stack_index: self.stack_index,
-        }
-    }
+    /// For a method, the superclass of the class that declared it, where `super` finds methods.
+    pub superclass: Option<Rc<Class>>,
+    /// The number of instructions this call ran itself, not counting the calls it made.
+    pub instruction_count: usize,
 }
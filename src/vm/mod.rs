@@ -1,2 +1,6 @@
-pub mod vm;
 pub mod call_frame;
+mod slot;
+#[cfg(feature = "template_jit")]
+pub mod template;
+#[allow(clippy::module_inception)]
+pub mod vm;
@@ -0,0 +1,250 @@
+//! The slots of the VM's value stack.
+//!
+//! By default, a slot holds a [Value] as it is, in 16 bytes. With the `nan_boxing` feature, a
+//! slot packs the value into 8 bytes instead, halving the stack: a number is stored as its own
+//! bits, and any other value in the payload of a quiet NaN, which no arithmetic produces since
+//! NaNs are stored in the one form arithmetic does produce. Strings, functions and the other
+//! values shared with [Rc] keep their pointer in the payload, with the kind of value in its
+//! unused bits. Strings are [LoxString]s, whose text is boxed behind the [Rc] so that their
+//! pointer fits too, and packing a value never allocates.
+//!
+//! Only the stack is packed: values are unpacked when pushed and popped, and stay [Value]s
+//! everywhere else.
+
+use std::cell::Cell;
+
+#[cfg(doc)]
+use crate::value::string::LoxString;
+use crate::value::value::Value;
+
+#[cfg(all(feature = "nan_boxing", not(target_pointer_width = "64")))]
+compile_error!("The `nan_boxing` feature needs 64-bit pointers.");
+
+/// A slot of the value stack, which hands out copies of its value.
+#[cfg(not(feature = "nan_boxing"))]
+pub(crate) struct Slot(Cell<Value>);
+
+#[cfg(not(feature = "nan_boxing"))]
+impl Slot {
+    pub(crate) const fn new() -> Slot {
+        Slot(Cell::new(Value::Nil))
+    }
+
+    /// Removes the value, leaving nil.
+    pub(crate) fn take(&self) -> Value {
+        self.0.take()
+    }
+
+    pub(crate) fn set(&self, value: Value) {
+        self.0.set(value);
+    }
+
+    pub(crate) fn replace(&self, value: Value) -> Value {
+        self.0.replace(value)
+    }
+
+    /// A copy of the value, which stays in the slot.
+    pub(crate) fn get(&self) -> Value {
+        let value = self.0.take();
+        self.0.set(value.clone());
+        value
+    }
+}
+
+/// A slot of the value stack, which hands out copies of its value.
+#[cfg(feature = "nan_boxing")]
+pub(crate) struct Slot(Cell<u64>);
+
+#[cfg(feature = "nan_boxing")]
+impl Slot {
+    pub(crate) const fn new() -> Slot {
+        Slot(Cell::new(packed::NIL))
+    }
+
+    /// Removes the value, leaving nil.
+    pub(crate) fn take(&self) -> Value {
+        // Safety: the slot only ever holds packed values, and gives up its own.
+        unsafe { packed::unpack(self.0.replace(packed::NIL)) }
+    }
+
+    pub(crate) fn set(&self, value: Value) {
+        drop(self.replace(value));
+    }
+
+    pub(crate) fn replace(&self, value: Value) -> Value {
+        // Safety: the slot only ever holds packed values, and gives up the previous one.
+        unsafe { packed::unpack(self.0.replace(packed::pack(value))) }
+    }
+
+    /// A copy of the value, which stays in the slot.
+    pub(crate) fn get(&self) -> Value {
+        // Safety: the slot only ever holds packed values.
+        unsafe { packed::copy(self.0.get()) }
+    }
+}
+
+#[cfg(feature = "nan_boxing")]
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.take();
+    }
+}
+
+#[cfg(feature = "nan_boxing")]
+mod packed {
+    use std::mem::ManuallyDrop;
+    use std::rc::Rc;
+
+    use crate::value::string::LoxString;
+    use crate::value::value::Value;
+
+    /// The bits set in every packed value that is not a number.
+    const QUIET_NAN: u64 = 0x7ffc_0000_0000_0000;
+    /// Set, with [QUIET_NAN], in the values shared with [Rc].
+    const SHARED: u64 = 1 << 63;
+    pub(super) const NIL: u64 = QUIET_NAN | 1;
+    const FALSE: u64 = QUIET_NAN | 2;
+    const TRUE: u64 = QUIET_NAN | 3;
+    /// The bits of a shared value's pointer, which is below 2^48 and a multiple of 8 on 64-bit
+    /// platforms. The low 3 bits and the 2 bits above hold the kind of value instead.
+    const POINTER: u64 = ((1 << 48) - 1) & !0b111;
+    /// The kind of the strings, packed as the pointer of their [LoxString].
+    const STRING: u64 = 0;
+
+    /// Declares the kinds of values shared with [Rc], with the number stored for each.
+    macro_rules! shared_values {
+        ($($(#[$attr:meta])* $kind:literal => $variant:ident,)*) => {
+            fn pack_shared(value: Value) -> u64 {
+                let (kind, pointer) = match value {
+                    Value::String(string) => (STRING, LoxString::into_raw(string) as usize as u64),
+                    $($(#[$attr])* Value::$variant(rc) => ($kind, Rc::into_raw(rc) as usize as u64),)*
+                    _ => unreachable!("{:?} is not shared", value),
+                };
+                assert!(
+                    pointer & !POINTER == 0,
+                    "The pointer {:#x} cannot be packed.",
+                    pointer
+                );
+                QUIET_NAN | SHARED | (kind >> 3) << 48 | pointer | (kind & 0b111)
+            }
+
+            /// Safety: the bits must have been packed by [pack_shared], and are consumed.
+            unsafe fn unpack_shared(bits: u64) -> Value {
+                let kind = (bits >> 48 & 0b11) << 3 | (bits & 0b111);
+                let pointer = (bits & POINTER) as usize;
+                match kind {
+                    STRING => Value::String(LoxString::from_raw(pointer as *const _)),
+                    $($(#[$attr])* $kind => Value::$variant(Rc::from_raw(pointer as *const _)),)*
+                    _ => unreachable!("{:#x} is not a packed value", bits),
+                }
+            }
+        };
+    }
+
+    shared_values! {
+        1 => Function,
+        2 => NativeFunction,
+        3 => Class,
+        4 => Instance,
+        5 => BoundMethod,
+        6 => List,
+        7 => HostObject,
+        #[cfg(feature = "vector_math")]
+        8 => Vector,
+        #[cfg(feature = "vector_math")]
+        9 => Matrix,
+    }
+
+    /// Packs a value, taking over its reference if it is shared.
+    pub(super) fn pack(value: Value) -> u64 {
+        match value {
+            // Arithmetic only produces this NaN, which is not a packed value, but the constants of
+            // compiled code are read from their bits.
+            Value::Number(n) if n.is_nan() => f64::NAN.to_bits(),
+            Value::Number(n) => n.to_bits(),
+            Value::Nil => NIL,
+            Value::Boolean(false) => FALSE,
+            Value::Boolean(true) => TRUE,
+            value => pack_shared(value),
+        }
+    }
+
+    /// Unpacks a value, giving back the reference it took over if it is shared.
+    ///
+    /// Safety: the bits must have been packed by [pack], and must not be unpacked again.
+    pub(super) unsafe fn unpack(bits: u64) -> Value {
+        if bits & QUIET_NAN != QUIET_NAN {
+            return Value::Number(f64::from_bits(bits));
+        }
+        match bits {
+            NIL => Value::Nil,
+            FALSE => Value::Boolean(false),
+            TRUE => Value::Boolean(true),
+            bits => unpack_shared(bits),
+        }
+    }
+
+    /// Unpacks a copy of a value, which stays packed.
+    ///
+    /// Safety: the bits must have been packed by [pack].
+    pub(super) unsafe fn copy(bits: u64) -> Value {
+        let value = ManuallyDrop::new(unpack(bits));
+        Value::clone(&value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::string::LoxString;
+
+    #[test]
+    fn holds_values() {
+        let slot = Slot::new();
+        assert!(matches!(slot.get(), Value::Nil));
+        for value in [
+            Value::Number(1.5),
+            Value::Number(-0.0),
+            Value::Number(f64::INFINITY),
+            Value::Boolean(true),
+            Value::Boolean(false),
+        ] {
+            slot.set(value.clone());
+            assert!(Value::equals(value, slot.get()));
+        }
+        slot.set(Value::Number(f64::NAN));
+        assert!(matches!(slot.take(), Value::Number(n) if n.is_nan()));
+        assert!(matches!(slot.take(), Value::Nil));
+    }
+
+    #[test]
+    fn shares_references() {
+        let string = LoxString::from("text");
+        let slot = Slot::new();
+        slot.set(Value::String(string.clone()));
+        assert_eq!(2, LoxString::strong_count(&string));
+        match slot.get() {
+            Value::String(copy) => {
+                assert!(LoxString::ptr_eq(&string, &copy));
+                assert_eq!(3, LoxString::strong_count(&string));
+            }
+            value => panic!("expected the string, got {:?}", value),
+        }
+        assert_eq!(2, LoxString::strong_count(&string));
+
+        let previous = slot.replace(Value::Nil);
+        assert!(matches!(previous, Value::String(_)));
+        drop(previous);
+        assert_eq!(1, LoxString::strong_count(&string));
+
+        slot.set(Value::String(string.clone()));
+        drop(slot);
+        assert_eq!(1, LoxString::strong_count(&string));
+    }
+
+    #[cfg(feature = "nan_boxing")]
+    #[test]
+    fn packs_into_eight_bytes() {
+        assert_eq!(8, std::mem::size_of::<Slot>());
+    }
+}
@@ -0,0 +1,274 @@
+//! An experimental backend, behind the `template_jit` feature, that translates hot functions
+//! into chains of Rust closures, one per instruction, each specialized for its operands.
+//!
+//! Only pure functions are translated: functions that read and write nothing but their own
+//! locals and constants, and call nothing. Any other function keeps being interpreted. A
+//! compiled call gives up as soon as an operand is not what its fast path expects, like adding
+//! strings or negating `nil`, and the [VM] then interprets the call from the start instead. As
+//! the function had no side effects, the interpreter produces the same result, or reports the
+//! runtime error with its stack trace.
+//!
+//! Whether the backend stays depends on the `template_jit` benchmark in the tests, run with
+//! `cargo test --release --no-default-features --features template_jit -- --ignored --nocapture template_jit`.
+
+use crate::chunk::Instruction;
+use crate::value::function::Function;
+use crate::value::value::Value;
+
+use super::vm::is_falsey;
+
+/// What to do after an instruction.
+enum Step {
+    Next,
+    /// Continue at the instruction with the given index.
+    Jump(usize),
+    /// Return the value at the top of the stack.
+    Return,
+    /// Give up, so that the call is interpreted instead.
+    Bail,
+}
+
+/// An instruction translated to a closure, which runs on the call's stack.
+type Op = Box<dyn Fn(&mut Vec<Value>) -> Step>;
+
+/// A function translated to closures.
+pub(crate) struct CompiledFunction {
+    ops: Vec<Op>,
+}
+
+impl CompiledFunction {
+    /// Translates a function, if it is pure.
+    pub(crate) fn compile(function: &Function) -> Option<CompiledFunction> {
+        let chunk = &function.chunk;
+        let ops = chunk
+            .bytecode
+            .iter()
+            .enumerate()
+            .map(|(index, instruction)| -> Option<Op> {
+                Some(match *instruction {
+                    Instruction::OpConstant(constant) => {
+                        let value = chunk.constants[constant].clone();
+                        push(move || value.clone())
+                    }
+                    Instruction::OpNil => push(|| Value::Nil),
+                    Instruction::OpTrue => push(|| Value::Boolean(true)),
+                    Instruction::OpFalse => push(|| Value::Boolean(false)),
+                    Instruction::OpZero => push(|| Value::Number(0.0)),
+                    Instruction::OpOne => push(|| Value::Number(1.0)),
+                    Instruction::OpSmallInt(n) => push(move || Value::Number(n as f64)),
+                    Instruction::OpGetLocal(slot) => Box::new(move |stack| {
+                        let value = stack[slot].clone();
+                        stack.push(value);
+                        Step::Next
+                    }),
+                    Instruction::OpSetLocal(slot) => Box::new(move |stack| {
+                        stack[slot] = stack[stack.len() - 1].clone();
+                        Step::Next
+                    }),
+                    Instruction::OpPop => Box::new(|stack| {
+                        stack.pop();
+                        Step::Next
+                    }),
+                    Instruction::OpPopN(count) => Box::new(move |stack| {
+                        stack.truncate(stack.len() - count);
+                        Step::Next
+                    }),
+                    Instruction::OpDup => Box::new(|stack| {
+                        let value = stack[stack.len() - 1].clone();
+                        stack.push(value);
+                        Step::Next
+                    }),
+                    Instruction::OpAdd => numbers(|a, b| Value::Number(a + b)),
+                    Instruction::OpAddLocals(a, b) => {
+                        Box::new(move |stack| match (&stack[a], &stack[b]) {
+                            (Value::Number(a), Value::Number(b)) => {
+                                let sum = Value::Number(a + b);
+                                stack.push(sum);
+                                Step::Next
+                            }
+                            _ => Step::Bail,
+                        })
+                    }
+                    Instruction::OpSubtract => numbers(|a, b| Value::Number(a - b)),
+                    Instruction::OpMultiply => numbers(|a, b| Value::Number(a * b)),
+                    Instruction::OpDivide => numbers(|a, b| Value::Number(a / b)),
+                    Instruction::OpGreater => numbers(|a, b| Value::Boolean(a > b)),
+                    Instruction::OpLess => numbers(|a, b| Value::Boolean(a < b)),
+                    Instruction::OpEqual => Box::new(|stack| {
+                        let b = stack.pop().unwrap();
+                        let a = stack.pop().unwrap();
+                        // Instances may overload `==`, which only the interpreter calls.
+                        if let Value::Instance(_) = a {
+                            return Step::Bail;
+                        }
+                        stack.push(Value::Boolean(Value::equals(a, b)));
+                        Step::Next
+                    }),
+                    Instruction::OpNot => Box::new(|stack| {
+                        let value = stack.pop().unwrap();
+                        stack.push(Value::Boolean(is_falsey(&value)));
+                        Step::Next
+                    }),
+                    Instruction::OpNegate => Box::new(|stack| match stack.last_mut() {
+                        Some(Value::Number(n)) => {
+                            *n = -*n;
+                            Step::Next
+                        }
+                        _ => Step::Bail,
+                    }),
+                    Instruction::OpJump(offset) => {
+                        let target = index + 1 + offset;
+                        Box::new(move |_| Step::Jump(target))
+                    }
+                    Instruction::OpJumpIfFalse(offset) => {
+                        let target = index + 1 + offset;
+                        Box::new(move |stack| {
+                            if is_falsey(&stack[stack.len() - 1]) {
+                                Step::Jump(target)
+                            } else {
+                                Step::Next
+                            }
+                        })
+                    }
+                    Instruction::OpJumpIfTrue(offset) => {
+                        let target = index + 1 + offset;
+                        Box::new(move |stack| {
+                            if is_falsey(&stack[stack.len() - 1]) {
+                                Step::Next
+                            } else {
+                                Step::Jump(target)
+                            }
+                        })
+                    }
+                    Instruction::OpLoop(offset) => {
+                        let target = index + 1 - offset;
+                        Box::new(move |_| Step::Jump(target))
+                    }
+                    Instruction::OpReturn => Box::new(|_| Step::Return),
+                    _ => return None,
+                })
+            })
+            .collect::<Option<Vec<Op>>>()?;
+        Some(CompiledFunction { ops })
+    }
+
+    /// Runs a call, given the function and its arguments, returning the result and the number
+    /// of instructions it ran.
+    ///
+    /// Returns `None` if the call gave up, or would run more than `max_instructions`.
+    pub(crate) fn run(
+        &self,
+        mut stack: Vec<Value>,
+        max_instructions: usize,
+    ) -> Option<(Value, usize)> {
+        let mut ip = 0;
+        let mut count = 0;
+        loop {
+            count += 1;
+            if count > max_instructions {
+                return None;
+            }
+            match (self.ops[ip])(&mut stack) {
+                Step::Next => ip += 1,
+                Step::Jump(target) => ip = target,
+                Step::Return => return Some((stack.pop().unwrap(), count)),
+                Step::Bail => return None,
+            }
+        }
+    }
+}
+
+/// An instruction pushing a value.
+fn push(value: impl Fn() -> Value + 'static) -> Op {
+    Box::new(move |stack| {
+        stack.push(value());
+        Step::Next
+    })
+}
+
+/// A binary instruction on numbers, which gives up on other operands.
+fn numbers(operation: impl Fn(f64, f64) -> Value + 'static) -> Op {
+    Box::new(move |stack| {
+        let b = stack.pop().unwrap();
+        let a = stack.pop().unwrap();
+        match (a, b) {
+            (Value::Number(a), Value::Number(b)) => {
+                stack.push(operation(a, b));
+                Step::Next
+            }
+            _ => Step::Bail,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompilerManager;
+    use crate::value::class::{Class, Instance};
+    use crate::value::string::LoxString;
+
+    /// Compiles the first function declared in the source.
+    fn compile(source: &str) -> Option<CompiledFunction> {
+        let script = CompilerManager::compile(source.to_string()).unwrap();
+        let function = script
+            .chunk
+            .constants
+            .iter()
+            .find_map(|constant| match constant {
+                Value::Function(function) => Some(function.clone()),
+                _ => None,
+            })
+            .unwrap();
+        CompiledFunction::compile(&function)
+    }
+
+    fn call(function: &CompiledFunction, args: &[Value]) -> Option<Value> {
+        let mut stack = vec![Value::Nil];
+        stack.extend_from_slice(args);
+        function.run(stack, usize::MAX).map(|(value, _)| value)
+    }
+
+    #[test]
+    fn runs_pure_functions() {
+        let sum = compile(
+            "fun sum(n) { var total = 0; for (var i = 1; i <= n; i = i + 1) total = total + i; return total; }",
+        )
+        .unwrap();
+        match call(&sum, &[Value::Number(4.0)]) {
+            Some(Value::Number(n)) => assert_eq!(10.0, n),
+            result => panic!("expected 10, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn gives_up_on_other_operands() {
+        let add = compile("fun add(a, b) { return a + b; }").unwrap();
+        let strings = [
+            Value::String(LoxString::from("a")),
+            Value::String(LoxString::from("b")),
+        ];
+        assert!(call(&add, &strings).is_none());
+        assert!(call(&add, &[Value::Nil, Value::Number(1.0)]).is_none());
+
+        let equal = compile("fun equal(a, b) { return a == b; }").unwrap();
+        let class = std::rc::Rc::new(Class::new(LoxString::from("A")));
+        let instance = Value::Instance(std::rc::Rc::new(std::cell::RefCell::new(Instance::new(
+            class,
+        ))));
+        assert!(call(&equal, &[instance.clone(), instance]).is_none());
+    }
+
+    #[test]
+    fn gives_up_after_max_instructions() {
+        let spin = compile("fun spin() { while (true) {} }").unwrap();
+        assert!(spin.run(vec![Value::Nil], 1000).is_none());
+    }
+
+    #[test]
+    fn only_pure_functions() {
+        assert!(compile("fun f() { print 1; }").is_none());
+        assert!(compile("var g = 1; fun f() { return g; }").is_none());
+        assert!(compile("fun f() { return f(); }").is_none());
+    }
+}
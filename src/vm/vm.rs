@@ -1,17 +1,95 @@
-use std::cell::Cell;
-use std::collections::HashMap;
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufWriter, Write};
 use std::rc::Rc;
-use std::time::{Instant, SystemTime};
+use std::time::{Duration, Instant};
 
+use crate::diagnostic::{Catalog, Diagnostic, ErrorOutput};
+use crate::directives::parse_directives;
+use crate::serializer::DebugInfo;
+use crate::suggestion::{did_you_mean, KEYWORDS};
+use crate::value::class::{BoundMethod, Class, Instance, Method};
 use crate::value::function::Function;
-use crate::value::native_function::NativeFunction;
+use crate::value::inspect::inspect;
+use crate::value::native_function::{NativeFn, NativeFunction};
+use crate::value::number::{format_number_with, NumberFormat};
+use crate::value::string::LoxString;
 use crate::{binary_arithmetic_op, binary_boolean_op, compiler::*};
-use crate::{chunk::Instruction, value::value::Value};
+use crate::{
+    chunk::{format_location, Chunk, Instruction},
+    value::value::{List, Value},
+};
 
 use super::call_frame::CallFrame;
+use super::slot::Slot;
+#[cfg(feature = "template_jit")]
+use super::template::CompiledFunction;
 
-const FRAMES_MAX: usize = 64;
-const STACK_MAX: usize = 256 * FRAMES_MAX;
+/// The handler of an instruction registered with [VM::register_extension].
+///
+/// It receives the [VM] and the instruction's arguments, and returns the value to push.
+/// Like a [NativeFn], it must report errors with [VM::runtime_error] before returning them.
+pub type ExtensionHandler = Rc<dyn Fn(&mut VM, &[Value]) -> Result<Value, VMError>>;
+
+/// Loads the [DebugInfo] of stripped bytecode, see [VM::set_debug_info_loader].
+pub type DebugInfoLoader = Box<dyn FnOnce() -> Result<DebugInfo, String>>;
+
+/// An entry of the global variables table.
+struct Global {
+    value: Value,
+    /// Whether scripts cannot assign or redefine the global, see [VM::define_read_only_global].
+    read_only: bool,
+}
+
+/// A catch block registered by [Instruction::OpSetupCatch], which catches the values thrown
+/// until its try block ends.
+struct Handler {
+    /// The number of [CallFrame]s when the try block started, identifying the frame it belongs to.
+    depth: usize,
+    /// The top of the value stack when the try block started, where the caught value goes.
+    stack_top: usize,
+    /// The index of the catch block's first instruction.
+    ip: usize,
+}
+
+/// An instruction registered with [VM::register_extension].
+struct Extension {
+    name: String,
+    arity: u8,
+    handler: ExtensionHandler,
+}
+
+/// The default maximum number of nested calls.
+pub const FRAMES_MAX: usize = 64;
+/// The number of values a call is assumed to need at most, which must be left on the stack
+/// for a call to start.
+pub const FRAME_SLOTS: usize = 256;
+/// The default maximum number of values on the stack.
+pub const STACK_MAX: usize = FRAME_SLOTS * FRAMES_MAX;
+
+/// How deep the VM's stacks can grow, set with [VM::with_limits].
+///
+/// Recursion stops with a stack overflow error at whichever limit it reaches first.
+/// [VM::with_limits] raises limits too small for the top-level script: one frame and
+/// [FRAME_SLOTS] plus one values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// The maximum number of nested calls, counting the top-level script.
+    pub frames_max: usize,
+    /// The maximum number of values on the stack, which is allocated up front.
+    pub stack_max: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            frames_max: FRAMES_MAX,
+            stack_max: STACK_MAX,
+        }
+    }
+}
 
 /// A virtual machine that interprets chunks of bytecode.
 pub struct VM {
@@ -20,233 +98,888 @@ pub struct VM {
     frames: Vec<CallFrame>,
     /// The current number of [CallFrame].
     // frame_count: usize,
-    /// The VM's value stack.
-    stack: [Cell<Value>; STACK_MAX],
+    /// The VM's value stack, of [Limits::stack_max] slots.
+    stack: Vec<Slot>,
+    limits: Limits,
     /// The index pointing right after the last element of the stack.
     stack_top: usize,
-    /// All global variables.
-    globals: HashMap<String, Value>,
+    /// All global variables, by the slot the compiler gave them.
+    /// A slot is empty until its variable is defined.
+    globals: Vec<Option<Global>>,
+    /// The name of each global slot.
+    global_names: Vec<LoxString>,
+    /// The slot of each global, by name, so that scripts compiled later, like the lines of the
+    /// REPL, use the same slots.
+    global_slots: HashMap<LoxString, usize>,
+    /// The deferred code registered by [Instruction::OpDefer], in the order it was registered.
+    ///
+    /// Each entry holds the number of [CallFrame]s at the time of registration,
+    /// identifying the frame it belongs to, and the index of the deferred code's first instruction.
+    defers: Vec<(usize, usize)>,
+    /// The catch blocks of the try blocks being run, innermost last.
+    handlers: Vec<Handler>,
+    /// The number of [CallFrame]s of each call running its deferred code because a thrown value
+    /// unwinds it, innermost last. The value is thrown on once the deferred code is done.
+    unwinding: Vec<usize>,
+    /// The native functions being called, innermost last, each with the number of [CallFrame]s
+    /// at the time of the call, so that the stack trace of a runtime error raised inside a native
+    /// function shows the native function and the Lox code that called it.
+    native_calls: Vec<(usize, Rc<NativeFunction>)>,
+    /// The registered extra instructions, indexed by their id.
+    extensions: Vec<Extension>,
+    /// The names of the registered native functions, which scripts may be kept from replacing,
+    /// see [Lint::ShadowedNative].
+    natives: HashSet<String>,
+    /// The handlers registered by scripts with `on(event, handler)`, in the order they were
+    /// registered, to be called by [VM::dispatch].
+    event_handlers: HashMap<String, Vec<Value>>,
+    /// The globals watched with [VM::watch], with the values assigned to them in order.
+    ///
+    /// Kept across [VM::interpret] calls, like the globals, so that a variable watched on one
+    /// line of the REPL records the assignments made by the next ones.
+    watches: HashMap<String, Vec<WatchedWrite>>,
 
-    /// Only for testing.
+    /// The options used to compile the source code passed to [VM::interpret].
+    pub compile_options: CompileOptions,
+    /// The messages of runtime errors, and of compile errors and warnings,
+    /// replacing the catalog in [VM::compile_options].
+    pub catalog: Rc<Catalog>,
+    /// Where the print statement writes to, buffered.
     ///
-    ///Holds the values printed by the print statement,
-    /// so that they can be compared to the expected output in the tests.
-    pub printed_values: Vec<Value>,
-    /// Only for testing. Holds the latest error value
-    pub latest_error_message: String,
+    /// It is flushed when [VM::interpret] returns and before a runtime error is reported.
+    output: BufWriter<Box<dyn Write>>,
+    /// Where compile errors, warnings and runtime errors are written to,
+    /// replacing the one in [VM::compile_options].
+    error_output: ErrorOutput,
+    /// Whether the output is flushed after every print statement, instead of being buffered.
+    pub unbuffered_output: bool,
+    /// Whether the values printed by the print statement are collected in [Interpretation::printed_values].
+    ///
+    /// Off by default, so that print-heavy programs do not accumulate every printed value.
+    pub capture_output: bool,
+    /// When numbers are written with an exponent, by `print`, `inspect` and runtime errors.
+    pub number_format: NumberFormat,
+    /// Whether the peak stack and call depths are tracked, to be read with [VM::profile].
+    pub profiling: bool,
+    /// The most instructions a single call may run before a runtime error, to catch loops that
+    /// never end. The instructions of the calls it makes only count for those calls.
+    pub max_frame_instructions: Option<usize>,
+    /// The most instructions a [VM::interpret] call may run, counting every call, before it
+    /// stops with [VMError::Timeout], to safely run scripts that might never end.
+    pub max_instructions: Option<usize>,
+    /// Whether calls to native functions that are not deterministic are recorded,
+    /// to be read with [VM::nondeterministic_calls].
+    pub audit_determinism: bool,
+    /// Whether every instruction is printed with the stack before it runs.
+    ///
+    /// On by default, but only has an effect with the `debug_trace_execution` feature.
+    pub trace_execution: bool,
+
+    /// The values printed by the print statement during the current [VM::interpret] call,
+    /// if [VM::capture_output] is set.
+    printed_values: Vec<Value>,
+    /// The number of instructions executed during the current [VM::interpret] call.
+    instruction_count: usize,
+    /// The warnings reported while compiling the source passed to the current [VM::interpret] call.
+    warnings: Vec<Warning>,
+    /// The peak depths during the current [VM::interpret] call, if [VM::profiling] is set.
+    profile: Profile,
+    /// The time spent compiling and running during the current [VM::interpret] call.
+    timing: Timing,
+    /// The calls to native functions that are not deterministic during the current
+    /// [VM::interpret] call, if [VM::audit_determinism] is set.
+    nondeterministic_calls: Vec<NondeterministicCall>,
+    /// The message of the latest compile or runtime error.
+    error_message: String,
+    /// The code of the latest compile or runtime error, if it has one.
+    error_code: Option<Diagnostic>,
+    /// The stack trace of the latest runtime error.
+    stack_trace: Vec<String>,
+    /// Loads the names and lines of stripped functions, the first time a stack trace needs them.
+    debug_info_loader: Option<DebugInfoLoader>,
+    /// The names and lines of stripped functions, once loaded.
+    debug_info: Option<DebugInfo>,
+    /// The number of times each kind of instruction ran during the current [VM::interpret] call,
+    /// and the time spent on it.
+    #[cfg(feature = "opcode_timing")]
+    opcode_timings: HashMap<&'static str, (usize, Duration)>,
+    /// The instruction being timed and when it started.
+    #[cfg(feature = "opcode_timing")]
+    opcode_timer: Option<(&'static str, Instant)>,
+    /// The number of times each kind of instruction ran during the current [VM::interpret] call.
+    #[cfg(feature = "profile")]
+    opcode_counts: HashMap<&'static str, usize>,
+    /// The number of instructions each function ran during the current [VM::interpret] call,
+    /// keeping the functions alive so that their addresses keep identifying them.
+    #[cfg(feature = "profile")]
+    function_counts: HashMap<*const Function, (Rc<Function>, usize)>,
+    /// The number of calls and loop iterations of each function so far, to find the ones worth
+    /// compiling with the template backend.
+    #[cfg(feature = "template_jit")]
+    hotness: HashMap<*const Function, usize>,
+    /// The functions the template backend translated, or `None` for those it cannot translate,
+    /// keeping the functions alive so that their addresses keep identifying them.
+    #[cfg(feature = "template_jit")]
+    compiled: HashMap<*const Function, (Rc<Function>, Option<Rc<CompiledFunction>>)>,
+    /// The number of calls and loop iterations after which a function is compiled
+    /// with the template backend, see [crate::vm::template].
+    #[cfg(feature = "template_jit")]
+    pub jit_threshold: usize,
 }
 
 pub type VMResult = Result<(), VMError>;
 
+/// The deepest the VM's stacks got while running, to help tune the [Limits] and to spot runaway
+/// recursion.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Profile {
+    /// The largest number of values on the value stack.
+    pub peak_stack_depth: usize,
+    /// The largest number of nested calls, counting the top-level script.
+    pub peak_frame_depth: usize,
+}
+
+/// How long the latest [VM::interpret] call took to compile and to run its code.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timing {
+    pub compile_time: Duration,
+    pub run_time: Duration,
+    /// The number of instructions executed.
+    pub instruction_count: usize,
+}
+
+/// The calls from one place in the code to a native function that is not deterministic,
+/// recorded by [VM::audit_determinism].
+#[derive(Debug, Clone, PartialEq)]
+pub struct NondeterministicCall {
+    /// The name of the native function.
+    pub name: String,
+    /// Where the function was called from, like `main.lox:3`,
+    /// or `native code` when no Lox code was running.
+    pub location: String,
+    /// The number of calls from that place.
+    pub count: usize,
+}
+
+/// A value defined or assigned to a global watched with [VM::watch].
+#[derive(Debug, Clone)]
+pub struct WatchedWrite {
+    pub value: Value,
+    /// Where the value was written, like `main.lox:3`.
+    pub location: String,
+}
+
+/// The time spent on one kind of instruction during a run, measured with the `opcode_timing` feature.
+#[cfg(feature = "opcode_timing")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpcodeTiming {
+    /// The name of the instruction, as shown in disassembly.
+    pub name: &'static str,
+    /// The number of times it ran.
+    pub count: usize,
+    /// The wall time spent on it, including the native functions and nested runs it started.
+    pub total: Duration,
+}
+
+/// The number of times each kind of instruction ran during a run, and the number of instructions
+/// each function ran, counted with the `profile` feature.
+#[cfg(feature = "profile")]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProfileReport {
+    /// The name of each kind of instruction, as shown in disassembly, with the number of times
+    /// it ran, most frequent first.
+    pub opcodes: Vec<(&'static str, usize)>,
+    /// Each function, like `fib() at main.lox:3`, with the number of instructions it ran,
+    /// busiest first.
+    pub functions: Vec<(String, usize)>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum VMError {
     CompileError,
     RuntimeError,
+    /// The program ran out of instructions, set with [VM::max_instructions].
+    Timeout,
+}
+
+/// The result of [VM::interpret].
+pub type InterpretResult = Result<Interpretation, LoxError>;
+
+/// The outcome of successfully interpreting a program.
+#[derive(Debug)]
+pub struct Interpretation {
+    /// The values printed by the program, in order, if [VM::capture_output] is set.
+    pub printed_values: Vec<Value>,
+    /// A snapshot of the global variables after the program ran.
+    pub globals: HashMap<String, Value>,
+    /// The value returned by a top-level `return`, like `return 1;`, or nil.
+    ///
+    /// For a [Program], this is the value of its last script.
+    pub return_value: Value,
+    /// The number of instructions executed.
+    pub instruction_count: usize,
+    /// The warnings reported while compiling the program.
+    pub warnings: Vec<Warning>,
+}
+
+/// An error that stopped a program from being compiled or from running to completion.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoxError {
+    /// Whether compilation or execution failed.
+    pub kind: VMError,
+    /// The code of the error, for matching errors without comparing messages.
+    ///
+    /// Only runtime errors raised by native functions with [VM::runtime_error] have none.
+    pub code: Option<Diagnostic>,
+    /// The error message, without the line information or stack trace.
+    pub message: String,
+    /// The stack trace of a runtime error, innermost call first. Empty for compile errors.
+    pub stack_trace: Vec<String>,
 }
 
 impl VM {
     pub fn new() -> VM {
-        const V: Cell<Value> = Cell::new(Value::Nil);
+        VM::with_limits(Limits::default())
+    }
+
+    /// Creates a VM whose stacks grow up to the given limits, trading memory for recursion depth.
+    pub fn with_limits(limits: Limits) -> VM {
+        let limits = Limits {
+            frames_max: limits.frames_max.max(1),
+            stack_max: limits.stack_max.max(FRAME_SLOTS + 1),
+        };
         let mut vm = VM {
             frames: Vec::new(),
-            stack: [V; STACK_MAX],
+            stack: std::iter::repeat_with(Slot::new)
+                .take(limits.stack_max)
+                .collect(),
+            limits,
             stack_top: 0,
-            globals: HashMap::new(),
+            globals: Vec::new(),
+            global_names: Vec::new(),
+            global_slots: HashMap::new(),
+            defers: Vec::new(),
+            handlers: Vec::new(),
+            unwinding: Vec::new(),
+            native_calls: Vec::new(),
+            extensions: Vec::new(),
+            natives: HashSet::new(),
+            event_handlers: HashMap::new(),
+            watches: HashMap::new(),
+            compile_options: CompileOptions::default(),
+            catalog: Rc::new(Catalog::default()),
+            output: BufWriter::new(Box::new(std::io::stdout())),
+            error_output: ErrorOutput::default(),
+            unbuffered_output: false,
+            capture_output: false,
             printed_values: Vec::new(),
-            latest_error_message: String::new(),
+            instruction_count: 0,
+            warnings: Vec::new(),
+            number_format: NumberFormat::default(),
+            profiling: false,
+            max_frame_instructions: None,
+            max_instructions: None,
+            audit_determinism: false,
+            trace_execution: true,
+            profile: Profile::default(),
+            timing: Timing::default(),
+            nondeterministic_calls: Vec::new(),
+            error_message: String::new(),
+            error_code: None,
+            stack_trace: Vec::new(),
+            debug_info_loader: None,
+            debug_info: None,
+            #[cfg(feature = "opcode_timing")]
+            opcode_timings: HashMap::new(),
+            #[cfg(feature = "opcode_timing")]
+            opcode_timer: None,
+            #[cfg(feature = "profile")]
+            opcode_counts: HashMap::new(),
+            #[cfg(feature = "profile")]
+            function_counts: HashMap::new(),
+            #[cfg(feature = "template_jit")]
+            hotness: HashMap::new(),
+            #[cfg(feature = "template_jit")]
+            compiled: HashMap::new(),
+            #[cfg(feature = "template_jit")]
+            jit_threshold: 100,
         };
 
-        vm.define_native("clock", clock_native);
+        vm.define_nondeterministic_native("clock", 0, clock_native);
+        vm.define_native("on", 2, on_native);
+        vm.define_native("watch", 1, watch_native);
+        vm.define_native("history", 1, history_native);
+        crate::native_fn!(vm, "inspect", |vm; value: &Value| inspect(value, vm.number_format));
+        vm.define_native("freeze", 1, freeze_native);
+        crate::value::list::define_natives(&mut vm);
+        #[cfg(feature = "vector_math")]
+        crate::value::vector::define_natives(&mut vm);
 
         vm
     }
 
-    pub fn interpret(&mut self, source: String) -> VMResult {
-        let r = match CompilerManager::compile(source) {
-            Ok(r) => r,
-            Err(error_message) => {
-                self.latest_error_message = error_message;
+    pub fn interpret(&mut self, source: String) -> InterpretResult {
+        self.interpret_source("", source)
+    }
+
+    /// Interprets source code with a name, like a file path or `<repl>`,
+    /// which is shown in compile errors and stack traces.
+    pub fn interpret_source(&mut self, name: &str, source: String) -> InterpretResult {
+        self.begin_interpretation();
+        let result = self.compile_and_run(name, source);
+        self.finish_interpretation(result)
+    }
+
+    /// Runs the scripts of a [Program] in order, sharing this VM's globals.
+    ///
+    /// Stops at the first script with a runtime error. A top-level `return` only ends its own
+    /// script.
+    pub fn interpret_program(&mut self, program: Program) -> InterpretResult {
+        self.begin_interpretation();
+        self.warnings = program.warnings;
+        let result = program
+            .scripts
+            .into_iter()
+            .try_fold(Value::Nil, |_, (_, function)| self.run_script(function));
+        self.finish_interpretation(result)
+    }
+
+    fn begin_interpretation(&mut self) {
+        self.printed_values.clear();
+        self.instruction_count = 0;
+        self.warnings.clear();
+        self.profile = Profile::default();
+        self.timing = Timing::default();
+        self.nondeterministic_calls.clear();
+        #[cfg(feature = "opcode_timing")]
+        self.opcode_timings.clear();
+        #[cfg(feature = "profile")]
+        {
+            self.opcode_counts.clear();
+            self.function_counts.clear();
+        }
+    }
+
+    /// The peak depths during the latest [VM::interpret] call, also when it failed,
+    /// if [VM::profiling] is set.
+    pub fn profile(&self) -> Option<Profile> {
+        self.profiling.then_some(self.profile)
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// How long the latest [VM::interpret] call took, also when it failed.
+    pub fn timing(&self) -> Timing {
+        Timing {
+            instruction_count: self.instruction_count,
+            ..self.timing
+        }
+    }
+
+    /// The calls to native functions that are not deterministic during the latest
+    /// [VM::interpret] call, also when it failed, if [VM::audit_determinism] is set.
+    ///
+    /// Calls from the same place are counted together, in the order of their first call.
+    pub fn nondeterministic_calls(&self) -> Option<&[NondeterministicCall]> {
+        self.audit_determinism
+            .then_some(self.nondeterministic_calls.as_slice())
+    }
+
+    fn finish_interpretation(&mut self, result: Result<Value, VMError>) -> InterpretResult {
+        #[cfg(feature = "opcode_timing")]
+        self.time_opcode(None);
+        self.flush_output();
+
+        let printed_values = std::mem::take(&mut self.printed_values);
+        match result {
+            Ok(return_value) => Ok(Interpretation {
+                printed_values,
+                return_value,
+                globals: self
+                    .defined_globals()
+                    .map(|(name, global)| (name.to_string(), global.value.clone()))
+                    .collect(),
+                instruction_count: self.instruction_count,
+                warnings: std::mem::take(&mut self.warnings),
+            }),
+            Err(kind) => Err(LoxError {
+                kind,
+                code: self.error_code.take(),
+                message: std::mem::take(&mut self.error_message),
+                stack_trace: std::mem::take(&mut self.stack_trace),
+            }),
+        }
+    }
+
+    fn compile_and_run(&mut self, name: &str, source: String) -> Result<Value, VMError> {
+        let mut options = self.compile_options.clone();
+        options.catalog = Rc::clone(&self.catalog);
+        options.error_output = self.error_output.clone();
+        for (id, extension) in self.extensions.iter().enumerate() {
+            options.extensions.insert(
+                extension.name.clone(),
+                ExtensionOpcode {
+                    id: id as u8,
+                    arity: extension.arity,
+                },
+            );
+        }
+        if options.lint_level(Lint::UndefinedGlobal) != LintLevel::Allow {
+            options
+                .known_globals
+                .extend(self.defined_globals().map(|(name, _)| name.to_string()));
+        }
+        if options.lint_level(Lint::ShadowedNative) != LintLevel::Allow {
+            options.natives.extend(self.natives.iter().cloned());
+        }
+        options.global_slots = self
+            .global_names
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        let compile_start = Instant::now();
+        let compiled = parse_directives(name, &source, &options).and_then(|directives| {
+            directives.apply(&mut options);
+            let program = CompilerManager::compile_many(&[(name, &source)], &options)?;
+            Ok((directives, program))
+        });
+        self.timing.compile_time = compile_start.elapsed();
+        let (directives, r) = match compiled {
+            Ok((directives, mut program)) => {
+                self.warnings = program.warnings;
+                (directives, program.scripts.pop().unwrap().1)
+            }
+            Err(error) => {
+                self.error_message = error.message;
+                self.error_code = Some(error.code);
                 return Err(VMError::CompileError);
             }
         };
 
-        let function = Rc::new(r);
+        // The script's own limits only hold while it runs.
+        let max_frame_instructions = self.max_frame_instructions;
+        let max_instructions = self.max_instructions;
+        if directives.max_frame_instructions.is_some() {
+            self.max_frame_instructions = directives.max_frame_instructions;
+        }
+        if directives.max_instructions.is_some() {
+            self.max_instructions = directives.max_instructions;
+        }
+        let result = self.run_script(r);
+        self.max_frame_instructions = max_frame_instructions;
+        self.max_instructions = max_instructions;
+        result
+    }
+
+    /// Runs a compiled top-level script to completion, returning the value of its top-level
+    /// `return`, or nil.
+    fn run_script(&mut self, mut script: Function) -> Result<Value, VMError> {
+        self.link(&mut script);
+        let start = Instant::now();
+        let result = self.run_function(Rc::new(script));
+        self.timing.run_time += start.elapsed();
+        result
+    }
+
+    fn run_function(&mut self, function: Rc<Function>) -> Result<Value, VMError> {
         // Push the compiled function to the stack.
-        self.push_to_stack(Value::Function(Rc::clone(&function)));
+        self.push_to_stack(Value::Function(Rc::clone(&function)))?;
+
+        self.call(function, None, 0)?;
+        self.run(0)?;
+
+        // Like any other call, the script leaves its return value on the stack.
+        let return_value = self.pop_from_stack();
+        debug_assert_eq!(
+            0, self.stack_top,
+            "The stack is unbalanced after the script."
+        );
+        Ok(return_value)
+    }
+
+    /// Calls a Lox value with the given arguments and returns its result.
+    ///
+    /// Meant for native functions that call back into Lox, and for embedders.
+    /// If a Lox function is called, a nested run loop executes it until it returns.
+    /// Any runtime error has already been reported, and the stack reset, when this returns an error.
+    pub fn call_value(&mut self, callee: Value, args: &[Value]) -> Result<Value, VMError> {
+        if self.stack_top + args.len() + 1 > self.limits.stack_max {
+            self.report(Diagnostic::StackOverflow, &[]);
+            return Err(VMError::RuntimeError);
+        }
+
+        self.push_to_stack(callee.clone())?;
+        for arg in args {
+            self.push_to_stack(arg.clone())?;
+        }
+
+        match callee {
+            Value::Function(function) => self.run_call(function, None, args.len()),
+            Value::NativeFunction(native) => {
+                self.call_native(native, args.len())?;
+                Ok(self.pop_from_stack())
+            }
+            Value::Class(class) => match self.instantiate(class, args.len())? {
+                Some(init) => self.run_call(init.function, init.superclass, args.len()),
+                None => Ok(self.pop_from_stack()),
+            },
+            Value::BoundMethod(bound) => {
+                self.stack[self.stack_top - 1 - args.len()].replace(bound.receiver.clone());
+                let method = bound.method.clone();
+                self.run_call(method.function, method.superclass, args.len())
+            }
+            _ => {
+                self.report(Diagnostic::NotCallable, &[]);
+                Err(VMError::RuntimeError)
+            }
+        }
+    }
+
+    /// Calls the handlers that scripts registered for an event with `on(event, handler)`,
+    /// in the order they were registered, passing them the payload.
+    ///
+    /// Stops at the first handler with a runtime error. Handlers registered while the event is
+    /// dispatched are only called for later events.
+    pub fn dispatch(&mut self, event: &str, payload: &[Value]) -> InterpretResult {
+        self.begin_interpretation();
+        let handlers = self.event_handlers.get(event).cloned().unwrap_or_default();
+        let result = handlers
+            .into_iter()
+            .try_for_each(|handler| self.call_value(handler, payload).map(|_| ()))
+            .map(|()| Value::Nil);
+        self.finish_interpretation(result)
+    }
+
+    /// Calls a Lox function whose arguments are on the stack, in a nested run loop,
+    /// and returns its result.
+    fn run_call(
+        &mut self,
+        function: Rc<Function>,
+        superclass: Option<Rc<Class>>,
+        arg_count: usize,
+    ) -> Result<Value, VMError> {
+        let base_depth = self.frames.len();
+        if base_depth >= self.limits.frames_max {
+            self.report(Diagnostic::StackOverflow, &[]);
+            return Err(VMError::RuntimeError);
+        }
+        self.call(function, superclass, arg_count)?;
+        self.run(base_depth)?;
+        Ok(self.pop_from_stack())
+    }
 
-        self.call(function, 0, 0)?;
+    /// Replaces where the print statement writes to, flushing the previous output.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.flush_output();
+        self.output = BufWriter::new(output);
+    }
 
-        self.run()
+    /// Replaces where compile errors, warnings and runtime errors are written to.
+    pub fn set_error_output(&mut self, output: Box<dyn Write>) {
+        self.error_output = ErrorOutput::new(output);
+    }
+
+    /// Writes a value to the output like the print statement.
+    fn print(&mut self, value: Value) -> VMResult {
+        let value = self.display_value(value)?;
+        if self.capture_output {
+            self.printed_values.push(value.clone());
+        }
+        if writeln!(self.output, "{}", value.with_format(self.number_format)).is_err() {
+            self.report(Diagnostic::OutputFailed, &[]);
+            return Err(VMError::RuntimeError);
+        }
+        if self.unbuffered_output {
+            self.flush_output();
+        }
+        Ok(())
+    }
+
+    /// Writes out any buffered output.
+    pub fn flush_output(&mut self) {
+        // There is nowhere left to report a failure to write the output to.
+        let _ = self.output.flush();
     }
 
     pub fn reset_stack(&mut self) {
-        self.stack_top = 0;
+        self.truncate_stack(0);
         self.frames.clear();
+        self.defers.clear();
+        self.handlers.clear();
+        self.unwinding.clear();
+        self.native_calls.clear();
     }
 
-    fn run(&mut self) -> VMResult {
-        let mut frame = self.frames[self.frames.len() - 1].clone();
+    /// Executes instructions until the number of [CallFrame]s drops back to `base_depth`.
+    ///
+    /// A `base_depth` of 0 runs the script to completion,
+    /// while a larger one is used by [VM::call_value] for a nested call.
+    fn run(&mut self, base_depth: usize) -> VMResult {
+        // The function of the current frame. The frames themselves, with their ip, stay in
+        // [VM::frames], where calls from natives and stack traces find them.
+        let mut function = Rc::clone(&self.frame().function);
 
         loop {
-            let chunk = &frame.function.chunk;
+            let chunk = &function.chunk;
 
             // conditional compilation for logging
             #[cfg(feature = "debug_trace_execution")]
-            if cfg!(feature = "debug_trace_execution") {
+            if self.trace_execution {
                 for i in 0..self.stack_top {
-                    print!("[{}]", self.stack[i].get_mut());
+                    print!("[{}]", self.stack[i].get());
                 }
                 println!();
-                chunk.disassemble_instruction(frame.ip);
+                chunk.disassemble_instruction(self.frame().ip);
             }
             //
 
+            let frame = self.frame_mut();
             let instruction = chunk.read_code(frame.ip);
             frame.ip += 1;
+            frame.instruction_count += 1;
+            let frame_instruction_count = frame.instruction_count;
+            #[cfg(feature = "opcode_timing")]
+            self.time_opcode(Some(instruction.name()));
+            #[cfg(feature = "profile")]
+            self.count_instruction(instruction.name(), &function);
+            self.instruction_count += 1;
+            if let Some(max) = self.max_instructions {
+                if self.instruction_count > max {
+                    self.report(Diagnostic::InstructionLimit, &[&max.to_string()]);
+                    return Err(VMError::Timeout);
+                }
+            }
+            if let Some(max) = self.max_frame_instructions {
+                if frame_instruction_count > max {
+                    let name = match function.name.as_str() {
+                        "" => "script",
+                        name => name,
+                    };
+                    self.report(Diagnostic::FrameInstructionLimit, &[name, &max.to_string()]);
+                    return Err(VMError::RuntimeError);
+                }
+            }
+            // The fused instruction pushes its locals, then adds them like the OpAdd it replaced.
+            let instruction = match instruction {
+                Instruction::OpAddLocals(a, b) => {
+                    let stack_index = self.frame().stack_index;
+                    let a = self.stack[stack_index + a].get();
+                    let b = self.stack[stack_index + b].get();
+                    self.push_to_stack(a)?;
+                    self.push_to_stack(b)?;
+                    Instruction::OpAdd
+                }
+                instruction => instruction,
+            };
+            if let Some((method, arg_count)) = self.operator_method(instruction) {
+                self.call(method.function, method.superclass, arg_count)?;
+                function = Rc::clone(&self.frame().function);
+                continue;
+            }
             match instruction {
-                Instruction::OpCall(arg_count) => {
-                    // TODO: make peek function
-                    let val = self.stack[self.stack_top - 1 - arg_count].get_mut();
-                    //
+                Instruction::OpCall(arg_count) | Instruction::OpTailCall(arg_count) => {
+                    let val = self.peek(arg_count);
 
                     // TODO: Put into separate function?
-                    let mut function: Option<Rc<Function>> = None;
-                    match val {
-                        Value::Function(f) => {
-                            function = Some(Rc::clone(f));
-                        }
-                        Value::NativeFunction(f) => {
-                            let f = &f.function;
-                            let result = f();
-                            self.stack_top -= arg_count + 1;
-                            self.push_to_stack(result);
+                    let (callee, superclass) = match val {
+                        Value::Function(f) => (f, None),
+                        Value::NativeFunction(native) => {
+                            self.call_native(native, arg_count)?;
                             continue;
                         }
+                        Value::Class(class) => match self.instantiate(class, arg_count)? {
+                            Some(init) => (init.function, init.superclass),
+                            None => continue,
+                        },
+                        Value::BoundMethod(bound) => {
+                            // The method receives the instance as `this`, in slot 0.
+                            self.stack[self.stack_top - 1 - arg_count]
+                                .replace(bound.receiver.clone());
+                            let method = bound.method.clone();
+                            (method.function, method.superclass)
+                        }
                         _ => {
-                            self.runtime_error("Can only call functions and classes.");
+                            self.report(Diagnostic::NotCallable, &[]);
                             return Err(VMError::RuntimeError);
                         }
+                    };
+                    #[cfg(feature = "template_jit")]
+                    if self.run_compiled(&callee, arg_count) {
+                        continue;
                     }
-                    if function.is_some() {
-                        self.call(function.unwrap(), arg_count, frame.ip)?;
+                    if let Instruction::OpTailCall(_) = instruction {
+                        if self.frame_is_done() {
+                            self.tail_call(callee, superclass, arg_count)?;
+                            function = Rc::clone(&self.frame().function);
+                            continue;
+                        }
                     }
-                    //
-
-                    frame = self.frames[self.frames.len() - 1].clone();
+                    self.call(callee, superclass, arg_count)?;
+                    function = Rc::clone(&self.frame().function);
                 }
                 Instruction::OpNot => {
                     let b = is_falsey(&self.pop_from_stack());
-                    self.push_to_stack(Value::Boolean(b))
+                    self.push_to_stack(Value::Boolean(b))?
                 }
                 Instruction::OpNegate => {
-                    if let Value::Number(val) = self.pop_from_stack() {
-                        self.push_to_stack(Value::Number(-val))
+                    if let Value::Number(val) = self.peek(0) {
+                        self.pop_from_stack();
+                        self.push_to_stack(Value::Number(-val))?
                     } else {
-                        self.runtime_error("Operand must be a number.");
+                        self.report(Diagnostic::OperandNotNumber, &[]);
                         return Err(VMError::RuntimeError);
                     }
                 }
                 Instruction::OpJump(offset) => {
-                    frame.ip += offset;
+                    self.frame_mut().ip += offset;
                 }
                 Instruction::OpJumpIfFalse(offset) => {
                     let v: Value = self.pop_from_stack();
                     if is_falsey(&v) {
-                        frame.ip += offset;
+                        self.frame_mut().ip += offset;
+                    }
+                    self.push_to_stack(v)?;
+                }
+                Instruction::OpAddLocals(_, _) => unreachable!("replaced by OpAdd above"),
+                Instruction::OpJumpIfTrue(offset) => {
+                    if !is_falsey(&self.peek(0)) {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                Instruction::OpJumpIfNotEqual(offset) => {
+                    // The value is compared again by the next arm, so `equals` is called on
+                    // a copy of it, in a nested run loop.
+                    let value = self.peek(1);
+                    let pattern = self.pop_from_stack();
+                    let equal = self.values_equal(&value, &pattern)?;
+                    if !equal {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                Instruction::OpJumpIfNil(offset) => {
+                    if matches!(self.peek(0), Value::Nil) {
+                        self.frame_mut().ip += offset;
                     }
-                    self.push_to_stack(v);
+                }
+                Instruction::OpDefer(offset) => {
+                    let ip = self.frame().ip;
+                    self.defers.push((self.frames.len(), ip));
+                    self.frame_mut().ip += offset;
+                }
+                Instruction::OpSetupCatch(offset) => self.handlers.push(Handler {
+                    depth: self.frames.len(),
+                    stack_top: self.stack_top,
+                    ip: self.frame().ip + offset,
+                }),
+                Instruction::OpPopCatch => {
+                    self.handlers.pop();
+                }
+                Instruction::OpThrow => {
+                    let value = self.pop_from_stack();
+                    self.throw(value, base_depth)?;
+                    function = Rc::clone(&self.frame().function);
                 }
                 Instruction::OpLoop(offset) => {
-                    frame.ip -= offset;
+                    self.frame_mut().ip -= offset;
+                    #[cfg(feature = "template_jit")]
+                    {
+                        *self.hotness.entry(Rc::as_ptr(&function)).or_insert(0) += 1;
+                    }
                 }
                 Instruction::OpGetLocal(frame_index) => {
-                    let idx = frame.stack_index + frame_index;
-                    let v = self.stack[idx].take();
-                    self.stack[idx] = Cell::new(v.clone());
-                    self.push_to_stack(v);
+                    let idx = self.frame().stack_index + frame_index;
+                    let v = self.stack[idx].get();
+                    self.push_to_stack(v)?;
                 }
                 Instruction::OpSetLocal(frame_index) => {
-                    let idx = frame.stack_index + frame_index;
-                    let v = self.stack[self.stack_top - 1].take();
-                    self.stack[self.stack_top - 1] = Cell::new(v.clone());
-                    self.stack[idx] = Cell::new(v);
-                }
-                Instruction::OpGetGlobal(index) => {
-                    if let Value::String(name) = chunk.read_constant(index) {
-                        let v = self.globals.get(&name.to_string());
-                        if v.is_none() {
-                            self.runtime_error(&format!("Undefined variable '{}'.", &name));
-                            return Err(VMError::RuntimeError);
-                        }
-                        let v = v.unwrap().clone();
-                        self.push_to_stack(v);
-                    } else {
-                        return Err(VMError::RuntimeError);
-                    };
+                    let idx = self.frame().stack_index + frame_index;
+                    let v = self.peek(0);
+                    self.stack[idx].set(v);
                 }
-                Instruction::OpSetGlobal(index) => {
-                    if let Value::String(name) = chunk.read_constant(index) {
-                        // cannot set uninitialized variable
-                        // in case of error, delete it from the table (only relevant for the REPL)
-                        if !self.globals.contains_key(&name.to_string()) {
-                            self.globals.remove(&name.to_string());
-                            self.runtime_error(&format!("Undefined variable '{}'.", &name));
-                            return Err(VMError::RuntimeError);
-                        }
+                Instruction::OpGetGlobal(slot) => match &self.globals[slot] {
+                    Some(global) => {
+                        let v = global.value.clone();
+                        self.push_to_stack(v)?;
+                    }
+                    None => return Err(self.undefined_global(slot)),
+                },
+                Instruction::OpSetGlobal(slot) => {
+                    // cannot set uninitialized variable
+                    if self.globals[slot].is_none() {
+                        return Err(self.undefined_global(slot));
+                    }
 
-                        // value is not popped from the stack after setting
-                        // assignment is an expression so the value should be present at the top
-                        let val = self.stack[self.stack_top - 1].take();
-                        self.stack[self.stack_top - 1] = Cell::new(val.clone());
-                        self.globals
-                            .insert(name.to_string(), val)
-                            .ok_or(VMError::RuntimeError)?;
-                    } else {
-                        return Err(VMError::RuntimeError);
-                    };
+                    self.check_writable(slot)?;
+
+                    // value is not popped from the stack after setting
+                    // assignment is an expression so the value should be present at the top
+                    let val = self.peek(0);
+                    self.record_write(slot, &val, chunk, self.frame().ip - 1);
+                    self.globals[slot].as_mut().unwrap().value = val;
                 }
-                Instruction::OpDefineGlobal(index) => {
-                    if let Value::String(name) = chunk.read_constant(index) {
-                        let val = self.pop_from_stack();
-                        self.globals.insert(String::clone(name), val);
-                        //
-                        // TODO: remove this print
-                        // println!("\nDEFINING NEW GLOBAL");
-                        // self.print_globals();
-                        //
-                    } else {
-                        return Err(VMError::RuntimeError);
-                    };
+                Instruction::OpDefineGlobal(slot) => {
+                    self.check_writable(slot)?;
+                    let val = self.pop_from_stack();
+                    self.record_write(slot, &val, chunk, self.frame().ip - 1);
+                    self.globals[slot] = Some(Global {
+                        value: val,
+                        read_only: false,
+                    });
                 }
                 Instruction::OpEqual => {
                     let v_2 = self.pop_from_stack();
                     let v_1 = self.pop_from_stack();
-                    self.push_to_stack(Value::Boolean(Value::equals(v_1, v_2)));
+                    self.push_to_stack(Value::Boolean(Value::equals(v_1, v_2)))?;
                 }
                 Instruction::OpAdd => {
-                    let operand_2 = self.pop_from_stack();
-                    let operand_1 = self.pop_from_stack();
-                    if Value::is_string(&operand_1) {
-                        if let Ok(v) = Value::concatenate_strings(&operand_1, &operand_2) {
-                            self.push_to_stack(v);
-                        } else {
-                            return Err(VMError::RuntimeError);
-                        };
+                    // The operands stay on the stack until they are known to be valid.
+                    let operand_2 = self.peek(0);
+                    let operand_1 = self.peek(1);
+                    // An instance is converted when it is added to a string.
+                    let (operand_1, operand_2) = match (operand_1, operand_2) {
+                        (string @ Value::String(_), instance @ Value::Instance(_)) => {
+                            (string, self.display_value(instance)?)
+                        }
+                        (instance @ Value::Instance(_), string @ Value::String(_)) => {
+                            (self.display_value(instance)?, string)
+                        }
+                        operands => operands,
+                    };
+                    let result = if Value::is_string(&operand_1) {
+                        Value::concatenate_strings(&operand_1, &operand_2)
                     } else {
-                        if let Ok(v) = binary_arithmetic_op!(operand_1 + operand_2) {
-                            self.push_to_stack(v);
-                        } else {
-                            return Err(VMError::RuntimeError);
-                        };
+                        binary_arithmetic_op!(operand_1 + operand_2)
+                    };
+                    if let Ok(v) = result {
+                        self.pop_from_stack();
+                        self.pop_from_stack();
+                        self.push_to_stack(v)?;
+                    } else {
+                        self.report(Diagnostic::OperandsNotNumbersOrStrings, &[]);
+                        return Err(VMError::RuntimeError);
                     }
                 }
+                Instruction::OpGreater | Instruction::OpLess
+                    if instance_method(&self.peek(1), "compareTo").is_some() =>
+                {
+                    let operand_2 = self.pop_from_stack();
+                    let operand_1 = self.pop_from_stack();
+                    let ordering = self.compare(&operand_1, &operand_2)?;
+                    let expected = match instruction {
+                        Instruction::OpGreater => Ordering::Greater,
+                        _ => Ordering::Less,
+                    };
+                    self.push_to_stack(Value::Boolean(ordering == expected))?;
+                }
                 Instruction::OpSubtract
                 | Instruction::OpMultiply
                 | Instruction::OpDivide
                 | Instruction::OpGreater
                 | Instruction::OpLess => {
-                    let operand_2 = self.pop_from_stack();
-                    let operand_1 = self.pop_from_stack();
+                    let operand_2 = self.peek(0);
+                    let operand_1 = self.peek(1);
                     if let Ok(v) = match instruction {
                         Instruction::OpSubtract => binary_arithmetic_op!(operand_1 - operand_2),
                         Instruction::OpMultiply => binary_arithmetic_op!(operand_1 * operand_2),
@@ -255,47 +988,517 @@ impl VM {
                         Instruction::OpLess => binary_boolean_op!(operand_1 < operand_2),
                         _ => return Err(VMError::RuntimeError),
                     } {
-                        self.push_to_stack(v);
+                        self.pop_from_stack();
+                        self.pop_from_stack();
+                        self.push_to_stack(v)?;
                     } else {
+                        self.report(Diagnostic::OperandsNotNumbers, &[]);
                         return Err(VMError::RuntimeError);
                     };
                 }
-                Instruction::OpNil => self.push_to_stack(Value::Nil),
-                Instruction::OpTrue => self.push_to_stack(Value::Boolean(true)),
-                Instruction::OpFalse => self.push_to_stack(Value::Boolean(false)),
+                Instruction::OpNil => self.push_to_stack(Value::Nil)?,
+                Instruction::OpZero => self.push_to_stack(Value::Number(0.0))?,
+                Instruction::OpOne => self.push_to_stack(Value::Number(1.0))?,
+                Instruction::OpSmallInt(n) => self.push_to_stack(Value::Number(n as f64))?,
+                Instruction::OpTrue => self.push_to_stack(Value::Boolean(true))?,
+                Instruction::OpFalse => self.push_to_stack(Value::Boolean(false))?,
                 Instruction::OpConstant(idx) => {
                     let constant = chunk.read_constant(idx).clone();
-                    self.push_to_stack(constant.clone());
+                    self.push_to_stack(constant.clone())?;
+                }
+                Instruction::OpExtension(id, arg_count) => {
+                    let handler = match self.extensions.get(id as usize) {
+                        Some(extension) => Rc::clone(&extension.handler),
+                        None => {
+                            self.report(Diagnostic::UnknownExtension, &[&id.to_string()]);
+                            return Err(VMError::RuntimeError);
+                        }
+                    };
+                    let first = self.stack_top - arg_count as usize;
+                    let args: Vec<Value> = (first..self.stack_top)
+                        .map(|i| self.stack[i].take())
+                        .collect();
+                    self.stack_top = first;
+                    let result = handler(self, &args)?;
+                    self.push_to_stack(result)?;
+                }
+                Instruction::OpSlice => {
+                    let end = self.pop_from_stack();
+                    let start = self.pop_from_stack();
+                    let v = self.pop_from_stack();
+                    match Value::slice(&v, &start, &end) {
+                        Ok(sliced) => self.push_to_stack(sliced)?,
+                        Err(diagnostic) => {
+                            self.report(diagnostic, &[]);
+                            return Err(VMError::RuntimeError);
+                        }
+                    }
+                }
+                Instruction::OpBuildList(count) => {
+                    let elements = (self.stack_top - count..self.stack_top)
+                        .map(|i| self.stack[i].take())
+                        .collect();
+                    self.stack_top -= count;
+                    self.push_to_stack(Value::List(Rc::new(List::new(elements))))?;
+                }
+                Instruction::OpIndexGet => {
+                    let index = self.pop_from_stack();
+                    let list = self.pop_from_stack();
+                    let (list, index) = self.list_element(list, index)?;
+                    let element = list.elements.borrow()[index].clone();
+                    self.push_to_stack(element)?;
+                }
+                Instruction::OpIndexSet => {
+                    let value = self.pop_from_stack();
+                    let index = self.pop_from_stack();
+                    let list = self.pop_from_stack();
+                    let (list, index) = self.list_element(list, index)?;
+                    if list.frozen.get() {
+                        self.report(Diagnostic::FrozenValue, &["list"]);
+                        return Err(VMError::RuntimeError);
+                    }
+                    list.elements.borrow_mut()[index] = value.clone();
+                    // Like any assignment, setting an element evaluates to the assigned value.
+                    self.push_to_stack(value)?;
+                }
+                Instruction::OpClass(index) => {
+                    let name = chunk.read_name(index).clone();
+                    self.push_to_stack(Value::Class(Rc::new(Class::new(name))))?;
+                }
+                Instruction::OpMethod(index)
+                | Instruction::OpGetter(index)
+                | Instruction::OpSetter(index) => {
+                    let method = match self.pop_from_stack() {
+                        Value::Function(method) => method,
+                        _ => return Err(VMError::RuntimeError),
+                    };
+                    if let Value::Class(class) = self.peek(0) {
+                        let name = chunk.read_name(index).clone();
+                        let superclass = class.superclass.borrow().clone();
+                        let method = Method {
+                            function: method,
+                            superclass,
+                        };
+                        let methods = match instruction {
+                            Instruction::OpGetter(_) => &class.getters,
+                            Instruction::OpSetter(_) => &class.setters,
+                            _ => &class.methods,
+                        };
+                        methods.borrow_mut().insert(name, method);
+                    } else {
+                        return Err(VMError::RuntimeError);
+                    }
+                }
+                Instruction::OpIs => {
+                    let class = match self.pop_from_stack() {
+                        Value::Class(class) => class,
+                        _ => {
+                            self.report(Diagnostic::IsNotClass, &[]);
+                            return Err(VMError::RuntimeError);
+                        }
+                    };
+                    // Only instances have a class.
+                    let is = match self.pop_from_stack() {
+                        Value::Instance(instance) => instance.borrow().class.inherits_from(&class),
+                        _ => false,
+                    };
+                    self.push_to_stack(Value::Boolean(is))?;
+                }
+                Instruction::OpFields => {
+                    let function = match self.pop_from_stack() {
+                        Value::Function(function) => function,
+                        _ => return Err(VMError::RuntimeError),
+                    };
+                    if let Value::Class(class) = self.peek(0) {
+                        let superclass = class.superclass.borrow().clone();
+                        let initializer = Method {
+                            function,
+                            superclass,
+                        };
+                        class.field_initializers.borrow_mut().push(initializer);
+                    } else {
+                        return Err(VMError::RuntimeError);
+                    }
+                }
+                Instruction::OpGetProperty(index) => {
+                    let instance = match self.pop_from_stack() {
+                        Value::Instance(instance) => instance,
+                        _ => {
+                            self.report(Diagnostic::NotAnInstance, &[]);
+                            return Err(VMError::RuntimeError);
+                        }
+                    };
+                    let name = chunk.read_name(index);
+                    // Fields shadow getters, which shadow methods.
+                    let field = instance.borrow().fields.get(name).cloned();
+                    let getter = instance.borrow().class.getters.borrow().get(name).cloned();
+                    let method = instance.borrow().class.methods.borrow().get(name).cloned();
+                    match (field, getter, method) {
+                        (Some(value), _, _) => self.push_to_stack(value)?,
+                        (None, Some(getter), _) => {
+                            // The getter receives the instance as `this`, in slot 0,
+                            // and its result takes the instance's place.
+                            self.push_to_stack(Value::Instance(instance))?;
+                            self.call(getter.function, getter.superclass, 0)?;
+                            function = Rc::clone(&self.frame().function);
+                        }
+                        (None, None, Some(method)) => {
+                            let receiver = Value::Instance(instance);
+                            let bound = BoundMethod { receiver, method };
+                            self.push_to_stack(Value::BoundMethod(Rc::new(bound)))?;
+                        }
+                        (None, None, None) => {
+                            self.report(Diagnostic::UndefinedProperty, &[name]);
+                            return Err(VMError::RuntimeError);
+                        }
+                    }
+                }
+                Instruction::OpInherit => {
+                    let subclass = self.pop_from_stack();
+                    let superclass = match self.pop_from_stack() {
+                        Value::Class(superclass) => superclass,
+                        _ => {
+                            self.report(Diagnostic::SuperclassNotClass, &[]);
+                            return Err(VMError::RuntimeError);
+                        }
+                    };
+                    if let Value::Class(subclass) = subclass {
+                        // The methods are copied down, so that calls do not walk the hierarchy.
+                        let inherited = superclass.methods.borrow().clone();
+                        subclass.methods.borrow_mut().extend(inherited);
+                        let inherited = superclass.getters.borrow().clone();
+                        subclass.getters.borrow_mut().extend(inherited);
+                        let inherited = superclass.setters.borrow().clone();
+                        subclass.setters.borrow_mut().extend(inherited);
+                        let inherited = superclass.field_initializers.borrow().clone();
+                        subclass.field_initializers.borrow_mut().extend(inherited);
+                        subclass.superclass.replace(Some(superclass));
+                    } else {
+                        return Err(VMError::RuntimeError);
+                    }
+                }
+                Instruction::OpGetSuper(index) => {
+                    let name = chunk.read_name(index);
+                    let method = self
+                        .frame()
+                        .superclass
+                        .as_ref()
+                        .and_then(|superclass| superclass.methods.borrow().get(name).cloned());
+                    match method {
+                        Some(method) => {
+                            // Bound to `this`, in slot 0 of the calling method.
+                            let receiver = self.stack[self.frame().stack_index].get();
+                            let bound = BoundMethod { receiver, method };
+                            self.push_to_stack(Value::BoundMethod(Rc::new(bound)))?;
+                        }
+                        None => {
+                            self.report(Diagnostic::UndefinedProperty, &[name]);
+                            return Err(VMError::RuntimeError);
+                        }
+                    }
+                }
+                Instruction::OpSetProperty(index) => {
+                    let value = self.pop_from_stack();
+                    let instance = match self.pop_from_stack() {
+                        Value::Instance(instance) => instance,
+                        _ => {
+                            self.report(Diagnostic::FieldOnNonInstance, &[]);
+                            return Err(VMError::RuntimeError);
+                        }
+                    };
+                    if instance.borrow().frozen {
+                        self.report(Diagnostic::FrozenValue, &["instance"]);
+                        return Err(VMError::RuntimeError);
+                    }
+                    let name = chunk.read_name(index);
+                    let setter = instance.borrow().class.setters.borrow().get(name).cloned();
+                    if let Some(setter) = setter {
+                        // The setter receives the instance as `this` and the value as its
+                        // argument, and returns the value, which takes their place.
+                        self.push_to_stack(Value::Instance(instance))?;
+                        self.push_to_stack(value)?;
+                        self.call(setter.function, setter.superclass, 1)?;
+                        function = Rc::clone(&self.frame().function);
+                        continue;
+                    }
+                    let fields = &mut instance.borrow_mut().fields;
+                    match fields.get_mut(name) {
+                        Some(field) => *field = value.clone(),
+                        None => {
+                            fields.insert(name.clone(), value.clone());
+                        }
+                    }
+                    // Like any assignment, setting a field evaluates to the assigned value.
+                    self.push_to_stack(value)?;
                 }
                 Instruction::OpPop => {
                     self.pop_from_stack();
                 }
+                Instruction::OpPopN(count) => self.truncate_stack(self.stack_top - count),
+                Instruction::OpDup => {
+                    let value = self.peek(0);
+                    self.push_to_stack(value)?;
+                }
                 Instruction::OpPrint => {
                     let v = self.pop_from_stack();
-                    // TODO: conditional execution only for tests
-                    self.printed_values.push(v.clone());
-                    //
-                    println!("{}", v);
+                    self.print(v)?;
                 }
-                Instruction::OpReturn => {
-                    let return_val = self.pop_from_stack();
-                    self.frames.pop();
-                    if self.frames.is_empty() {
+                Instruction::OpReturn | Instruction::OpEndDefer => {
+                    if instruction == Instruction::OpEndDefer {
+                        // Discard the value of the deferred expression.
                         self.pop_from_stack();
-                        return Ok(());
+                    }
+                    // Run the frame's deferred code in reverse order of registration,
+                    // keeping the return value at the top of the stack in the meantime.
+                    if let Some(ip) = self.next_defer() {
+                        self.frame_mut().ip = ip;
+                        continue;
                     }
 
-                    self.stack_top = frame.stack_index;
-                    self.push_to_stack(return_val);
-                    frame = self.frames[self.frames.len() - 1].clone();
+                    // A call unwound by a thrown value throws it on once its deferred code is done.
+                    if self.unwinding.last() == Some(&self.frames.len()) {
+                        self.unwinding.pop();
+                        let value = self.pop_from_stack();
+                        self.throw(value, base_depth)?;
+                        function = Rc::clone(&self.frame().function);
+                        continue;
+                    }
+
+                    // Tear down the frame, including the slot of the function that was called,
+                    // and leave the return value in its place for the caller.
+                    let return_val = self.pop_from_stack();
+                    // Returning from inside try blocks ends them.
+                    while matches!(self.handlers.last(), Some(handler) if handler.depth == self.frames.len())
+                    {
+                        self.handlers.pop();
+                    }
+                    let frame = self.frames.pop().unwrap();
+                    self.truncate_stack(frame.stack_index);
+                    self.push_to_stack(return_val)?;
+                    if self.frames.len() == base_depth {
+                        return Ok(());
+                    }
+                    function = Rc::clone(&self.frame().function);
                 }
             }
         }
     }
 
-    fn push_to_stack(&mut self, value: Value) {
+    /// Returns the list being indexed and the position of the element, reporting the error
+    /// if the value is not a list or the index is not one of its elements.
+    ///
+    /// Negative indexes count from the end of the list, like slice bounds.
+    fn list_element(&mut self, list: Value, index: Value) -> Result<(Rc<List>, usize), VMError> {
+        let list = match list {
+            Value::List(list) => list,
+            _ => {
+                self.report(Diagnostic::IndexNotList, &[]);
+                return Err(VMError::RuntimeError);
+            }
+        };
+        let index = match index {
+            Value::Number(n) if n.fract() == 0.0 => n,
+            _ => {
+                self.report(Diagnostic::InvalidIndex, &[]);
+                return Err(VMError::RuntimeError);
+            }
+        };
+        let length = list.elements.borrow().len();
+        let position = if index < 0.0 {
+            length as f64 + index
+        } else {
+            index
+        };
+        if position < 0.0 || position >= length as f64 {
+            let index = format_number_with(index, self.number_format);
+            self.report(Diagnostic::IndexOutOfBounds, &[&index, &length.to_string()]);
+            return Err(VMError::RuntimeError);
+        }
+        Ok((list, position as usize))
+    }
+
+    /// Runs a call of a hot function with the template backend, replacing the function and its
+    /// arguments on the stack with the result. Returns whether it did, or whether the call must
+    /// be interpreted instead.
+    ///
+    /// The backend is not used while anything watches individual instructions, like tracing
+    /// or profiling.
+    #[cfg(feature = "template_jit")]
+    fn run_compiled(&mut self, function: &Rc<Function>, arg_count: usize) -> bool {
+        let watched = self.profiling
+            || (cfg!(feature = "debug_trace_execution") && self.trace_execution)
+            || cfg!(feature = "opcode_timing")
+            || cfg!(feature = "profile");
+        // The interpreter reports wrong calls.
+        if watched || arg_count != function.arity || self.frames.len() == self.limits.frames_max {
+            return false;
+        }
+
+        let key = Rc::as_ptr(function);
+        let hotness = self.hotness.entry(key).or_insert(0);
+        *hotness += 1;
+        if *hotness < self.jit_threshold {
+            return false;
+        }
+        let compiled = self
+            .compiled
+            .entry(key)
+            .or_insert_with(|| {
+                let compiled = CompiledFunction::compile(function).map(Rc::new);
+                (Rc::clone(function), compiled)
+            })
+            .1
+            .clone();
+        let compiled = match compiled {
+            Some(compiled) => compiled,
+            None => return false,
+        };
+
+        let first = self.stack_top - 1 - arg_count;
+        let stack = (first..self.stack_top)
+            .map(|i| self.stack[i].get())
+            .collect();
+        let remaining = self
+            .max_instructions
+            .map_or(usize::MAX, |max| max.saturating_sub(self.instruction_count));
+        let max_instructions = self
+            .max_frame_instructions
+            .unwrap_or(usize::MAX)
+            .min(remaining);
+        match compiled.run(stack, max_instructions) {
+            Some((result, count)) => {
+                self.instruction_count += count;
+                // The result takes the callee's slot, so it always fits.
+                self.stack[first].replace(result);
+                self.stack_top = first + 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Jumps to the innermost catch block with a thrown value, or reports it as uncaught.
+    ///
+    /// The calls made inside the try block are unwound innermost first, each running its
+    /// deferred code before it is unwound, most recent first. Deferred code is run like at a
+    /// `return`, with the thrown value kept at the top of the stack, so this only jumps to the
+    /// first of it and is called again once the call's deferred code is done.
+    fn throw(&mut self, value: Value, base_depth: usize) -> VMResult {
+        // A nested run, for a call from a native function, cannot unwind into the frames below
+        // it, so their catch blocks do not catch its values.
+        let (depth, stack_top, ip) = match self.handlers.last() {
+            Some(handler) if handler.depth > base_depth => {
+                (handler.depth, handler.stack_top, handler.ip)
+            }
+            _ => {
+                let value = value.with_format(self.number_format).to_string();
+                self.report(Diagnostic::UncaughtException, &[&value]);
+                return Err(VMError::RuntimeError);
+            }
+        };
+        // A value thrown by deferred code replaces the one that was unwinding its call.
+        self.unwinding.retain(|&unwinding| unwinding <= depth);
+
+        while self.frames.len() > depth {
+            if let Some(defer_ip) = self.next_defer() {
+                self.unwinding.push(self.frames.len());
+                self.push_to_stack(value)?;
+                self.frame_mut().ip = defer_ip;
+                return Ok(());
+            }
+            let frame = self.frames.pop().unwrap();
+            self.truncate_stack(frame.stack_index);
+        }
+        self.handlers.pop();
+        self.truncate_stack(stack_top);
+        self.push_to_stack(value)?;
+        self.frame_mut().ip = ip;
+        Ok(())
+    }
+
+    /// Removes and returns the start of the latest deferred code registered by the current [CallFrame].
+    fn next_defer(&mut self) -> Option<usize> {
+        match self.defers.last() {
+            Some(&(depth, ip)) if depth == self.frames.len() => {
+                self.defers.pop();
+                Some(ip)
+            }
+            _ => None,
+        }
+    }
+
+    /// Calls the native function with the arguments at the top of the stack,
+    /// and replaces them and the function with its result.
+    fn call_native(
+        &mut self,
+        native: Rc<NativeFunction>,
+        arg_count: usize,
+    ) -> Result<Value, VMError> {
+        if arg_count > native.arity || arg_count + native.optional < native.arity {
+            self.report(
+                Diagnostic::WrongArgumentCount,
+                &[&native.arity.to_string(), &arg_count.to_string()],
+            );
+            return Err(VMError::RuntimeError);
+        }
+        for _ in arg_count..native.arity {
+            self.push_to_stack(Value::Nil)?;
+        }
+        let arg_count = native.arity;
+
+        let args: Vec<Value> = (self.stack_top - arg_count..self.stack_top)
+            .map(|i| self.stack[i].get())
+            .collect();
+        if self.audit_determinism && !native.deterministic {
+            self.record_nondeterministic_call(&native.name);
+        }
+        let function = native.function;
+        self.native_calls.push((self.frames.len(), native));
+        let result = function(self, &args);
+        // A runtime error has already reset the stack, including the native calls.
+        self.native_calls.pop();
+        let result = result?;
+        self.truncate_stack(self.stack_top - arg_count - 1);
+        self.push_to_stack(result.clone())?;
+        Ok(result)
+    }
+
+    fn record_nondeterministic_call(&mut self, name: &str) {
+        let location = match self.frames.last() {
+            Some(frame) => format_location(
+                &frame.function.chunk.source_name,
+                frame.function.chunk.lines[frame.ip],
+            ),
+            None => "native code".to_string(),
+        };
+        match self
+            .nondeterministic_calls
+            .iter_mut()
+            .find(|call| call.name == name && call.location == location)
+        {
+            Some(call) => call.count += 1,
+            None => self.nondeterministic_calls.push(NondeterministicCall {
+                name: name.to_string(),
+                location,
+                count: 1,
+            }),
+        }
+    }
+
+    /// Pushes a value, reporting a stack overflow if the stack is full.
+    ///
+    /// A call only starts with [FRAME_SLOTS] free slots, which its temporaries can outgrow.
+    fn push_to_stack(&mut self, value: Value) -> VMResult {
+        if self.stack_top == self.stack.len() {
+            self.report(Diagnostic::StackOverflow, &[]);
+            return Err(VMError::RuntimeError);
+        }
         self.stack[self.stack_top].replace(value);
         self.stack_top += 1;
+        if self.profiling && self.stack_top > self.profile.peak_stack_depth {
+            self.profile.peak_stack_depth = self.stack_top;
+        }
+        Ok(())
     }
 
     fn pop_from_stack(&mut self) -> Value {
@@ -303,101 +1506,693 @@ impl VM {
         self.stack[self.stack_top].take()
     }
 
+    /// Drops the values above the given height of the stack, so that nothing a script stopped
+    /// using, like a [crate::value::host_object::HostObject], is kept alive by a stale slot.
+    fn truncate_stack(&mut self, top: usize) {
+        for slot in &self.stack[top..self.stack_top] {
+            slot.take();
+        }
+        self.stack_top = top;
+    }
+
     // fn call_value(&mut self, callee: Value, arg_count: usize) {
     // }
 
+    /// Replaces a called class on the stack with a new instance of the class,
+    /// and runs the field initializers on it.
+    ///
+    /// Returns the class's `init` method, which must then be called with the arguments above
+    /// the instance. Without one, there must be no arguments.
+    ///
+    /// The field initializers run in a nested run loop, like calls from native functions, so
+    /// the values they throw are not caught outside of them.
+    fn instantiate(
+        &mut self,
+        class: Rc<Class>,
+        arg_count: usize,
+    ) -> Result<Option<Method>, VMError> {
+        let init = class.methods.borrow().get("init").cloned();
+        if init.is_none() && arg_count != 0 {
+            self.report(
+                Diagnostic::WrongArgumentCount,
+                &["0", &arg_count.to_string()],
+            );
+            return Err(VMError::RuntimeError);
+        }
+        let initializers = class.field_initializers.borrow().clone();
+        let instance = Value::Instance(Rc::new(RefCell::new(Instance::new(class))));
+        self.stack[self.stack_top - 1 - arg_count].replace(instance.clone());
+        for initializer in initializers {
+            // The initializer receives the instance as `this`, in slot 0.
+            self.push_to_stack(instance.clone())?;
+            self.run_call(initializer.function, initializer.superclass, 0)?;
+        }
+        Ok(init)
+    }
+
+    /// Pushes a [CallFrame] for a function whose arguments are on the stack.
+    ///
+    /// For a method, `superclass` is where `super` finds methods.
     fn call(
         &mut self,
         function: Rc<Function>,
+        superclass: Option<Rc<Class>>,
         arg_count: usize,
-        current_frame_ip: usize,
     ) -> VMResult {
-        if arg_count != function.arity as usize {
-            self.runtime_error(&format!(
-                "Expected {} arguments but got {}.",
-                &function.arity, arg_count
-            ));
+        if arg_count != function.arity {
+            self.report(
+                Diagnostic::WrongArgumentCount,
+                &[&function.arity.to_string(), &arg_count.to_string()],
+            );
             return Err(VMError::RuntimeError);
         }
 
-        if self.frames.len() == FRAMES_MAX {
-            self.runtime_error("Stack overflow.");
+        if self.frames.len() == self.limits.frames_max
+            || self.stack_top + FRAME_SLOTS > self.limits.stack_max
+        {
+            self.report(Diagnostic::StackOverflow, &[]);
             return Err(VMError::RuntimeError);
         }
-        // Save the frame ip in the frame in the VM::frames array.
-        // The clone being used only has a copy of the ip, as the ip is not heap allocated.
-        if !self.frames.is_empty() {
-            self.frames.last_mut().unwrap().ip = current_frame_ip;
-        }
-
         let frame = CallFrame {
-            function: function,
+            function,
             ip: 0,
             stack_index: self.stack_top - 1 - arg_count,
+            superclass,
+            instruction_count: 0,
         };
-        //
         self.frames.push(frame);
+        if self.profiling && self.frames.len() > self.profile.peak_frame_depth {
+            self.profile.peak_frame_depth = self.frames.len();
+        }
         Ok(())
     }
 
-    // TODO: use peek in some cases instead of popping immediately?
-    // cloning must be refactored in that case
-    //
-    // fn peek(&self, distance: usize) -> Value {
-    //     self.stack[self.stack_top - 1 - distance].clone().take()
-    // }
+    /// Converts an instance to the string shown for it by print statements and string
+    /// concatenation: the result of its `toString()` method if its class defines one, called in a
+    /// nested run loop, or its default description. Other values are returned as they are.
+    fn display_value(&mut self, value: Value) -> Result<Value, VMError> {
+        if !matches!(value, Value::Instance(_)) {
+            return Ok(value);
+        }
+        let method = match instance_method(&value, "toString") {
+            Some(method) => method,
+            None => return Ok(Value::String(LoxString::from(value.to_string()))),
+        };
+        self.push_to_stack(value)?;
+        match self.run_call(method.function, method.superclass, 0)? {
+            string @ Value::String(_) => Ok(string),
+            _ => {
+                self.report(Diagnostic::ToStringNotString, &[]);
+                Err(VMError::RuntimeError)
+            }
+        }
+    }
+
+    /// Orders two values for sorting and for `<` and `>` on instances: numbers and strings by
+    /// their value, and an instance by the number its class's `compareTo(other)` returns, which
+    /// is negative, zero or positive when the instance comes before, with or after the other.
+    pub(crate) fn compare(&mut self, a: &Value, b: &Value) -> Result<Ordering, VMError> {
+        if let Some(method) = instance_method(a, "compareTo") {
+            self.push_to_stack(a.clone())?;
+            self.push_to_stack(b.clone())?;
+            let result = self.run_call(method.function, method.superclass, 1)?;
+            return self.ordering(result);
+        }
+        match (a, b) {
+            (Value::Number(a), Value::Number(b)) => Ok(a.partial_cmp(b).unwrap_or(Ordering::Equal)),
+            (Value::String(a), Value::String(b)) => Ok(a.cmp(b)),
+            _ => {
+                self.report(Diagnostic::NotComparable, &[]);
+                Err(VMError::RuntimeError)
+            }
+        }
+    }
+
+    /// Orders two values with a comparator function called with both like `compareTo`, or with
+    /// [VM::compare] for a `nil` comparator.
+    pub(crate) fn compare_with(
+        &mut self,
+        comparator: &Value,
+        a: &Value,
+        b: &Value,
+    ) -> Result<Ordering, VMError> {
+        match comparator {
+            Value::Nil => self.compare(a, b),
+            comparator => {
+                let result = self.call_value(comparator.clone(), &[a.clone(), b.clone()])?;
+                self.ordering(result)
+            }
+        }
+    }
+
+    /// Whether two values are equal like with `==`, calling the first one's `equals` method if
+    /// its class overloads it.
+    pub(crate) fn values_equal(&mut self, a: &Value, b: &Value) -> Result<bool, VMError> {
+        match instance_method(a, "equals") {
+            Some(method) => {
+                self.push_to_stack(a.clone())?;
+                self.push_to_stack(b.clone())?;
+                let result = self.run_call(method.function, method.superclass, 1)?;
+                Ok(!is_falsey(&result))
+            }
+            None => Ok(Value::equals(a.clone(), b.clone())),
+        }
+    }
+
+    /// The ordering a comparison's result stands for, by its sign.
+    fn ordering(&mut self, result: Value) -> Result<Ordering, VMError> {
+        match result {
+            Value::Number(n) => Ok(n.partial_cmp(&0.0).unwrap_or(Ordering::Equal)),
+            _ => {
+                self.report(Diagnostic::ComparisonNotNumber, &[]);
+                Err(VMError::RuntimeError)
+            }
+        }
+    }
+
+    /// The method overloading the operator of an instruction, with the number of arguments it
+    /// takes, if the left operand on the stack is an instance whose class defines it.
+    ///
+    /// The operands are then already in place for the call, with the instance as `this`.
+    fn operator_method(&mut self, instruction: Instruction) -> Option<(Method, usize)> {
+        let (name, arg_count) = match instruction {
+            Instruction::OpAdd => ("plus", 1),
+            Instruction::OpSubtract => ("minus", 1),
+            Instruction::OpMultiply => ("times", 1),
+            Instruction::OpDivide => ("divide", 1),
+            Instruction::OpLess => ("less", 1),
+            Instruction::OpGreater => ("greater", 1),
+            Instruction::OpEqual => ("equals", 1),
+            Instruction::OpNegate => ("negate", 0),
+            _ => return None,
+        };
+        match self.peek(arg_count) {
+            Value::Instance(instance) => {
+                let method = instance.borrow().class.methods.borrow().get(name).cloned();
+                method.map(|method| (method, arg_count))
+            }
+            _ => None,
+        }
+    }
+
+    /// The [CallFrame] of the innermost ongoing call.
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    /// Whether the current [CallFrame] has nothing left to do after a call in its `return`,
+    /// like running deferred code or catching what the call throws.
+    fn frame_is_done(&self) -> bool {
+        let depth = self.frames.len();
+        !matches!(self.defers.last(), Some(&(defer_depth, _)) if defer_depth == depth)
+            && !matches!(self.handlers.last(), Some(handler) if handler.depth == depth)
+    }
+
+    /// Replaces the current [CallFrame] with one for a function whose arguments are on the
+    /// stack, moving the function and its arguments down to the start of the current frame.
+    ///
+    /// The new frame keeps counting the instructions of the current one, so that a chain of
+    /// tail calls is stopped by [VM::max_frame_instructions] like a loop would be.
+    fn tail_call(
+        &mut self,
+        function: Rc<Function>,
+        superclass: Option<Rc<Class>>,
+        arg_count: usize,
+    ) -> VMResult {
+        if arg_count != function.arity {
+            self.report(
+                Diagnostic::WrongArgumentCount,
+                &[&function.arity.to_string(), &arg_count.to_string()],
+            );
+            return Err(VMError::RuntimeError);
+        }
+
+        let stack_index = self.frame().stack_index;
+        let first = self.stack_top - 1 - arg_count;
+        for i in 0..=arg_count {
+            self.stack[stack_index + i].set(self.stack[first + i].take());
+        }
+        self.truncate_stack(stack_index + arg_count + 1);
+        *self.frames.last_mut().unwrap() = CallFrame {
+            function,
+            ip: 0,
+            stack_index,
+            superclass,
+            instruction_count: self.frame().instruction_count,
+        };
+        Ok(())
+    }
+
+    /// A copy of the value `distance` slots below the top of the stack, which stays in place.
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack_top - 1 - distance].get()
+    }
+
+    /// Stops timing the current instruction, if any, and starts timing the next one.
+    #[cfg(feature = "opcode_timing")]
+    fn time_opcode(&mut self, next: Option<&'static str>) {
+        let now = Instant::now();
+        if let Some((name, start)) = self.opcode_timer.take() {
+            let (count, total) = self.opcode_timings.entry(name).or_default();
+            *count += 1;
+            *total += now - start;
+        }
+        self.opcode_timer = next.map(|name| (name, now));
+    }
+
+    /// The time spent on each kind of instruction during the latest [VM::interpret] call,
+    /// slowest first.
+    #[cfg(feature = "opcode_timing")]
+    pub fn opcode_timings(&self) -> Vec<OpcodeTiming> {
+        let mut timings: Vec<OpcodeTiming> = self
+            .opcode_timings
+            .iter()
+            .map(|(name, (count, total))| OpcodeTiming {
+                name,
+                count: *count,
+                total: *total,
+            })
+            .collect();
+        timings.sort_by(|a, b| b.total.cmp(&a.total).then(a.name.cmp(b.name)));
+        timings
+    }
+
+    #[cfg(feature = "profile")]
+    fn count_instruction(&mut self, name: &'static str, function: &Rc<Function>) {
+        *self.opcode_counts.entry(name).or_default() += 1;
+        self.function_counts
+            .entry(Rc::as_ptr(function))
+            .or_insert_with(|| (Rc::clone(function), 0))
+            .1 += 1;
+    }
+
+    /// The number of times each kind of instruction ran during the latest [VM::interpret] call,
+    /// and the number of instructions each function ran, also when it failed.
+    #[cfg(feature = "profile")]
+    pub fn profile_report(&self) -> ProfileReport {
+        let mut opcodes: Vec<(&'static str, usize)> = self
+            .opcode_counts
+            .iter()
+            .map(|(name, count)| (*name, *count))
+            .collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+        let mut functions: Vec<(String, usize)> = self
+            .function_counts
+            .values()
+            .map(|(function, count)| {
+                let name = match function.name.as_str() {
+                    "" => "script".to_string(),
+                    name => format!("{}()", name),
+                };
+                let label = match function.chunk.lines.first() {
+                    Some(line) => format!(
+                        "{} at {}",
+                        name,
+                        format_location(&function.chunk.source_name, *line)
+                    ),
+                    None => name,
+                };
+                (label, *count)
+            })
+            .collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ProfileReport { opcodes, functions }
+    }
+
+    /// Reports the use of a global that is not defined, suggesting a defined global or a keyword
+    /// with a close name.
+    fn undefined_global(&mut self, slot: usize) -> VMError {
+        let name = &self.global_names[slot];
+        let suggestion = did_you_mean(
+            name,
+            self.defined_globals()
+                .map(|(name, _)| &**name)
+                .chain(KEYWORDS),
+            &self.catalog,
+        );
+        let name = name.clone();
+        self.report(Diagnostic::UndefinedVariable, &[&name, &suggestion]);
+        VMError::RuntimeError
+    }
+
+    /// The value of a global variable, if it is defined.
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        let slot = *self.global_slots.get(name)?;
+        self.globals[slot].as_ref().map(|global| &global.value)
+    }
+
+    /// The defined global variables, with their names.
+    fn defined_globals(&self) -> impl Iterator<Item = (&LoxString, &Global)> {
+        self.global_names
+            .iter()
+            .zip(&self.globals)
+            .filter_map(|(name, global)| Some((name, global.as_ref()?)))
+    }
+
+    /// Returns the slot of a global, giving it the next one if it has none yet.
+    fn global_slot(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.global_slots.get(name) {
+            return slot;
+        }
+        let slot = self.globals.len();
+        self.globals.push(None);
+        let name = LoxString::from(name);
+        self.global_names.push(name.clone());
+        self.global_slots.insert(name, slot);
+        slot
+    }
+
+    /// Gives the globals of a compiled script the slots of this VM's globals with the same names.
+    ///
+    /// The scripts compiled by [VM::interpret] already use these slots, while the instructions
+    /// of others, like bytecode compiled ahead of time, are rewritten.
+    fn link(&mut self, script: &mut Function) {
+        let names = Rc::clone(&script.chunk.globals);
+        let slots: Vec<usize> = names.iter().map(|name| self.global_slot(name)).collect();
+        if slots
+            .iter()
+            .enumerate()
+            .any(|(slot, linked)| slot != *linked)
+        {
+            let names = self
+                .global_names
+                .iter()
+                .map(|name| name.to_string())
+                .collect();
+            relink(script, &slots, &Rc::new(names));
+        }
+    }
+
+    /// Defines a global that scripts can read but not assign or redefine, like a configuration
+    /// value or a handle given by the host. Defining it again from the host replaces it.
+    pub fn define_read_only_global(&mut self, name: &str, value: Value) {
+        let slot = self.global_slot(name);
+        self.globals[slot] = Some(Global {
+            value,
+            read_only: true,
+        });
+    }
+
+    /// Reports an error if a global is read-only.
+    fn check_writable(&mut self, slot: usize) -> VMResult {
+        if self.globals[slot]
+            .as_ref()
+            .is_some_and(|global| global.read_only)
+        {
+            let name = self.global_names[slot].clone();
+            self.report(Diagnostic::ReadOnlyGlobal, &[&name]);
+            return Err(VMError::RuntimeError);
+        }
+        Ok(())
+    }
+
+    /// Starts recording the values defined or assigned to a global, which scripts can also do
+    /// with `watch(name)`. Watching a global again keeps its history.
+    ///
+    /// Local variables cannot be watched: they are compiled to stack slots without names.
+    pub fn watch(&mut self, name: &str) {
+        self.watches.entry(name.to_string()).or_default();
+    }
+
+    /// The values defined or assigned to a watched global, oldest first,
+    /// which scripts can print with `history(name)`.
+    pub fn history(&self, name: &str) -> Option<&[WatchedWrite]> {
+        self.watches.get(name).map(Vec::as_slice)
+    }
+
+    /// Records a write to a global if it is watched.
+    fn record_write(&mut self, slot: usize, value: &Value, chunk: &Chunk, instruction_idx: usize) {
+        if self.watches.is_empty() {
+            return;
+        }
+        if let Some(writes) = self.watches.get_mut(&*self.global_names[slot]) {
+            writes.push(WatchedWrite {
+                value: value.clone(),
+                location: format_location(&chunk.source_name, chunk.lines[instruction_idx]),
+            });
+        }
+    }
+
+    /// Sets how to load the debug information of the stripped bytecode being run, to show the
+    /// names and lines of its functions in stack traces.
+    ///
+    /// The loader is only called when a runtime error happens in a stripped function.
+    pub fn set_debug_info_loader(&mut self, loader: DebugInfoLoader) {
+        self.debug_info_loader = Some(loader);
+        self.debug_info = None;
+    }
+
+    /// Loads the debug information if it has not been loaded yet, reporting why it cannot be.
+    fn load_debug_info(&mut self) {
+        if let Some(loader) = self.debug_info_loader.take() {
+            match loader() {
+                Ok(debug_info) => self.debug_info = Some(debug_info),
+                Err(message) => {
+                    let _ = writeln!(
+                        &self.error_output,
+                        "Could not load debug information: {}",
+                        message
+                    );
+                }
+            }
+        }
+    }
+
+    /// Reports a runtime error from the catalog, see [VM::runtime_error].
+    pub(crate) fn report(&mut self, diagnostic: Diagnostic, args: &[&str]) {
+        let message = self.catalog.message(diagnostic, args);
+        self.report_error(Some(diagnostic), &message);
+    }
+
+    /// Reports a runtime error with a stack trace and resets the stack.
+    pub fn runtime_error(&mut self, message: &str) {
+        self.report_error(None, message);
+    }
+
+    /// Returns the Rust value of a [crate::value::host_object::HostObject] argument of a native
+    /// function, reporting the error if the argument is not a host object wrapping a `T`.
+    ///
+    /// The type name is shown in the error, like `File` in "Operand must be a File.".
+    pub fn host_object<'a, T: Any>(
+        &mut self,
+        value: &'a Value,
+        type_name: &str,
+    ) -> Result<&'a T, VMError> {
+        let object = match value {
+            Value::HostObject(object) => object.downcast_ref::<T>(),
+            _ => None,
+        };
+        object.ok_or_else(|| {
+            self.report(Diagnostic::NotHostObject, &[type_name]);
+            VMError::RuntimeError
+        })
+    }
 
     // TODO: Make a RuntimeError struct and refactor this method?
-    fn runtime_error(&mut self, message: &str) {
-        eprint!("{}", &message);
-        self.latest_error_message = message.to_string();
-        eprintln!();
+    fn report_error(&mut self, code: Option<Diagnostic>, message: &str) {
+        // Keep the program's output in order with the error on stderr.
+        self.flush_output();
+        let _ = match code {
+            Some(code) => writeln!(&self.error_output, "error[{}]: {}", code.code(), message),
+            None => writeln!(&self.error_output, "error: {}", message),
+        };
+        self.error_message = message.to_string();
+        self.error_code = code;
 
         // let line = chunk.lines[ip];
         // eprintln!("[line {}] in script", line);
 
+        self.stack_trace.clear();
+        if self
+            .frames
+            .iter()
+            .any(|frame| frame.function.symbol.is_some())
+        {
+            self.load_debug_info();
+        }
+        let debug_info = self.debug_info.as_ref();
         for i in (0..self.frames.len()).rev() {
             let frame = &self.frames[i];
             let function = &frame.function;
 
-            // TODO: fix index?
-            // let instruction_idx = function.chunk.bytecode.len() - 1;
-            let instruction_idx = frame.ip;
-            eprint!(
-                "[line {}] in ",
-                function.chunk.lines[instruction_idx as usize]
-            );
-            if function.name.is_empty() {
-                eprintln!("script");
+            // The ip is already past the instruction that failed, or the call being made.
+            let instruction_idx = frame.ip.saturating_sub(1);
+            let symbols = function
+                .symbol
+                .map(|symbol| debug_info.and_then(|debug_info| debug_info.functions.get(symbol)));
+            let (location, name) = match symbols {
+                None => (
+                    format_location(
+                        &function.chunk.source_name,
+                        function.chunk.lines[instruction_idx],
+                    ),
+                    function.name.as_str(),
+                ),
+                Some(Some(symbols)) => (
+                    format_location(
+                        &symbols.source_name,
+                        symbols.lines.get(instruction_idx).copied().unwrap_or(0),
+                    ),
+                    symbols.name.as_str(),
+                ),
+                // Without debug information, only the position in the bytecode is known.
+                Some(None) => (
+                    format!("instruction {}", instruction_idx),
+                    function.name.as_str(),
+                ),
+            };
+
+            // Native functions called from this frame are shown at the line that called them.
+            for (_, native) in self
+                .native_calls
+                .iter()
+                .rev()
+                .filter(|(depth, _)| *depth == i + 1)
+            {
+                self.stack_trace
+                    .push(format!("[{}] in {}()", location, native.name));
+            }
+            if name.is_empty() {
+                self.stack_trace.push(format!("[{}] in script", location));
             } else {
-                eprintln!("{}()", &function.name);
+                self.stack_trace
+                    .push(format!("[{}] in {}()", location, name));
             }
         }
+        for line in &self.stack_trace {
+            let _ = writeln!(&self.error_output, "{}", line);
+        }
 
         self.reset_stack();
     }
 
-    fn define_native(&mut self, name: &str, function: fn() -> Value) {
+    /// Registers an extra instruction, for prototyping instructions without changing [Instruction].
+    ///
+    /// Calls to `name` with `arity` arguments compile to an [Instruction::OpExtension] that runs
+    /// the handler, unless a local variable has the same name. Registering a name again replaces
+    /// its handler. Returns the instruction's id.
+    ///
+    /// # Panics
+    ///
+    /// If more than 256 instructions are registered.
+    pub fn register_extension(
+        &mut self,
+        name: &str,
+        arity: u8,
+        handler: impl Fn(&mut VM, &[Value]) -> Result<Value, VMError> + 'static,
+    ) -> u8 {
+        let extension = Extension {
+            name: name.to_string(),
+            arity,
+            handler: Rc::new(handler),
+        };
+        if let Some(id) = self.extensions.iter().position(|e| e.name == name) {
+            self.extensions[id] = extension;
+            return id as u8;
+        }
+        assert!(
+            self.extensions.len() <= u8::MAX as usize,
+            "At most 256 extensions can be registered."
+        );
+        self.extensions.push(extension);
+        (self.extensions.len() - 1) as u8
+    }
+
+    /// Defines a global native function with the given number of parameters.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        self.insert_native(name, arity, 0, function, true);
+    }
+
+    /// Defines a native function whose last `optional` parameters can be left out, in which case
+    /// it receives `nil` for them.
+    pub fn define_native_with_optional(
+        &mut self,
+        name: &str,
+        arity: usize,
+        optional: usize,
+        function: NativeFn,
+    ) {
+        self.insert_native(name, arity, optional, function, true);
+    }
+
+    /// Defines a native function whose result depends on more than its arguments and the
+    /// program's state, like the time or user input, see [VM::audit_determinism].
+    pub fn define_nondeterministic_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        self.insert_native(name, arity, 0, function, false);
+    }
+
+    fn insert_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        optional: usize,
+        function: NativeFn,
+        deterministic: bool,
+    ) {
         let native = NativeFunction {
-            arity: 0,
+            arity,
+            optional,
             name: name.to_string(),
             function,
+            deterministic,
         };
-        self.globals
-            .insert(name.to_string(), Value::NativeFunction(Rc::new(native)));
+        self.natives.insert(name.to_string());
+        let slot = self.global_slot(name);
+        self.globals[slot] = Some(Global {
+            value: Value::NativeFunction(Rc::new(native)),
+            read_only: false,
+        });
     }
 
+    #[allow(dead_code)]
     fn print_globals(&self) {
         println!("VM globals:");
-        self.globals.iter().for_each(|(global_name, global_value)| {
-            println!("\t{}: {}", global_name, global_value)
-        });
+        self.defined_globals()
+            .for_each(|(global_name, global)| println!("\t{}: {}", global_name, global.value));
         println!();
     }
 }
 
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
+    }
+}
+
+/// Rewrites the global slots used by a function and the functions among its constants, from the
+/// slots they were compiled with to the ones at the same index in `slots`, named in `names`.
+fn relink(function: &mut Function, slots: &[usize], names: &Rc<Vec<String>>) {
+    for instruction in &mut function.chunk.bytecode {
+        *instruction = match *instruction {
+            Instruction::OpDefineGlobal(slot) => Instruction::OpDefineGlobal(slots[slot]),
+            Instruction::OpGetGlobal(slot) => Instruction::OpGetGlobal(slots[slot]),
+            Instruction::OpSetGlobal(slot) => Instruction::OpSetGlobal(slots[slot]),
+            instruction => instruction,
+        };
+    }
+    function.chunk.globals = Rc::clone(names);
+    for constant in &mut function.chunk.constants {
+        if let Value::Function(function) = constant {
+            relink(Rc::make_mut(function), slots, names);
+        }
+    }
+}
+
+/// The method an instance's class defines with a name, if the value is an instance.
+fn instance_method(value: &Value, name: &str) -> Option<Method> {
+    match value {
+        Value::Instance(instance) => instance.borrow().class.methods.borrow().get(name).cloned(),
+        _ => None,
+    }
+}
+
 // TODO: move to value.rs
-fn is_falsey(v: &Value) -> bool {
+pub(crate) fn is_falsey(v: &Value) -> bool {
     match v {
         Value::Nil => true,
         Value::Boolean(b) => !b,
@@ -405,10 +2200,82 @@ fn is_falsey(v: &Value) -> bool {
     }
 }
 
-fn clock_native() -> Value {
+/// Makes the fields of an instance or the elements of a list read-only, and returns it. Other
+/// values can't be changed anyway, and are returned as they are.
+///
+/// Freezing is shallow: the values in the fields or elements can still change.
+fn freeze_native(_vm: &mut VM, args: &[Value]) -> Result<Value, VMError> {
+    match &args[0] {
+        Value::Instance(instance) => instance.borrow_mut().frozen = true,
+        Value::List(list) => list.frozen.set(true),
+        _ => {}
+    }
+    Ok(args[0].clone())
+}
+
+/// Registers a handler for an event dispatched by the host with [VM::dispatch].
+fn on_native(vm: &mut VM, args: &[Value]) -> Result<Value, VMError> {
+    let event = match &args[0] {
+        Value::String(event) => event.to_string(),
+        _ => {
+            vm.report(Diagnostic::EventNotString, &[]);
+            return Err(VMError::RuntimeError);
+        }
+    };
+    if !matches!(
+        args[1],
+        Value::Function(_) | Value::NativeFunction(_) | Value::BoundMethod(_) | Value::Class(_)
+    ) {
+        vm.report(Diagnostic::NotCallable, &[]);
+        return Err(VMError::RuntimeError);
+    }
+    vm.event_handlers
+        .entry(event)
+        .or_default()
+        .push(args[1].clone());
+    Ok(Value::Nil)
+}
+
+/// Reads the variable name argument of `watch` and `history`.
+fn variable_name(vm: &mut VM, value: &Value) -> Result<String, VMError> {
+    match value {
+        Value::String(name) => Ok(name.to_string()),
+        _ => {
+            vm.report(Diagnostic::VariableNameNotString, &[]);
+            Err(VMError::RuntimeError)
+        }
+    }
+}
+
+fn watch_native(vm: &mut VM, args: &[Value]) -> Result<Value, VMError> {
+    let name = variable_name(vm, &args[0])?;
+    vm.watch(&name);
+    Ok(Value::Nil)
+}
+
+/// Prints the values written to a watched global, one line each like `[line 3] count = 1`.
+fn history_native(vm: &mut VM, args: &[Value]) -> Result<Value, VMError> {
+    let name = variable_name(vm, &args[0])?;
+    let lines: Vec<String> = match vm.history(&name) {
+        Some(writes) => writes
+            .iter()
+            .map(|write| format!("[{}] {} = {}", write.location, name, write.value))
+            .collect(),
+        None => {
+            vm.report(Diagnostic::NotWatched, &[&name]);
+            return Err(VMError::RuntimeError);
+        }
+    };
+    for line in lines {
+        vm.print(Value::String(LoxString::from(line)))?;
+    }
+    Ok(Value::Nil)
+}
+
+fn clock_native(_vm: &mut VM, _args: &[Value]) -> Result<Value, VMError> {
     let time = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .expect("Native function error.")
         .as_secs_f64();
-    Value::Number(time as f64)
+    Ok(Value::Number(time))
 }